@@ -5,6 +5,35 @@ uint8_t rawhidData[255];
 word pwmA = 80 * 1; // 25% duty (0-320 = 0-100% duty cycle)
 // word pwmB = 288; // 90% duty (0-320 = 0-100% duty cycle)
 
+// Status LED, common cathode, on pins with PWM that timer 1 doesn't drive
+#define LED_RED_PIN 3
+#define LED_GREEN_PIN 5
+#define LED_BLUE_PIN 6
+// Half the period of a blinking LED
+#define LED_BLINK_MS 500
+
+// A channel set on its own keeps its speed through fan speed messages for
+// this long after the last time it was set, then goes back to following them,
+// so a host that stops driving it separately doesn't leave it stranded
+#define CHANNEL_HOLD_MS 120000UL
+
+// The speed every channel follows, and each channel's own (0-320)
+word commonSpeed = pwmA;
+word channelSpeed[2] = {pwmA, pwmA};
+bool channelSeparate[2] = {false, false};
+unsigned long channelSetAt[2] = {0, 0};
+
+// An active buzzer, sounding while the pin is high
+#define BUZZER_PIN 7
+
+uint8_t ledColour[3] = {0, 0, 0};
+bool ledBlink = false;
+bool ledLit = true;
+unsigned long ledToggledAt = 0;
+
+uint8_t buf_len = 0;
+uint8_t buf[64];
+
 void setup() {
     pinMode(9, OUTPUT);  //pwmA
     pinMode(10, OUTPUT); //pwmB
@@ -16,6 +45,12 @@ void setup() {
     TCCR1B |= _BV(CS10);   //no prescaler
     ICR1 = 320;            //PWM mode counts up 320 then down 320 counts (25kHz)
 
+    pinMode(LED_RED_PIN, OUTPUT);
+    pinMode(LED_GREEN_PIN, OUTPUT);
+    pinMode(LED_BLUE_PIN, OUTPUT);
+    pinMode(BUZZER_PIN, OUTPUT);
+    digitalWrite(BUZZER_PIN, LOW);
+
     OCR1A = pwmA;          //0-320 = 0-100% duty cycle
     TCCR1A |= _BV(COM1A1); //output A clear rising/set falling
 
@@ -30,28 +65,79 @@ void setup() {
     RawHID.write((uint8_t)0);
 }
 
+// Sets each output to its own speed while it's being driven separately, and
+// to the common speed otherwise.
+void applySpeeds() {
+    for (uint8_t channel = 0; channel < 2; channel++) {
+        if (channelSeparate[channel] && millis() - channelSetAt[channel] >= CHANNEL_HOLD_MS) {
+            channelSeparate[channel] = false;
+        }
+    }
+    OCR1A = channelSeparate[0] ? channelSpeed[0] : commonSpeed;
+    OCR1B = channelSeparate[1] ? channelSpeed[1] : commonSpeed;
+}
+
+void showLed() {
+    analogWrite(LED_RED_PIN, ledLit ? ledColour[0] : 0);
+    analogWrite(LED_GREEN_PIN, ledLit ? ledColour[1] : 0);
+    analogWrite(LED_BLUE_PIN, ledLit ? ledColour[2] : 0);
+}
 
 void loop() {
-    uint8_t buf_len = 0;
-    uint8_t buf[64];
-
-    // We expect to receive messages 64-bytes at a time.
-    while (buf_len < 64) {
-        int temp = RawHID.read();
-        if (temp != -1) {
-            buf[buf_len++] = temp;
-        }
+    if (ledBlink && millis() - ledToggledAt >= LED_BLINK_MS) {
+        ledLit = !ledLit;
+        ledToggledAt = millis();
+        showLed();
     }
+    applySpeeds();
+
+    // We expect to receive messages 64-bytes at a time. They're gathered a
+    // byte at a time as they come, so the LED keeps blinking in between.
+    int temp = RawHID.read();
+    if (temp == -1) {
+        return;
+    }
+    buf[buf_len++] = temp;
+    if (buf_len < 64) {
+        return;
+    }
+    buf_len = 0;
 
     if (buf[0] == 1) {
-        // Raw fan speed message (0-255)
+        // Raw fan speed message (0-255), for every output not being set on
+        // its own
         uint16_t speed = ((float)buf[1]) * 320.0 / 255.0;
-        OCR1A = speed;          //0-320 = 0-100% duty cycle
-        OCR1B = speed;          //0-320 = 0-100% duty cycle
+        commonSpeed = speed;    //0-320 = 0-100% duty cycle
+        applySpeeds();
         Serial.print("New speed ");
         Serial.print(speed);
         Serial.print("\n");
-#endif
+    } else if (buf[0] == 2) {
+        // Status LED: red, green, blue, then 1 to blink
+        ledColour[0] = buf[1];
+        ledColour[1] = buf[2];
+        ledColour[2] = buf[3];
+        ledBlink = buf[4] != 0;
+        ledLit = true;
+        ledToggledAt = millis();
+        showLed();
+    } else if (buf[0] == 3) {
+        // Buzzer: 1 to sound, 0 for silence
+        digitalWrite(BUZZER_PIN, buf[1] != 0 ? HIGH : LOW);
+    } else if (buf[0] == 4) {
+        // Fan speed for a single channel: 0 for pwmA, 1 for pwmB
+        uint16_t speed = ((float)buf[2]) * 320.0 / 255.0;
+        if (buf[1] < 2) {
+            channelSpeed[buf[1]] = speed;
+            channelSeparate[buf[1]] = true;
+            channelSetAt[buf[1]] = millis();
+            applySpeeds();
+        }
+        Serial.print("New channel speed ");
+        Serial.print(buf[1]);
+        Serial.print(" ");
+        Serial.print(speed);
+        Serial.print("\n");
     }  else {
         Serial.print("Unsupported message: ");
         Serial.print(buf[0]);
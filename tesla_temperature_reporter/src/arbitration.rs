@@ -0,0 +1,191 @@
+//! Deciding which of the competing speed requests actually gets applied.
+//!
+//! Precedence is manual override > emergency max > curve. Only one override is
+//! active at a time: the newest one replaces whatever was there before, and an
+//! override may expire, after which control goes back to the curve.
+//!
+//! A boost sits below the emergency max: it only ever speeds the fans up from
+//! what the curve asks for, and can't be set while an override is in force.
+//!
+//! After all of that comes the safety stage, which can't be turned off: at a
+//! critical temperature, or with a fan stalled, the fans run at full speed no
+//! matter who asked for what. That includes any noise cap placed on the curve.
+
+use std::time::Instant;
+
+use crate::telemetry::event;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpeedSource {
+    Curve,
+    Boost,
+    EmergencyMax,
+    Override,
+    Safety,
+}
+
+impl SpeedSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpeedSource::Curve => "curve",
+            SpeedSource::Boost => "boost",
+            SpeedSource::EmergencyMax => "emergency max",
+            SpeedSource::Override => "override",
+            SpeedSource::Safety => "safety",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Override {
+    pub speed: u8,
+    /// When set, the override lapses at this point
+    pub expires: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Arbiter {
+    current: Option<Override>,
+    boost: Option<Override>,
+    safety_engaged: bool,
+    /// Upper limit on what the curve may ask for
+    speed_cap: Option<u8>,
+}
+
+impl Arbiter {
+    pub fn new(speed_cap: Option<u8>) -> Self {
+        Arbiter {
+            current: None,
+            boost: None,
+            safety_engaged: false,
+            speed_cap,
+        }
+    }
+
+    /// Changes the cap, e.g. for quiet hours.
+    pub fn set_speed_cap(&mut self, speed_cap: Option<u8>) {
+        self.speed_cap = speed_cap;
+    }
+
+    pub fn set_override(&mut self, o: Override) {
+        self.current = Some(o);
+    }
+
+    /// Whether an override is in force.
+    pub fn has_override(&mut self) -> bool {
+        self.expire();
+        self.current.is_some()
+    }
+
+    /// Runs the fans at least at `boost.speed` until it expires, replacing any
+    /// boost before it. Refused while an override is in force.
+    pub fn set_boost(&mut self, boost: Override) -> Result<(), String> {
+        if self.has_override() {
+            Err("a speed override is in force")?
+        }
+        self.boost = Some(boost);
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        if let Some(Override { expires: Some(expires), .. }) = self.current {
+            if now >= expires {
+                event!("Speed override expired");
+                self.current = None;
+            }
+        }
+        if let Some(Override { expires: Some(expires), .. }) = self.boost {
+            if now >= expires {
+                event!("Boost expired");
+                self.boost = None;
+            }
+        }
+    }
+
+    /// `emergency` is the speed to run at when something's wrong, if it is.
+    pub fn decide(&mut self, curve_speed: u8, emergency: Option<u8>) -> (u8, SpeedSource) {
+        self.expire();
+
+        if let Some(o) = self.current {
+            (o.speed, SpeedSource::Override)
+        } else if let Some(speed) = emergency {
+            (speed, SpeedSource::EmergencyMax)
+        } else {
+            let speed = self.speed_cap.map_or(curve_speed, |cap| curve_speed.min(cap));
+            match self.boost {
+                Some(boost) if boost.speed > speed => (boost.speed, SpeedSource::Boost),
+                _ => (speed, SpeedSource::Curve),
+            }
+        }
+    }
+
+    /// The final stage, applied after everything else. A stalled fan has
+    /// already raised its own alert, so only a critical temperature is
+    /// logged here.
+    pub fn enforce_safety(&mut self, speed: u8, source: SpeedSource, critical: bool, fan_stalled: bool) -> (u8, SpeedSource) {
+        if critical != self.safety_engaged {
+            self.safety_engaged = critical;
+            if critical {
+                event!(
+                    "SAFETY: critical temperature reached, forcing fans to max (was {} from {})",
+                    speed,
+                    source.name()
+                );
+                if let Some(cap) = self.speed_cap {
+                    event!("SAFETY: speed cap of {} released", cap);
+                }
+            } else {
+                event!("SAFETY: temperature back below critical, releasing fans");
+                if let Some(cap) = self.speed_cap {
+                    event!("SAFETY: speed cap of {} back in effect", cap);
+                }
+            }
+        }
+
+        if critical || fan_stalled {
+            (255, SpeedSource::Safety)
+        } else {
+            (speed, source)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lasting(speed: u8) -> Override {
+        Override { speed, expires: None }
+    }
+
+    #[test]
+    fn boost_only_speeds_the_curve_up() {
+        let mut arbiter = Arbiter::new(Some(150));
+        arbiter.set_boost(lasting(200)).unwrap();
+        assert_eq!(arbiter.decide(100, None), (200, SpeedSource::Boost));
+        let mut arbiter = Arbiter::new(None);
+        arbiter.set_boost(lasting(120)).unwrap();
+        assert_eq!(arbiter.decide(180, None), (180, SpeedSource::Curve));
+    }
+
+    #[test]
+    fn boost_gives_way_to_emergencies_and_overrides() {
+        let mut arbiter = Arbiter::new(None);
+        arbiter.set_boost(lasting(255)).unwrap();
+        assert_eq!(arbiter.decide(100, Some(200)), (200, SpeedSource::EmergencyMax));
+        arbiter.set_override(lasting(90));
+        assert_eq!(arbiter.decide(100, None), (90, SpeedSource::Override));
+        assert!(arbiter.set_boost(lasting(255)).is_err());
+    }
+
+    #[test]
+    fn safety_beats_everything() {
+        let mut arbiter = Arbiter::new(Some(100));
+        arbiter.set_override(lasting(50));
+        let (speed, source) = arbiter.decide(80, None);
+        assert_eq!(arbiter.enforce_safety(speed, source, true, false), (255, SpeedSource::Safety));
+        assert_eq!(arbiter.enforce_safety(speed, source, false, true), (255, SpeedSource::Safety));
+        assert_eq!(arbiter.enforce_safety(speed, source, false, false), (50, SpeedSource::Override));
+    }
+}
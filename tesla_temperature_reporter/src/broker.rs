@@ -0,0 +1,290 @@
+//! Splitting the HID device off from everything else, so the part that talks
+//! to NVML, the network and the config can run without access to devices.
+//!
+//! `broker` runs as the user that can open the fan controller, and does nothing
+//! but own it and answer one request line at a time on a Unix socket:
+//!
+//! - `set <duty>`: sets every fan output, answered with `ok`
+//! - `rpm`: the last RPM the controller sent for each channel, as
+//!   `rpm <channel>=<rpm> ...`, leaving out channels it hasn't heard from
+//!   lately
+//!
+//! Anything that goes wrong is answered with `error: <message>`. `run
+//! --broker <socket>` then drives the fans through it.
+//!
+//! Both ends give up on the other after a timeout, so a client that stops
+//! talking can't keep the broker to itself, and a broker that stops answering
+//! shows up in the controller as a lost output rather than a hang.
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use hidapi::{HidApi, HidDevice};
+
+use crate::controller::{ReportFormat, ReportTemplate, open_controllers};
+use crate::output::RPM_MAX_AGE;
+use crate::telemetry::event;
+
+/// How long a client may go without sending a request before it's dropped
+/// for the next one. Longer than any sensible --update-interval.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long either end waits on a write, or the controller on an answer.
+/// Reopening the controller after it's been unplugged is the slowest answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The broker's end: owns the fan controllers, opening them again whenever
+/// one has been unplugged.
+struct Broker {
+    hidapi: HidApi,
+    /// Serial numbers or HID paths, as for `open_controllers`
+    selectors: Vec<String>,
+    devices: Option<Vec<HidDevice>>,
+    format: ReportFormat,
+    template: ReportTemplate,
+    /// (controller, channel, rpm, when), as last heard from each controller
+    rpms: Vec<(usize, u8, u16, Instant)>,
+}
+
+/// The fan controllers, opening them first if they aren't already.
+fn open<'a>(
+    hidapi: &mut HidApi,
+    format: &mut ReportFormat,
+    selectors: &[String],
+    devices: &'a mut Option<Vec<HidDevice>>,
+) -> Result<&'a [HidDevice], Box<dyn Error>> {
+    if devices.is_none() {
+        let _ = hidapi.refresh_devices();
+        let opened = open_controllers(hidapi, format, selectors)?;
+        event!("Fan controller connected");
+        *devices = Some(opened);
+    }
+    Ok(devices.as_deref().expect("just opened"))
+}
+
+impl Broker {
+    fn set(&mut self, speed: u8) -> Result<(), Box<dyn Error>> {
+        let devices = open(&mut self.hidapi, &mut self.format, &self.selectors, &mut self.devices)?;
+        let written = devices.iter()
+            .try_for_each(|device| self.format.write_template(device, &self.template, speed).map(drop));
+        if let Err(e) = written {
+            self.devices = None;
+            self.rpms.clear();
+            Err(format!("Error updating fan controller: {}", e))?
+        }
+        Ok(())
+    }
+
+    /// With several controllers, each channel's slowest fan, so one that
+    /// stalls on any of them shows up.
+    fn rpm(&mut self) -> Result<String, Box<dyn Error>> {
+        let devices = open(&mut self.hidapi, &mut self.format, &self.selectors, &mut self.devices)?;
+        let mut heard = vec![];
+        let drained = devices.iter().enumerate().try_for_each(|(i, device)| loop {
+            match self.format.read_rpm(device) {
+                Ok(Some((channel, rpm))) => heard.push((i, channel, rpm)),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        });
+        if let Err(e) = drained {
+            self.devices = None;
+            self.rpms.clear();
+            Err(format!("Error reading fan controller: {}", e))?
+        }
+        let now = Instant::now();
+        for (board, channel, rpm) in heard {
+            match self.rpms.iter_mut().find(|(b, c, _, _)| (*b, *c) == (board, channel)) {
+                Some(known) => (known.2, known.3) = (rpm, now),
+                None => self.rpms.push((board, channel, rpm, now)),
+            }
+        }
+        self.rpms.retain(|(_, _, _, heard)| now.duration_since(*heard) <= RPM_MAX_AGE);
+        let mut slowest: Vec<(u8, u16)> = vec![];
+        for &(_, channel, rpm, _) in &self.rpms {
+            match slowest.iter_mut().find(|(c, _)| *c == channel) {
+                Some(known) => known.1 = known.1.min(rpm),
+                None => slowest.push((channel, rpm)),
+            }
+        }
+        slowest.sort();
+        let mut answer = String::from("rpm");
+        for (channel, rpm) in slowest {
+            answer += &format!(" {}={}", channel, rpm);
+        }
+        Ok(answer)
+    }
+
+    fn answer(&mut self, request: &str) -> String {
+        let mut words = request.split_whitespace();
+        let answer = match (words.next(), words.next(), words.next()) {
+            (Some("set"), Some(speed), None) => match speed.parse() {
+                Ok(speed) => self.set(speed).map(|()| "ok".to_string()),
+                Err(e) => Err(format!("bad duty {:?}: {}", speed, e).into()),
+            },
+            (Some("rpm"), None, _) => self.rpm(),
+            _ => Err(format!("unknown request {:?}", request).into()),
+        };
+        answer.unwrap_or_else(|e| format!("error: {}", e))
+    }
+}
+
+/// The ID of `group`, given by name or number.
+pub fn group_id(group: &str) -> Result<u32, Box<dyn Error>> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid)
+    }
+    let groups = std::fs::read_to_string("/etc/group")
+        .map_err(|e| format!("Failed to read /etc/group: {}", e))?;
+    groups.lines()
+        .map(|line| line.split(':'))
+        .find_map(|mut fields| {
+            let name = fields.next()?;
+            let gid = fields.nth(1)?;
+            (name == group).then(|| gid.parse().ok()).flatten()
+        })
+        .ok_or_else(|| format!("No group {:?} in /etc/group", group).into())
+}
+
+/// Serves requests on `path` until killed. The socket is left readable and
+/// writable by its group, `group` if given, so the controller can run as a
+/// member of it.
+pub fn serve(
+    path: &Path,
+    group: Option<u32>,
+    format: ReportFormat,
+    template: ReportTemplate,
+    selectors: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let hidapi = HidApi::new()
+        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+    let mut broker = Broker {
+        hidapi,
+        selectors,
+        devices: None,
+        format,
+        template,
+        rpms: vec![],
+    };
+    if let Err(e) = open(&mut broker.hidapi, &mut broker.format, &broker.selectors, &mut broker.devices) {
+        event!("{}", e);
+    }
+
+    // Left behind by a previous run that didn't shut down cleanly. Anything
+    // other than a socket there is somebody's file, and left for bind to fail on.
+    let stale = std::fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_type().is_socket());
+    if stale && UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    if let Some(gid) = group {
+        std::os::unix::fs::chown(path, None, Some(gid))
+            .map_err(|e| format!("Failed to give {} to group {}: {}", path.display(), gid, e))?;
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+    event!("Listening on {}", path.display());
+
+    // One client at a time: there's only the one controller to drive the fans
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                event!("Failed to accept a connection: {}", e);
+                continue
+            },
+        };
+        let timeouts = stream.set_read_timeout(Some(CLIENT_IDLE_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(REQUEST_TIMEOUT)));
+        if let Err(e) = timeouts {
+            event!("Failed to set timeouts on a connection: {}", e);
+            continue
+        }
+        let reader = BufReader::new(stream.try_clone()?);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) {
+                        event!("Dropping a client that sent nothing for {:?}", CLIENT_IDLE_TIMEOUT);
+                    }
+                    break
+                },
+            };
+            let answer = broker.answer(line.trim());
+            if writeln!(stream, "{}", answer).is_err() {
+                break
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The controller's end: drives the fans through a broker.
+pub struct BrokerOutput {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+    /// Set once the connection can't be trusted to line answers up with
+    /// requests any more, after a timeout or the broker hanging up
+    broken: bool,
+}
+
+impl BrokerOutput {
+    pub fn connect(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let stream = UnixStream::connect(path)
+            .map_err(|e| format!("Failed to connect to broker at {}: {}", path.display(), e))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(BrokerOutput { stream, reader, broken: false })
+    }
+
+    /// Whether the connection is still usable; a broken one gets dropped and
+    /// made again.
+    pub fn is_connected(&self) -> bool {
+        !self.broken
+    }
+
+    fn request(&mut self, request: std::fmt::Arguments) -> Result<String, Box<dyn Error>> {
+        let mut line = String::new();
+        let exchanged = self.stream.write_fmt(request)
+            .and_then(|()| self.stream.write_all(b"\n"))
+            .and_then(|()| self.reader.read_line(&mut line));
+        match exchanged {
+            Ok(0) => {
+                self.broken = true;
+                Err("broker hung up")?
+            },
+            Ok(_) => (),
+            Err(e) => {
+                self.broken = true;
+                Err(format!("broker didn't answer within {:?}: {}", REQUEST_TIMEOUT, e))?
+            },
+        }
+        match line.trim().strip_prefix("error: ") {
+            Some(error) => Err(format!("broker reported: {}", error))?,
+            None => Ok(line.trim().to_string()),
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: u8) -> Result<(), Box<dyn Error>> {
+        self.request(format_args!("set {}", speed)).map(drop)
+    }
+
+    /// (channel, rpm) as the broker last heard from the controller.
+    pub fn rpm(&mut self) -> Result<Vec<(u8, u16)>, Box<dyn Error>> {
+        let answer = self.request(format_args!("rpm"))?;
+        let rpms = answer.strip_prefix("rpm").ok_or_else(|| format!("unexpected answer {:?}", answer))?;
+        rpms.split_whitespace()
+            .map(|field| {
+                let (channel, rpm) = field.split_once('=')
+                    .ok_or_else(|| format!("Missing '=' in {:?}", field))?;
+                Ok((channel.parse()?, rpm.parse()?))
+            })
+            .collect()
+    }
+}
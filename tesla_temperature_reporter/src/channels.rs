@@ -0,0 +1,177 @@
+//! Driving each of the controller's fan channels from its own GPU (or group of
+//! GPUs), for firmware that runs its outputs separately.
+//!
+//! The main loop still looks at all the mapped GPUs together for everything
+//! safety related; while it's following the curve, each channel instead
+//! follows its own GPU through its own curve and history, worked out the same
+//! way as the main loop's (see `decision`).
+//!
+//! A channel can also take its speed from an expression over the GPU fans'
+//! speed, the readings and the other outputs, e.g. for chassis intake fans
+//! that should scale with the blower.
+
+use std::error::Error;
+
+use crate::expr::Expr;
+use crate::gpu::{Combine, Gpu};
+use crate::decision::{self, History};
+use crate::{FanSpeedTable, PowerCurves, StartupHistory, Tunables};
+
+/// Which GPU drives which channel, written as e.g. "1=GPU-...".
+#[derive(Clone, Debug)]
+pub struct ChannelMapping {
+    pub channel: u8,
+    /// NVML indexes, PCI bus IDs or UUIDs
+    pub gpus: Vec<String>,
+    pub combine: Combine,
+    /// The channel's own curve, if it shouldn't use the main one
+    pub fan_curve: Option<FanSpeedTable>,
+    /// Duty added to (or taken off) whatever the curve asks for, e.g. for a
+    /// fan that's louder than the rest
+    pub offset: i16,
+}
+
+impl std::str::FromStr for ChannelMapping {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channel, gpu) = s.split_once('=')
+            .ok_or("channel mapping needs a channel and a GPU, e.g. 1=0")?;
+        Ok(ChannelMapping {
+            channel: channel.trim().parse()?,
+            gpus: vec![gpu.trim().to_string()],
+            combine: Combine::default(),
+            fan_curve: None,
+            offset: 0,
+        })
+    }
+}
+
+pub struct FanChannel<'nvml> {
+    pub channel: u8,
+    gpu: Gpu<'nvml>,
+    /// The channel's own curve; otherwise it follows the main one
+    curve: Option<FanSpeedTable>,
+    offset: i16,
+    history: History,
+}
+
+impl<'nvml> FanChannel<'nvml> {
+    pub fn new(
+        channel: u8,
+        gpu: Gpu<'nvml>,
+        curve: Option<FanSpeedTable>,
+        offset: i16,
+        samples: usize,
+        startup: StartupHistory,
+    ) -> Result<Self, Box<dyn Error>> {
+        let reading = gpu.reading()?;
+        Ok(FanChannel {
+            channel,
+            gpu,
+            curve,
+            offset,
+            history: History::new(samples, startup, reading.temp, reading.power_fraction()),
+        })
+    }
+
+    /// Samples the channel's GPU and returns the speed its curve asks for,
+    /// offset, with the same boost and critical thresholds as the main loop.
+    /// Without a curve of its own it follows `curves`, the main loop's as
+    /// rescaled for the current power limit.
+    pub fn update(&mut self, tunables: &Tunables, curves: &PowerCurves, quiet: bool) -> Result<u8, Box<dyn Error>> {
+        let reading = self.gpu.reading()?;
+        let temp = reading.hottest(&tunables.temp_sensors);
+        let window = self.history.push(temp, reading.power_fraction(), tunables.ema_alpha);
+        let power_speed = match &self.curve {
+            Some(curve) => Some(curve.lookup_speed(window.average_power)),
+            None => tunables.follow_power.then(|| curves.get(quiet).lookup_speed(window.average_power)),
+        };
+        let speed = decision::curves_speed(tunables, temp, power_speed);
+        Ok(decision::decide(tunables, &window, speed, self.offset as f64).speed)
+    }
+}
+
+/// A channel whose speed comes from an expression (see `expr`), e.g. chassis
+/// intake fans at `max(40, 0.6 * gpu)`. Expressions can use:
+///
+/// - `gpu`: the speed of the GPU fans, in duty counts
+/// - `temp`: the GPU temperature in degrees C
+/// - `power`: the GPU's power draw, as a percentage of its limit
+/// - `out_<name>`: the speed of an output listed before this one
+#[derive(Clone, Debug)]
+pub struct DerivedChannel {
+    pub name: String,
+    pub channel: u8,
+    speed: Expr,
+}
+
+impl DerivedChannel {
+    pub fn new(name: String, channel: u8, speed: Expr) -> Self {
+        DerivedChannel {
+            name,
+            channel,
+            speed,
+        }
+    }
+
+    /// Runs at `ratio` times the GPU fans' speed plus `floor`.
+    pub fn intake(channel: u8, ratio: f64, floor: u8) -> Result<Self, Box<dyn Error>> {
+        if !(ratio >= 0.0 && ratio.is_finite()) {
+            Err("intake ratio can't be negative")?
+        }
+        let speed = format!("{} * gpu + {}", ratio, floor).parse()?;
+        Ok(DerivedChannel::new("intake".to_string(), channel, speed))
+    }
+
+    /// Checks that every output has its own name and only refers to the
+    /// outputs before it.
+    pub fn check_all(outputs: &[DerivedChannel]) -> Result<(), Box<dyn Error>> {
+        for (i, output) in outputs.iter().enumerate() {
+            if outputs[..i].iter().any(|other| other.name == output.name) {
+                Err(format!("There's more than one output called {}", output.name))?
+            }
+            for var in output.speed.variables() {
+                let known = match var.strip_prefix("out_") {
+                    Some(name) => outputs[..i].iter().any(|other| other.name == name),
+                    None => matches!(var, "gpu" | "temp" | "power"),
+                };
+                if !known {
+                    Err(format!(
+                        "Output {} uses {}, which isn't gpu, temp, power or an out_ listed before it",
+                        output.name, var
+                    ))?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every output's speed, in order. An output that can't be worked out,
+    /// because a reading is missing, runs at full speed.
+    pub fn speeds(
+        outputs: &[DerivedChannel],
+        gpu_fan_speed: u8,
+        temp: Option<u32>,
+        power: Option<f64>,
+        speeds: &mut Vec<u8>,
+    ) {
+        speeds.clear();
+        for output in outputs {
+            let speed = output.speed.eval(|var| match var {
+                "gpu" => Some(gpu_fan_speed as f64),
+                "temp" => temp.map(f64::from),
+                "power" => power.map(|power| power * 100.0),
+                _ => {
+                    let name = var.strip_prefix("out_")?;
+                    let j = outputs.iter().position(|other| other.name == name)?;
+                    speeds.get(j).map(|&speed| speed as f64)
+                },
+            });
+            speeds.push(match speed {
+                Some(speed) if !speed.is_nan() => speed.clamp(0.0, 255.0) as u8,
+                _ => 255,
+            });
+        }
+    }
+}
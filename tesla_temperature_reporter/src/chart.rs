@@ -0,0 +1,106 @@
+//! Drawing the recorded history as an SVG, for sharing what happened overnight
+//! without a monitoring stack.
+//!
+//! Reads either a `--telemetry-log` CSV or a debug bundle, and plots
+//! temperature, power and fan speed on the one 0-100 scale: degrees C for the
+//! temperature and percent for the others.
+
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use crate::telemetry::LoggedSample;
+
+const WIDTH: f64 = 960.0;
+const HEIGHT: f64 = 400.0;
+/// Room around the plot for the labels
+const MARGIN: f64 = 48.0;
+/// (name, colour) of each series, in the order of `values`
+const SERIES: &[(&str, &str)] = &[
+    ("temperature (C)", "#d62728"),
+    ("power (%)", "#1f77b4"),
+    ("fan (%)", "#2ca02c"),
+];
+
+/// Temperature, power and fan speed on the chart's scale, each missing when it
+/// wasn't read.
+fn values(sample: &LoggedSample) -> [Option<f64>; 3] {
+    [
+        sample.temp.map(f64::from),
+        sample.power.map(|power| power * 100.0),
+        sample.speed.map(|speed| speed as f64 / 255.0 * 100.0),
+    ]
+}
+
+/// Plots the last `since` of the history in `input`, counting back from its
+/// newest sample, to `out`.
+pub fn render(input: &Path, since: Duration, out: &Path) -> Result<(), Box<dyn Error>> {
+    if out.extension().is_some_and(|extension| !extension.eq_ignore_ascii_case("svg")) {
+        Err("Charts are drawn as SVG; give --out a .svg name and convert it if you need a PNG")?
+    }
+    let mut samples = LoggedSample::load(input)?;
+    let end = samples.last().ok_or_else(|| format!("No samples in {}", input.display()))?.time;
+    let start = end - chrono::Duration::from_std(since)?;
+    samples.retain(|sample| sample.time >= start);
+    let span = (end - start).num_milliseconds().max(1) as f64 / 1000.0;
+
+    let x = |time: NaiveDateTime| MARGIN + (time - start).num_milliseconds() as f64 / 1000.0 / span * (WIDTH - 2.0 * MARGIN);
+    let y = |value: f64| HEIGHT - MARGIN - value.clamp(0.0, 100.0) / 100.0 * (HEIGHT - 2.0 * MARGIN);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">
+<rect width="{w}" height="{h}" fill="white"/>
+"#,
+        w = WIDTH,
+        h = HEIGHT,
+    );
+    for value in (0..=100).step_by(20) {
+        svg += &format!(
+            "<line x1=\"{}\" y1=\"{y:.1}\" x2=\"{}\" y2=\"{y:.1}\" stroke=\"#ddd\"/><text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+            MARGIN,
+            WIDTH - MARGIN,
+            MARGIN - 6.0,
+            y(value as f64) + 4.0,
+            value,
+            y = y(value as f64),
+        );
+    }
+    for (time, anchor) in [(start, "start"), (end, "end")] {
+        svg += &format!(
+            "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"{}\">{}</text>\n",
+            x(time),
+            HEIGHT - MARGIN + 18.0,
+            anchor,
+            time.format("%Y-%m-%d %H:%M"),
+        );
+    }
+    for (i, (name, colour)) in SERIES.iter().enumerate() {
+        // A gap in the readings breaks the line rather than bridging it
+        let mut path = String::new();
+        let mut pen_down = false;
+        for sample in &samples {
+            match values(sample)[i] {
+                Some(value) => {
+                    path += &format!("{}{:.1},{:.1} ", if pen_down { "L" } else { "M" }, x(sample.time), y(value));
+                    pen_down = true;
+                },
+                None => pen_down = false,
+            }
+        }
+        svg += &format!("<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>\n", path.trim_end(), colour);
+        let legend_x = MARGIN + i as f64 * 160.0;
+        svg += &format!(
+            "<rect x=\"{:.1}\" y=\"16\" width=\"12\" height=\"12\" fill=\"{}\"/><text x=\"{:.1}\" y=\"26\">{}</text>\n",
+            legend_x,
+            colour,
+            legend_x + 18.0,
+            name,
+        );
+    }
+    svg += "</svg>\n";
+    std::fs::write(out, svg)
+        .map_err(|e| format!("Failed to write {}: {}", out.display(), e))?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Output backend for the Corsair Commander Pro fan hub. The protocol is the
+//! one documented by the Linux kernel's corsair-cpro driver.
+
+use std::error::Error;
+
+use hidapi::{HidApi, HidDevice};
+
+use crate::output::LoadSharing;
+
+pub const COMMANDER_PRO_VID: u16 = 0x1b1c;
+pub const COMMANDER_PRO_PID: u16 = 0x0c10;
+
+// [0x23, channel, percent]
+const CTL_SET_FAN_FPWM: u8 = 0x23;
+
+const OUT_BUFFER_SIZE: usize = 63;
+const IN_BUFFER_SIZE: usize = 16;
+const RESPONSE_TIMEOUT_MS: i32 = 500;
+
+/// Fan channels cooling the same card, which share its speed between them,
+/// given as "0,1" or "0,1:staged". Without a policy of its own the group
+/// takes the default one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FanGroup {
+    pub channels: Vec<u8>,
+    pub sharing: Option<LoadSharing>,
+}
+
+impl std::str::FromStr for FanGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channels, sharing) = match s.split_once(':') {
+            Some((channels, sharing)) => (channels, Some(sharing.parse()?)),
+            None => (s, None),
+        };
+        let channels = channels.split(',')
+            .map(|channel| channel.trim().parse()
+                .map_err(|e| format!("Bad Commander Pro channel {}: {}", channel, e)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        Ok(FanGroup { channels, sharing })
+    }
+}
+
+pub struct CommanderPro {
+    device: HidDevice,
+    groups: Vec<FanGroup>,
+    sharing: LoadSharing,
+}
+
+impl CommanderPro {
+    /// Opens the hub to drive `groups`, where `sharing` is the policy for
+    /// those that don't give one.
+    pub fn open(
+        hidapi: &HidApi,
+        groups: &[FanGroup],
+        sharing: LoadSharing,
+    ) -> Result<Self, Box<dyn Error>> {
+        check_groups(groups)?;
+        let device = hidapi.open(COMMANDER_PRO_VID, COMMANDER_PRO_PID)
+            .map_err(|e| format!("Failed to find Commander Pro: {}", e))?;
+        Ok(CommanderPro { device, groups: groups.to_vec(), sharing })
+    }
+
+    fn command(&self, cmd: &[u8]) -> Result<[u8; IN_BUFFER_SIZE], Box<dyn Error>> {
+        // The hub doesn't use report IDs, so lead with a 0
+        let mut buf = [0u8; OUT_BUFFER_SIZE + 1];
+        buf[1..1 + cmd.len()].copy_from_slice(cmd);
+        self.device.write(&buf[..])?;
+
+        let mut response = [0u8; IN_BUFFER_SIZE];
+        if self.device.read_timeout(&mut response[..], RESPONSE_TIMEOUT_MS)? == 0 {
+            Err("Commander Pro did not respond")?
+        }
+        if response[0] != 0 {
+            Err(format!("Commander Pro rejected command {:#04x}: status {:#04x}", cmd[0], response[0]))?
+        }
+        Ok(response)
+    }
+
+    pub fn set_speed(&self, speed: u8) -> Result<(), Box<dyn Error>> {
+        for group in &self.groups {
+            let duties = group.sharing.unwrap_or(self.sharing).duties(speed, group.channels.len());
+            for (channel, duty) in group.channels.iter().zip(duties) {
+                // The hub takes a percentage rather than a raw duty
+                let percent = (duty as u32 * 100 / 255) as u8;
+                self.command(&[CTL_SET_FAN_FPWM, *channel, percent])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks every channel is one the hub has and appears in only one group.
+fn check_groups(groups: &[FanGroup]) -> Result<(), Box<dyn Error>> {
+    let mut seen = Vec::new();
+    for channel in groups.iter().flat_map(|group| &group.channels) {
+        if *channel > 5 {
+            Err(format!("Commander Pro only has fan channels 0-5, not {}", channel))?
+        }
+        if seen.contains(channel) {
+            Err(format!("Commander Pro channel {} is in more than one fan group", channel))?
+        }
+        seen.push(*channel);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_group_shares_its_own_speed() {
+        let groups: Vec<FanGroup> = ["0,1:staged", "2,3"].iter().map(|g| g.parse().unwrap()).collect();
+        check_groups(&groups).unwrap();
+        assert_eq!(groups[0], FanGroup { channels: vec![0, 1], sharing: Some(LoadSharing::Staged) });
+        let duties: Vec<Vec<u8>> = groups.iter()
+            .map(|group| group.sharing.unwrap_or(LoadSharing::Split).duties(150, group.channels.len()).collect())
+            .collect();
+        assert_eq!(duties, vec![vec![255, 45], vec![150, 150]]);
+    }
+
+    #[test]
+    fn channels_belong_to_one_group() {
+        let groups: Vec<FanGroup> = ["0,1", "1"].iter().map(|g| g.parse().unwrap()).collect();
+        assert!(check_groups(&groups).is_err());
+        assert!(check_groups(&["6".parse().unwrap()]).is_err());
+        assert!("0:loud".parse::<FanGroup>().is_err());
+    }
+}
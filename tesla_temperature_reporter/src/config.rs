@@ -0,0 +1,772 @@
+//! Settings loaded from a TOML file with `--config`.
+//!
+//! Anything given on the command line wins over the file, and anything in
+//! neither falls back to the usual defaults. The file is read again on SIGHUP
+//! or whenever it changes, though only the fan curves, temperature thresholds
+//! and deadband take effect without a restart. Lengths of time, temperatures
+//! and powers can be written with their units, as in "500ms", "80C" or
+//! "180W", or as bare seconds, degrees C and watts.
+//!
+//! `config_version` says which layout the file is written in. A file in an
+//! older layout, or with no `config_version` at all (version 1), is migrated
+//! as it's loaded, with a warning for each key that had to change:
+//!
+//! - 2: `uuid` is now `gpu`
+//!
+//! For example:
+//!
+//! ```toml
+//! config_version = 2
+//! gpu = "GPU-b60cae4e-f524-14a8-2233-2dc2126b6754"
+//! update_interval = "5s"
+//! # Sample on multiples of update_interval, so several machines line up
+//! align_samples = true
+//! fan_curve = [[0.3, 0], [0.4, 70], [0.6, 120], [0.95, 255]]
+//! # Optional: (degrees C, fan speed) points. With fan_curve as well, the fan
+//! # runs at whichever speed is higher; without, this replaces it.
+//! temp_curve = [[40, 0], [60, 120], [75, 255]]
+//! # Optional: "linear", "step" or "cubic" between the points of each curve
+//! fan_curve_interpolation = "cubic"
+//! # Optional: below the first and above the last point of fan_curve, "ramp"
+//! # to off at no power and full speed at the limit, "clamp" to the end
+//! # point, "extrapolate" along the end points, or "max"
+//! fan_curve_below = "clamp"
+//! fan_curve_above = "max"
+//! temp_curve_interpolation = "step"
+//! # Optional: also follow the memory temperature, on cards that report it
+//! temp_sensors = ["gpu", "memory"]
+//! critical_temp = "77C"
+//! boost_temp = "72C"
+//! boost_amount = 50
+//! # Optional: at least this duty while the GPU throttles itself for heat
+//! throttle_boost = 220
+//! # Optional: if the fans are flat out and the GPU still reaches 83C, take
+//! # 10 W off its power limit every 30 seconds until it stops climbing
+//! power_guard_temp = "83C"
+//! power_guard_step = "10W"
+//! # Optional: smooth with an exponential moving average instead of a one
+//! # minute window
+//! ema_alpha = 0.3
+//! # Optional: when utilization jumps, spin up as if power had gone straight
+//! # to 90% of the limit at full utilization, until the average catches up
+//! utilization_lead = 0.9
+//! # Optional: 30 more duty while more than 2 jobs are running or
+//! # utilization is over 90%, before the heat from them arrives
+//! busy_bias = 30
+//! busy_processes = 2
+//! busy_utilization = 90
+//! # Warn when a cycle takes more than this fraction of update_interval
+//! latency_budget = 0.5
+//! # Keep the last speed through this many failed reads, then go to failsafe_speed
+//! hold_on_error = 2
+//! failsafe_speed = 255
+//! # Needed for a failsafe_speed under 128
+//! allow_slow_failsafe = false
+//! # Duty counts ("12"), a percentage ("5%") or "off"
+//! deadband = "5%"
+//! # Optional: stop the fan below 45C, start it again at 50C with a kick
+//! fan_stop = "45:50"
+//! kick_start_duty = 200
+//! # Optional: wander 2 duty either side of the speed over 30 seconds, for
+//! # fans that whine at some steady duties
+//! dither = 2
+//! dither_period = "30s"
+//! # Optional: run the fans 3 duty faster per degree the intake air is over
+//! # 25C (and slower under), from an hwmon sensor as lm-sensors names it
+//! ambient_sensor = "nct6775:SYSTIN"
+//! ambient_reference = "25C"
+//! ambient_bias = 3.0
+//! # Optional: add 50 duty to every fan while the exhaust air is over 45C,
+//! # for the PSU and drives sharing the case
+//! chassis_sensor = "nct6775:AUXTIN0"
+//! chassis_temp = "45C"
+//! chassis_boost = 50
+//! logging = true
+//! # Optional: turn on persistence mode for GPUs without it (Linux, as root)
+//! enable_persistence = true
+//! # Optional: read back each fan's RPM from the controller
+//! read_rpm = true
+//! # Optional: run every fan flat out and raise an alert once a fan has been
+//! # stopped, or under a quarter of the 3000 RPM it manages at full duty, for
+//! # 3 cycles
+//! stall_cycles = 3
+//! max_rpm = 3000
+//! # Optional: only log a decision when it changes, or once a minute at 1 s
+//! log_every = 60
+//! # Optional: between these local hours, follow a gentler curve and cap the
+//! # fans, e.g. for overnight jobs next to a bedroom
+//! quiet_hours = "22-7"
+//! quiet_fan_curve = [[0.3, 0], [0.6, 80], [0.95, 160]]
+//! quiet_max_speed = 180
+//! # Move between the normal and quiet curves over this long [default: 60s]
+//! profile_transition = "2m"
+//!
+//! # Optional: drive each of the controller's fan channels (0 and 1) from its
+//! # own GPU
+//! [[channel]]
+//! channel = 0
+//! gpu = "teslas"
+//! fan_curve = [[0.2, 0], [0.5, 100], [0.9, 255]]
+//! fan_curve_interpolation = "linear"
+//!
+//! # Optional: channels whose speed is worked out from the GPU fans' speed
+//! # (gpu), the temperature (temp), power as a percentage of the limit (power)
+//! # and the outputs listed before them (out_<name>), or fixed, as in "180"
+//! [[output]]
+//! name = "intake"
+//! channel = 1
+//! speed = "max(40, 0.6 * gpu)"
+//!
+//! # Optional: named sets of fan curve and thresholds. A profile can inherit
+//! # another's and override just some of it, down to single points of the
+//! # curve. Pick one at the top level with active_profile = "...", where the
+//! # keys above override it in turn, for the quiet curve with quiet_profile,
+//! # or for a channel with profile.
+//! [profile.balanced]
+//! fan_curve = [[0.3, 0], [0.4, 70], [0.6, 120], [0.95, 255]]
+//! critical_temp = 80
+//!
+//! [profile.aggressive]
+//! inherit = "balanced"
+//! boost_amount = 80
+//! points."0.8" = 220
+//!
+//! # Optional: for a home-built controller whose firmware has its own USB
+//! # IDs or lays the fan speed report out differently. The template gives the
+//! # bytes of the report after the report ID, with {speed} where the speed
+//! # goes, and the rest up to report_length is zero padding.
+//! [controller]
+//! vid = 0x16c0
+//! pid = 0x0486
+//! report_id = 0
+//! report_template = "0x01,0x00,{speed}"
+//! report_length = 32
+//!
+//! # Optional: several GPUs that can be named anywhere a GPU can, and act as
+//! # one, combining their readings with "max" or "average"
+//! [group.teslas]
+//! gpus = ["1", "2"]
+//! combine = "max"
+//!
+//! # Optional: where events go, by severity (debug, info, warning or
+//! # critical). Each severity not listed goes to the log and the ctl socket.
+//! [events]
+//! webhook = "https://example.com/hooks/fans"
+//!
+//! [events.severity]
+//! profile_switched = "warning"
+//!
+//! [events.route]
+//! debug = []
+//! warning = ["log", "ctl", "webhook"]
+//! critical = ["log", "ctl", "webhook"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::channels::{ChannelMapping, DerivedChannel};
+use crate::controller::{CHANNELS, ReportTemplate};
+use crate::gpu::{Combine, TempSource};
+use crate::telemetry::{Event, EventRoutes, Severity, Sink, event, json_string};
+use crate::units::{self, WithUnit};
+use crate::{Args, ReportArgs, Deadband, Extrapolation, FanSpeedTable, FanStop, Interpolation, QuietHours, TempCurve};
+
+/// The layout `Config` is in now.
+pub const CONFIG_VERSION: i64 = 2;
+
+/// Top level keys each version renamed, from what to what, indexed by the
+/// version before it.
+const RENAMED: &[&[(&str, &str)]] = &[
+    // 1 to 2
+    &[("uuid", "gpu")],
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// NVML index, PCI bus ID or UUID, or several separated by commas
+    gpu: Option<String>,
+    combine: Option<Combine>,
+    /// (fraction of the power limit, fan speed) points
+    fan_curve: Option<Vec<(f64, u8)>>,
+    /// (degrees C, fan speed) points
+    temp_curve: Option<Vec<(f64, u8)>>,
+    fan_curve_interpolation: Option<Interpolation>,
+    temp_curve_interpolation: Option<Interpolation>,
+    fan_curve_below: Option<Extrapolation>,
+    fan_curve_above: Option<Extrapolation>,
+    update_interval: Option<WithUnit>,
+    align_samples: Option<bool>,
+    temp_sensors: Option<Vec<TempSource>>,
+    critical_temp: Option<WithUnit>,
+    boost_temp: Option<WithUnit>,
+    boost_amount: Option<u8>,
+    throttle_boost: Option<u8>,
+    power_guard_temp: Option<WithUnit>,
+    power_guard_step: Option<WithUnit>,
+    ema_alpha: Option<f64>,
+    utilization_lead: Option<f64>,
+    busy_bias: Option<u8>,
+    busy_processes: Option<u32>,
+    busy_utilization: Option<u32>,
+    latency_budget: Option<f64>,
+    hold_on_error: Option<u32>,
+    failsafe_speed: Option<u8>,
+    allow_slow_failsafe: Option<bool>,
+    deadband: Option<String>,
+    fan_stop: Option<String>,
+    kick_start_duty: Option<u8>,
+    dither: Option<u8>,
+    dither_period: Option<WithUnit>,
+    ambient_sensor: Option<String>,
+    ambient_reference: Option<WithUnit>,
+    ambient_bias: Option<f64>,
+    chassis_sensor: Option<String>,
+    chassis_temp: Option<WithUnit>,
+    chassis_boost: Option<u8>,
+    logging: Option<bool>,
+    enable_persistence: Option<bool>,
+    read_rpm: Option<bool>,
+    stall_cycles: Option<u32>,
+    max_rpm: Option<u16>,
+    log_every: Option<std::num::NonZeroU32>,
+    quiet_hours: Option<String>,
+    /// (fraction of the power limit, fan speed) points
+    quiet_fan_curve: Option<Vec<(f64, u8)>>,
+    quiet_max_speed: Option<u8>,
+    profile_transition: Option<WithUnit>,
+    /// Profile the fan curve and thresholds above start from
+    active_profile: Option<String>,
+    /// Profile to take the quiet fan curve from
+    quiet_profile: Option<String>,
+    #[serde(rename = "profile")]
+    profiles: BTreeMap<String, ProfileConfig>,
+    #[serde(rename = "channel")]
+    channels: Vec<ChannelConfig>,
+    #[serde(rename = "group")]
+    groups: BTreeMap<String, GroupConfig>,
+    #[serde(rename = "output")]
+    outputs: Vec<OutputConfig>,
+    controller: ControllerConfig,
+    events: EventsConfig,
+}
+
+/// A fan curve and thresholds, possibly built on another profile's.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileConfig {
+    inherit: Option<String>,
+    /// (fraction of the power limit, fan speed) points
+    fan_curve: Option<Vec<(f64, u8)>>,
+    /// Fan speed by fraction of the power limit, replacing or adding to the
+    /// inherited curve's points
+    points: BTreeMap<String, u8>,
+    fan_curve_interpolation: Option<Interpolation>,
+    critical_temp: Option<WithUnit>,
+    boost_temp: Option<WithUnit>,
+    boost_amount: Option<u8>,
+}
+
+impl ProfileConfig {
+    /// `self` with whatever `child` gives taking its place. `name` is for
+    /// errors.
+    fn overridden_by(self, child: &ProfileConfig, name: &str) -> Result<ProfileConfig, Box<dyn Error>> {
+        let has_curve = child.fan_curve.is_some() || !child.points.is_empty() || self.fan_curve.is_some();
+        let mut fan_curve = child.fan_curve.clone()
+            .or(self.fan_curve)
+            .unwrap_or_else(|| crate::DEFAULT_FAN_SPEED.to_vec());
+        for (power, speed) in &child.points {
+            let power: f64 = power.parse()
+                .map_err(|e| format!("Bad point {} in {}: {}", power, name, e))?;
+            fan_curve.retain(|(p, _)| *p != power);
+            fan_curve.push((power, *speed));
+        }
+        fan_curve.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Ok(ProfileConfig {
+            inherit: None,
+            fan_curve: has_curve.then_some(fan_curve),
+            points: BTreeMap::new(),
+            fan_curve_interpolation: child.fan_curve_interpolation.or(self.fan_curve_interpolation),
+            critical_temp: child.critical_temp.clone().or(self.critical_temp),
+            boost_temp: child.boost_temp.clone().or(self.boost_temp),
+            boost_amount: child.boost_amount.or(self.boost_amount),
+        })
+    }
+}
+
+/// How to reach our own HID fan controller, for firmware other than ours.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ControllerConfig {
+    vid: Option<u16>,
+    pid: Option<u16>,
+    report_id: Option<u8>,
+    report_template: Option<String>,
+    report_length: Option<usize>,
+}
+
+/// Which typed events go where.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EventsConfig {
+    /// Severity of each event type, overriding its default
+    severity: BTreeMap<String, Severity>,
+    /// Sinks for each severity
+    route: BTreeMap<Severity, Vec<Sink>>,
+    webhook: Option<String>,
+}
+
+/// A channel driven by an expression.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputConfig {
+    name: String,
+    channel: u8,
+    speed: String,
+}
+
+/// Several GPUs acting as one.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupConfig {
+    gpus: Vec<String>,
+    #[serde(default)]
+    combine: Combine,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelConfig {
+    channel: u8,
+    gpu: String,
+    fan_curve: Option<Vec<(f64, u8)>>,
+    fan_curve_interpolation: Option<Interpolation>,
+    fan_curve_below: Option<Extrapolation>,
+    fan_curve_above: Option<Extrapolation>,
+    /// Profile to take the curve from when there's no fan_curve
+    profile: Option<String>,
+    /// Duty added to the curve's speed, -255 to 255
+    #[serde(default)]
+    offset: i16,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        let config = Config::parse(&contents)
+            .map_err(|e| format!("Failed to parse config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Parses `contents`, migrating it from an older `config_version`.
+    fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let mut table: toml::value::Table = toml::from_str(contents)?;
+        let version = match table.remove("config_version") {
+            Some(toml::Value::Integer(version)) if (1..=CONFIG_VERSION).contains(&version) => version,
+            Some(toml::Value::Integer(version)) if version > CONFIG_VERSION => Err(format!(
+                "config_version {} is for a newer release; this one reads up to {}",
+                version, CONFIG_VERSION
+            ))?,
+            Some(version) => Err(format!("Bad config_version {}: expected 1 to {}", version, CONFIG_VERSION))?,
+            None => 1,
+        };
+        for (from, renamed) in RENAMED.iter().enumerate().skip(version as usize - 1) {
+            for (old, new) in *renamed {
+                let Some(value) = table.remove(*old) else {
+                    continue
+                };
+                if table.contains_key(*new) {
+                    Err(format!("Both {} and {}, which replaced it in config_version {}, are set", old, new, from + 2))?
+                }
+                event!(
+                    "Config key {} is now {} (config_version {}); reading it as that, but update the file and set config_version = {}",
+                    old, new, from + 2, CONFIG_VERSION
+                );
+                table.insert(new.to_string(), value);
+            }
+        }
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    /// The named profile with everything it inherits filled in.
+    fn profile(&self, name: &str) -> Result<ProfileConfig, Box<dyn Error>> {
+        let mut chain = vec![];
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if chain.iter().any(|(seen, _)| *seen == name) {
+                Err(format!("Profile {} inherits from itself", name))?
+            }
+            let profile = self.profiles.get(name)
+                .ok_or_else(|| format!("No profile named {}", name))?;
+            chain.push((name, profile));
+            next = profile.inherit.as_deref();
+        }
+        chain.into_iter()
+            .rev()
+            .try_fold(ProfileConfig::default(), |parent, (name, profile)| {
+                parent.overridden_by(profile, &format!("profile {}", name))
+            })
+    }
+
+    /// The top level's curve and thresholds over its profile's, if any.
+    fn main_profile(&self) -> Result<ProfileConfig, Box<dyn Error>> {
+        let profile = match &self.active_profile {
+            Some(name) => self.profile(name)?,
+            None => ProfileConfig::default(),
+        };
+        profile.overridden_by(
+            &ProfileConfig {
+                fan_curve: self.fan_curve.clone(),
+                fan_curve_interpolation: self.fan_curve_interpolation,
+                critical_temp: self.critical_temp.clone(),
+                boost_temp: self.boost_temp.clone(),
+                boost_amount: self.boost_amount,
+                ..ProfileConfig::default()
+            },
+            "config",
+        )
+    }
+
+    pub fn fan_curve(&self) -> Result<Option<FanSpeedTable>, Box<dyn Error>> {
+        let profile = self.main_profile()?;
+        profile.fan_curve
+            .map(FanSpeedTable::from_points)
+            .transpose()
+            .map_err(|e| format!("Bad fan_curve in config: {}", e).into())
+            .map(|curve| curve.map(|curve| {
+                curve.with_interpolation(profile.fan_curve_interpolation.unwrap_or_default())
+                    .with_extrapolation(
+                        self.fan_curve_below.unwrap_or_default(),
+                        self.fan_curve_above.unwrap_or_default(),
+                    )
+            }))
+    }
+
+    pub fn temp_curve(&self) -> Result<Option<TempCurve>, Box<dyn Error>> {
+        self.temp_curve.clone()
+            .map(TempCurve::from_points)
+            .transpose()
+            .map_err(|e| format!("Bad temp_curve in config: {}", e).into())
+            .map(|curve| curve.map(|curve| curve.with_interpolation(self.temp_curve_interpolation.unwrap_or_default())))
+    }
+
+    /// Swaps any group names in `selectors` for the group's GPUs, along with
+    /// how the group combines them.
+    fn expand_groups(&self, selectors: &[String]) -> Result<(Vec<String>, Option<Combine>), Box<dyn Error>> {
+        let mut gpus = vec![];
+        let mut combine = None;
+        for selector in selectors {
+            match self.groups.get(selector) {
+                Some(group) => {
+                    if combine.is_some_and(|combine| combine != group.combine) {
+                        Err(format!("Group {} combines its GPUs differently to the others", selector))?
+                    }
+                    combine = Some(group.combine);
+                    gpus.extend(group.gpus.iter().cloned());
+                },
+                None => gpus.push(selector.clone()),
+            }
+        }
+        Ok((gpus, combine))
+    }
+
+    /// Fills in whatever wasn't given on the command line, noting in
+    /// `args.from_file` which settings that was.
+    pub fn apply(self, args: &mut Args) -> Result<(), Box<dyn Error>> {
+        let before = args.settings();
+        self.fill_in(args)?;
+        args.from_file = args.settings().into_iter()
+            .zip(before)
+            .filter(|((_, after), (_, before))| after != before)
+            .map(|((name, _), _)| name)
+            .collect();
+        Ok(())
+    }
+
+    fn fill_in(self, args: &mut Args) -> Result<(), Box<dyn Error>> {
+        let profile = self.main_profile()?;
+        if args.gpu.is_empty() {
+            args.gpu = self.gpu.iter()
+                .flat_map(|gpu| gpu.split(','))
+                .map(|gpu| gpu.trim().to_string())
+                .collect();
+        }
+        let (gpus, group_combine) = self.expand_groups(&args.gpu)?;
+        args.gpu = gpus;
+        args.combine = args.combine.or(self.combine).or(group_combine);
+        args.update_interval = args.update_interval
+            .or(WithUnit::parse(&self.update_interval, "update_interval", units::seconds)?);
+        if args.temp_sensors.is_empty() {
+            args.temp_sensors = self.temp_sensors.clone().unwrap_or_default();
+        }
+        args.critical_temp = args.critical_temp
+            .or(WithUnit::parse(&profile.critical_temp, "critical_temp", units::whole_celsius)?);
+        args.boost_temp = args.boost_temp
+            .or(WithUnit::parse(&profile.boost_temp, "boost_temp", units::whole_celsius)?);
+        args.boost_amount = args.boost_amount.or(profile.boost_amount);
+        args.throttle_boost = args.throttle_boost.or(self.throttle_boost);
+        args.power_guard_temp = args.power_guard_temp
+            .or(WithUnit::parse(&self.power_guard_temp, "power_guard_temp", units::whole_celsius)?);
+        args.power_guard_step = args.power_guard_step
+            .or(WithUnit::parse(&self.power_guard_step, "power_guard_step", units::watts)?);
+        args.ema_alpha = args.ema_alpha.or(self.ema_alpha);
+        args.utilization_lead = args.utilization_lead.or(self.utilization_lead);
+        args.busy_bias = args.busy_bias.or(self.busy_bias);
+        args.busy_processes = args.busy_processes.or(self.busy_processes);
+        args.busy_utilization = args.busy_utilization.or(self.busy_utilization);
+        args.latency_budget = args.latency_budget.or(self.latency_budget);
+        args.hold_on_error = args.hold_on_error.or(self.hold_on_error);
+        args.failsafe_speed = args.failsafe_speed.or(self.failsafe_speed);
+        args.allow_slow_failsafe |= self.allow_slow_failsafe.unwrap_or(false);
+        if args.deadband.is_none() && args.speed_step.is_none() {
+            args.deadband = self.deadband.as_deref()
+                .map(str::parse::<Deadband>)
+                .transpose()
+                .map_err(|e| format!("Bad deadband in config: {}", e))?;
+        }
+        if args.fan_stop.is_none() {
+            args.fan_stop = self.fan_stop.as_deref()
+                .map(str::parse::<FanStop>)
+                .transpose()
+                .map_err(|e| format!("Bad fan_stop in config: {}", e))?;
+        }
+        args.kick_start_duty = args.kick_start_duty.or(self.kick_start_duty);
+        args.dither = args.dither.or(self.dither);
+        args.dither_period = args.dither_period
+            .or(WithUnit::parse(&self.dither_period, "dither_period", units::seconds)?);
+        args.ambient_sensor = args.ambient_sensor.take().or_else(|| self.ambient_sensor.clone());
+        args.ambient_reference = args.ambient_reference
+            .or(WithUnit::parse(&self.ambient_reference, "ambient_reference", units::celsius)?);
+        args.ambient_bias = args.ambient_bias.or(self.ambient_bias);
+        args.chassis_sensor = args.chassis_sensor.take().or_else(|| self.chassis_sensor.clone());
+        args.chassis_temp = args.chassis_temp
+            .or(WithUnit::parse(&self.chassis_temp, "chassis_temp", units::celsius)?);
+        args.chassis_boost = args.chassis_boost.or(self.chassis_boost);
+        if args.quiet_hours.is_none() {
+            args.quiet_hours = self.quiet_hours.as_deref()
+                .map(str::parse::<QuietHours>)
+                .transpose()
+                .map_err(|e| format!("Bad quiet_hours in config: {}", e))?;
+        }
+        if args.quiet_fan_curve.is_none() {
+            let quiet_profile = self.quiet_profile.as_deref()
+                .map(|name| self.profile(name))
+                .transpose()?;
+            args.quiet_fan_curve = self.quiet_fan_curve.clone()
+                .or(quiet_profile.and_then(|profile| profile.fan_curve))
+                .map(FanSpeedTable::from_points)
+                .transpose()
+                .map_err(|e| format!("Bad quiet_fan_curve in config: {}", e))?;
+        }
+        args.quiet_max_speed = args.quiet_max_speed.or(self.quiet_max_speed);
+        args.profile_transition = args.profile_transition
+            .or(WithUnit::parse(&self.profile_transition, "profile_transition", units::seconds)?);
+        let logging = (args.logging || args.no_logging).then_some(args.logging);
+        args.logging = logging.or(self.logging).unwrap_or(false);
+        args.enable_persistence |= self.enable_persistence.unwrap_or(false);
+        args.read_rpm |= self.read_rpm.unwrap_or(false);
+        args.stall_cycles = args.stall_cycles.or(self.stall_cycles);
+        args.max_rpm = args.max_rpm.or(self.max_rpm);
+        args.log_every = args.log_every.or(self.log_every);
+        args.align_samples |= self.align_samples.unwrap_or(false);
+        self.apply_controller(&mut args.report)?;
+        if args.channel_map.is_empty() {
+            args.channel_map = self.channels.iter()
+                .map(|channel| {
+                    check_channel(channel.channel, "[[channel]]")?;
+                    let offset = channel.offset.clamp(-255, 255);
+                    if offset != channel.offset {
+                        event!(
+                            "Offset {} for channel {} in config is more than a fan's whole range; using {}",
+                            channel.offset, channel.channel, offset
+                        );
+                    }
+                    let channel_profile = channel.profile.as_deref()
+                        .map(|name| self.profile(name))
+                        .transpose()?
+                        .unwrap_or_default();
+                    Ok(ChannelMapping {
+                        channel: channel.channel,
+                        gpus: vec![channel.gpu.clone()],
+                        combine: Combine::default(),
+                        fan_curve: channel.fan_curve.clone()
+                            .or(channel_profile.fan_curve)
+                            .map(FanSpeedTable::from_points)
+                            .transpose()
+                            .map_err(|e| format!("Bad fan_curve for channel {} in config: {}", channel.channel, e))?
+                            .map(|curve| {
+                                curve.with_interpolation(
+                                    channel.fan_curve_interpolation
+                                        .or(channel_profile.fan_curve_interpolation)
+                                        .or(profile.fan_curve_interpolation)
+                                        .unwrap_or_default()
+                                )
+                                .with_extrapolation(
+                                    channel.fan_curve_below.or(self.fan_curve_below).unwrap_or_default(),
+                                    channel.fan_curve_above.or(self.fan_curve_above).unwrap_or_default(),
+                                )
+                            }),
+                        offset,
+                    })
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?;
+        }
+        for output in &self.outputs {
+            check_channel(output.channel, &format!("[[output]] {}", output.name))?;
+        }
+        args.outputs = self.outputs.iter()
+            .map(|output| Ok(DerivedChannel::new(
+                output.name.clone(),
+                output.channel,
+                output.speed.parse()
+                    .map_err(|e| format!("Bad speed for output {} in config: {}", output.name, e))?,
+            )))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        for mapping in &mut args.channel_map {
+            let (gpus, combine) = self.expand_groups(&mapping.gpus)?;
+            mapping.gpus = gpus;
+            mapping.combine = combine.unwrap_or(mapping.combine);
+        }
+        // Any curve on the command line replaces the file's curves
+        if args.fan_curve.is_none() && args.fan_curve_watts.is_none() && args.temp_curve.is_none() {
+            args.fan_curve = self.fan_curve()?;
+            args.temp_curve = self.temp_curve()?;
+        }
+        args.fan_curve_interpolation = args.fan_curve_interpolation.or(profile.fan_curve_interpolation);
+        args.temp_curve_interpolation = args.temp_curve_interpolation.or(self.temp_curve_interpolation);
+        args.fan_curve_below = args.fan_curve_below.or(self.fan_curve_below);
+        args.fan_curve_above = args.fan_curve_above.or(self.fan_curve_above);
+        if let Some(name) = self.events.severity.keys().find(|name| !Event::NAMES.contains(&name.as_str())) {
+            Err(format!("Unknown event {} in [events.severity]; expected one of {}", name, Event::NAMES.join(", ")))?
+        }
+        if self.events.webhook.is_none() && self.events.route.values().flatten().any(|sink| *sink == Sink::Webhook) {
+            Err("Events are routed to a webhook, but [events] has no webhook URL")?
+        }
+        args.event_routes = EventRoutes {
+            severities: self.events.severity,
+            sinks: self.events.route,
+            webhook: self.events.webhook,
+        };
+        Ok(())
+    }
+
+    /// Fills in whatever `report` doesn't set from [controller], for the
+    /// commands that only open the controller.
+    pub fn apply_controller(&self, report: &mut ReportArgs) -> Result<(), Box<dyn Error>> {
+        report.usb_vid = report.usb_vid.or(self.controller.vid);
+        report.usb_pid = report.usb_pid.or(self.controller.pid);
+        report.report_id = report.report_id.or(self.controller.report_id);
+        report.report_length = report.report_length.or(self.controller.report_length);
+        if report.report_template.is_none() {
+            report.report_template = self.controller.report_template.as_deref()
+                .map(str::parse::<ReportTemplate>)
+                .transpose()
+                .map_err(|e| format!("Bad report_template in [controller] in config: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails for a channel the controller doesn't have, given in `section`.
+fn check_channel(channel: u8, section: &str) -> Result<(), Box<dyn Error>> {
+    if channel as usize >= CHANNELS {
+        Err(format!(
+            "Bad channel {} for {} in config: the controller's channels are 0 to {}",
+            channel, section, CHANNELS - 1
+        ))?
+    }
+    Ok(())
+}
+
+/// Where a setting's value came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    CommandLine,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::File => "config file",
+            Source::CommandLine => "command line",
+        }
+    }
+}
+
+/// Every setting after merging the command line over the config file over
+/// the defaults, with where each came from. Settings left unset show as
+/// `None`, and get the default their `--help` gives.
+pub struct EffectiveConfig {
+    settings: Vec<(&'static str, String, Source)>,
+}
+
+impl EffectiveConfig {
+    /// `args` is the command line, noted with `Args::given`, with the config
+    /// file applied.
+    pub fn new(args: &Args) -> Self {
+        let settings = args.settings().into_iter()
+            .map(|(name, value)| {
+                let source = if args.from_command_line.contains(&name) {
+                    Source::CommandLine
+                } else if args.from_file.contains(&name) {
+                    Source::File
+                } else {
+                    Source::Default
+                };
+                (name, value, source)
+            })
+            .collect();
+        EffectiveConfig { settings }
+    }
+
+    pub fn to_json(&self) -> String {
+        let settings: Vec<String> = self.settings.iter()
+            .map(|(name, value, source)| format!(
+                "{}:{{\"value\":{},\"source\":{}}}",
+                json_string(name), json_string(value), json_string(source.name())
+            ))
+            .collect();
+        format!("{{\"config\":{{{}}}}}", settings.join(","))
+    }
+}
+
+impl std::fmt::Display for EffectiveConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", EFFECTIVE_CONFIG_HEADER)?;
+        let width = self.settings.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+        for (name, value, source) in &self.settings {
+            writeln!(f, "{:width$} = {}  # {}", name, value, source.name(), width = width)?;
+        }
+        Ok(())
+    }
+}
+
+/// First line of the plain effective config, so a client can pick it out
+/// from whatever else the control socket sends.
+pub const EFFECTIVE_CONFIG_HEADER: &str = "# Effective configuration";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_layouts_are_migrated() {
+        let config = Config::parse("uuid = \"0\"\n").unwrap();
+        assert_eq!(config.gpu.as_deref(), Some("0"));
+        let config = Config::parse("config_version = 2\ngpu = \"0\"\n").unwrap();
+        assert_eq!(config.gpu.as_deref(), Some("0"));
+        // Renamed by then, so no longer known
+        assert!(Config::parse("config_version = 2\nuuid = \"0\"\n").is_err());
+        assert!(Config::parse("uuid = \"0\"\ngpu = \"1\"\n").is_err());
+        assert!(Config::parse("config_version = 3\n").is_err());
+        assert!(Config::parse("config_version = 0\n").is_err());
+    }
+}
@@ -0,0 +1,474 @@
+//! The HID protocol spoken by the fan controller firmware.
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::path::Path;
+
+use hidapi::{DeviceInfo, HidApi, HidDevice, HidResult};
+
+use crate::telemetry::{event, json_string};
+
+pub const FAN_CONTROLLER_VID: u16 = 0x1209;
+pub const FAN_CONTROLLER_PID: u16 = 0x0010;
+/// Fan outputs on the controller
+pub const CHANNELS: usize = 2;
+
+/// What a message means, for `protocol dump`.
+pub struct MessageSpec {
+    pub id: u8,
+    pub name: &'static str,
+    pub description: &'static str,
+    /// (name, meaning) of each byte after the message type
+    pub fields: &'static [(&'static str, &'static str)],
+}
+
+/// Declares each message's MSG_* constant along with its entry in
+/// `MESSAGES`, so the two can't drift apart.
+macro_rules! messages {
+    ($(
+        $(#[$doc:meta])*
+        $constant:ident = $id:literal, $name:literal, $description:literal,
+        [$(($field:literal, $meaning:literal)),* $(,)?];
+    )*) => {
+        $(
+            $(#[$doc])*
+            pub const $constant: u8 = $id;
+        )*
+
+        pub const MESSAGES: &[MessageSpec] = &[$(
+            MessageSpec {
+                id: $constant,
+                name: $name,
+                description: $description,
+                fields: &[$(($field, $meaning)),*],
+            },
+        )*];
+    };
+}
+
+// Message types understood by the controller firmware. Each message is sent as
+// a single report with the message type in the first byte, followed by its
+// fields, and zero padded to the report length.
+messages! {
+    MSG_FAN_SPEED = 1, "fan_speed",
+        "Sets every fan output to the same speed, other than those set on their own in the last 2 minutes",
+        [("speed", "duty cycle, 0-255 for 0-100%")];
+    MSG_LED = 2, "led", "Sets the status LED",
+        [
+            ("red", "0-255"),
+            ("green", "0-255"),
+            ("blue", "0-255"),
+            ("blink", "1 to blink, 0 to stay lit"),
+        ];
+    MSG_BUZZER = 3, "buzzer", "Turns the buzzer on or off",
+        [("on", "1 to sound, 0 for silence")];
+    /// For firmware that drives its outputs separately
+    MSG_FAN_CHANNEL_SPEED = 4, "fan_channel_speed",
+        "Sets a single fan output, which then ignores fan_speed for 2 minutes",
+        [
+            ("channel", "0 for the first output, 1 for the second"),
+            ("speed", "duty cycle, 0-255 for 0-100%"),
+        ];
+    MSG_FAN_RPM = 5, "fan_rpm", "Sent by the controller: how fast a fan is turning",
+        [
+            ("channel", "0 for the first output, 1 for the second"),
+            ("rpm_high", "high byte of the RPM"),
+            ("rpm_low", "low byte of the RPM"),
+        ];
+}
+
+/// How long the firmware keeps a channel set on its own out of the way of
+/// MSG_FAN_SPEED, after the last MSG_FAN_CHANNEL_SPEED for it
+pub const CHANNEL_HOLD: std::time::Duration = std::time::Duration::from_secs(120);
+/// What reports are padded out to their full length with
+const PADDING: u8 = 0;
+
+// Report descriptor item prefixes, less the size bits
+const HID_OUTPUT: u8 = 0x90;
+const HID_REPORT_SIZE: u8 = 0x74;
+const HID_REPORT_ID: u8 = 0x84;
+const HID_REPORT_COUNT: u8 = 0x94;
+const HID_PUSH: u8 = 0xa4;
+const HID_POP: u8 = 0xb4;
+
+/// Everything a firmware author needs to talk to us, as one JSON object.
+pub fn protocol_json(format: &ReportFormat, template: &ReportTemplate) -> String {
+    let messages = MESSAGES.iter()
+        .map(|msg| {
+            let fields = msg.fields.iter()
+                .enumerate()
+                .map(|(i, (name, meaning))| format!(
+                    r#"{{"offset":{},"name":{},"meaning":{}}}"#,
+                    i + 1, json_string(name), json_string(meaning)
+                ))
+                .collect::<Vec<_>>();
+            format!(
+                r#"{{"type":{},"name":{},"description":{},"fields":[{}]}}"#,
+                msg.id, json_string(msg.name), json_string(msg.description), fields.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        concat!(
+            r#"{{"tool":{},"version":{},"vid":{},"pid":{},"#,
+            r#""report_id":{},"report_length":{},"padding":{},"#,
+            r#""fan_speed_report":{},"messages":[{}]}}"#,
+        ),
+        json_string(env!("CARGO_PKG_NAME")),
+        json_string(env!("CARGO_PKG_VERSION")),
+        format.vid,
+        format.pid,
+        format.report_id.map(|id| id.to_string()).unwrap_or_else(|| "null".to_string()),
+        format.len,
+        PADDING,
+        json_string(&template.to_string()),
+        messages.join(","),
+    )
+}
+
+/// Opens the fan controllers with `format`'s VID/PID picked out by serial
+/// number or HID path, as `list-devices` shows them, or without any, the first
+/// one found. With `format.detect`, the framing is then taken from the first
+/// one's report descriptor where it can be read.
+pub fn open_controllers(
+    hidapi: &HidApi,
+    format: &mut ReportFormat,
+    selectors: &[String],
+) -> Result<Vec<HidDevice>, Box<dyn Error>> {
+    let controllers: Vec<&DeviceInfo> = hidapi.device_list()
+        .filter(|device| (device.vendor_id(), device.product_id()) == (format.vid, format.pid))
+        .collect();
+    let infos = if selectors.is_empty() {
+        let first = controllers.first()
+            .ok_or_else(|| format!("Failed to find fan controller {:04x}:{:04x}", format.vid, format.pid))?;
+        vec![*first]
+    } else {
+        selectors.iter()
+            .map(|selector| {
+                controllers.iter()
+                    .find(|device| {
+                        device.serial_number() == Some(selector.as_str()) || device.path().to_string_lossy() == selector.as_str()
+                    })
+                    .copied()
+                    .ok_or_else(|| format!("Failed to find fan controller {}; see list-devices", selector))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    if let Some(info) = infos.first().filter(|_| format.detect) {
+        match probe_output_report(info.path()) {
+            Some((report_id, len)) => {
+                if (Some(report_id), len) != (format.report_id, format.len) {
+                    event!("Fan controller takes {} byte reports with report ID {}, going by its report descriptor", len, report_id);
+                    format.report_id = Some(report_id);
+                    format.len = len;
+                }
+            },
+            // As with the libusb backend, or on Windows and macOS
+            None => event!(
+                "Couldn't read the fan controller's report descriptor, so sending {} byte reports with {}; \
+                see --report-id and --report-length if it doesn't respond",
+                format.len,
+                format.report_id.map_or("no report ID".to_string(), |id| format!("report ID {}", id)),
+            ),
+        }
+    }
+    infos.iter()
+        .map(|info| {
+            info.open_device(hidapi)
+                .map_err(|e| format!("Failed to open fan controller {}: {}", info.path().to_string_lossy(), e).into())
+        })
+        .collect()
+}
+
+/// The first output report a controller's report descriptor describes, where
+/// the descriptor can be read: from sysfs, for the hidraw backend on Linux.
+fn probe_output_report(path: &CStr) -> Option<(u8, usize)> {
+    let name = Path::new(path.to_str().ok()?).file_name()?.to_str()?;
+    let descriptor = std::fs::read(format!("/sys/class/hidraw/{}/device/report_descriptor", name)).ok()?;
+    output_report(&descriptor)
+}
+
+/// (report ID, length including the ID) of the first output report in a HID
+/// report descriptor. The ID is 0 for devices that don't number their reports,
+/// which hidapi wants written all the same.
+fn output_report(descriptor: &[u8]) -> Option<(u8, usize)> {
+    // (report size, report count, report ID): the globals that matter here,
+    // saved and restored by push and pop items
+    let mut globals = (0u32, 0u32, 0u8);
+    let mut stack = vec![];
+    // (report ID, bits) of each output report, in the order they appear
+    let mut outputs: Vec<(u8, u32)> = vec![];
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        // Long items: [0xfe, data size, tag, data...]
+        if prefix == 0xfe {
+            i += 3 + usize::from(*descriptor.get(i + 1)?);
+            continue
+        }
+        let size = [0, 1, 2, 4][usize::from(prefix & 3)];
+        let value = descriptor.get(i + 1..i + 1 + size)?
+            .iter()
+            .rev()
+            .fold(0u32, |value, &b| value << 8 | u32::from(b));
+        match prefix & 0xfc {
+            HID_REPORT_SIZE => globals.0 = value,
+            HID_REPORT_COUNT => globals.1 = value,
+            HID_REPORT_ID => globals.2 = value as u8,
+            HID_PUSH => stack.push(globals),
+            HID_POP => globals = stack.pop()?,
+            HID_OUTPUT => {
+                let bits = globals.0 * globals.1;
+                match outputs.iter_mut().find(|(id, _)| *id == globals.2) {
+                    Some(output) => output.1 += bits,
+                    None => outputs.push((globals.2, bits)),
+                }
+            },
+            _ => (),
+        }
+        i += 1 + size;
+    }
+    let &(id, bits) = outputs.first()?;
+    Some((id, 1 + bits.div_ceil(8) as usize))
+}
+
+/// Parses a byte written either in decimal or as 0x-prefixed hex.
+pub fn parse_u8(s: &str) -> Result<u8, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Parses a USB vendor or product ID, written as hex with or without 0x.
+pub fn parse_usb_id(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s), 16)
+}
+
+/// Which device to talk to, and how messages get framed into HID reports.
+#[derive(Clone, Debug)]
+pub struct ReportFormat {
+    pub vid: u16,
+    pub pid: u16,
+    /// Prepended to every report when set
+    pub report_id: Option<u8>,
+    /// Total report length, including the report ID; reports are zero padded
+    pub len: usize,
+    /// Take the report ID and length from the controller's report descriptor
+    /// when it's opened, where that can be read
+    pub detect: bool,
+}
+
+impl Default for ReportFormat {
+    fn default() -> Self {
+        ReportFormat {
+            vid: FAN_CONTROLLER_VID,
+            pid: FAN_CONTROLLER_PID,
+            // For when the report descriptor can't be read: the Windows
+            // backend wants the report ID up front
+            report_id: if cfg!(windows) { Some(1) } else { None },
+            len: 64,
+            detect: true,
+        }
+    }
+}
+
+/// Reports up to this long are built on the stack, which covers every
+/// full-speed USB device
+const STACK_REPORT_LEN: usize = 64;
+
+impl ReportFormat {
+    pub fn write(&self, device: &HidDevice, msg: &[u8]) -> HidResult<usize> {
+        self.send(device, msg.len(), |buf| buf.copy_from_slice(msg))
+    }
+
+    /// Writes the fan speed message laid out by `template`.
+    pub fn write_template(&self, device: &HidDevice, template: &ReportTemplate, speed: u8) -> HidResult<usize> {
+        self.send(device, template.fields.len(), |buf| template.fill(speed, buf))
+    }
+
+    /// The fan speed message laid out by `template`, before framing.
+    pub fn template_message(&self, template: &ReportTemplate, speed: u8) -> Vec<u8> {
+        let mut msg = vec![0; template.fields.len()];
+        template.fill(speed, &mut msg);
+        msg
+    }
+
+    /// The next RPM report the controller has sent, as (channel, rpm), without
+    /// waiting for one. Other reports are skipped.
+    pub fn read_rpm(&self, device: &HidDevice) -> HidResult<Option<(u8, u16)>> {
+        let offset = usize::from(self.report_id.is_some());
+        let mut buf = [0; STACK_REPORT_LEN];
+        loop {
+            let n = device.read_timeout(&mut buf, 0)?;
+            if n == 0 {
+                return Ok(None)
+            }
+            if let [MSG_FAN_RPM, channel, high, low, ..] = buf[offset.min(n)..n] {
+                return Ok(Some((channel, u16::from_be_bytes([high, low]))))
+            }
+        }
+    }
+
+    /// Pads, frames and sends a message of `msg_len` bytes that `fill` writes
+    /// in place, so the control loop doesn't allocate a report per write.
+    fn send(&self, device: &HidDevice, msg_len: usize, fill: impl FnOnce(&mut [u8])) -> HidResult<usize> {
+        let offset = usize::from(self.report_id.is_some());
+        let len = self.len.max(offset + msg_len);
+        let mut stack = [PADDING; STACK_REPORT_LEN];
+        let mut heap = vec![];
+        let buf = if len <= STACK_REPORT_LEN {
+            &mut stack[..len]
+        } else {
+            heap.resize(len, PADDING);
+            &mut heap[..]
+        };
+        if let Some(report_id) = self.report_id {
+            buf[0] = report_id;
+        }
+        fill(&mut buf[offset..offset + msg_len]);
+        device.write(buf)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum ReportField {
+    Byte(u8),
+    Speed,
+}
+
+/// Layout of the fan speed message, for controllers whose firmware doesn't
+/// speak our protocol. Written as e.g. `0x01,{speed},0x00`.
+#[derive(Clone, Debug)]
+pub struct ReportTemplate {
+    fields: Vec<ReportField>,
+}
+
+impl Default for ReportTemplate {
+    fn default() -> Self {
+        ReportTemplate {
+            fields: vec![ReportField::Byte(MSG_FAN_SPEED), ReportField::Speed],
+        }
+    }
+}
+
+impl ReportTemplate {
+    /// Lays the message out in `buf`, which is as long as the template.
+    fn fill(&self, speed: u8, buf: &mut [u8]) {
+        for (out, field) in buf.iter_mut().zip(&self.fields) {
+            *out = match field {
+                ReportField::Byte(b) => *b,
+                ReportField::Speed => speed,
+            };
+        }
+    }
+}
+
+impl std::fmt::Display for ReportTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match field {
+                ReportField::Byte(b) => write!(f, "{:#04x}", b)?,
+                ReportField::Speed => f.write_str("{speed}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for ReportTemplate {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix('[').unwrap_or(s);
+        let s = s.strip_suffix(']').unwrap_or(s);
+        let fields = s.split(',')
+            .enumerate()
+            .map(|(i, field)| {
+                // Trailing padding is implied by the report length
+                let field = field.trim().trim_end_matches("...").trim_matches('"');
+                if field == "{speed}" {
+                    Ok(ReportField::Speed)
+                } else {
+                    parse_u8(field)
+                        .map(ReportField::Byte)
+                        .map_err(|e| format!("Invalid byte in entry {} of report template: {}", i, e))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !fields.iter().any(|field| matches!(field, ReportField::Speed)) {
+            Err("report template must contain {speed}")?
+        }
+        Ok(ReportTemplate { fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HID-Project's RawHID, as the firmware uses: 64 byte reports in each
+    /// direction, unnumbered.
+    const RAWHID: &[u8] = &[
+        0x06, 0xc0, 0xff, 0x0a, 0x00, 0x0c, 0xa1, 0x01, 0x75, 0x08, 0x15, 0x00, 0x26, 0xff, 0x00,
+        0x95, 0x40, 0x09, 0x01, 0x81, 0x02, 0x95, 0x40, 0x09, 0x02, 0x91, 0x02, 0xc0,
+    ];
+
+    /// A keyboard-style descriptor with numbered reports: report 1 is input
+    /// only, report 2 a 2 byte output made of a 5 bit and a 3 bit field and
+    /// a byte, and report 3 a 63 byte output.
+    const NUMBERED: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01,
+        0x85, 0x01, 0x75, 0x08, 0x95, 0x08, 0x81, 0x02,
+        0x85, 0x02, 0x75, 0x01, 0x95, 0x05, 0x91, 0x02, 0x75, 0x03, 0x95, 0x01, 0x91, 0x01,
+        0x75, 0x08, 0x95, 0x01, 0x91, 0x02,
+        0x85, 0x03, 0x95, 0x3f, 0x91, 0x02,
+        0xc0,
+    ];
+
+    #[test]
+    fn unnumbered_reports() {
+        assert_eq!(output_report(RAWHID), Some((0, 65)));
+    }
+
+    #[test]
+    fn numbered_reports_take_the_first_output() {
+        assert_eq!(output_report(NUMBERED), Some((2, 3)));
+    }
+
+    #[test]
+    fn push_pop_and_long_items() {
+        let descriptor = [
+            0x75, 0x08, 0x95, 0x10,
+            // Push, change the count, pop it back
+            0xa4, 0x95, 0x02, 0xb4,
+            // A long item with 2 bytes of data, which should be skipped
+            0xfe, 0x02, 0x00, 0x91, 0x02,
+            // 2 byte report count
+            0x96, 0x20, 0x00, 0x91, 0x02,
+        ];
+        assert_eq!(output_report(&descriptor), Some((0, 1 + 32)));
+        assert_eq!(output_report(&descriptor[..10]), None);
+    }
+
+    #[test]
+    fn no_output_or_truncated() {
+        assert_eq!(output_report(&[0x75, 0x08, 0x95, 0x40, 0x81, 0x02]), None);
+        assert_eq!(output_report(&RAWHID[..RAWHID.len() - 5]), None);
+        assert_eq!(output_report(&[0x75]), None);
+        assert_eq!(output_report(&[0xb4]), None);
+        assert_eq!(output_report(&[]), None);
+    }
+
+    #[test]
+    fn messages_are_listed_once_in_order() {
+        let ids: Vec<u8> = MESSAGES.iter().map(|msg| msg.id).collect();
+        assert_eq!(ids, [MSG_FAN_SPEED, MSG_LED, MSG_BUZZER, MSG_FAN_CHANNEL_SPEED, MSG_FAN_RPM]);
+        let led = MESSAGES.iter().find(|msg| msg.id == MSG_LED).unwrap();
+        assert_eq!(led.fields.len(), crate::ThermalState::Normal.led_report().len() - 1);
+    }
+}
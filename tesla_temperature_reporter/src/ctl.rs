@@ -0,0 +1,191 @@
+//! Local control socket, for poking at a running daemon.
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::config::EFFECTIVE_CONFIG_HEADER;
+
+/// Streams a line per control loop cycle to everyone connected, after the JSON
+/// of any typed events (see `telemetry::Event`) since the last one.
+///
+/// A client can also send a request line, answered once per cycle before
+/// hanging up on it:
+///
+/// - `show-config`: the effective configuration as of startup
+/// - `boost <speed> <minutes>`: run the fans at least that fast for that
+///   long, unless a speed override is in force
+pub struct CtlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>,
+    config: String,
+    /// Speed and minutes of the last boost asked for, until the control loop
+    /// takes it
+    boost: Option<(u8, f64)>,
+    /// Boosts are refused while this is set
+    override_active: bool,
+}
+
+struct Client {
+    stream: UnixStream,
+    request: Vec<u8>,
+}
+
+impl CtlServer {
+    /// `config` is what `show-config` answers with.
+    pub fn bind(path: &Path, config: String) -> std::io::Result<Self> {
+        // Left behind by a previous run that didn't shut down cleanly
+        if UnixStream::connect(path).is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(CtlServer {
+            path: path.to_owned(),
+            listener,
+            clients: vec![],
+            config,
+            boost: None,
+            override_active: false,
+        })
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // Never let a slow reader hold up the control loop
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.clients.push(Client { stream, request: vec![] });
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Answers any requests that have come in, keeping the clients that are
+    /// still listening.
+    fn answer_requests(&mut self) {
+        let config = &self.config;
+        let boost = &mut self.boost;
+        let override_active = self.override_active;
+        self.clients.retain_mut(|client| {
+            let mut buf = [0; 256];
+            loop {
+                match client.stream.read(&mut buf) {
+                    Ok(0) => return false,
+                    Ok(n) => client.request.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => return false,
+                }
+            }
+            let Some(end) = client.request.iter().position(|&b| b == b'\n') else {
+                return true
+            };
+            let request = String::from_utf8_lossy(&client.request[..end]);
+            let mut words = request.split_whitespace();
+            let answer = match (words.next(), words.next(), words.next()) {
+                (Some("show-config"), None, _) => config.clone(),
+                (Some("boost"), Some(speed), Some(minutes)) if words.next().is_none() => {
+                    match (speed.parse::<u8>(), minutes.parse::<f64>()) {
+                        _ if override_active => "error: a speed override is in force\n".to_string(),
+                        (Ok(speed), Ok(minutes)) if minutes > 0.0 && minutes.is_finite() => {
+                            *boost = Some((speed, minutes));
+                            format!("ok: boosting to at least {} for {} minutes\n", speed, minutes)
+                        },
+                        _ => format!("error: bad boost {:?}\n", request.trim()),
+                    }
+                },
+                _ => format!("error: unknown request {:?}\n", request.trim()),
+            };
+            let _ = client.stream.write_all(answer.as_bytes());
+            false
+        });
+    }
+
+    /// The speed and minutes of boost asked for since last time, if any was.
+    pub fn take_boost(&mut self) -> Option<(u8, f64)> {
+        self.boost.take()
+    }
+
+    /// Tells the server whether to refuse boosts.
+    pub fn set_override_active(&mut self, active: bool) {
+        self.override_active = active;
+    }
+
+    pub fn broadcast(&mut self, line: &str) {
+        self.accept_clients();
+        self.answer_requests();
+        self.clients.retain_mut(|client| {
+            client.stream.write_all(line.as_bytes())
+                .and_then(|()| client.stream.write_all(b"\n"))
+                .is_ok()
+        });
+    }
+}
+
+impl Drop for CtlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Prints whatever the daemon streams over its control socket, for `ctl
+/// tail`.
+pub fn tail(path: &Path) -> Result<(), Box<dyn Error>> {
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("Failed to connect to {}: {}", path.display(), e))?;
+    for line in BufReader::new(stream).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
+
+/// Asks a running daemon for its effective configuration and prints it.
+pub fn show_config(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("Failed to connect to {}: {}", path.display(), e))?;
+    stream.write_all(b"show-config\n")?;
+    // Skip anything streamed before the daemon got round to the request
+    let mut answered = false;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if let Some(error) = line.strip_prefix("error: ") {
+            Err(error.to_string())?
+        }
+        answered |= line == EFFECTIVE_CONFIG_HEADER;
+        if answered {
+            println!("{}", line);
+        }
+    }
+    if !answered {
+        Err("The daemon hung up without sending its configuration")?
+    }
+    Ok(())
+}
+
+/// Asks a running daemon to run the fans at least at `speed` for `minutes`.
+pub fn boost(path: &Path, speed: u8, minutes: f64) -> Result<(), Box<dyn Error>> {
+    if !(minutes > 0.0 && minutes.is_finite()) {
+        Err("minutes must be positive")?
+    }
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("Failed to connect to {}: {}", path.display(), e))?;
+    writeln!(stream, "boost {} {}", speed, minutes)?;
+    // Skip anything streamed before the daemon got round to the request
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if let Some(error) = line.strip_prefix("error: ") {
+            Err(error.to_string())?
+        }
+        if let Some(ok) = line.strip_prefix("ok: ") {
+            println!("{}", ok);
+            return Ok(())
+        }
+    }
+    Err("The daemon hung up without answering")?
+}
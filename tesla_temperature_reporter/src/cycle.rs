@@ -0,0 +1,493 @@
+//! The end of each control cycle: settling on a speed between the curve, the
+//! overrides and the safety stage, and getting it out to the fans.
+//!
+//! This only ever talks to the output through `FanController`, so it can be
+//! driven against a pretend controller as easily as a real one.
+
+use std::error::Error;
+use std::num::NonZeroU8;
+use std::time::{Duration, Instant};
+
+use crate::arbitration::{Arbiter, SpeedSource};
+use crate::controller::{CHANNEL_HOLD, MSG_BUZZER, MSG_FAN_CHANNEL_SPEED};
+use crate::output::{Dither, FanController, SpinUp};
+use crate::state::Counters;
+use crate::telemetry::{Event, event};
+use crate::{Args, DEFAULT_DITHER_PERIOD, Deadband, Duty, ThermalState, quantize_speed, within_deadband};
+
+/// Puts `speed` through the arbiter and then the safety stage, which has the
+/// last word, and onto the output's steps. The main speed and every channel's
+/// go through this alike.
+pub fn arbitrate(
+    arbiter: &mut Arbiter,
+    speed: u8,
+    emergency: Option<u8>,
+    critical: bool,
+    fan_stalled: bool,
+    speed_step: Option<NonZeroU8>,
+) -> (u8, SpeedSource) {
+    let (speed, source) = arbiter.decide(speed, emergency);
+    // The other fans have to make up for a dead one, whatever's been asked for
+    let (speed, source) = arbiter.enforce_safety(speed, source, critical, fan_stalled);
+    match speed_step {
+        Some(step) => (quantize_speed(speed, step), source),
+        None => (speed, source),
+    }
+}
+
+/// What one cycle settled on, ready to be written out.
+pub struct Decided<'a> {
+    pub speed: u8,
+    pub source: SpeedSource,
+    pub thermal_state: ThermalState,
+    /// One for each of the writer's channels, in order
+    pub channel_speeds: &'a [u8],
+    /// One for each of the writer's derived outputs, in order
+    pub derived_speeds: &'a [u8],
+    /// Whether it's quiet hours, when the buzzer keeps quiet
+    pub quiet_hours: bool,
+    pub deadband: Deadband,
+}
+
+/// One of our controller's separately driven outputs.
+struct Channel {
+    channel: u8,
+    /// What it was last sent, if it's been sent anything since the
+    /// controller was opened
+    prev_speed: Option<u8>,
+    sent_at: Option<Instant>,
+}
+
+impl Channel {
+    fn new(channel: u8) -> Self {
+        Channel { channel, prev_speed: None, sent_at: None }
+    }
+
+    /// Whether `speed` has moved past the deadband from what was last sent.
+    fn moved(&self, speed: u8, deadband: Deadband) -> bool {
+        !self.prev_speed.is_some_and(|prev| within_deadband(prev, speed, deadband))
+    }
+
+    /// Whether the firmware's hold on the last speed (see `CHANNEL_HOLD`) is
+    /// running out, after which it would go back to the plain speed.
+    fn hold_expiring(&self, now: Instant) -> bool {
+        self.sent_at.is_none_or(|at| now.saturating_duration_since(at) >= CHANNEL_HOLD / 2)
+    }
+
+    fn forget(&mut self) {
+        self.prev_speed = None;
+        self.sent_at = None;
+    }
+}
+
+/// Everything the control loop remembers about what it's sent the fans.
+pub struct FanWriter {
+    led: bool,
+    buzzer: bool,
+    dither: Option<Dither>,
+    dither_started: Instant,
+    /// The speed being dithered around, which the deadband applies to instead
+    dither_base: Option<u8>,
+    spin_up_ramp: Option<Duration>,
+    kick_start_duty: Option<u8>,
+    /// What the plain speed message last sent
+    pub prev_speed: Option<u8>,
+    prev_thermal_state: Option<ThermalState>,
+    prev_buzzer: Option<bool>,
+    needs_spin_up: bool,
+    spin_up: Option<SpinUp>,
+    channels: Vec<Channel>,
+    derived: Vec<(String, Channel)>,
+}
+
+impl FanWriter {
+    /// `channels` are the separately driven channels' numbers, and `derived`
+    /// the derived outputs' names and channels.
+    pub fn from_args(
+        args: &Args,
+        channels: impl IntoIterator<Item = u8>,
+        derived: impl IntoIterator<Item = (String, u8)>,
+        now: Instant,
+    ) -> Result<Self, Box<dyn Error>> {
+        let spin_up_ramp = args.spin_up_ramp
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| format!("Bad --spin-up-ramp: {}", e))?;
+        let dither = args.dither
+            .map(|amplitude| Ok::<_, Box<dyn Error>>(Dither {
+                amplitude,
+                period: Duration::try_from_secs_f64(args.dither_period.unwrap_or(DEFAULT_DITHER_PERIOD))
+                    .map_err(|e| format!("Bad --dither-period: {}", e))?,
+            }))
+            .transpose()?;
+        Ok(FanWriter {
+            led: args.led,
+            buzzer: args.buzzer,
+            dither,
+            dither_started: now,
+            dither_base: None,
+            spin_up_ramp,
+            kick_start_duty: args.kick_start_duty.or(args.fan_stop.map(|_| 255)),
+            prev_speed: None,
+            prev_thermal_state: None,
+            prev_buzzer: None,
+            needs_spin_up: false,
+            spin_up: None,
+            channels: channels.into_iter().map(Channel::new).collect(),
+            derived: derived.into_iter()
+                .map(|(name, channel)| (name, Channel::new(channel)))
+                .collect(),
+        })
+    }
+
+    /// Starts over with a freshly opened output. One that was there before
+    /// may have browned out and let the fans stop, so they're spun up again
+    /// and sent everything afresh.
+    pub fn opened(&mut self, reconnected: bool) {
+        self.prev_thermal_state = None;
+        self.prev_buzzer = None;
+        if reconnected {
+            self.prev_speed = None;
+            for channel in self.channels.iter_mut().chain(self.derived.iter_mut().map(|(_, channel)| channel)) {
+                channel.forget();
+            }
+            self.needs_spin_up = true;
+            self.spin_up = None;
+        }
+    }
+
+    /// What each of the controller's channels was last sent, for the stall
+    /// check to compare the RPMs against.
+    pub fn duties<const N: usize>(&self) -> [Option<u8>; N] {
+        let mut duties = [self.prev_speed; N];
+        for channel in self.channels.iter().chain(self.derived.iter().map(|(_, channel)| channel)) {
+            if let Some(duty) = duties.get_mut(channel.channel as usize) {
+                *duty = channel.prev_speed;
+            }
+        }
+        duties
+    }
+
+    /// Sends `decided` to `output`, or as much of it as has changed. Returns
+    /// false if the output failed, having said why; it should be dropped and
+    /// opened afresh.
+    pub fn write(&mut self, output: &mut dyn FanController, decided: &Decided, counters: &mut Counters, now: Instant) -> bool {
+        let result = self.try_write(output, decided, counters, now);
+        if let Err((what, e)) = &result {
+            event!(Event::ControllerLost { error: e.to_string() } => "Error updating fan controller{}: {}", what, e);
+            counters.controller_errors += 1;
+        }
+        result.is_ok()
+    }
+
+    /// Fails with what it was updating when the output failed, and why.
+    fn try_write(
+        &mut self,
+        output: &mut dyn FanController,
+        decided: &Decided,
+        counters: &mut Counters,
+        now: Instant,
+    ) -> Result<(), (String, Box<dyn Error>)> {
+        let &Decided { speed, source, thermal_state, deadband, .. } = decided;
+        // A failsafe or critical speed goes out however close it is to the
+        // last one
+        let deadband = match thermal_state {
+            ThermalState::Critical | ThermalState::Fault => Deadband::OFF,
+            _ => deadband,
+        };
+
+        // The status LED and buzzer only exist on our own controller
+        if output.speaks_our_protocol() {
+            if self.led && self.prev_thermal_state != Some(thermal_state) {
+                output.write(&thermal_state.led_report()).map_err(|e| (" LED".to_string(), e))?;
+                self.prev_thermal_state = Some(thermal_state);
+            }
+            if self.buzzer {
+                let buzzer = thermal_state == ThermalState::Critical && !decided.quiet_hours;
+                if self.prev_buzzer != Some(buzzer) {
+                    output.write(&[MSG_BUZZER, buzzer as u8]).map_err(|e| (" buzzer".to_string(), e))?;
+                    self.prev_buzzer = Some(buzzer);
+                }
+            }
+        }
+
+        // After the safety logic, so it never holds back a critical
+        // temperature's full speed
+        let dithering = self.dither.is_some() && matches!(thermal_state, ThermalState::Normal | ThermalState::Warm);
+        let base = match self.dither_base {
+            Some(base) if dithering && within_deadband(base, speed, deadband) => base,
+            _ => speed,
+        };
+        let prev_base = self.dither_base;
+        self.dither_base = dithering.then_some(base);
+        let out_speed = match (self.dither, dithering) {
+            (Some(dither), true) => dither.apply(base, now.saturating_duration_since(self.dither_started)),
+            _ => speed,
+        };
+        let settled = if dithering {
+            self.prev_speed == Some(out_speed)
+        } else {
+            self.prev_speed.is_some_and(|prev| within_deadband(prev, speed, deadband))
+        };
+
+        if !self.channels.is_empty() && output.speaks_our_protocol() {
+            for (channel, &speed) in self.channels.iter_mut().zip(decided.channel_speeds) {
+                let moved = channel.moved(speed, deadband);
+                // Sent again before the firmware lets go of it, however
+                // steady it's been
+                if !moved && !channel.hold_expiring(now) {
+                    continue
+                }
+                output.write(&[MSG_FAN_CHANNEL_SPEED, channel.channel, speed])
+                    .map_err(|e| (format!(" channel {}", channel.channel), e))?;
+                if moved {
+                    event!(
+                        Event::SpeedChanged { speed, source: source.name(), channel: Some(channel.channel) } =>
+                        "Setting channel {} speed to {} ({})", channel.channel, Duty(speed), source.name()
+                    );
+                    counters.speed_changes += 1;
+                }
+                channel.prev_speed = Some(speed);
+                channel.sent_at = Some(now);
+            }
+            // Whatever the firmware isn't holding follows the plain speed, so
+            // that's kept current too, every cycle
+            output.set_speed(speed, thermal_state).map_err(|e| (String::new(), e))?;
+            self.prev_speed = Some(speed);
+            counters.last_speed = Some(speed);
+        } else if !settled || self.spin_up.is_some() {
+            if self.needs_spin_up && out_speed > 0 {
+                self.spin_up = Some(SpinUp::start(self.spin_up_ramp, self.kick_start_duty.unwrap_or(255), now));
+                self.needs_spin_up = false;
+            } else if let (Some(kick), Some(0), 1..) = (self.kick_start_duty, self.prev_speed, out_speed) {
+                self.spin_up = Some(SpinUp::start(None, kick, now));
+            }
+            // Nothing holds back a critical temperature's or a failed read's
+            // speed
+            if matches!(thermal_state, ThermalState::Critical | ThermalState::Fault) {
+                self.spin_up = None;
+            }
+            let speed = match self.spin_up.and_then(|spin_up| spin_up.speed(out_speed, now)) {
+                Some(speed) => speed,
+                None => {
+                    self.spin_up = None;
+                    out_speed
+                },
+            };
+            output.set_speed(speed, thermal_state).map_err(|e| (String::new(), e))?;
+            // The dither's own steps aren't worth a line each
+            if !dithering || prev_base != Some(base) {
+                event!(
+                    Event::SpeedChanged { speed, source: source.name(), channel: None } =>
+                    "Setting speed to {} ({})", Duty(speed), source.name()
+                );
+                counters.speed_changes += 1;
+            }
+            self.prev_speed = Some(speed);
+            counters.last_speed = Some(speed);
+            // Our controller's plain speed message sets every output
+            for (_, output) in &mut self.derived {
+                output.forget();
+            }
+        }
+
+        if output.speaks_our_protocol() {
+            for ((name, channel), &speed) in self.derived.iter_mut().zip(decided.derived_speeds) {
+                let moved = channel.moved(speed, deadband);
+                // Like the mapped channels, or the firmware would have it
+                // follow the plain speed instead of its expression
+                if !moved && !channel.hold_expiring(now) {
+                    continue
+                }
+                output.write(&[MSG_FAN_CHANNEL_SPEED, channel.channel, speed])
+                    .map_err(|e| (format!(" channel {}", channel.channel), e))?;
+                if moved {
+                    event!(
+                        Event::SpeedChanged { speed, source: "derived", channel: Some(channel.channel) } =>
+                        "Setting {} (channel {}) speed to {}", name, channel.channel, Duty(speed)
+                    );
+                }
+                channel.prev_speed = Some(speed);
+                channel.sent_at = Some(now);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    /// A controller that remembers what it's sent, and fails once told to.
+    #[derive(Default)]
+    struct MockController {
+        sent: Vec<u8>,
+        failing: bool,
+        /// Whether it's our own controller, taking `write`s
+        ours: bool,
+        messages: Vec<Vec<u8>>,
+    }
+
+    impl FanController for MockController {
+        fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+            if self.failing {
+                Err("unplugged")?
+            }
+            self.sent.push(speed);
+            Ok(())
+        }
+
+        fn is_connected(&mut self) -> bool {
+            !self.failing
+        }
+
+        fn speaks_our_protocol(&self) -> bool {
+            self.ours
+        }
+
+        fn write(&mut self, msg: &[u8]) -> Result<(), Box<dyn Error>> {
+            self.messages.push(msg.to_vec());
+            Ok(())
+        }
+    }
+
+    fn writer(args: &[&str], now: Instant) -> FanWriter {
+        let args = Args::from_iter(["run"].iter().chain(args));
+        FanWriter::from_args(&args, [], [], now).unwrap()
+    }
+
+    #[test]
+    fn steady_channels_are_sent_again_before_the_firmware_lets_go() {
+        let now = Instant::now();
+        let args = Args::from_iter(["run"]);
+        let mut writer = FanWriter::from_args(&args, [0], [], now).unwrap();
+        let mut counters = Counters::default();
+        let mut output = MockController { ours: true, ..MockController::default() };
+        let critical = Decided {
+            channel_speeds: &[255],
+            ..decided(255, ThermalState::Critical)
+        };
+        for secs in [0, 30, 59, 61, 100, CHANNEL_HOLD.as_secs() + 1] {
+            assert!(writer.write(&mut output, &critical, &mut counters, now + Duration::from_secs(secs)));
+        }
+        let channel_writes = output.messages.iter()
+            .filter(|msg| msg[..] == [MSG_FAN_CHANNEL_SPEED, 0, 255])
+            .count();
+        // At the start, then on each half of the hold
+        assert_eq!(channel_writes, 3);
+        // The plain speed it would fall back to is kept at full speed too
+        assert_eq!(output.sent, [255; 6]);
+        assert_eq!(counters.speed_changes, 1);
+    }
+
+    #[test]
+    fn derived_outputs_are_sent_again_at_a_steady_main_speed() {
+        let now = Instant::now();
+        let args = Args::from_iter(["run"]);
+        let mut writer = FanWriter::from_args(&args, [], [("intake".to_string(), 1)], now).unwrap();
+        let mut counters = Counters::default();
+        let mut output = MockController { ours: true, ..MockController::default() };
+        let steady = Decided {
+            derived_speeds: &[90],
+            ..decided(150, ThermalState::Normal)
+        };
+        for secs in [0, 30, 61, CHANNEL_HOLD.as_secs() + 1] {
+            assert!(writer.write(&mut output, &steady, &mut counters, now + Duration::from_secs(secs)));
+        }
+        // The main speed only went out once, and didn't take the intake with it
+        assert_eq!(output.sent, [150]);
+        let intake_writes = output.messages.iter()
+            .filter(|msg| msg[..] == [MSG_FAN_CHANNEL_SPEED, 1, 90])
+            .count();
+        assert_eq!(intake_writes, 3);
+    }
+
+    fn decided(speed: u8, thermal_state: ThermalState) -> Decided<'static> {
+        Decided {
+            speed,
+            source: SpeedSource::Curve,
+            thermal_state,
+            channel_speeds: &[],
+            derived_speeds: &[],
+            quiet_hours: false,
+            deadband: Deadband::default(),
+        }
+    }
+
+    #[test]
+    fn a_reopened_controller_is_spun_up_again() {
+        let now = Instant::now();
+        let mut writer = writer(&["--spin-up-ramp", "10"], now);
+        let mut counters = Counters::default();
+        let mut output = MockController::default();
+        writer.opened(false);
+        assert!(writer.write(&mut output, &decided(100, ThermalState::Normal), &mut counters, now));
+        assert_eq!(output.sent, [100]);
+
+        // Settled, so nothing more goes out until the controller's lost
+        let later = now + Duration::from_secs(1);
+        assert!(writer.write(&mut output, &decided(104, ThermalState::Normal), &mut counters, later));
+        output.failing = true;
+        assert!(!writer.write(&mut output, &decided(200, ThermalState::Normal), &mut counters, later));
+        assert!(!output.is_connected());
+        assert_eq!(counters.controller_errors, 1);
+
+        // It could have stopped meanwhile, so the same speed is ramped up to
+        let mut output = MockController::default();
+        writer.opened(true);
+        for secs in [2, 7, 12, 13] {
+            let at = now + Duration::from_secs(secs);
+            assert!(writer.write(&mut output, &decided(100, ThermalState::Normal), &mut counters, at));
+        }
+        assert_eq!(output.sent, [10, 60, 100]);
+        assert_eq!(counters.last_speed, Some(100));
+    }
+
+    #[test]
+    fn a_stopped_fan_is_kicked_into_turning() {
+        let now = Instant::now();
+        let mut writer = writer(&["--kick-start-duty", "180"], now);
+        let mut counters = Counters::default();
+        let mut output = MockController::default();
+        for (millis, speed) in [(0, 0), (1000, 60), (1500, 60), (2100, 60)] {
+            let at = now + Duration::from_millis(millis);
+            assert!(writer.write(&mut output, &decided(speed, ThermalState::Normal), &mut counters, at));
+        }
+        assert_eq!(output.sent, [0, 180, 180, 60]);
+    }
+
+    #[test]
+    fn failsafe_speed_skips_the_spin_up() {
+        let now = Instant::now();
+        let mut writer = writer(&["--spin-up-ramp", "10"], now);
+        let mut counters = Counters::default();
+        let mut output = MockController::default();
+        writer.opened(true);
+        let mut arbiter = Arbiter::new(Some(120));
+        // A failed read asks for the failsafe speed, over the cap
+        let (speed, source) = arbitrate(&mut arbiter, 90, Some(230), false, false, None);
+        assert_eq!((speed, source), (230, SpeedSource::EmergencyMax));
+        let failsafe = Decided { source, ..decided(speed, ThermalState::Fault) };
+        assert!(writer.write(&mut output, &failsafe, &mut counters, now));
+        assert_eq!(output.sent, [230]);
+
+        // A stalled fan trumps even an override
+        arbiter.set_override(crate::arbitration::Override { speed: 50, expires: None });
+        assert_eq!(arbitrate(&mut arbiter, 90, None, false, true, None), (255, SpeedSource::Safety));
+    }
+
+    #[test]
+    fn failsafe_speed_goes_out_inside_the_deadband() {
+        let now = Instant::now();
+        let mut writer = writer(&[], now);
+        let mut counters = Counters::default();
+        let mut output = MockController::default();
+        assert!(writer.write(&mut output, &decided(190, ThermalState::Normal), &mut counters, now));
+        assert!(writer.write(&mut output, &decided(195, ThermalState::Normal), &mut counters, now));
+        assert!(writer.write(&mut output, &decided(200, ThermalState::Fault), &mut counters, now));
+        assert!(writer.write(&mut output, &decided(200, ThermalState::Fault), &mut counters, now));
+        assert_eq!(output.sent, [190, 200]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Working out a cycle's speed from the GPU's readings: the minute of
+//! history, the curves, the boost and the critical temperature.
+//!
+//! The control loop and each fan channel go through this, and so do the
+//! replays in `simulate`, `soak` and `traces`, so a replay does what the loop
+//! would have done.
+
+use crate::{CircleBuf, Ema, StartupHistory, ThermalState, Tunables};
+
+/// How many readings make up the minute of history, at `update_interval`
+/// seconds apart.
+pub fn history_samples(update_interval: f64) -> usize {
+    (60.0 / update_interval).ceil() as usize
+}
+
+/// The last minute of readings, and the EMAs that can stand in for its
+/// averages.
+pub struct History {
+    temps: CircleBuf<u8>,
+    /// Fractions of the power limit
+    powers: CircleBuf<f64>,
+    temp_ema: Ema,
+    power_ema: Ema,
+}
+
+/// What the history comes to after a reading.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Window {
+    /// The hottest reading, which the critical temperature goes by
+    pub max_temp: u32,
+    /// What the boost goes by: `max_temp`, or with EMA smoothing one that
+    /// follows a rise straight away but backs off gradually
+    pub boost_check_temp: u32,
+    /// Fraction of the power limit
+    pub average_power: f64,
+}
+
+impl Window {
+    pub fn is_critical(&self, tunables: &Tunables) -> bool {
+        self.max_temp >= tunables.critical_temp
+    }
+}
+
+impl History {
+    /// `samples` readings long, starting out from the first reading as
+    /// `startup` says.
+    pub fn new(samples: usize, startup: StartupHistory, temp: u32, power: f64) -> Self {
+        History {
+            temps: startup.history(temp as u8, samples),
+            powers: startup.history(power, samples),
+            temp_ema: Ema(temp as f64),
+            power_ema: Ema(power),
+        }
+    }
+
+    /// Picks up readings from before we started, oldest first, e.g. the ones
+    /// `once` saved last time. The EMAs start over from their average.
+    pub fn resume(&mut self, readings: impl IntoIterator<Item = (u8, f64)>) {
+        for (temp, power) in readings {
+            self.temps.push(temp);
+            self.powers.push(power);
+        }
+        if !self.temps.is_empty() {
+            self.temp_ema = Ema(self.temps.iter().map(|t| *t as f64).sum::<f64>() / self.temps.len() as f64);
+            self.power_ema = Ema(self.average_power());
+        }
+    }
+
+    /// Keeps the power history meaning the same wattage when the power limit
+    /// is `factor` times what it was.
+    pub fn rescale_power(&mut self, factor: f64) {
+        for power in self.powers.iter_mut() {
+            *power /= factor;
+        }
+        self.power_ema.0 /= factor;
+    }
+
+    /// Adds a reading, `power` being a fraction of the limit.
+    pub fn push(&mut self, temp: u32, power: f64, ema_alpha: Option<f64>) -> Window {
+        self.temps.push(temp as u8);
+        self.powers.push(power);
+        let max_temp = self.max_temp();
+        let (boost_check_temp, average_power) = match ema_alpha {
+            Some(alpha) => (
+                (self.temp_ema.push(temp as f64, alpha).round() as u32).max(temp),
+                self.power_ema.push(power, alpha),
+            ),
+            None => (max_temp, self.average_power()),
+        };
+        Window { max_temp, boost_check_temp, average_power }
+    }
+
+    pub fn max_temp(&self) -> u32 {
+        self.temps.iter().max().map_or(0, |temp| u32::from(*temp))
+    }
+
+    fn average_power(&self) -> f64 {
+        self.powers.iter().sum::<f64>() / self.powers.len().max(1) as f64
+    }
+}
+
+/// What the curves ask for: `power_speed`, the power curve's speed if it's
+/// followed, or the temperature curve's at `temp`, whichever is faster.
+pub fn curves_speed(tunables: &Tunables, temp: u32, power_speed: Option<u8>) -> u8 {
+    // Power leads, but temperature is the ground truth; with both, follow
+    // whichever asks for more
+    let temp_speed = tunables.temp_curve.as_ref().map(|curve| curve.lookup_speed(temp));
+    power_speed.max(temp_speed).unwrap_or_default()
+}
+
+/// What a cycle's readings come to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// The curves' (or the PID's) speed with the bias added, before the boost
+    pub curve_speed: u8,
+    pub speed: u8,
+    pub thermal_state: ThermalState,
+}
+
+/// `speed`, from the curves or the PID, with `bias` added and the boost on top
+/// from the boost temperature; or full speed from the critical temperature.
+pub fn decide(tunables: &Tunables, window: &Window, speed: u8, bias: f64) -> Decision {
+    // Safety condition in case we get run away temps
+    if window.is_critical(tunables) {
+        return Decision { curve_speed: 255, speed: 255, thermal_state: ThermalState::Critical }
+    }
+    let curve_speed = (speed as f64 + bias).clamp(0.0, 255.0) as u8;
+    // If we're at or over the boost temperature, increase the fan speed just in case
+    if window.boost_check_temp >= tunables.boost_temp {
+        Decision {
+            curve_speed,
+            speed: curve_speed.saturating_add(tunables.boost_amount),
+            thermal_state: ThermalState::Warm,
+        }
+    } else {
+        Decision { curve_speed, speed: curve_speed, thermal_state: ThermalState::Normal }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, TempDefaults};
+    use structopt::StructOpt;
+
+    fn tunables(extra: &[&str]) -> Tunables {
+        let args = Args::from_iter(["run", "--critical-temp", "85", "--boost-temp", "75", "--boost-amount", "40"].iter().chain(extra));
+        Tunables::from_args(&args, TempDefaults::default()).unwrap()
+    }
+
+    #[test]
+    fn history_averages_power_and_keeps_the_hottest_temperature() {
+        let mut history = History::new(3, StartupHistory::WarmUp, 60, 0.5);
+        history.push(60, 0.5, None);
+        history.push(70, 0.2, None);
+        let window = history.push(65, 0.2, None);
+        assert_eq!(window.max_temp, 70);
+        assert_eq!(window.boost_check_temp, 70);
+        assert!((window.average_power - 0.3).abs() < 1e-9);
+        // The 70 falls out of the minute
+        history.push(65, 0.2, None);
+        assert_eq!(history.push(62, 0.2, None).max_temp, 65);
+
+        let mut filled = History::new(4, StartupHistory::Fill, 80, 1.0);
+        let window = filled.push(60, 0.0, None);
+        assert_eq!(window.max_temp, 80);
+        assert!((window.average_power - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ema_boost_temperature_never_lags_a_rise() {
+        let mut history = History::new(12, StartupHistory::WarmUp, 60, 0.5);
+        let window = history.push(80, 0.5, Some(0.1));
+        assert_eq!(window.boost_check_temp, 80);
+        let window = history.push(60, 0.5, Some(0.1));
+        assert_eq!(window.boost_check_temp, 62);
+        assert_eq!(window.max_temp, 80);
+    }
+
+    #[test]
+    fn a_changed_power_limit_keeps_the_history_in_watts() {
+        let mut history = History::new(2, StartupHistory::Fill, 60, 0.5);
+        history.rescale_power(2.0);
+        assert!((history.push(60, 0.25, Some(0.5)).average_power - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn resumed_history_counts_towards_the_window() {
+        let mut history = History::new(4, StartupHistory::WarmUp, 50, 0.1);
+        history.resume([(84, 0.9), (70, 0.9)]);
+        let window = history.push(50, 0.3, Some(0.5));
+        assert_eq!(window.max_temp, 84);
+        // Halfway from the resumed average of 0.9 to 0.3
+        assert!((window.average_power - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn decide_biases_boosts_and_goes_flat_out_when_critical() {
+        let tunables = tunables(&[]);
+        let window = |max_temp, boost_check_temp| Window { max_temp, boost_check_temp, average_power: 0.5 };
+        assert_eq!(
+            decide(&tunables, &window(70, 70), 100, 10.6),
+            Decision { curve_speed: 110, speed: 110, thermal_state: ThermalState::Normal },
+        );
+        assert_eq!(
+            decide(&tunables, &window(76, 76), 100, -150.0),
+            Decision { curve_speed: 0, speed: 40, thermal_state: ThermalState::Warm },
+        );
+        assert_eq!(
+            decide(&tunables, &window(76, 76), 250, 0.0),
+            Decision { curve_speed: 250, speed: 255, thermal_state: ThermalState::Warm },
+        );
+        assert_eq!(decide(&tunables, &window(85, 60), 0, 0.0).thermal_state, ThermalState::Critical);
+        assert_eq!(decide(&tunables, &window(85, 60), 0, 0.0).speed, 255);
+    }
+
+    #[test]
+    fn curves_speed_follows_the_faster_curve() {
+        let tunables = tunables(&["--temp-curve", "50:0,80:255"]);
+        assert_eq!(curves_speed(&tunables, 65, Some(20)), 127);
+        assert_eq!(curves_speed(&tunables, 65, Some(200)), 200);
+        assert_eq!(curves_speed(&tunables, 40, None), 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! Spotting hardware problems from how the GPU responds to what we do, and
+//! workloads that the edge temperature sensor doesn't do justice to.
+
+use std::collections::VecDeque;
+
+use crate::controller::CHANNELS;
+use crate::gpu::Reading;
+use crate::telemetry::{Event, Sample, Telemetry, event};
+
+// How much the fans must have sped up, the temperature risen and the power
+// stayed put over the window for us to call it an airflow problem
+const MIN_SPEED_RISE: f64 = 25.0;
+const MIN_TEMP_RISE: f64 = 3.0;
+const MAX_POWER_CHANGE: f64 = 0.05;
+
+/// Flags a probable airflow problem (fan mounted backwards, blocked shroud)
+/// when the fans keep speeding up but the temperature still climbs at steady
+/// power: the curve alone would just keep ramping uselessly.
+pub struct AirflowCheck {
+    window: usize,
+    alerted: bool,
+}
+
+impl AirflowCheck {
+    /// `window` is the number of samples to look back over.
+    pub fn new(window: usize) -> Self {
+        AirflowCheck {
+            window: window.max(2),
+            alerted: false,
+        }
+    }
+
+    pub fn update(&mut self, telemetry: &Telemetry) {
+        let samples: Vec<&Sample> = telemetry.recent(self.window).collect();
+        if samples.len() < self.window {
+            return
+        }
+
+        // Average a few samples at either end to ride out noise
+        let k = (self.window / 6).max(1);
+        let average = |samples: &[&Sample]| -> Option<(f64, f64, f64)> {
+            let mut sums = (0.0, 0.0, 0.0);
+            for sample in samples {
+                sums.0 += sample.speed as f64;
+                sums.1 += sample.temp? as f64;
+                sums.2 += sample.power?;
+            }
+            let n = samples.len() as f64;
+            Some((sums.0 / n, sums.1 / n, sums.2 / n))
+        };
+        let (Some(before), Some(after)) = (average(&samples[..k]), average(&samples[samples.len() - k..])) else {
+            return
+        };
+
+        let suspicious = after.0 - before.0 >= MIN_SPEED_RISE
+            && after.1 - before.1 >= MIN_TEMP_RISE
+            && (after.2 - before.2).abs() <= MAX_POWER_CHANGE;
+        if suspicious && !self.alerted {
+            event!(
+                "ALERT: probable airflow problem: fan speed rose by {:.0} but temperature still rose by {:.1}c at steady power. \
+                Check for a fan mounted backwards or a blocked shroud.",
+                after.0 - before.0,
+                after.1 - before.1
+            );
+        }
+        self.alerted = suspicious;
+    }
+}
+
+/// How much the temperature must have risen with the fans flat out to call it
+/// a runaway
+const MIN_RUNAWAY_RISE: f64 = 2.0;
+
+/// Raises the alarm when the temperature keeps climbing even though the fans
+/// have been as fast as they're allowed to go the whole time, which means a
+/// fan has died or the shroud has come off and no curve can help.
+pub struct RunawayCheck {
+    window: usize,
+    minutes: f64,
+    alerted: bool,
+}
+
+impl RunawayCheck {
+    /// `window` is the number of samples, covering `minutes`, that must all
+    /// be at the top speed.
+    pub fn new(window: usize, minutes: f64) -> Self {
+        RunawayCheck {
+            window: window.max(2),
+            minutes,
+            alerted: false,
+        }
+    }
+
+    /// `max_speed` is the fastest the fans may currently be run, which is
+    /// below full speed under a cap or during quiet hours.
+    pub fn update(&mut self, telemetry: &Telemetry, max_speed: u8) {
+        // Runs every cycle, so walks the samples rather than collecting them
+        if telemetry.recent(self.window).count() < self.window {
+            return
+        }
+        let saturated = telemetry.recent(self.window).all(|sample| sample.speed >= max_speed);
+        // Compare a few samples at either end, and want the latest one to be
+        // the hottest yet, so a plateau doesn't count
+        let k = (self.window / 6).max(1);
+        let average = |samples: &mut dyn Iterator<Item = &Sample>| -> Option<f64> {
+            let sum: u32 = samples.map(|sample| sample.temp).sum::<Option<u32>>()?;
+            Some(sum as f64 / k as f64)
+        };
+        let (Some(before), Some(after)) = (
+            average(&mut telemetry.recent(self.window).take(k)),
+            average(&mut telemetry.recent(k)),
+        ) else {
+            return
+        };
+        let peak = telemetry.recent(self.window).filter_map(|sample| sample.temp).max().unwrap_or(0);
+        let still_rising = telemetry.recent(1).next().and_then(|sample| sample.temp) == Some(peak);
+        let runaway = saturated && still_rising && after - before >= MIN_RUNAWAY_RISE;
+        if runaway && !self.alerted {
+            event!(
+                Event::ThermalRunaway { temp: peak, rise: after - before, minutes: self.minutes } =>
+                "ALERT: thermal runaway: temperature rose by {:.1}c to {}c over {} minutes with the fans at speed {}. \
+                Check for a dead fan or a loose shroud.",
+                after - before,
+                peak,
+                self.minutes,
+                max_speed,
+            );
+        } else if !runaway && self.alerted && !saturated {
+            event!("Fans are below their top speed again, thermal runaway cleared");
+        }
+        self.alerted = runaway || (self.alerted && saturated);
+    }
+}
+
+/// Below this duty plenty of fans don't turn at all, so stopping there isn't
+/// a stall
+const MIN_STALL_DUTY: u8 = 51;
+/// Fraction of the expected RPM under which a fan counts as stalling
+const STALL_FRACTION: f64 = 0.25;
+
+/// Notices a fan that has stopped, slowed right down or stopped reporting
+/// while it's being told to run: seized bearings, a cable that's come loose,
+/// fluff.
+pub struct StallCheck {
+    cycles: u32,
+    /// RPM at full duty, to judge slow fans against
+    max_rpm: Option<u16>,
+    /// Channels with a fan on them. The rest may have nothing plugged in, and
+    /// aren't watched.
+    watched: [bool; CHANNELS],
+    slow_cycles: [u32; CHANNELS],
+    stalled: [bool; CHANNELS],
+}
+
+impl StallCheck {
+    /// `cycles` is how many readings in a row must look stalled.
+    pub fn new(cycles: u32, max_rpm: Option<u16>, watched: [bool; CHANNELS]) -> Self {
+        StallCheck {
+            cycles: cycles.max(1),
+            max_rpm,
+            watched,
+            slow_cycles: [0; CHANNELS],
+            stalled: [false; CHANNELS],
+        }
+    }
+
+    /// Forgets what the fans were doing, e.g. when the controller reconnects.
+    pub fn reset(&mut self) {
+        *self = StallCheck::new(self.cycles, self.max_rpm, self.watched);
+    }
+
+    /// Returns whether any fan has stalled, given the duty each was last set
+    /// to and the RPM it's reporting, if it has lately. One that's silent at
+    /// a duty it should turn at counts as stalled too.
+    pub fn update(&mut self, duties: &[Option<u8>; CHANNELS], rpms: &[Option<u16>; CHANNELS]) -> bool {
+        for channel in (0..CHANNELS).filter(|channel| self.watched[*channel]) {
+            let Some(duty) = duties[channel] else {
+                continue
+            };
+            let rpm = rpms[channel];
+            let expected = self.max_rpm.map_or(0.0, |max_rpm| duty as f64 / 255.0 * max_rpm as f64);
+            let slow = duty >= MIN_STALL_DUTY
+                && rpm.is_none_or(|rpm| rpm == 0 || (rpm as f64) < expected * STALL_FRACTION);
+            self.slow_cycles[channel] = if slow { self.slow_cycles[channel] + 1 } else { 0 };
+            let stalled = self.slow_cycles[channel] >= self.cycles;
+            if stalled && !self.stalled[channel] {
+                event!(
+                    Event::FanStall { channel: channel as u8, rpm: rpm.unwrap_or(0), duty } =>
+                    "ALERT: fan on channel {} is {} at duty {}, running the other fans at full speed. \
+                    Check it for a seized bearing or a loose cable.",
+                    channel,
+                    rpm.map_or("not reporting its RPM".to_string(), |rpm| format!("turning at {} RPM", rpm)),
+                    duty,
+                );
+            } else if !stalled && self.stalled[channel] {
+                event!("Fan on channel {} is turning again at {} RPM", channel, rpm.unwrap_or(0));
+            }
+            self.stalled[channel] = stalled;
+        }
+        self.stalled.iter().any(|stalled| *stalled)
+    }
+}
+
+// How busy memory must be, both outright and next to the SMs, and how much
+// power must be drawn, for a sample to count as memory-bound
+const MIN_MEM_UTIL: u32 = 40;
+const MIN_MEM_TO_SM_RATIO: f64 = 0.75;
+const MIN_MEMORY_BOUND_POWER: f64 = 0.3;
+
+/// Notices workloads that have been bound by memory bandwidth for a while.
+/// These heat the memory and VRMs more than the core, and on cards like the
+/// P40 the edge sensor underreports it.
+pub struct MemoryBoundCheck {
+    window: usize,
+    recent: VecDeque<bool>,
+    memory_bound: bool,
+}
+
+impl MemoryBoundCheck {
+    /// `window` is the number of samples that must all look memory-bound.
+    pub fn new(window: usize) -> Self {
+        MemoryBoundCheck {
+            window: window.max(1),
+            recent: VecDeque::with_capacity(window.max(1)),
+            memory_bound: false,
+        }
+    }
+
+    /// Returns whether the workload is memory-bound.
+    pub fn update(&mut self, reading: &Reading) -> bool {
+        let sample = match (reading.sm_util, reading.mem_util) {
+            (Some(sm_util), Some(mem_util)) => {
+                mem_util >= MIN_MEM_UTIL
+                    && mem_util as f64 >= sm_util as f64 * MIN_MEM_TO_SM_RATIO
+                    && reading.power_fraction() >= MIN_MEMORY_BOUND_POWER
+            },
+            _ => false,
+        };
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(sample);
+
+        let memory_bound = self.recent.len() == self.window && self.recent.iter().all(|&s| s);
+        if memory_bound != self.memory_bound {
+            if memory_bound {
+                event!(
+                    "Workload looks memory-bound (memory {}%, SM {}%)",
+                    reading.mem_util.unwrap_or(0),
+                    reading.sm_util.unwrap_or(0),
+                );
+            } else {
+                event!("Workload no longer looks memory-bound");
+            }
+        }
+        self.memory_bound = memory_bound;
+        memory_bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telemetry(samples: &[(u8, u32)]) -> Telemetry {
+        let mut telemetry = Telemetry::new(samples.len());
+        for (speed, temp) in samples {
+            let mut sample = Sample::offline(Some(*speed));
+            sample.temp = Some(*temp);
+            telemetry.record(sample);
+        }
+        telemetry
+    }
+
+    #[test]
+    fn runaway_counts_at_the_commanded_top_speed() {
+        let rising: Vec<(u8, u32)> = (0..12).map(|i| (180, 70 + i / 2)).collect();
+        let mut check = RunawayCheck::new(12, 1.0);
+        check.update(&telemetry(&rising), 255);
+        assert!(!check.alerted);
+        check.update(&telemetry(&rising), 180);
+        assert!(check.alerted);
+    }
+
+    #[test]
+    fn runaway_needs_the_temperature_at_its_peak() {
+        let mut cooling: Vec<(u8, u32)> = (0..12).map(|i| (255, 70 + i / 2)).collect();
+        cooling[11].1 = 74;
+        let mut check = RunawayCheck::new(12, 1.0);
+        check.update(&telemetry(&cooling), 255);
+        assert!(!check.alerted);
+    }
+
+    #[test]
+    fn a_silent_fan_at_speed_is_a_stall() {
+        let mut watched = [false; CHANNELS];
+        watched[0] = true;
+        let mut check = StallCheck::new(2, None, watched);
+        let duties = [Some(200); CHANNELS];
+        let rpms = [None; CHANNELS];
+        assert!(!check.update(&duties, &rpms));
+        assert!(check.update(&duties, &rpms));
+        // Back to turning
+        let mut rpms = rpms;
+        rpms[0] = Some(1500);
+        assert!(!check.update(&duties, &rpms));
+    }
+
+    #[test]
+    fn unwatched_and_slow_duty_channels_are_left_alone() {
+        let mut check = StallCheck::new(1, Some(3000), [false; CHANNELS]);
+        assert!(!check.update(&[Some(255); CHANNELS], &[Some(0); CHANNELS]));
+        let mut check = StallCheck::new(1, Some(3000), [true; CHANNELS]);
+        assert!(!check.update(&[Some(MIN_STALL_DUTY - 1); CHANNELS], &[None; CHANNELS]));
+        // Under a quarter of what it should be doing at full duty
+        assert!(check.update(&[Some(255); CHANNELS], &[Some(700); CHANNELS]));
+    }
+}
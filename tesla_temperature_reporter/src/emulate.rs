@@ -0,0 +1,185 @@
+//! A pretend fan controller, made with Linux's uhid, for working on the
+//! firmware protocol without the hardware on the desk.
+//!
+//! It shows up as a raw HID device with our VID/PID, logs every report the
+//! host sends it, and answers with the RPM a fan would settle at for the speed
+//! it's been given.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::controller::{
+    CHANNEL_HOLD, CHANNELS, FAN_CONTROLLER_PID, FAN_CONTROLLER_VID, MESSAGES, MSG_FAN_CHANNEL_SPEED, MSG_FAN_RPM,
+    MSG_FAN_SPEED,
+};
+use crate::telemetry::{emit, json_string};
+
+// From linux/uhid.h. Every event is a u32 type followed by a packed union
+// whose largest member is the create2 request.
+const UHID_START: u32 = 2;
+const UHID_STOP: u32 = 3;
+const UHID_OPEN: u32 = 4;
+const UHID_CLOSE: u32 = 5;
+const UHID_OUTPUT: u32 = 6;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+const UHID_DATA_MAX: usize = 4096;
+const UHID_EVENT_SIZE: usize = 4 + 128 + 64 + 64 + 2 + 2 + 4 * 4 + UHID_DATA_MAX;
+const BUS_USB: u16 = 0x03;
+
+/// The same vendor-defined 64 byte in/out reports as HID-Project's RawHID.
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xc0, 0xff,   // Usage Page (Vendor 0xffc0)
+    0x0a, 0x00, 0x0c,   // Usage (0x0c00)
+    0xa1, 0x01,         // Collection (Application)
+    0x75, 0x08,         //   Report Size (8)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x26, 0xff, 0x00,   //   Logical Maximum (255)
+    0x95, 0x40,         //   Report Count (64)
+    0x09, 0x01,         //   Usage (1)
+    0x81, 0x02,         //   Input (Data, Var, Abs)
+    0x95, 0x40,         //   Report Count (64)
+    0x09, 0x02,         //   Usage (2)
+    0x91, 0x02,         //   Output (Data, Var, Abs)
+    0xc0,               // End Collection
+];
+const REPORT_LEN: usize = 64;
+/// How often the simulated fans report their RPM
+const RPM_INTERVAL: Duration = Duration::from_secs(1);
+/// Fraction of the way to the new RPM a fan gets each interval
+const SPIN_RATE: f64 = 0.5;
+
+fn create_event() -> Vec<u8> {
+    let mut event = vec![0; UHID_EVENT_SIZE];
+    event[..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+    let name = b"Tesla fan controller (emulated)";
+    event[4..4 + name.len()].copy_from_slice(name);
+    // name[128], phys[64], uniq[64]
+    let mut at = 4 + 128 + 64 + 64;
+    let mut put = |bytes: &[u8]| {
+        event[at..at + bytes.len()].copy_from_slice(bytes);
+        at += bytes.len();
+    };
+    put(&(REPORT_DESCRIPTOR.len() as u16).to_ne_bytes());
+    put(&BUS_USB.to_ne_bytes());
+    put(&(FAN_CONTROLLER_VID as u32).to_ne_bytes());
+    put(&(FAN_CONTROLLER_PID as u32).to_ne_bytes());
+    // version, country
+    put(&0u32.to_ne_bytes());
+    put(&0u32.to_ne_bytes());
+    put(REPORT_DESCRIPTOR);
+    event
+}
+
+fn input_event(report: &[u8]) -> Vec<u8> {
+    let mut event = vec![0; UHID_EVENT_SIZE];
+    event[..4].copy_from_slice(&UHID_INPUT2.to_ne_bytes());
+    event[4..6].copy_from_slice(&(report.len() as u16).to_ne_bytes());
+    event[6..6 + report.len()].copy_from_slice(report);
+    event
+}
+
+/// `report` as hex with the zero padding left off, plus what it means.
+fn describe(report: &[u8]) -> (String, String) {
+    let len = report.iter().rposition(|b| *b != 0).map_or(1, |i| i + 1);
+    let hex = report[..len].iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let meaning = match MESSAGES.iter().find(|msg| Some(&msg.id) == report.first()) {
+        Some(msg) => {
+            let fields = msg.fields.iter()
+                .enumerate()
+                .map(|(i, (name, _))| format!("{}={}", name, report.get(i + 1).copied().unwrap_or(0)))
+                .collect::<Vec<_>>();
+            format!("{} {}", msg.name, fields.join(" "))
+        },
+        None => "unknown message".to_string(),
+    };
+    (hex, meaning)
+}
+
+pub fn run(max_rpm: u16) -> Result<(), Box<dyn Error>> {
+    let mut uhid = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/uhid")
+        .map_err(|e| format!("Failed to open /dev/uhid (is the uhid module loaded?): {}", e))?;
+    uhid.write_all(&create_event())?;
+    emit(
+        format!("Emulating fan controller {:04x}:{:04x}", FAN_CONTROLLER_VID, FAN_CONTROLLER_PID),
+        || format!(r#"{{"emulating":"{:04x}:{:04x}"}}"#, FAN_CONTROLLER_VID, FAN_CONTROLLER_PID),
+    );
+
+    // Speed asked for and RPM reached, per channel
+    let fans = Arc::new(Mutex::new([(0u8, 0.0f64); CHANNELS]));
+    let mut writer: File = uhid.try_clone()?;
+    let shared = fans.clone();
+    thread::spawn(move || loop {
+        thread::sleep(RPM_INTERVAL);
+        let mut fans = shared.lock().unwrap_or_else(|e| e.into_inner());
+        for (channel, (speed, rpm)) in fans.iter_mut().enumerate() {
+            let target = *speed as f64 / 255.0 * max_rpm as f64;
+            *rpm += (target - *rpm) * SPIN_RATE;
+            let [hi, lo] = (rpm.round() as u16).to_be_bytes();
+            let mut report = [0; REPORT_LEN];
+            report[..4].copy_from_slice(&[MSG_FAN_RPM, channel as u8, hi, lo]);
+            if writer.write_all(&input_event(&report)).is_err() {
+                return
+            }
+        }
+    });
+
+    // When each channel was last set on its own, which keeps it out of the
+    // way of MSG_FAN_SPEED for a while, as the firmware does
+    let mut set_alone: [Option<Instant>; CHANNELS] = [None; CHANNELS];
+    let mut event = vec![0; UHID_EVENT_SIZE];
+    loop {
+        let n = uhid.read(&mut event)?;
+        if n < 4 {
+            continue
+        }
+        let kind = u32::from_ne_bytes([event[0], event[1], event[2], event[3]]);
+        match kind {
+            UHID_START | UHID_STOP | UHID_OPEN | UHID_CLOSE => {
+                let name = match kind {
+                    UHID_START => "started",
+                    UHID_STOP => "stopped",
+                    UHID_OPEN => "opened by the host",
+                    _ => "closed by the host",
+                };
+                emit(format!("Device {}", name), || format!(r#"{{"device":{}}}"#, json_string(name)));
+            },
+            UHID_OUTPUT => {
+                // data[4096], then a u16 size
+                let size = u16::from_ne_bytes([event[4 + UHID_DATA_MAX], event[5 + UHID_DATA_MAX]]) as usize;
+                let report = &event[4..4 + size.min(UHID_DATA_MAX)];
+                let (hex, meaning) = describe(report);
+                emit(
+                    format!("Report: {} ({})", hex, meaning),
+                    || format!(r#"{{"report":{},"meaning":{}}}"#, json_string(&hex), json_string(&meaning)),
+                );
+                let mut fans = fans.lock().unwrap_or_else(|e| e.into_inner());
+                match report {
+                    [MSG_FAN_SPEED, speed, ..] => {
+                        for (fan, set_alone) in fans.iter_mut().zip(&set_alone) {
+                            if !set_alone.is_some_and(|at| at.elapsed() < CHANNEL_HOLD) {
+                                fan.0 = *speed;
+                            }
+                        }
+                    },
+                    [MSG_FAN_CHANNEL_SPEED, channel, speed, ..] if (*channel as usize) < CHANNELS => {
+                        fans[*channel as usize].0 = *speed;
+                        set_alone[*channel as usize] = Some(Instant::now());
+                    },
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+}
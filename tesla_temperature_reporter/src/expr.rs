@@ -0,0 +1,322 @@
+//! Small arithmetic expressions for outputs that derive their speed from
+//! other things, e.g. `max(40, 0.6 * gpu + 10)`.
+//!
+//! Numbers, variables, `+ - * /`, parentheses and the functions `min`, `max`
+//! and `clamp(x, lo, hi)` are all there is. What the variables mean is up to
+//! whoever evaluates the expression.
+
+use std::error::Error;
+
+/// How deeply parentheses, function calls and minus signs may nest, so a
+/// pathological expression can't overflow the stack
+const MAX_DEPTH: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Func {
+    Min,
+    Max,
+    Clamp,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(f64),
+    Var(String),
+    Neg(Box<Node>),
+    Add(Box<Node>, Box<Node>),
+    Sub(Box<Node>, Box<Node>),
+    Mul(Box<Node>, Box<Node>),
+    Div(Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+impl Node {
+    fn eval(&self, vars: &impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        Some(match self {
+            Node::Num(n) => *n,
+            Node::Var(name) => vars(name)?,
+            Node::Neg(a) => -a.eval(vars)?,
+            Node::Add(a, b) => a.eval(vars)? + b.eval(vars)?,
+            Node::Sub(a, b) => a.eval(vars)? - b.eval(vars)?,
+            Node::Mul(a, b) => a.eval(vars)? * b.eval(vars)?,
+            Node::Div(a, b) => a.eval(vars)? / b.eval(vars)?,
+            Node::Call(func, args) => {
+                let mut values = args.iter().map(|arg| arg.eval(vars));
+                match func {
+                    Func::Min => values.try_fold(f64::INFINITY, |acc, v| Some(acc.min(v?)))?,
+                    Func::Max => values.try_fold(f64::NEG_INFINITY, |acc, v| Some(acc.max(v?)))?,
+                    Func::Clamp => {
+                        let (x, lo, hi) = (values.next()??, values.next()??, values.next()??);
+                        x.max(lo).min(hi)
+                    },
+                }
+            },
+        })
+    }
+
+    fn variables<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Node::Num(_) => (),
+            Node::Var(name) => out.push(name),
+            Node::Neg(a) => a.variables(out),
+            Node::Add(a, b) | Node::Sub(a, b) | Node::Mul(a, b) | Node::Div(a, b) => {
+                a.variables(out);
+                b.variables(out);
+            },
+            Node::Call(_, args) => {
+                for arg in args {
+                    arg.variables(out);
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let num = s[start..end].parse()
+                .map_err(|_| format!("Bad number {:?}", &s[start..end]))?;
+            tokens.push(Token::Num(num));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(s[start..end].to_string()));
+        } else if "+-*/(),".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            Err(format!("Unexpected {:?} at position {}", c, start))?
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: char) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), Box<dyn Error>> {
+        if !self.eat(op) {
+            Err(format!("Expected '{}'", op))?
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Node, Box<dyn Error>> {
+        let mut node = self.term()?;
+        loop {
+            if self.eat('+') {
+                node = Node::Add(Box::new(node), Box::new(self.term()?));
+            } else if self.eat('-') {
+                node = Node::Sub(Box::new(node), Box::new(self.term()?));
+            } else {
+                return Ok(node)
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Node, Box<dyn Error>> {
+        let mut node = self.factor()?;
+        loop {
+            if self.eat('*') {
+                node = Node::Mul(Box::new(node), Box::new(self.factor()?));
+            } else if self.eat('/') {
+                node = Node::Div(Box::new(node), Box::new(self.factor()?));
+            } else {
+                return Ok(node)
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<Node, Box<dyn Error>> {
+        if self.depth == MAX_DEPTH {
+            Err(format!("Nested more than {} deep", MAX_DEPTH))?
+        }
+        self.depth += 1;
+        let node = self.operand();
+        self.depth -= 1;
+        node
+    }
+
+    fn operand(&mut self) -> Result<Node, Box<dyn Error>> {
+        if self.eat('-') {
+            return Ok(Node::Neg(Box::new(self.factor()?)))
+        }
+        if self.eat('(') {
+            let node = self.expr()?;
+            self.expect(')')?;
+            return Ok(node)
+        }
+        let token = self.peek().cloned().ok_or("Unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Num(n) => Ok(Node::Num(n)),
+            Token::Ident(name) if self.eat('(') => {
+                let func = match name.as_str() {
+                    "min" => Func::Min,
+                    "max" => Func::Max,
+                    "clamp" => Func::Clamp,
+                    _ => Err(format!("Unknown function {}; expected min, max or clamp", name))?,
+                };
+                let mut args = vec![self.expr()?];
+                while self.eat(',') {
+                    args.push(self.expr()?);
+                }
+                self.expect(')')?;
+                match (func, args.len()) {
+                    (Func::Clamp, 3) => (),
+                    (Func::Clamp, _) => Err("clamp takes 3 arguments: clamp(x, lo, hi)")?,
+                    _ => (),
+                }
+                Ok(Node::Call(func, args))
+            },
+            Token::Ident(name) => Ok(Node::Var(name)),
+            Token::Op(op) => Err(format!("Unexpected '{}'", op))?,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Evaluates with `vars` looking up variables; None if any are unknown.
+    pub fn eval(&self, vars: impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        self.root.eval(&vars)
+    }
+
+    /// Every variable the expression uses.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut out = vec![];
+        self.root.variables(&mut out);
+        out
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            depth: 0,
+        };
+        let root = parser.expr()
+            .map_err(|e| format!("Bad expression {:?}: {}", s, e))?;
+        if parser.pos != parser.tokens.len() {
+            Err(format!("Bad expression {:?}: trailing input", s))?
+        }
+        Ok(Expr {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, vars: &[(&str, f64)]) -> Option<f64> {
+        let expr: Expr = s.parse().unwrap();
+        expr.eval(|name| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| *value))
+    }
+
+    #[test]
+    fn precedence_and_grouping() {
+        assert_eq!(eval("1 + 2 * 3", &[]), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3", &[]), Some(9.0));
+        assert_eq!(eval("10 - 4 - 3", &[]), Some(3.0));
+        assert_eq!(eval("12 / 3 / 2", &[]), Some(2.0));
+        assert_eq!(eval("-2 * -3", &[]), Some(6.0));
+        assert_eq!(eval("- (1 + 2)", &[]), Some(-3.0));
+    }
+
+    #[test]
+    fn functions_and_variables() {
+        let vars = [("gpu", 200.0), ("out_pump", 90.0)];
+        assert_eq!(eval("max(40, 0.6 * gpu + 10)", &vars), Some(130.0));
+        assert_eq!(eval("min(gpu, out_pump, 100)", &vars), Some(90.0));
+        assert_eq!(eval("clamp(gpu, 0, 150)", &vars), Some(150.0));
+        assert_eq!(eval("clamp(-gpu, 0, 150)", &vars), Some(0.0));
+        assert_eq!(eval("gpu + temp", &vars), None);
+    }
+
+    #[test]
+    fn lists_its_variables() {
+        let expr: Expr = "max(40, 0.6 * gpu) + out_pump - gpu".parse().unwrap();
+        assert_eq!(expr.variables(), ["gpu", "out_pump", "gpu"]);
+        assert_eq!(expr.to_string(), "max(40, 0.6 * gpu) + out_pump - gpu");
+    }
+
+    #[test]
+    fn bad_expressions_are_refused() {
+        for bad in [
+            "", "1 +", "(1", "1)", "1 2", "foo(1)", "clamp(1, 2)", "max()", "1..2", "gpu $ 2", "min(1,)",
+        ] {
+            assert!(bad.parse::<Expr>().is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(nested(MAX_DEPTH - 1).parse::<Expr>().is_ok());
+        assert!(nested(MAX_DEPTH + 1).parse::<Expr>().is_err());
+        assert!(nested(100_000).parse::<Expr>().is_err());
+        assert!(format!("{}1", "-".repeat(100_000)).parse::<Expr>().is_err());
+        assert!(format!("{}1{}", "max(".repeat(100_000), ")".repeat(100_000)).parse::<Expr>().is_err());
+    }
+}
@@ -0,0 +1,750 @@
+//! The GPU we follow, read either through NVML on this machine or from an
+//! agent running wherever the card actually lives, e.g. inside a VM it's been
+//! passed through to.
+//!
+//! The agent sends a `temp=.. power_usage=.. power_limit=..` line per update,
+//! plus `sm_clock=.. mem_clock=..` and `sm_util=.. mem_util=..` when the card
+//! reports its clocks and utilization, `mem_temp=..` when it reports its
+//! memory temperature, `thermal_throttle=0|1` when it says why it's
+//! throttling, and `compute_processes=..` when it says what's running on it,
+//! over TCP, or over a virtio-serial port that the host has wired up to our
+//! listening socket (`-chardev socket,host=..,port=..` in QEMU).
+//!
+//! We only listen on localhost unless given an address, and off localhost only
+//! with a shared secret, which the agent sends as a `secret ..` line before
+//! its readings. Anyone who can reach the port could otherwise keep the fans
+//! slow with made-up readings.
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use nvml_wrapper::{Device, Nvml};
+use nvml_wrapper::bitmasks::device::ThrottleReasons;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::enum_wrappers::device::{Brand, Clock, TemperatureSensor, TemperatureThreshold};
+use nvml_wrapper::enums::device::SampleValue;
+use nvml_wrapper::struct_wrappers::device::FieldValueSample;
+use nvml_wrapper::structs::device::FieldId;
+use serde::Deserialize;
+
+use crate::sensors;
+use crate::telemetry::event;
+
+/// NVML_FI_DEV_MEMORY_TEMP, which the wrapper has no name for
+const FIELD_MEMORY_TEMP: u32 = 82;
+
+/// One set of readings, with power in milliwatts and clocks in MHz as NVML
+/// reports them.
+#[derive(Clone, Copy, Debug)]
+pub struct Reading {
+    /// The core's edge sensor
+    pub temp: u32,
+    /// HBM cards like the P100 report this; GDDR ones mostly don't
+    pub mem_temp: Option<u32>,
+    pub power_usage: u32,
+    pub power_limit: u32,
+    /// Not every card will tell us
+    pub sm_clock: Option<u32>,
+    pub mem_clock: Option<u32>,
+    /// Percent of the last sample period the SMs were running anything
+    pub sm_util: Option<u32>,
+    /// Percent of the last sample period memory was being read or written
+    pub mem_util: Option<u32>,
+    /// Whether the card is slowing itself down because it's too hot, in its
+    /// firmware or in the driver
+    pub thermal_throttle: Option<bool>,
+    /// How many compute jobs are running on the card
+    pub compute_processes: Option<u32>,
+}
+
+impl Reading {
+    pub fn power_fraction(&self) -> f64 {
+        self.power_usage as f64 / self.power_limit as f64
+    }
+
+    /// The hottest of `sensors`, or the core's if the card has none of them.
+    pub fn hottest(&self, sensors: &[TempSource]) -> u32 {
+        sensors.iter()
+            .filter_map(|sensor| match sensor {
+                TempSource::Gpu => Some(self.temp),
+                TempSource::Memory => self.mem_temp,
+            })
+            .max()
+            .unwrap_or(self.temp)
+    }
+
+    pub fn from_device(device: &Device) -> Result<Self, Box<dyn Error>> {
+        let utilization = device.utilization_rates().ok();
+        Ok(Reading {
+            temp: device.temperature(TemperatureSensor::Gpu)?,
+            mem_temp: memory_temp(device),
+            power_usage: device.power_usage()?,
+            power_limit: device.power_management_limit()?,
+            sm_clock: device.clock_info(Clock::SM).ok(),
+            mem_clock: device.clock_info(Clock::Memory).ok(),
+            sm_util: utilization.as_ref().map(|u| u.gpu),
+            mem_util: utilization.as_ref().map(|u| u.memory),
+            thermal_throttle: device.current_throttle_reasons().ok()
+                .map(|reasons| reasons.intersects(ThrottleReasons::HW_THERMAL_SLOWDOWN | ThrottleReasons::SW_THERMAL_SLOWDOWN)),
+            compute_processes: device.running_compute_processes_count().ok(),
+        })
+    }
+}
+
+/// Whether the driver stays loaded for the card with nothing using it, on the
+/// platforms that have persistence mode (Linux).
+pub fn persistence_mode(device: &Device) -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    return device.is_in_persistent_mode().ok();
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = device;
+        None
+    }
+}
+
+fn set_persistence_mode(device: &mut Device) -> Result<(), Box<dyn Error>> {
+    #[cfg(target_os = "linux")]
+    return Ok(device.set_persistent(true)?);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = device;
+        Err("persistence mode is only on Linux")?
+    }
+}
+
+/// The memory temperature, if the card and driver will say.
+fn memory_temp(device: &Device) -> Option<u32> {
+    let samples = device.field_values_for(&[FieldId(FIELD_MEMORY_TEMP)]).ok()?;
+    match samples.into_iter().next()? {
+        Ok(FieldValueSample { value: Ok(value), .. }) => match value {
+            SampleValue::F64(temp) => Some(temp as u32),
+            SampleValue::U32(temp) => Some(temp),
+            SampleValue::U64(temp) => u32::try_from(temp).ok(),
+            SampleValue::I64(temp) => u32::try_from(temp).ok(),
+        },
+        _ => None,
+    }
+}
+
+/// One card's power limit, in milliwatts like NVML's.
+pub struct PowerLimit<'nvml> {
+    device: Device<'nvml>,
+    uuid: String,
+    /// What it was when we found it, and what it goes back to
+    pub initial: u32,
+    /// The lowest it will take
+    pub min: u32,
+    current: u32,
+}
+
+/// The cards' power limits, each changed from and put back to its own. Any
+/// that have been changed are put back when this is dropped, however the
+/// control loop ends.
+pub struct PowerLimits<'nvml> {
+    cards: Vec<PowerLimit<'nvml>>,
+    /// Only log what would have been set
+    dry_run: bool,
+}
+
+impl PowerLimits<'_> {
+    /// Sets each card's limit to whatever `limit` gives for it, kept within
+    /// what the card will take. Cards already there are left alone.
+    pub fn set(&mut self, limit: impl Fn(&PowerLimit) -> u32) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for card in &mut self.cards {
+            let limit = limit(card).max(card.min);
+            if limit == card.current {
+                continue
+            }
+            if self.dry_run {
+                event!("Dry run, not setting the power limit of {} to {:.0} W", card.uuid, limit as f64 / 1000.0);
+                card.current = limit;
+                continue
+            }
+            match card.device.set_power_management_limit(limit) {
+                Ok(()) => card.current = limit,
+                // Carry on with the others
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(format!("{}: {}", card.uuid, e));
+                    }
+                },
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Puts every card back to the limit it had when we found it.
+    pub fn restore(&mut self) -> Result<(), Box<dyn Error>> {
+        self.set(|card| card.initial)
+    }
+
+    fn changed(&self) -> bool {
+        self.cards.iter().any(|card| card.current != card.initial)
+    }
+}
+
+impl Drop for PowerLimits<'_> {
+    fn drop(&mut self) {
+        if !self.changed() {
+            return
+        }
+        match self.restore() {
+            Ok(()) if self.dry_run => (),
+            Ok(()) => event!("Restored the power limits"),
+            Err(e) => event!("Failed to restore power limit: {}", e),
+        }
+    }
+}
+
+/// A temperature sensor on the card that can drive the fans.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempSource {
+    /// The core's edge sensor, which every card has
+    Gpu,
+    /// The memory, which on HBM cards can cook well before the core looks hot
+    Memory,
+}
+
+impl TempSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TempSource::Gpu => "gpu",
+            TempSource::Memory => "memory",
+        }
+    }
+}
+
+impl std::str::FromStr for TempSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpu" => Ok(TempSource::Gpu),
+            "memory" => Ok(TempSource::Memory),
+            // NVML has no sensor for it
+            "hotspot" => Err("NVML doesn't report the hotspot temperature; expected gpu or memory".to_string()),
+            _ => Err(format!("Unknown temperature sensor {}; expected gpu or memory", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Reading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "temp={} power_usage={} power_limit={}",
+            self.temp, self.power_usage, self.power_limit
+        )?;
+        if let Some(sm_clock) = self.sm_clock {
+            write!(f, " sm_clock={}", sm_clock)?;
+        }
+        if let Some(mem_clock) = self.mem_clock {
+            write!(f, " mem_clock={}", mem_clock)?;
+        }
+        if let Some(sm_util) = self.sm_util {
+            write!(f, " sm_util={}", sm_util)?;
+        }
+        if let Some(mem_util) = self.mem_util {
+            write!(f, " mem_util={}", mem_util)?;
+        }
+        if let Some(mem_temp) = self.mem_temp {
+            write!(f, " mem_temp={}", mem_temp)?;
+        }
+        if let Some(thermal_throttle) = self.thermal_throttle {
+            write!(f, " thermal_throttle={}", u8::from(thermal_throttle))?;
+        }
+        if let Some(compute_processes) = self.compute_processes {
+            write!(f, " compute_processes={}", compute_processes)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Reading {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut temp, mut power_usage, mut power_limit) = (None, None, None);
+        let (mut sm_clock, mut mem_clock) = (None, None);
+        let (mut sm_util, mut mem_util) = (None, None);
+        let mut mem_temp = None;
+        let mut thermal_throttle = None;
+        let mut compute_processes = None;
+        for field in s.split_whitespace() {
+            let (key, value) = field.split_once('=')
+                .ok_or_else(|| format!("Missing '=' in {:?}", field))?;
+            match key {
+                "temp" => temp = Some(value.parse()?),
+                "power_usage" => power_usage = Some(value.parse()?),
+                "power_limit" => power_limit = Some(value.parse()?),
+                "sm_clock" => sm_clock = Some(value.parse()?),
+                "mem_clock" => mem_clock = Some(value.parse()?),
+                "sm_util" => sm_util = Some(value.parse()?),
+                "mem_util" => mem_util = Some(value.parse()?),
+                "mem_temp" => mem_temp = Some(value.parse()?),
+                "thermal_throttle" => thermal_throttle = Some(value.parse::<u8>()? != 0),
+                "compute_processes" => compute_processes = Some(value.parse()?),
+                // Room for the agent to grow
+                _ => (),
+            }
+        }
+        Ok(Reading {
+            temp: temp.ok_or("missing temp")?,
+            mem_temp,
+            power_usage: power_usage.ok_or("missing power_usage")?,
+            power_limit: power_limit.ok_or("missing power_limit")?,
+            sm_clock,
+            mem_clock,
+            sm_util,
+            mem_util,
+            thermal_throttle,
+            compute_processes,
+        })
+    }
+}
+
+/// Looks a GPU up by NVML index ("0"), PCI bus ID ("0000:03:00.0") or UUID.
+pub fn device_by_selector<'nvml>(nvml: &'nvml Nvml, selector: &str) -> Result<Device<'nvml>, NvmlError> {
+    if let Ok(index) = selector.parse::<u32>() {
+        nvml.device_by_index(index)
+    } else if selector.contains(':') {
+        nvml.device_by_pci_bus_id(selector)
+    } else {
+        nvml.device_by_uuid(selector)
+    }
+}
+
+/// The GPU picked out by `selector` (see `device_by_selector`), or without
+/// one, the only Tesla card in the machine.
+pub fn find_device<'nvml>(nvml: &'nvml Nvml, selector: Option<&str>) -> Result<Device<'nvml>, Box<dyn Error>> {
+    if let Some(selector) = selector {
+        let device = device_by_selector(nvml, selector)
+            .map_err(|e| format!("Failed to find Tesla GPU {}: {}", selector, e))?;
+        return Ok(device)
+    }
+
+    let devices = (0..nvml.device_count()?)
+        .map(|i| nvml.device_by_index(i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (mut teslas, others): (Vec<_>, Vec<_>) = devices.into_iter()
+        .partition(|device| device.brand().is_ok_and(|brand| brand == Brand::Tesla));
+    if teslas.len() == 1 {
+        let device = teslas.remove(0);
+        event!("Found {} ({})", device.name()?, device.uuid()?);
+        return Ok(device)
+    }
+
+    let mut msg = match teslas.len() {
+        0 => "No Tesla GPU found; pick one with --gpu:".to_string(),
+        _ => "Found several Tesla GPUs; pick one with --gpu:".to_string(),
+    };
+    for device in teslas.iter().chain(&others) {
+        msg.push_str(&format!(
+            "\n  {} - {}",
+            device.name().unwrap_or_else(|_| "unknown".to_string()),
+            device.uuid().unwrap_or_else(|_| "unknown".to_string()),
+        ));
+    }
+    Err(msg)?
+}
+
+/// The GPUs picked out by `selectors`, or without any, the only Tesla card in
+/// the machine.
+pub fn find_devices<'nvml>(nvml: &'nvml Nvml, selectors: &[String]) -> Result<Vec<Device<'nvml>>, Box<dyn Error>> {
+    if selectors.is_empty() {
+        return Ok(vec![find_device(nvml, None)?])
+    }
+    selectors.iter()
+        .map(|selector| find_device(nvml, Some(selector)))
+        .collect()
+}
+
+/// How the readings of several cards become one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Combine {
+    /// The worst case: the hottest temperature, and the power of whichever
+    /// card is closest to its limit. Clocks and utilization are the highest of
+    /// any card, and the compute processes those on every card.
+    #[default]
+    Max,
+    /// The average temperature, clocks and utilization, and the total power
+    /// against the total limit. Compute processes are those on every card.
+    Average,
+}
+
+impl Combine {
+    pub fn name(self) -> &'static str {
+        match self {
+            Combine::Max => "max",
+            Combine::Average => "average",
+        }
+    }
+
+    pub fn combine(self, readings: &[Reading]) -> Option<Reading> {
+        match self {
+            Combine::Max => {
+                let busiest = readings.iter()
+                    .max_by(|a, b| a.power_fraction().total_cmp(&b.power_fraction()))?;
+                Some(Reading {
+                    temp: readings.iter().map(|r| r.temp).max()?,
+                    mem_temp: readings.iter().filter_map(|r| r.mem_temp).max(),
+                    sm_clock: readings.iter().filter_map(|r| r.sm_clock).max(),
+                    mem_clock: readings.iter().filter_map(|r| r.mem_clock).max(),
+                    sm_util: readings.iter().filter_map(|r| r.sm_util).max(),
+                    mem_util: readings.iter().filter_map(|r| r.mem_util).max(),
+                    thermal_throttle: any_throttling(readings),
+                    compute_processes: total_processes(readings),
+                    ..*busiest
+                })
+            },
+            Combine::Average => {
+                if readings.is_empty() {
+                    return None
+                }
+                let n = readings.len() as u32;
+                Some(Reading {
+                    temp: (readings.iter().map(|r| r.temp).sum::<u32>() + n / 2) / n,
+                    mem_temp: average(readings.iter().filter_map(|r| r.mem_temp)),
+                    power_usage: readings.iter().map(|r| r.power_usage).sum(),
+                    power_limit: readings.iter().map(|r| r.power_limit).sum(),
+                    sm_clock: average(readings.iter().filter_map(|r| r.sm_clock)),
+                    mem_clock: average(readings.iter().filter_map(|r| r.mem_clock)),
+                    sm_util: average(readings.iter().filter_map(|r| r.sm_util)),
+                    mem_util: average(readings.iter().filter_map(|r| r.mem_util)),
+                    thermal_throttle: any_throttling(readings),
+                    compute_processes: total_processes(readings),
+                })
+            },
+        }
+    }
+}
+
+/// The rounded average, or None for no values.
+fn average(values: impl Iterator<Item = u32>) -> Option<u32> {
+    let (sum, n) = values.fold((0, 0), |(sum, n), value| (sum + value, n + 1));
+    (n > 0).then(|| (sum + n / 2) / n)
+}
+
+/// Whether any card is throttling for heat: one cooking is enough, whatever
+/// the others are doing.
+fn any_throttling(readings: &[Reading]) -> Option<bool> {
+    readings.iter().filter_map(|r| r.thermal_throttle).reduce(|a, b| a || b)
+}
+
+/// The compute processes on every card, since they all heat the same box.
+fn total_processes(readings: &[Reading]) -> Option<u32> {
+    readings.iter().filter_map(|r| r.compute_processes).reduce(|a, b| a + b)
+}
+
+impl std::str::FromStr for Combine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Combine::Max),
+            "average" => Ok(Combine::Average),
+            _ => Err(format!("Unknown way to combine GPUs {}; expected max or average", s)),
+        }
+    }
+}
+
+/// The GPU, or GPUs sharing the same fans, that we're cooling.
+pub enum Gpu<'nvml> {
+    /// Never empty
+    Local(Vec<Device<'nvml>>, Combine),
+    Remote(RemoteGpu),
+}
+
+impl Gpu<'_> {
+    /// All the cards' readings, combined into one.
+    pub fn reading(&self) -> Result<Reading, Box<dyn Error>> {
+        match self {
+            // Nothing to combine, so nothing to collect
+            Gpu::Local(devices, _) if devices.len() == 1 => Reading::from_device(&devices[0]),
+            Gpu::Local(devices, combine) => {
+                let readings = devices.iter()
+                    .map(Reading::from_device)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(combine.combine(&readings).ok_or("no GPUs")?)
+            },
+            Gpu::Remote(remote) => remote.latest(),
+        }
+    }
+
+    /// Each card's power limits, to change and later put back. Fails for a
+    /// remote GPU, whose limit isn't ours to change.
+    pub fn power_limits<'nvml>(&self, nvml: &'nvml Nvml, dry_run: bool) -> Result<PowerLimits<'nvml>, Box<dyn Error>> {
+        let Gpu::Local(devices, _) = self else {
+            Err("can't change the power limit of a remote GPU")?
+        };
+        let cards = devices.iter()
+            .map(|device| {
+                // A handle of our own, so the limits can be put back whatever
+                // happens to the Gpu
+                let uuid = device.uuid()?;
+                let device = nvml.device_by_uuid(uuid.as_str())?;
+                let initial = device.power_management_limit()?;
+                let min = device.power_management_limit_constraints()?.min_limit;
+                Ok(PowerLimit { device, uuid, initial, min, current: initial })
+            })
+            .collect::<Result<_, NvmlError>>()?;
+        Ok(PowerLimits { cards, dry_run })
+    }
+
+    /// The lowest temperature at which any of the cards starts throttling
+    /// itself.
+    pub fn slowdown_temp(&self) -> Result<u32, Box<dyn Error>> {
+        match self {
+            Gpu::Local(devices, _) => {
+                let thresholds = devices.iter()
+                    .map(|device| device.temperature_threshold(TemperatureThreshold::Slowdown))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(thresholds.into_iter().min().ok_or("no GPUs")?)
+            },
+            Gpu::Remote(_) => Err("the agent doesn't report temperature thresholds")?,
+        }
+    }
+
+    /// Makes sure every card is in persistence mode, turning it on with
+    /// `enable` and otherwise warning about each that isn't. Without it, on a
+    /// headless Linux box the driver can unload between reads, making them
+    /// slow and letting the card power down.
+    pub fn check_persistence(&mut self, enable: bool, dry_run: bool) {
+        let Gpu::Local(devices, _) = self else {
+            return
+        };
+        for device in devices {
+            if persistence_mode(device) != Some(false) {
+                continue
+            }
+            let name = device.uuid().unwrap_or_else(|_| "the GPU".to_string());
+            if enable && dry_run {
+                event!("Dry run, not turning on persistence mode for {}", name);
+                continue
+            }
+            let enabled = if enable { set_persistence_mode(device) } else { Ok(()) };
+            match (enable, enabled) {
+                (true, Ok(())) => event!("Turned on persistence mode for {}", name),
+                (true, Err(e)) => event!(
+                    "Failed to turn on persistence mode for {}: {}. Run `nvidia-smi -pm 1` as root or start nvidia-persistenced.",
+                    name, e
+                ),
+                (false, _) => event!(
+                    "Persistence mode is off for {}, so reading it can be slow and it may power down between reads. \
+                    Run `nvidia-smi -pm 1`, start nvidia-persistenced, or pass --enable-persistence.",
+                    name
+                ),
+            }
+        }
+    }
+
+    /// The first card's UUID.
+    pub fn uuid(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            Gpu::Local(devices, _) => Ok(devices.first().ok_or("no GPUs")?.uuid()?),
+            Gpu::Remote(_) => Err("the agent doesn't report a UUID")?,
+        }
+    }
+
+    /// The first card's serial number.
+    pub fn serial(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            Gpu::Local(devices, _) => Ok(devices.first().ok_or("no GPUs")?.serial()?),
+            Gpu::Remote(_) => Err("the agent doesn't report a serial number")?,
+        }
+    }
+}
+
+/// Readings sent to us by an agent, kept up to date by background threads.
+pub struct RemoteGpu {
+    latest: Arc<Mutex<Option<(Reading, SystemTime)>>>,
+    max_age: Duration,
+    /// Where we're listening, with the port filled in if it was 0
+    addr: SocketAddr,
+}
+
+/// `addr`, or localhost when it's only a port.
+fn listen_addr(addr: &str) -> String {
+    match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// Compares without bailing at the first difference, so the time taken
+/// doesn't give away how much of a guess was right.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl RemoteGpu {
+    /// Listens on `addr`, or on localhost when it's just a port, for agents to
+    /// connect. Each is served on its own thread, so one that has gone quiet
+    /// doesn't keep its replacement waiting, and is dropped after `max_age`.
+    /// Whichever sent a reading last is the one we follow.
+    pub fn listen(addr: &str, secret: Option<String>, max_age: Duration) -> Result<Self, Box<dyn Error>> {
+        let addr = listen_addr(addr);
+        let listener = TcpListener::bind(&addr)
+            .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+        let bound = listener.local_addr()?;
+        if secret.is_none() && !bound.ip().is_loopback() {
+            Err(format!("Listening for a GPU agent on {} needs a shared secret", addr))?
+        }
+        let latest = Arc::new(Mutex::new(None));
+        let shared = latest.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream.and_then(|s| s.set_read_timeout(Some(max_age)).map(|()| s)) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        event!("Failed to accept GPU agent: {}", e);
+                        continue
+                    },
+                };
+                let shared = shared.clone();
+                let secret = secret.clone();
+                thread::spawn(move || serve_agent(stream, secret.as_deref(), &shared));
+            }
+        });
+        Ok(RemoteGpu { latest, max_age, addr: bound })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits up to `timeout` for an agent to send something, returning
+    /// whether one has.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let started = std::time::Instant::now();
+        while self.latest().is_err() {
+            if started.elapsed() >= timeout {
+                return false
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+
+    fn latest(&self) -> Result<Reading, Box<dyn Error>> {
+        let (reading, received) = self.latest.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .ok_or("no readings from the GPU agent yet")?;
+        sensors::check_fresh("GPU agent", received, self.max_age)?;
+        Ok(reading)
+    }
+}
+
+/// Takes readings from one agent until it disconnects, goes quiet or sends
+/// something we can't make sense of.
+fn serve_agent(stream: TcpStream, secret: Option<&str>, latest: &Mutex<Option<(Reading, SystemTime)>>) {
+    let peer = stream.peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    let mut lines = BufReader::new(stream).lines();
+    if let Some(secret) = secret {
+        let sent = lines.next()
+            .and_then(Result::ok)
+            .and_then(|line| line.strip_prefix("secret ").map(str::to_string));
+        if !sent.is_some_and(|sent| same_secret(&sent, secret)) {
+            event!("GPU agent at {} didn't send the right secret, dropping it", peer);
+            return
+        }
+    }
+    event!("GPU agent connected from {}", peer);
+    for line in lines {
+        let reading = line.map_err(Box::<dyn Error>::from)
+            .and_then(|line| line.parse::<Reading>());
+        match reading {
+            Ok(reading) => {
+                *latest.lock().unwrap_or_else(|e| e.into_inner()) = Some((reading, SystemTime::now()));
+            },
+            Err(e) => {
+                event!("Bad reading from GPU agent at {}: {}", peer, e);
+                break
+            },
+        }
+    }
+    event!("GPU agent at {} disconnected", peer);
+}
+
+/// Sends `device`'s readings to `target` every `interval`, forever: either a
+/// "host:port" to connect to, or a path such as a virtio-serial port. With a
+/// `secret`, it goes first on each connection.
+pub fn run_agent(device: &Device, target: &str, secret: Option<&str>, interval: Duration) -> Result<(), Box<dyn Error>> {
+    loop {
+        let connection: std::io::Result<Box<dyn Write>> = if target.starts_with('/') {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(target)
+                .map(|file| Box::new(file) as Box<dyn Write>)
+        } else {
+            TcpStream::connect(target).map(|stream| Box::new(stream) as Box<dyn Write>)
+        };
+        let mut out = match connection {
+            Ok(out) => out,
+            Err(e) => {
+                event!("Failed to connect to {}: {}", target, e);
+                thread::sleep(interval);
+                continue
+            },
+        };
+        if let Some(secret) = secret {
+            if let Err(e) = writeln!(out, "secret {}", secret).and_then(|()| out.flush()) {
+                event!("Lost connection to {}: {}", target, e);
+                thread::sleep(interval);
+                continue
+            }
+        }
+        event!("Sending readings to {}", target);
+
+        loop {
+            // Send nothing rather than a guess; the host treats silence as a fault
+            match Reading::from_device(device) {
+                Ok(reading) => {
+                    if let Err(e) = writeln!(out, "{}", reading).and_then(|()| out.flush()) {
+                        event!("Lost connection to {}: {}", target, e);
+                        break
+                    }
+                },
+                Err(e) => event!("Failed to read GPU: {}", e),
+            }
+            thread::sleep(interval);
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_bare_port_listens_on_localhost() {
+        assert_eq!(listen_addr("9100"), "127.0.0.1:9100");
+        assert_eq!(listen_addr("0.0.0.0:9100"), "0.0.0.0:9100");
+        assert!(RemoteGpu::listen("0.0.0.0:0", None, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn agents_have_to_know_the_secret() {
+        let remote = RemoteGpu::listen("127.0.0.1:0", Some("hunter2".to_string()), Duration::from_secs(5)).unwrap();
+        let addr = remote.addr();
+        let mut wrong = TcpStream::connect(addr).unwrap();
+        writeln!(wrong, "secret hunter3\ntemp=40 power_usage=50000 power_limit=250000").unwrap();
+        assert!(!remote.wait(Duration::from_millis(300)));
+
+        let mut right = TcpStream::connect(addr).unwrap();
+        writeln!(right, "secret hunter2\ntemp=60 power_usage=100000 power_limit=250000").unwrap();
+        assert!(remote.wait(Duration::from_secs(5)));
+        assert_eq!(remote.latest().unwrap().temp, 60);
+    }
+}
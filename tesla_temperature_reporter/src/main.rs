@@ -1,14 +1,184 @@
 use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
+use chrono::Timelike;
 use hidapi::HidApi;
-use nvml_wrapper::{Nvml, enum_wrappers::device::TemperatureSensor};
+use nvml_wrapper::{Nvml, enum_wrappers::device::{TemperatureSensor, TemperatureThreshold}};
+use serde::Deserialize;
 use structopt::StructOpt;
 
+mod arbitration;
+#[cfg(unix)]
+mod broker;
+mod channels;
+mod chart;
+mod commander_pro;
+mod config;
+mod controller;
+#[cfg(unix)]
+mod ctl;
+mod cycle;
+mod decision;
+mod diagnostics;
+#[cfg(target_os = "linux")]
+mod emulate;
+mod expr;
+mod gpu;
+mod measurements;
+mod output;
+mod pid;
+mod sensors;
+mod session;
+mod simulate;
+mod soak;
+mod state;
+mod telemetry;
+mod traces;
+mod units;
+mod update;
+mod watchdog;
+
+use arbitration::{Arbiter, Override};
+use channels::{ChannelMapping, DerivedChannel, FanChannel};
+use commander_pro::{CommanderPro, FanGroup};
+use config::{Config, EffectiveConfig};
+use gpu::{Gpu, RemoteGpu, TempSource};
+use controller::{MSG_LED, ReportFormat, ReportTemplate};
+use cycle::{Decided, FanWriter, arbitrate};
+use decision::{Decision, History, Window};
+use output::{DryRunOutput, ExtraOutput, FanController, FanOutput, HidOutput, LoadSharing, ProcessOutput};
+use pid::{Pid, PidParams, RelayTune};
+use sensors::{FileSensor, HwmonSensor};
+use state::{Counters, HistorySample};
+use telemetry::{Event, IdentityField, Mode, OutputFormat, OutputHealth, Sample, Telemetry, TelemetryLog, emit, event, json_string};
+use watchdog::Watchdog;
+
+
+/// How a curve gets from one point to the next.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Interpolation {
+    /// Straight lines between points
+    #[default]
+    Linear,
+    /// Hold each point's speed until the next one, for abrupt changes
+    Step,
+    /// A smooth monotone cubic, which never overshoots the points
+    Cubic,
+}
+
+impl Interpolation {
+    /// The speed at `x` along the `len` points given by `point`, holding the
+    /// end points' speeds outside of them.
+    fn lookup(self, len: usize, point: impl Fn(usize) -> (f64, u8), x: f64) -> u8 {
+        let upper = match (0..len).find(|i| point(*i).0 > x) {
+            Some(0) => return point(0).1,
+            Some(upper) => upper,
+            None => return point(len - 1).1,
+        };
+        let (lower_x, lower_speed) = point(upper - 1);
+        let (upper_x, upper_speed) = point(upper);
+        let (lower_speed, upper_speed) = (lower_speed as f64, upper_speed as f64);
+        let t = (x - lower_x) / (upper_x - lower_x);
+        let speed = match self {
+            Interpolation::Linear => upper_speed * t + lower_speed * (1.0 - t),
+            Interpolation::Step => lower_speed,
+            Interpolation::Cubic => {
+                // Cubic Hermite, with PCHIP's slopes
+                let width = upper_x - lower_x;
+                let lower_slope = cubic_slope(len, &point, upper - 1);
+                let upper_slope = cubic_slope(len, &point, upper);
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * lower_speed
+                    + (t3 - 2.0 * t2 + t) * width * lower_slope
+                    + (3.0 * t2 - 2.0 * t3) * upper_speed
+                    + (t3 - t2) * width * upper_slope
+            },
+        };
+        speed.clamp(0.0, 255.0) as u8
+    }
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Interpolation::Linear),
+            "step" => Ok(Interpolation::Step),
+            "cubic" => Ok(Interpolation::Cubic),
+            _ => Err(format!("Unknown interpolation {}; expected linear, step or cubic", s)),
+        }
+    }
+}
+
+/// The curve's slope at point `i` for monotone cubic interpolation: a
+/// weighted harmonic mean of the slopes either side, and flat wherever the
+/// curve changes direction.
+fn cubic_slope(len: usize, point: &impl Fn(usize) -> (f64, u8), i: usize) -> f64 {
+    let secant = |i: usize| {
+        let ((x0, y0), (x1, y1)) = (point(i), point(i + 1));
+        if x1 > x0 {
+            (y1 as f64 - y0 as f64) / (x1 - x0)
+        } else {
+            0.0
+        }
+    };
+    if i == 0 {
+        return secant(0)
+    }
+    if i == len - 1 {
+        return secant(len - 2)
+    }
+    let (before, after) = (secant(i - 1), secant(i));
+    if before * after <= 0.0 {
+        return 0.0
+    }
+    let width_before = point(i).0 - point(i - 1).0;
+    let width_after = point(i + 1).0 - point(i).0;
+    let (w1, w2) = (2.0 * width_after + width_before, width_after + 2.0 * width_before);
+    (w1 + w2) / (w1 / before + w2 / after)
+}
+
+/// What a power curve does beyond its first or last point.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Extrapolation {
+    /// Head for off at no power draw, or flat out at the power limit
+    #[default]
+    Ramp,
+    /// Hold the end point's speed
+    Clamp,
+    /// Carry on along the line through the last two points
+    Extrapolate,
+    /// Run flat out
+    Max,
+}
+
+impl std::str::FromStr for Extrapolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ramp" => Ok(Extrapolation::Ramp),
+            "clamp" => Ok(Extrapolation::Clamp),
+            "extrapolate" => Ok(Extrapolation::Extrapolate),
+            "max" => Ok(Extrapolation::Max),
+            _ => Err(format!("Unknown extrapolation {}; expected ramp, clamp, extrapolate or max", s)),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 struct FanSpeedTable {
     table: Vec<(f64, u8)>,
+    interpolation: Interpolation,
+    /// Below the first point
+    below: Extrapolation,
+    /// Above the last point
+    above: Extrapolation,
 }
 
 impl FanSpeedTable {
@@ -16,51 +186,184 @@ impl FanSpeedTable {
         table.sort_by(|(a, _), (b, _)| a.total_cmp(b));
         FanSpeedTable {
             table,
+            interpolation: Interpolation::default(),
+            below: Extrapolation::default(),
+            above: Extrapolation::default(),
         }
     }
 
+    /// Like `new`, but rejects inputs outside of 0.0 to 1.0.
+    fn from_points(points: Vec<(f64, u8)>) -> Result<Self, Box<dyn std::error::Error>> {
+        if points.iter().any(|(power_usage, _)| !(0.0..=1.0).contains(power_usage)) {
+            Err("power usage must be between 0.0 and 1.0")?
+        }
+        Ok(FanSpeedTable::new(points))
+    }
+
+    fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    fn with_extrapolation(mut self, below: Extrapolation, above: Extrapolation) -> Self {
+        self.below = below;
+        self.above = above;
+        self
+    }
+
+    /// This curve's points, with `other`'s interpolation and extrapolation.
+    fn shaped_like(self, other: &FanSpeedTable) -> Self {
+        self.with_interpolation(other.interpolation)
+            .with_extrapolation(other.below, other.above)
+    }
+
+    /// The same curve with every input multiplied by `factor`.
+    fn rescaled(&self, factor: f64) -> Self {
+        FanSpeedTable::new(
+            self.table.iter()
+                .map(|(power_usage, speed)| (power_usage * factor, *speed))
+                .collect()
+        )
+        .shaped_like(self)
+    }
+
     fn lookup_speed(&self, power_usage: f64) -> u8 {
         let power_usage = power_usage.clamp(0.0, 1.0);
+        let (Some(first), Some(last)) = (self.table.first(), self.table.last()) else {
+            return (power_usage * 255.0) as u8
+        };
+        // Carries on along the line through points `a` and `b`
+        let extend = |a: (f64, u8), b: (f64, u8)| {
+            if b.0 != a.0 {
+                let slope = (b.1 as f64 - a.1 as f64) / (b.0 - a.0);
+                (a.1 as f64 + slope * (power_usage - a.0)).clamp(0.0, 255.0) as u8
+            } else {
+                a.1
+            }
+        };
+        let n = self.table.len();
+        if power_usage < first.0 {
+            match self.below {
+                Extrapolation::Clamp => return first.1,
+                Extrapolation::Extrapolate => return extend(*first, self.table[1.min(n - 1)]),
+                Extrapolation::Max => return 255,
+                Extrapolation::Ramp => (),
+            }
+        }
+        if power_usage > last.0 {
+            match self.above {
+                Extrapolation::Clamp => return last.1,
+                Extrapolation::Extrapolate => return extend(*last, self.table[n.saturating_sub(2)]),
+                Extrapolation::Max => return 255,
+                Extrapolation::Ramp => (),
+            }
+        }
+        // Ramping is the same as having a point at (0.0, 0) or (1.0, 255)
+        let ramp_below = usize::from(self.below == Extrapolation::Ramp);
+        let ramp_above = usize::from(self.above == Extrapolation::Ramp);
+        let len = n + ramp_below + ramp_above;
+        let point = |i| match i {
+            0 if ramp_below == 1 => (0.0, 0),
+            i if ramp_above == 1 && i == len - 1 => (1.0, 255),
+            i => self.table[i - ramp_below],
+        };
+        self.interpolation.lookup(len, point, power_usage)
+    }
+}
 
-        let (upper_usage, upper_speed) = self.table.iter()
-            .find(|(pct, _)| power_usage < *pct)
-            .copied()
-            .unwrap_or((1.0, 255));
-        let (lower_usage, lower_speed) = self.table.iter()
-            .rev()
-            .find(|(pct, _)| power_usage > *pct)
-            .copied()
-            .unwrap_or((0.0, 0));
+impl std::str::FromStr for FanSpeedTable {
+    type Err = Box<dyn std::error::Error>;
 
-        let usage_pct = (power_usage - lower_usage) as f64 / (upper_usage - lower_usage) as f64;
-        (upper_speed as f64 * usage_pct + lower_speed as f64 * (1.0 - usage_pct)) as u8
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FanSpeedTable::from_points(parse_curve_points(s)?)
     }
+
 }
 
-impl std::str::FromStr for FanSpeedTable {
+/// Parses "input:speed,input:speed,..." pairs.
+fn parse_curve_points(s: &str) -> Result<Vec<(f64, u8)>, Box<dyn std::error::Error>> {
+    s.split(',')
+        .enumerate()
+        .map(|(i, s)| {
+            let (before, after) = s.split_once(':')
+                .ok_or_else(|| format!(
+                    "Missing ':' in entry {}: \
+                    Each entry needs a seperate power usage and fan speed",
+                    i
+                ))?;
+            let input: f64 = before.trim().parse()?;
+            let fan_speed: u8 = after.trim().parse()?;
+            Ok((input, fan_speed))
+        })
+        .collect()
+}
+
+/// A fan curve keyed on absolute power draw in watts rather than a fraction of
+/// the power limit, so lowering the limit with `nvidia-smi -pl` doesn't shift
+/// the whole curve.
+#[derive(Clone, Debug)]
+struct WattCurve {
+    points: Vec<(f64, u8)>,
+}
+
+impl WattCurve {
+    fn to_fraction_table(&self, power_limit_watts: f64) -> FanSpeedTable {
+        FanSpeedTable::new(
+            self.points.iter()
+                .map(|(watts, speed)| (watts / power_limit_watts, *speed))
+                .collect()
+        )
+    }
+}
+
+impl std::str::FromStr for WattCurve {
     type Err = Box<dyn std::error::Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.split(',')
-            .enumerate()
-            .map(|(i, s)| {
-                let (before, after) = s.split_once(':')
-                    .ok_or_else(|| format!(
-                        "Missing ':' in entry {}: \
-                        Each entry needs a seperate power usage percent and fan speed",
-                        i
-                    ))?;
-                let power_usage: f64 = before.parse()?;
-                if power_usage < 0.0 || power_usage > 1.0 {
-                    Err("power usage must be between 0.0 and 1.0")?
-                }
-                let fan_speed: u8 = after.parse()?;
-                Ok((power_usage, fan_speed))
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .map(FanSpeedTable::new)
+        let points = parse_curve_points(s)?;
+        if points.iter().any(|(watts, _)| *watts < 0.0) {
+            Err("power usage in watts can't be negative")?
+        }
+        Ok(WattCurve { points })
+    }
+}
+
+/// A fan curve keyed directly on GPU temperature in degrees C, holding the
+/// speed of the nearest point outside of its range.
+#[derive(Clone, Debug)]
+struct TempCurve {
+    points: Vec<(f64, u8)>,
+    interpolation: Interpolation,
+}
+
+impl TempCurve {
+    fn from_points(mut points: Vec<(f64, u8)>) -> Result<Self, Box<dyn std::error::Error>> {
+        if points.is_empty() {
+            Err("temperature curve needs at least one point")?
+        }
+        points.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Ok(TempCurve {
+            points,
+            interpolation: Interpolation::default(),
+        })
+    }
+
+    fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    fn lookup_speed(&self, temp: u32) -> u8 {
+        self.interpolation.lookup(self.points.len(), |i| self.points[i], temp as f64)
     }
+}
 
+impl std::str::FromStr for TempCurve {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TempCurve::from_points(parse_curve_points(s)?)
+    }
 }
 
 // 10% @   0/255 => 37c
@@ -97,288 +400,3487 @@ const DEFAULT_FAN_SPEED: &[(f64, u8)] = &[
     (0.95, 255),
 ];
 
+const DEFAULT_UPDATE_INTERVAL: f64 = 5.0;
+/// Fraction of the update interval a cycle may take before we warn about it
+const DEFAULT_LATENCY_BUDGET: f64 = 0.5;
+const DEFAULT_CRITICAL_TEMP: u32 = 77;
+const DEFAULT_BOOST_TEMP: u32 = 72;
+/// How far below the card's own slowdown temperature the defaults sit
+const CRITICAL_BELOW_SLOWDOWN: u32 = 10;
+const BOOST_BELOW_CRITICAL: u32 = 5;
+const DEFAULT_BOOST_AMOUNT: u8 = 50;
+/// A typical room, which is what most curves get made in
+const DEFAULT_AMBIENT_REFERENCE: f64 = 25.0;
+const DEFAULT_AMBIENT_BIAS: f64 = 3.0;
+/// Slow enough not to be heard as a wobble in its own right
+const DEFAULT_DITHER_PERIOD: f64 = 30.0;
+const DEFAULT_POWER_GUARD_STEP: f64 = 10.0;
+/// Below this a fan is barely moving air, which is no way to run blind
+const MIN_FAILSAFE_SPEED: u8 = 128;
+const DEFAULT_BUSY_PROCESSES: u32 = 1;
+/// Long enough that the fans change pitch too slowly to notice
+const DEFAULT_PROFILE_TRANSITION: f64 = 60.0;
+const DEFAULT_CHASSIS_BOOST: u8 = 50;
+/// How far back we keep samples in memory, which the diagnostics look over
+const TELEMETRY_HISTORY: std::time::Duration = std::time::Duration::from_secs(3600);
+
 fn default_fan_speed_table() -> FanSpeedTable {
     FanSpeedTable::new(DEFAULT_FAN_SPEED.to_vec())
 }
 
-/*
-#[derive(Copy, Clone, Debug)]
-struct PidParams {
-    p: f64,
-    i: f64,
-    d: f64,
+/// Rounds `speed` to the nearest multiple of `step`, keeping full speed reachable.
+fn quantize_speed(speed: u8, step: std::num::NonZeroU8) -> u8 {
+    let step = step.get() as u32;
+    let rounded = (speed as u32 + step / 2) / step * step;
+    if speed == 255 {
+        255
+    } else {
+        rounded.min(255) as u8
+    }
+}
+
+/// How far the speed has to move, in duty counts, before the controller hears
+/// about it. Written as counts ("12"), a percentage ("5%") or "off".
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Deadband(f64);
+
+impl Deadband {
+    const OFF: Deadband = Deadband(0.0);
+}
+
+impl Default for Deadband {
+    fn default() -> Self {
+        // +/- 5%
+        Deadband(12.75)
+    }
 }
 
-impl std::str::FromStr for PidParams {
+impl std::str::FromStr for Deadband {
     type Err = Box<dyn std::error::Error>;
+
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(':');
-        Ok(PidParams {
-            p: parts.next().ok_or("Too few parts; missing p")?.parse()?,
-            i: parts.next().ok_or("Too few parts; missing i")?.parse()?,
-            d: parts.next().ok_or("Too few parts; missing d")?.parse()?,
-        })
+        let s = s.trim();
+        let counts = if s == "off" {
+            0.0
+        } else if let Some(percent) = s.strip_suffix('%') {
+            percent.trim().parse::<f64>()? * 255.0 / 100.0
+        } else {
+            s.parse()?
+        };
+        if !(0.0..=255.0).contains(&counts) {
+            Err("deadband must be between 0 and 255 counts (100%)")?
+        }
+        Ok(Deadband(counts))
     }
 }
-*/
 
-struct CircleBuf<T> {
-    n: usize,
-    buf: T,
+/// Lets the fan stop while the GPU idles, with separate thresholds for
+/// stopping and starting again so it doesn't flap. Written as "45:50" to stop
+/// below 45C and start again at 50C, or "15%:25%" to go by power draw.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FanStop {
+    Temp { stop: u32, start: u32 },
+    /// As fractions of the power limit
+    Power { stop: f64, start: f64 },
 }
 
-impl<T> CircleBuf<T> {
-    fn new(buf: T) -> Self {
-        CircleBuf {
-            n: 0,
-            buf,
+impl FanStop {
+    /// Whether the fan should be stopped, given whether it is now.
+    fn stopped(self, stopped: bool, temp: u32, power: f64) -> bool {
+        match self {
+            FanStop::Temp { stop, start } => temp < if stopped { start } else { stop },
+            FanStop::Power { stop, start } => power < if stopped { start } else { stop },
         }
     }
+}
 
-    fn push<E>(&mut self, e: E)
-        where T: AsMut<[E]>,
-    {
-        let buf = self.buf.as_mut();
-        self.n = self.n % buf.len();
-        buf[self.n] = e;
-        self.n += 1;
+impl std::str::FromStr for FanStop {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stop, start) = s.split_once(':')
+            .ok_or("expected stop:start, e.g. \"45:50\" or \"15%:25%\"")?;
+        let (stop, start) = (stop.trim(), start.trim());
+        let fan_stop = match (stop.strip_suffix('%'), start.strip_suffix('%')) {
+            (Some(stop), Some(start)) => FanStop::Power {
+                stop: stop.trim().parse::<f64>()? / 100.0,
+                start: start.trim().parse::<f64>()? / 100.0,
+            },
+            (None, None) => FanStop::Temp {
+                stop: stop.parse()?,
+                start: start.parse()?,
+            },
+            _ => Err("both thresholds must be temperatures or both percentages")?,
+        };
+        let ordered = match fan_stop {
+            FanStop::Temp { stop, start } => stop < start,
+            FanStop::Power { stop, start } => stop < start,
+        };
+        if !ordered {
+            Err("the start threshold must be above the stop threshold")?
+        }
+        Ok(fan_stop)
     }
 }
 
-impl<E, T> std::ops::Deref for CircleBuf<T>
-    where T: std::ops::Deref<Target = [E]>
-{
-    type Target = [E];
-    fn deref(&self) -> &Self::Target {
-        self.buf.deref()
-    }
+/// Whether a move from `prev_speed` to `speed` is too small to bother the
+/// controller with.
+fn within_deadband(prev_speed: u8, speed: u8, deadband: Deadband) -> bool {
+    (speed as f64 - prev_speed as f64).abs() <= deadband.0
+        // Make sure if we reach max speed, we report that (but only once)
+        && !(prev_speed != 0 && speed == 0)
+        && !(prev_speed != 255 && speed == 255)
 }
 
+/// How often the running totals get written to the state file
+const COUNTERS_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
 
-#[derive(Debug, Clone, StructOpt)]
-#[structopt(
-    name = "fan_controller",
-    about = "Updates the fan controller of the GPU's temperature.",
-    rename_all = "kebab-case",
-)]
-struct Args {
-    #[structopt(short, long, default_value = "GPU-b60cae4e-f524-14a8-2233-2dc2126b6754")]
-    uuid: String,
+/// A raw 0-255 fan duty, displayed alongside its percentage so nobody reading
+/// the output has to do the conversion themselves.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Duty(u8);
 
-    #[structopt(short, long)]
-    speed_override: Option<u8>,
+impl Duty {
+    fn percent(self) -> f64 {
+        self.0 as f64 * 100.0 / 255.0
+    }
+}
 
-    #[structopt(short = "t", long, default_value = "5.0")]
-    update_interval: f64,
+impl std::fmt::Display for Duty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/255 ({:.0}%)", self.0, self.percent())
+    }
+}
 
-    #[structopt(short, long)]
-    fan_curve: Option<FanSpeedTable>,
+/// How far SM utilization has to jump between samples to count as a job
+/// starting, in percentage points
+const UTILIZATION_JUMP: u32 = 50;
 
-    #[structopt(short, long)]
-    logging: bool,
+/// Gets the fans going as soon as utilization jumps, rather than waiting for
+/// the power average to catch up: until it does, or the averaging window has
+/// passed, the curve sees the power that utilization implies instead.
+struct UtilizationLead {
+    /// Fraction of the power limit we expect at 100% utilization
+    full_power: f64,
+    /// How long the power average takes to catch up
+    hold: std::time::Duration,
+    prev_util: Option<u32>,
+    leading_until: Option<std::time::Instant>,
 }
 
-fn inner_main(args: Args) -> Result<(), Box<dyn Error>> {
-    let fan_curve = args.fan_curve
-        .unwrap_or_else(default_fan_speed_table);
+impl UtilizationLead {
+    fn new(full_power: f64, hold: std::time::Duration) -> Self {
+        UtilizationLead {
+            full_power,
+            hold,
+            prev_util: None,
+            leading_until: None,
+        }
+    }
 
-    let mut hidapi = HidApi::new()
-        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+    /// The power for the curve to follow, given the latest utilization.
+    fn update(&mut self, util: Option<u32>, average_power: f64) -> f64 {
+        let Some(util) = util else {
+            return average_power
+        };
+        let now = std::time::Instant::now();
+        if self.prev_util.is_some_and(|prev| util >= prev + UTILIZATION_JUMP) {
+            event!("Utilization jumped to {}%, spinning up ahead of power", util);
+            self.leading_until = Some(now + self.hold);
+        }
+        self.prev_util = Some(util);
+        let implied_power = util as f64 / 100.0 * self.full_power;
+        match self.leading_until {
+            Some(until) if now < until && average_power < implied_power => implied_power,
+            _ => {
+                self.leading_until = None;
+                average_power
+            },
+        }
+    }
+}
 
-    let _ = hidapi.refresh_devices();
-    if let Some(speed_override) = args.speed_override {
-        let fan_controller = hidapi.open(0x1209, 0x0010)
-            .map_err(|e| format!("Failed to find fan controller: {}", e))?;
-
-        let mut buf = [0u8; 64];
-        if cfg!(windows) {
-            buf[0] = 1;
-            buf[1] = 1;
-            buf[2] = speed_override;
-        } else {
-            buf[0] = 1;
-            buf[1] = speed_override;
+/// How long the GPU gets to respond to each step of the power guard
+const POWER_GUARD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Degrees under --power-guard-temp before the power limit goes back up
+const POWER_GUARD_HYSTERESIS: u32 = 5;
+
+/// The last line of defence once the fans have nothing left to give: steps
+/// the power limit down while the temperature keeps climbing. Each card steps
+/// down from its own usual limit, rather than all of them from their total.
+struct PowerGuard {
+    temp: u32,
+    /// In milliwatts, like NVML's limits
+    step: u32,
+    /// How many steps down the cards are
+    steps: u32,
+    /// When we last stepped down, and the temperature then
+    stepped: Option<(std::time::Instant, u32)>,
+}
+
+impl PowerGuard {
+    fn new(temp: u32, step: u32) -> Self {
+        PowerGuard {
+            temp,
+            step,
+            steps: 0,
+            stepped: None,
         }
-        fan_controller.write(&buf[..])
-            .map_err(|e| format!("Error updating fan controller: {}", e))?;
+    }
 
-        return Ok(())
+    fn engaged(&self) -> bool {
+        self.stepped.is_some()
     }
 
-    let nvml = if cfg!(windows) {
-        Nvml::init()
-    } else {
-        Nvml::builder()
-            .lib_path("./libnvidia-ml.so".as_ref())
-            .init()
-    };
-    let nvml = nvml
-        .map_err(|e| format!("Failed to init NVML: {}", e))?;
-
-    let gpu = nvml.device_by_uuid(&args.uuid[..])
-        .map_err(|e| format!("Failed to find Tesla GPU: {}", e))?;
-
-    if args.logging {
-        println!(
-            "{:?} - {} - {} - {}",
-            gpu,
-            gpu.name()?,
-            gpu.uuid()?,
-            gpu.temperature(TemperatureSensor::Gpu)?
+    /// How far below its usual limit to hold each card, if that should change.
+    fn update(&mut self, temp: u32, speed: u8) -> Option<u32> {
+        let now = std::time::Instant::now();
+        let step_down = match self.stepped {
+            None => speed == 255 && temp >= self.temp,
+            Some(_) if temp + POWER_GUARD_HYSTERESIS <= self.temp => {
+                self.stepped = None;
+                self.steps = 0;
+                event!("Cooled off to {} C, restoring power limits", temp);
+                return Some(0)
+            },
+            // Still climbing, or at least not coming down
+            Some((at, prev_temp)) => now - at >= POWER_GUARD_INTERVAL && temp >= prev_temp && temp >= self.temp,
+        };
+        if !step_down {
+            return None
+        }
+        self.stepped = Some((now, temp));
+        self.steps = self.steps.saturating_add(1);
+        let below = self.steps.saturating_mul(self.step);
+        event!(
+            "Fans are flat out and the GPU is at {} C, reducing power limits to {:.0} W under usual",
+            temp,
+            below as f64 / 1000.0
         );
+        Some(below)
     }
+}
 
-    let temp = gpu.temperature(TemperatureSensor::Gpu)?;
-    let power_usage = gpu.power_usage()?;
-    let power_limit = gpu.power_management_limit()?;
-
-    // We want to keep a 1 minute history
-    let samples = (60.0 / args.update_interval).ceil() as usize;
-    let mut temp_history = CircleBuf::new(vec![temp as u8; samples]);
-    let mut power_history = CircleBuf::new(vec![power_usage as f64 / power_limit as f64; samples]);
+/// Degrees under --chassis-temp before the chassis boost comes off
+const CHASSIS_HYSTERESIS: f64 = 2.0;
 
-    let mut prev_speed = None;
+/// Extra duty for every fan output while the case is running hot, whatever the
+/// GPU is doing.
+struct ChassisGuard {
+    sensor: HwmonSensor,
+    temp: f64,
+    boost: u8,
+    hot: bool,
+    failing: bool,
+}
 
-    let mut fan_controller = None;
-    loop {
-        thread::sleep(std::time::Duration::from_millis((args.update_interval * 1000.0) as u64));
+impl ChassisGuard {
+    fn new(sensor: HwmonSensor, temp: f64, boost: u8) -> Self {
+        ChassisGuard {
+            sensor,
+            temp,
+            boost,
+            hot: false,
+            failing: false,
+        }
+    }
 
-        // The fan controller might get disconnected, so handle that potential
-        // Ugh, this code is ugly :(
-        let fan_controller_ref = match &mut fan_controller {
-            Some(device) => device,
-            None => {
-                let _ = hidapi.refresh_devices();
-                match hidapi.open(0x1209, 0x0010) {
-                    Ok(device) => fan_controller.insert(device),
-                    Err(e) => {
-                        println!("Failed to find fan controller: {}", e);
-                        continue
-                    },
+    /// The duty to add to every output this cycle.
+    fn update(&mut self) -> u8 {
+        // Like the ambient sensor, losing it only loses the extra margin
+        let chassis_temp = match self.sensor.read() {
+            Ok(chassis_temp) => {
+                self.failing = false;
+                chassis_temp
+            },
+            Err(e) => {
+                if !self.failing {
+                    event!("Failed to read the chassis temperature, not boosting for it: {}", e);
                 }
+                self.failing = true;
+                return 0
             },
         };
-
-        let speed = loop {
-            let temp = match gpu.temperature(TemperatureSensor::Gpu) {
-                Ok(temp) => temp,
-                Err(e) => {
-                    println!("Error updating fan controller: {}", e);
-                    break 255
-                },
-            };
-            let power_usage = match gpu.power_usage() {
-                Ok(power_usage) => power_usage,
-                Err(e) => {
-                    println!("Error updating fan controller: {}", e);
-                    break 255
-                },
-            };
-            let power_limit = match gpu.power_management_limit() {
-                Ok(power_limit) => power_limit,
-                Err(e) => {
-                    println!("Error updating fan controller: {}", e);
-                    break 255
-                },
-            };
-
-            temp_history.push(temp as u8);
-            power_history.push(power_usage as f64 / power_limit as f64);
-            let max_temp = *temp_history.iter().max().unwrap();
-
-            // Safety condition in case we get run away temps
-            if max_temp >= 77 {
-                break 255
+        let hot = if self.hot {
+            chassis_temp > self.temp - CHASSIS_HYSTERESIS
+        } else {
+            chassis_temp >= self.temp
+        };
+        if hot != self.hot {
+            if hot {
+                event!("Chassis reached {:.1} C, boosting every fan by {}", chassis_temp, self.boost);
+            } else {
+                event!("Chassis cooled to {:.1} C, back to the curve", chassis_temp);
             }
+            self.hot = hot;
+        }
+        if hot { self.boost } else { 0 }
+    }
+}
 
-            let average_power = power_history.iter().sum::<f64>() / power_history.len() as f64;
-            let speed = fan_curve.lookup_speed(average_power);
+/// Exponential moving average, for when a flat window reacts too slowly.
+#[derive(Copy, Clone, Debug)]
+struct Ema(f64);
 
-            // If we're at or over 72 degrees, increase the fan speed just in case
-            let adj_speed = if max_temp >= 72 {
-                speed.saturating_add(50)
-            } else {
-                speed
-            };
+impl Ema {
+    /// Mixes in `value`, weighted by `alpha`, and returns the new average.
+    fn push(&mut self, value: f64, alpha: f64) -> f64 {
+        self.0 += alpha * (value - self.0);
+        self.0
+    }
+}
 
-            if args.logging {
-                println!(
-                    "Avg power {:.1}, Max temp {}, Comp speed {}, Prev speed {}, Adj speed {}",
-                    average_power * 100.0,
-                    max_temp,
-                    speed,
-                    prev_speed.map(|i| i as i32).unwrap_or(-1),
-                    adj_speed
-                );
-            }
-            break adj_speed
-        };
+struct CircleBuf<E> {
+    n: usize,
+    capacity: usize,
+    buf: Vec<E>,
+}
 
-        // If the new speed is within +/- 5% of the old speed, don't report it
-        if let Some(prev_speed) = prev_speed {
-            if (speed as f64 - prev_speed as f64).abs() <= 12.75
-                    // Make sure if we reach max speed, we report that (but only once)
-                    && !(prev_speed != 0 && speed == 0)
-                    && !(prev_speed != 255 && speed == 255) {
-                // Do not update
-                continue
-            }
+impl<E: Clone> CircleBuf<E> {
+    /// Starts out as if `value` had already been pushed `capacity` times.
+    fn filled(value: E, capacity: usize) -> Self {
+        CircleBuf {
+            n: 0,
+            capacity,
+            buf: vec![value; capacity],
         }
+    }
 
-        let mut buf = [0u8; 64];
-        if cfg!(windows) {
-            buf[0] = 1;
-            buf[1] = 1;
-            buf[2] = speed;
-        } else {
-            buf[0] = 1;
-            buf[1] = speed;
-        }
-        match fan_controller_ref.write(&buf[..]) {
-            Ok(_) => {
-                println!("Setting speed to {}", speed);
-                prev_speed = Some(speed);
-            },
-            Err(e) => {
-                println!("Error updating fan controller: {}", e);
-                fan_controller = None;
-            },
+    /// Starts out empty and grows to `capacity` before it wraps around.
+    fn with_capacity(capacity: usize) -> Self {
+        CircleBuf {
+            n: 0,
+            capacity,
+            buf: Vec::with_capacity(capacity),
         }
     }
 
-    // Ok(())
+    fn push(&mut self, e: E) {
+        if self.buf.len() < self.capacity {
+            self.buf.push(e);
+            return
+        }
+        self.n %= self.capacity;
+        self.buf[self.n] = e;
+        self.n += 1;
+    }
 }
 
-
-fn main() {
-    let args = Args::from_args();
-    match inner_main(args) {
-        Ok(()) => (),
-        Err(e) => {
-            println!("Error occurred: {}", e.to_string());
-        },
+impl<E> std::ops::Deref for CircleBuf<E> {
+    type Target = [E];
+    fn deref(&self) -> &Self::Target {
+        &self.buf
     }
-    /*
-    let device = (0..nvml.device_count().unwrap())
-        .map(|i| nvml.device_by_index(i).unwrap())
-        .find(|device| device.uuid().unwrap() == "GPU-b60cae4e-f524-14a8-2233-2dc2126b6754")
-        .unwrap();
-    */
+}
 
-    // for i in 0..nvml.device_count().unwrap() {
-    //     let device = nvml.device_by_index(i).unwrap();
-    //     println!("{:?} - {} - {} - {}", device, device.name().unwrap(), device.uuid().unwrap(), device.temperature(TemperatureSensor::Gpu).unwrap());
-    // }
+impl<E> std::ops::DerefMut for CircleBuf<E> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
 
-    /*
-    let arg: u8 = env::args().nth(1).unwrap().parse().unwrap();
-    println!("{}", arg);
+/// How the one minute history starts out, before there's a minute of samples.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum StartupHistory {
+    /// Only look at the samples we've actually taken
+    WarmUp,
+    /// Treat the first reading as if it had held for the whole minute
+    Fill,
+}
 
-    let hidapi = HidApi::new().unwrap();
-    for device in hidapi.device_list() {
-        println!("{:?}", device.path());
+impl StartupHistory {
+    fn history<E: Clone>(self, first: E, capacity: usize) -> CircleBuf<E> {
+        match self {
+            StartupHistory::WarmUp => CircleBuf::with_capacity(capacity),
+            StartupHistory::Fill => CircleBuf::filled(first, capacity),
+        }
     }
-    let device = hidapi.open(0x1209, 0x0010).unwrap();
+}
+
+impl std::str::FromStr for StartupHistory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warm-up" => Ok(StartupHistory::WarmUp),
+            "fill" => Ok(StartupHistory::Fill),
+            _ => Err(format!("Unknown startup history {}; expected warm-up or fill", s)),
+        }
+    }
+}
+
+/// What to do with a fractional fan curve when the GPU's power limit changes
+/// underneath us.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PowerLimitPolicy {
+    /// Keep the curve as a fraction of whatever the limit now is
+    Keep,
+    /// Re-anchor the curve so each point keeps the wattage it had at startup
+    Rescale,
+}
+
+impl std::str::FromStr for PowerLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(PowerLimitPolicy::Keep),
+            "rescale" => Ok(PowerLimitPolicy::Rescale),
+            _ => Err(format!("Unknown power limit policy {}; expected keep or rescale", s)),
+        }
+    }
+}
+
+/// Coarse summary of how the GPU is doing, shown on the controller's status LED.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ThermalState {
+    Normal,
+    Warm,
+    Critical,
+    Fault,
+}
+
+impl ThermalState {
+    fn name(self) -> &'static str {
+        match self {
+            ThermalState::Normal => "normal",
+            ThermalState::Warm => "warm",
+            ThermalState::Critical => "critical",
+            ThermalState::Fault => "fault",
+        }
+    }
+
+    fn led_report(self) -> [u8; 5] {
+        match self {
+            ThermalState::Normal => [MSG_LED, 0, 255, 0, 0],
+            ThermalState::Warm => [MSG_LED, 255, 128, 0, 0],
+            ThermalState::Critical => [MSG_LED, 255, 0, 0, 0],
+            ThermalState::Fault => [MSG_LED, 255, 0, 0, 1],
+        }
+    }
+}
+
+/// A range of local hours, e.g. "22-7", during which we keep quiet. The range
+/// may wrap around midnight.
+#[derive(Copy, Clone, Debug)]
+struct QuietHours {
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            self.start <= hour || hour < self.end
+        }
+    }
+
+    fn is_now(&self) -> bool {
+        self.contains(chrono::Local::now().hour())
+    }
+}
+
+impl std::str::FromStr for QuietHours {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-')
+            .ok_or("Quiet hours must be formatted as start-end, e.g. 22-7")?;
+        let start: u32 = start.trim().parse()?;
+        let end: u32 = end.trim().parse()?;
+        if start > 23 || end > 23 {
+            Err("quiet hours must be between 0 and 23")?
+        }
+        Ok(QuietHours { start, end })
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fan_controller",
+    about = "Updates the fan controller of the GPU's temperature.",
+    rename_all = "kebab-case",
+)]
+struct Cli {
+    /// How to print results: "plain" for people, "json" for one JSON object
+    /// per line, or "quiet" for nothing but errors
+    #[structopt(long, global = true, default_value = "plain")]
+    output: OutputFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+// Only ever parsed once, so the size of `Run` doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Run the control loop
+    Run(Args),
+    /// Print the settings `run` would start with, and whether each came from
+    /// the command line, the config file or the defaults
+    ShowConfig(Args),
+    /// Read the sensors, set the fan speed and exit, for running from cron or
+    /// Task Scheduler. Keep the state across runs with --state-file.
+    Once(Args),
+    /// Set the fan controller to a fixed speed once and exit
+    Set(SetArgs),
+    /// List the GPUs and fan controllers we can see
+    ListDevices(ControllerArgs),
+    /// Print the speed the fan curve gives across the range of power usage
+    TestCurve(TestCurveArgs),
+    /// Send this machine's GPU readings to a controller elsewhere, e.g. from
+    /// inside a VM the GPU is passed through to
+    Agent(AgentArgs),
+    /// Find gains for --pid by switching the fan between two speeds around
+    /// the target temperature and timing the swings. Takes a steady load on
+    /// the GPU and usually a good few minutes.
+    Autotune(AutotuneArgs),
+    /// Measure the temperature the GPU settles at for each of a set of fan
+    /// speeds, under a steady load, and print the results in the form
+    /// check-measurements reads
+    Characterize(CharacterizeArgs),
+    /// Draw temperature, power and fan speed history as an SVG chart
+    Chart(ChartArgs),
+    /// Replay a --telemetry-log file or debug bundle through the fan curve,
+    /// boost, critical temperature and deadband, and print the speed changes
+    /// they would have made. Takes the same settings as `run`.
+    Simulate(SimulateArgs),
+    /// Check the fan curve `run` would use against measurements of what
+    /// each power level needs. Takes the same settings as `run`.
+    CheckMeasurements(CheckMeasurementsArgs),
+    /// Run the GPU through hours of alternating load plateaus while `run
+    /// --telemetry-log` drives the fans, then check from the log that it never
+    /// reached the critical temperature and the fans kept up with the curve.
+    /// Takes the same settings as `run`.
+    Soak(SoakArgs),
+    /// See whether there's a newer release, and optionally install it
+    SelfUpdate(SelfUpdateArgs),
+    /// Own the fan controller and drive it for `run --broker` over a Unix
+    /// socket, so only this needs access to the device
+    Broker(BrokerArgs),
+    /// Describe the HID protocol we speak to the fan controller
+    Protocol(ProtocolCommand),
+    /// Talk to a daemon running with --ctl-socket
+    Ctl(CtlCommand),
+    /// Pretend to be a fan controller (Linux only, through uhid), logging
+    /// every report and answering with simulated fan RPM
+    EmulateController(EmulateArgs),
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct EmulateArgs {
+    /// RPM of the simulated fans at full speed
+    #[structopt(long, default_value = "3000")]
+    max_rpm: u16,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum CtlCommand {
+    /// Print what the daemon is doing, cycle by cycle
+    Tail {
+        /// The daemon's --ctl-socket
+        socket: std::path::PathBuf,
+    },
+    /// Print the settings the daemon started with, and whether each came
+    /// from the command line, the config file or the defaults
+    ShowConfig {
+        /// The daemon's --ctl-socket
+        socket: std::path::PathBuf,
+    },
+    /// Run the fans at least this fast for a while, e.g. to get the heatsink
+    /// cold before a heavy job. The curve still takes them faster if it wants
+    /// to, and anything wrong with the GPU or fans still takes over. Refused
+    /// while a speed override is in force.
+    Boost {
+        /// The daemon's --ctl-socket
+        socket: std::path::PathBuf,
+
+        #[structopt(long, default_value = "255", parse(try_from_str = parse_duty))]
+        speed: u8,
+
+        #[structopt(long, default_value = "10")]
+        minutes: f64,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum ProtocolCommand {
+    /// Print the VID/PID, report layout and messages as JSON, for keeping
+    /// firmware in sync
+    Dump(ControllerArgs),
+}
+
+/// How to talk to our own HID fan controller.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct ReportArgs {
+    /// USB vendor ID of the fan controller, in hex, for home-built ones with
+    /// their own [default: 1209]
+    #[structopt(long, parse(try_from_str = controller::parse_usb_id))]
+    usb_vid: Option<u16>,
+
+    /// USB product ID of the fan controller, in hex [default: 0010]
+    #[structopt(long, parse(try_from_str = controller::parse_usb_id))]
+    usb_pid: Option<u16>,
+
+    /// Layout of the fan speed report for controllers with different firmware,
+    /// e.g. "0x01,{speed},0x00"
+    #[structopt(long)]
+    report_template: Option<ReportTemplate>,
+
+    /// Total length of each HID report, including the report ID, with the rest
+    /// zero padded [default: 64]
+    #[structopt(long)]
+    report_length: Option<usize>,
+
+    /// Report ID prepended to every HID report
+    #[structopt(long, parse(try_from_str = controller::parse_u8))]
+    report_id: Option<u8>,
+
+    /// Fan controllers to drive, by serial number or HID path as
+    /// list-devices shows them, for when there are several identical ones.
+    /// Each gets the same speeds. Without any, the first one found.
+    #[structopt(long, use_delimiter = true)]
+    controller: Vec<String>,
+}
+
+/// How to talk to the fan controller, for the commands that open it without
+/// taking the rest of `run`'s settings.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct ControllerArgs {
+    /// Settings file in TOML to take [controller] from, as `run` does;
+    /// anything also given on the command line overrides it
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
+
+    #[structopt(flatten)]
+    report: ReportArgs,
+}
+
+impl ControllerArgs {
+    /// The command line's settings, with the config file's filling the gaps.
+    fn report(&self) -> Result<ReportArgs, Box<dyn Error>> {
+        let mut report = self.report.clone();
+        if let Some(path) = &self.config {
+            Config::load(path)?.apply_controller(&mut report)?;
+        }
+        Ok(report)
+    }
+}
+
+impl ReportArgs {
+    fn format(&self) -> ReportFormat {
+        let default = ReportFormat::default();
+        ReportFormat {
+            vid: self.usb_vid.unwrap_or(default.vid),
+            pid: self.usb_pid.unwrap_or(default.pid),
+            report_id: self.report_id.or(default.report_id),
+            len: self.report_length.unwrap_or(default.len),
+            detect: self.report_id.is_none() && self.report_length.is_none(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct SetArgs {
+    /// Duty to set, 0-255; the same as --duty
+    #[structopt(
+        parse(try_from_str = parse_duty),
+        required_unless_one = &["duty", "percent", "rpm-target"],
+        conflicts_with_all = &["duty", "percent", "rpm-target"],
+    )]
+    speed: Option<u8>,
+
+    /// Duty to set, 0-255
+    #[structopt(long, parse(try_from_str = parse_duty), conflicts_with_all = &["percent", "rpm-target"])]
+    duty: Option<u8>,
+
+    /// Speed to set in percent, e.g. 60 or 62.5; a decimal comma as in 62,5
+    /// is taken too
+    #[structopt(long, parse(try_from_str = parse_percent), conflicts_with = "rpm-target")]
+    percent: Option<f64>,
+
+    /// RPM to aim for, turned into a duty by scaling --max-rpm. Fans aren't
+    /// quite linear, so the readback shows how close it came
+    #[structopt(long, requires = "max-rpm")]
+    rpm_target: Option<u16>,
+
+    /// RPM of the fans at full duty, for --rpm-target
+    #[structopt(long)]
+    max_rpm: Option<u16>,
+
+    /// How long to wait for the controller to report the fans' RPM after
+    /// setting the speed, e.g. "3s"; 0 to not wait
+    #[structopt(long, default_value = "3s", parse(try_from_str = units::duration))]
+    readback: std::time::Duration,
+
+    #[structopt(flatten)]
+    controller: ControllerArgs,
+}
+
+/// A duty as a whole number from 0 to 255.
+fn parse_duty(s: &str) -> Result<u8, String> {
+    s.trim().parse()
+        .map_err(|_| format!("Bad duty {:?}: expected a whole number from 0 to 255, e.g. 153", s.trim()))
+}
+
+/// A percentage from 0 to 100, with or without a '%', and with a decimal
+/// point or comma whatever the locale.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let number = s.strip_suffix('%').unwrap_or(s).trim().replace(',', ".");
+    match number.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("Bad percentage {:?}: expected a number from 0 to 100, e.g. 60 or 62.5", s)),
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct TestCurveArgs {
+    /// Settings file in TOML to take the fan curve from
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
+
+    #[structopt(short, long)]
+    fan_curve: Option<FanSpeedTable>,
+
+    /// "linear", "step" or "cubic"; overrides the config file's
+    #[structopt(long)]
+    fan_curve_interpolation: Option<Interpolation>,
+
+    /// "ramp", "clamp", "extrapolate" or "max"; overrides the config file's
+    #[structopt(long)]
+    fan_curve_below: Option<Extrapolation>,
+
+    /// Likewise above the last point
+    #[structopt(long)]
+    fan_curve_above: Option<Extrapolation>,
+
+    /// Power usage, as a fraction of the limit, between printed lines
+    #[structopt(long, default_value = "0.05")]
+    step: f64,
+
+    /// Instead of printing the curve, replay it and the default curve against
+    /// traces of typical workloads (see traces/README.md), and report how hot
+    /// each got and how long the fans spent at full speed
+    #[structopt(long)]
+    traces: bool,
+
+    /// Directory of traces to replay instead of the built-in ones
+    #[structopt(long, requires = "traces")]
+    trace_dir: Option<std::path::PathBuf>,
+
+    /// Temperature at which the replay runs the fans at full speed
+    /// [default: 77]
+    #[structopt(long, requires = "traces", parse(try_from_str = units::whole_celsius))]
+    critical_temp: Option<u32>,
+
+    /// Temperature from which the replay boosts the fans [default: 72]
+    #[structopt(long, requires = "traces", parse(try_from_str = units::whole_celsius))]
+    boost_temp: Option<u32>,
+
+    /// Duty counts the boost adds [default: 50]
+    #[structopt(long, requires = "traces")]
+    boost_amount: Option<u8>,
+
+    /// Temperature the curve should keep every trace at or under [default:
+    /// the critical temperature]
+    #[structopt(long, requires = "traces", parse(try_from_str = units::whole_celsius))]
+    temp_limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct BrokerArgs {
+    /// Socket to listen on, e.g. /run/tesla_fan/broker.sock
+    socket: std::path::PathBuf,
+
+    /// Group, by name or ID, to give the socket to, so that the controller
+    /// can run as one of its members [default: the broker's own group]
+    #[structopt(long)]
+    socket_group: Option<String>,
+
+    #[structopt(flatten)]
+    controller: ControllerArgs,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct AgentArgs {
+    /// "host:port" of the controller's --remote-gpu, or a virtio-serial port
+    /// such as /dev/virtio-ports/fan_controller
+    target: String,
+
+    /// GPU to report on, by NVML index, PCI bus ID or UUID; picks the only
+    /// Tesla card if not given
+    #[structopt(short = "u", long, alias = "uuid")]
+    gpu: Option<String>,
+
+    #[structopt(short = "t", long, default_value = "5s", parse(try_from_str = units::duration))]
+    update_interval: std::time::Duration,
+
+    /// File holding the secret the controller's --remote-gpu-secret-file
+    /// holds, to send before the readings
+    #[structopt(long)]
+    secret_file: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct SelfUpdateArgs {
+    /// Only report whether there's a newer release (the default)
+    #[structopt(long)]
+    check: bool,
+
+    /// Download the newer release's binary and replace this one with it
+    #[structopt(long, conflicts_with = "check")]
+    install: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct AutotuneArgs {
+    /// GPU to tune against, by NVML index, PCI bus ID or UUID; picks the only
+    /// Tesla card if not given
+    #[structopt(short = "u", long, alias = "uuid")]
+    gpu: Option<String>,
+
+    /// Temperature to swing around, e.g. "70C"; use what you'll give
+    /// --target-temp
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    target_temp: u32,
+
+    /// Fan speed while the GPU is below the target
+    #[structopt(long, default_value = "60")]
+    low_speed: u8,
+
+    /// Fan speed while the GPU is above the target
+    #[structopt(long, default_value = "200")]
+    high_speed: u8,
+
+    /// Oscillations to average over, after the first
+    #[structopt(long, default_value = "3")]
+    cycles: usize,
+
+    #[structopt(short = "t", long, default_value = "2s", parse(try_from_str = units::seconds))]
+    update_interval: f64,
+
+    /// Give up if the oscillations haven't settled after this long, in
+    /// minutes unless given a unit
+    #[structopt(long, default_value = "120", parse(try_from_str = units::minutes))]
+    max_minutes: std::time::Duration,
+
+    /// Temperature at which to abort and run the fan at full speed
+    /// [default: 10C below the GPU's slowdown temperature, or 77]
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    critical_temp: Option<u32>,
+
+    #[structopt(flatten)]
+    controller: ControllerArgs,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct SimulateArgs {
+    /// History to replay: a --telemetry-log file or a debug bundle
+    #[structopt(long)]
+    from: std::path::PathBuf,
+
+    /// Slowdown temperature of the GPU the history came from, which the
+    /// critical and boost temperatures default to below as for `run`. Needed
+    /// unless both of those are given.
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    slowdown_temp: Option<u32>,
+
+    #[structopt(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct CheckMeasurementsArgs {
+    /// Measurements of duty against temperature at each power level (see
+    /// m40_measurements.txt)
+    measurements: std::path::PathBuf,
+
+    /// Temperature the curve should keep the GPU at or under [default: the
+    /// boost temperature]
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    temp_limit: Option<u32>,
+
+    #[structopt(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct SoakArgs {
+    /// How long to soak for
+    #[structopt(long, default_value = "8")]
+    hours: f64,
+
+    /// Command that loads the GPU, run for each plateau with {load} replaced
+    /// by its level and stopped at the end of it
+    #[structopt(long)]
+    load_command: String,
+
+    /// Load of each plateau in percent, taken in turn and round again;
+    /// nothing runs at 0
+    #[structopt(long, use_delimiter = true, default_value = "100,0,50,0")]
+    load_levels: Vec<u8>,
+
+    #[structopt(long, default_value = "30", parse(try_from_str = units::minutes))]
+    plateau_minutes: std::time::Duration,
+
+    #[structopt(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct ChartArgs {
+    /// History to draw: a --telemetry-log file or a debug bundle
+    #[structopt(long)]
+    from: std::path::PathBuf,
+
+    /// How far back from the newest sample to draw, e.g. "6h", "30m" or "2d"
+    #[structopt(long, default_value = "6h", parse(try_from_str = units::duration))]
+    since: std::time::Duration,
+
+    /// SVG file to write
+    #[structopt(long)]
+    out: std::path::PathBuf,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct CharacterizeArgs {
+    /// GPU to measure, by NVML index, PCI bus ID or UUID; picks the only
+    /// Tesla card if not given
+    #[structopt(short = "u", long, alias = "uuid")]
+    gpu: Option<String>,
+
+    /// Fan speeds to measure at, in order. Going from fast to slow keeps the
+    /// card from having to cool back down between steps.
+    #[structopt(long, use_delimiter = true, default_value = "255,210,170,120,70,0")]
+    duties: Vec<u8>,
+
+    /// Command to put a steady load on the GPU while measuring, stopped at the
+    /// end; without it, start the load yourself first
+    #[structopt(long)]
+    load_command: Option<String>,
+
+    /// How long the temperature has to hold steady to count as settled, in
+    /// minutes unless given a unit
+    #[structopt(long, default_value = "3", parse(try_from_str = units::minutes))]
+    settle_minutes: std::time::Duration,
+
+    /// How far the temperature may wander while counting as steady
+    #[structopt(long, default_value = "1")]
+    settle_tolerance: u32,
+
+    /// Move on from a speed that hasn't settled after this long, in minutes
+    /// unless given a unit
+    #[structopt(long, default_value = "20", parse(try_from_str = units::minutes))]
+    max_minutes: std::time::Duration,
+
+    #[structopt(short = "t", long, default_value = "5s", parse(try_from_str = units::seconds))]
+    update_interval: f64,
+
+    /// Write the measurements to this file as well, ready for
+    /// check-measurements
+    #[structopt(long)]
+    out: Option<std::path::PathBuf>,
+
+    /// Temperature at which to stop and run the fan at full speed
+    /// [default: 10C below the GPU's slowdown temperature, or 77]
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    critical_temp: Option<u32>,
+
+    #[structopt(flatten)]
+    controller: ControllerArgs,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct Args {
+    /// Settings file in TOML; anything also given on the command line
+    /// overrides it
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
+
+    /// GPU to follow, by NVML index (0), PCI bus ID (0000:03:00.0) or UUID;
+    /// picks the only Tesla card if not given. Give several, e.g. "0,1", for
+    /// cards sharing the same fans and the fans follow whichever is worst off.
+    #[structopt(short = "u", long, alias = "uuid", use_delimiter = true)]
+    gpu: Vec<String>,
+
+    /// How to combine the readings of several GPUs: "max" for whichever is
+    /// worst off, or "average" [default: max]
+    #[structopt(long)]
+    combine: Option<gpu::Combine>,
+
+    /// Hold the fan at this speed for --override-minutes before going over
+    /// to the curve
+    #[structopt(short, long, requires = "override-minutes")]
+    speed_override: Option<u8>,
+
+    #[structopt(long, requires = "speed-override", parse(try_from_str = units::minutes))]
+    override_minutes: Option<std::time::Duration>,
+
+    /// Only ever change the fan speed in steps of this many duty counts,
+    /// instead of the default +/- 5% deadband
+    #[structopt(long)]
+    speed_step: Option<std::num::NonZeroU8>,
+
+    /// Leave the fan alone until the speed moves by more than this, in duty
+    /// counts ("12"), as a percentage ("5%"), or "off" to follow every change
+    /// [default: 5%]
+    #[structopt(long, conflicts_with = "speed-step")]
+    deadband: Option<Deadband>,
+
+    /// Never let the fan curve go above this speed. Ignored once the GPU
+    /// reaches a critical temperature.
+    #[structopt(long)]
+    max_speed: Option<u8>,
+
+    /// Time between updates, e.g. "2s" or "500ms" [default: 5s]
+    #[structopt(short = "t", long, parse(try_from_str = units::seconds))]
+    update_interval: Option<f64>,
+
+    /// Sample on multiples of the update interval since the Unix epoch, so
+    /// telemetry from several machines lines up
+    #[structopt(long)]
+    align_samples: bool,
+
+    #[structopt(short, long)]
+    fan_curve: Option<FanSpeedTable>,
+
+    /// Like --fan-curve, but keyed on power draw in watts, e.g. "100:0,150:70,250:255"
+    #[structopt(long, conflicts_with = "fan-curve")]
+    fan_curve_watts: Option<WattCurve>,
+
+    /// Follow the GPU's temperature with a curve in degrees C, e.g.
+    /// "40:0,60:120,75:255". On its own it replaces the power curve; given
+    /// alongside --fan-curve or --fan-curve-watts, the fan runs at whichever
+    /// of the two speeds is higher.
+    #[structopt(long)]
+    temp_curve: Option<TempCurve>,
+
+    /// How the power curve gets between its points: "linear", "step" to hold
+    /// each point's speed until the next, or "cubic" for a smooth curve
+    /// [default: linear]
+    #[structopt(long)]
+    fan_curve_interpolation: Option<Interpolation>,
+
+    /// What the power curve does below its first point: "ramp" down to off at
+    /// no power draw, "clamp" to the first point's speed, "extrapolate" along
+    /// the first two points, or "max" [default: ramp]
+    #[structopt(long)]
+    fan_curve_below: Option<Extrapolation>,
+
+    /// Likewise above the last point, where "ramp" heads for full speed at
+    /// the power limit [default: ramp]
+    #[structopt(long)]
+    fan_curve_above: Option<Extrapolation>,
+
+    /// Like --fan-curve-interpolation, for --temp-curve [default: linear]
+    #[structopt(long)]
+    temp_curve_interpolation: Option<Interpolation>,
+
+    /// Instead of a curve, hold the GPU at --target-temp with a PID loop of
+    /// these gains, e.g. "8:0.2:10". The output is in duty counts.
+    #[structopt(
+        long,
+        requires = "target-temp",
+        conflicts_with_all = &["fan-curve", "fan-curve-watts", "temp-curve"],
+    )]
+    pid: Option<PidParams>,
+
+    /// Temperature for --pid to hold the GPU at, e.g. "70C"
+    #[structopt(long, requires = "pid", parse(try_from_str = units::whole_celsius))]
+    target_temp: Option<u32>,
+
+    /// What to do with the fan curve if the power limit changes while running:
+    /// "keep" it as a fraction of the new limit, or "rescale" it to keep the
+    /// wattages it had at startup. Curves in watts always keep their wattages.
+    #[structopt(long, default_value = "keep")]
+    on_power_limit_change: PowerLimitPolicy,
+
+    /// Until there's a minute of samples, either go by only the ones taken so
+    /// far ("warm-up"), or act as though the first reading had held for the
+    /// whole minute ("fill"). Filling is more cautious, but after a restart
+    /// with a hot GPU it keeps the fan up for a minute as it cools.
+    #[structopt(long, default_value = "warm-up")]
+    startup_history: StartupHistory,
+
+    /// Print every decision, along with the readings behind it
+    #[structopt(short, long)]
+    logging: bool,
+
+    /// Don't print every decision, even if the config file turns --logging on
+    #[structopt(long, conflicts_with = "logging")]
+    no_logging: bool,
+
+    /// With --logging, only print the decision line when the speed or the
+    /// temperature changes, or otherwise once every this many cycles
+    #[structopt(long)]
+    log_every: Option<std::num::NonZeroU32>,
+
+    /// Keep the fan at its last speed through this many failed sensor reads
+    /// in a row before going to --failsafe-speed [default: 0]
+    #[structopt(long)]
+    hold_on_error: Option<u32>,
+
+    /// Speed to run at while the sensors can't be read, at least 128 unless
+    /// --allow-slow-failsafe is given [default: 255]
+    #[structopt(long)]
+    failsafe_speed: Option<u8>,
+
+    /// Let --failsafe-speed go below 128, down to stopping the fans
+    #[structopt(long)]
+    allow_slow_failsafe: bool,
+
+    /// Temperature at which the fan goes to full speed regardless of the
+    /// curve [default: 10C below the GPU's slowdown temperature, or 77]
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    critical_temp: Option<u32>,
+
+    /// Sensors on the card whose hottest reading drives the curves and the
+    /// thresholds: "gpu" for the core and "memory" for HBM cards like the
+    /// P100. Memory usually runs warmer than the core, so the thresholds may
+    /// want raising to match. Cards without a sensor ignore it. [default: gpu]
+    #[structopt(long, use_delimiter = true)]
+    temp_sensors: Vec<TempSource>,
+
+    /// Smooth power and temperature with an exponential moving average that
+    /// weights each new sample by this much (0 to 1, higher reacts faster),
+    /// instead of averaging over the last minute
+    #[structopt(long)]
+    ema_alpha: Option<f64>,
+
+    /// Warn when a cycle, from reading the sensors to writing the fan
+    /// controller, takes longer than this fraction of the update interval
+    /// [default: 0.5]
+    #[structopt(long)]
+    latency_budget: Option<f64>,
+
+    /// Temperature at which the curve's speed gets a boost [default: 5C below
+    /// the default critical temperature, or 72]
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    boost_temp: Option<u32>,
+
+    /// Duty counts added to the curve's speed from --boost-temp on
+    /// [default: 50]
+    #[structopt(long)]
+    boost_amount: Option<u8>,
+
+    /// Run the fans at least this fast while the GPU says it's slowing itself
+    /// down for heat, whatever the power average says
+    #[structopt(long)]
+    throttle_boost: Option<u8>,
+
+    /// With the fans at full speed and the GPU still at or above this
+    /// temperature, step its power limit down until it stops climbing, and put
+    /// it back once it's cooled off
+    #[structopt(long, parse(try_from_str = units::whole_celsius))]
+    power_guard_temp: Option<u32>,
+
+    /// Power to take off each card's power limit each step of
+    /// --power-guard-temp [default: 10W]
+    #[structopt(long, parse(try_from_str = units::watts))]
+    power_guard_step: Option<f64>,
+
+    /// Drive the controller's status LED from the GPU's thermal state
+    #[structopt(long)]
+    led: bool,
+
+    /// Sound the controller's buzzer while the GPU is at a critical temperature
+    #[structopt(long)]
+    buzzer: bool,
+
+    /// Local hours during which we keep quiet, e.g. "22-7": the buzzer stays
+    /// silent and any of --quiet-power-limit, --quiet-fan-curve and
+    /// --quiet-max-speed apply
+    #[structopt(long)]
+    quiet_hours: Option<QuietHours>,
+
+    /// Power limit to hold the GPU to during quiet hours, e.g. "180W", so the
+    /// fan curve stays quiet. The original limit is restored afterwards.
+    #[structopt(long, parse(try_from_str = units::watts))]
+    quiet_power_limit: Option<f64>,
+
+    /// Fan curve to follow in place of the power curve while keeping quiet,
+    /// in the same form as --fan-curve
+    #[structopt(long)]
+    quiet_fan_curve: Option<FanSpeedTable>,
+
+    /// Never let the curve go above this speed while keeping quiet. Like
+    /// --max-speed, it's ignored at a critical temperature.
+    #[structopt(long)]
+    quiet_max_speed: Option<u8>,
+
+    /// Also keep quiet while someone's using the machine, going by logind's
+    /// local sessions and their idle hint (Linux only), and run at the full
+    /// power limit for unattended jobs
+    #[structopt(long)]
+    quiet_when_active: bool,
+
+    /// On switching into or out of keeping quiet, move from one curve's
+    /// speed to the other's over this long rather than all at once
+    /// [default: 60s]
+    #[structopt(long, parse(try_from_str = units::seconds))]
+    profile_transition: Option<f64>,
+
+    #[structopt(flatten)]
+    report: ReportArgs,
+
+    /// Drive each of the controller's fan channels from its own GPU, e.g.
+    /// "0=0,1=1" for channel 0 from GPU 0 and channel 1 from GPU 1
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with_all = &["output-command", "gpio-pwm-channel", "commander-pro", "broker", "remote-gpu"],
+    )]
+    channel_map: Vec<ChannelMapping>,
+
+    /// Drive this channel of the controller, e.g. chassis intake fans, at
+    /// --intake-ratio times the GPU fans' speed plus --intake-floor
+    #[structopt(
+        long,
+        conflicts_with_all = &["output-command", "gpio-pwm-channel", "commander-pro", "broker"],
+    )]
+    intake_channel: Option<u8>,
+
+    #[structopt(long, default_value = "0.6")]
+    intake_ratio: f64,
+
+    /// Lowest speed for the intake channel, in duty counts
+    #[structopt(long, default_value = "0")]
+    intake_floor: u8,
+
+    /// Channels driven by expressions, from [[output]] in the config
+    #[structopt(skip)]
+    outputs: Vec<DerivedChannel>,
+
+    /// From [events] in the config
+    #[structopt(skip)]
+    event_routes: telemetry::EventRoutes,
+
+    /// Settings given on the command line, as the effective config shows them
+    #[structopt(skip)]
+    from_command_line: Vec<&'static str>,
+
+    /// Settings the config file filled in
+    #[structopt(skip)]
+    from_file: Vec<&'static str>,
+
+    /// After the fan controller reconnects, bring the fan up to speed over
+    /// this long, e.g. "10s", rather than kicking it at full speed. It goes up
+    /// a step each cycle, so a ramp shorter than --update-interval is over in
+    /// one.
+    #[structopt(long, parse(try_from_str = units::seconds))]
+    spin_up_ramp: Option<f64>,
+
+    /// Let the fan stop while the GPU idles: "45:50" stops it below 45C and
+    /// starts it again at 50C, "15%:25%" does the same by power draw
+    #[structopt(long)]
+    fan_stop: Option<FanStop>,
+
+    /// Kick the fan at this duty for a cycle whenever it starts from stopped,
+    /// for fans that won't start at low duty [default: 255 with --fan-stop,
+    /// otherwise no kick]
+    #[structopt(long)]
+    kick_start_duty: Option<u8>,
+
+    /// Wander up to this much duty either side of the commanded speed, for
+    /// fans that whine at some steady duties. Only the main output is
+    /// dithered, and never at a critical temperature or on a sensor failure.
+    #[structopt(long)]
+    dither: Option<u8>,
+
+    /// Time for the dither to swing down and back up [default: 30s]
+    #[structopt(long, parse(try_from_str = units::seconds))]
+    dither_period: Option<f64>,
+
+    /// Turn on persistence mode for any GPU that doesn't have it, rather than
+    /// just warning (Linux, needs root)
+    #[structopt(long)]
+    enable_persistence: bool,
+
+    /// Instead of the HID controller, send each decision as a JSON line to this
+    /// long-running command and expect "ok" back
+    #[structopt(long)]
+    output_command: Option<String>,
+
+    /// Also send each decision to this command, as for --output-command, e.g.
+    /// for chassis fans behind IPMI. Can be given more than once. Each runs on
+    /// its own, so one that's slow or failing never holds up the others or
+    /// the main output, and shows up in the status as DEGRADED.
+    #[structopt(long, number_of_values = 1)]
+    extra_output_command: Vec<String>,
+
+    /// Instead of the HID controller, drive the fan from this Raspberry Pi
+    /// hardware PWM channel (requires the "gpio" feature)
+    #[structopt(long)]
+    gpio_pwm_channel: Option<u8>,
+
+    /// Instead of opening our own HID controller, have the broker listening on
+    /// this socket drive it (see the broker subcommand), so we can run without
+    /// access to the device
+    #[structopt(long, conflicts_with_all = &["output-command", "gpio-pwm-channel", "commander-pro"])]
+    broker: Option<std::path::PathBuf>,
+
+    /// Instead of our own controller, drive these fan channels (0-5) of a
+    /// Corsair Commander Pro, e.g. "0,1". Repeat it for each card's group of
+    /// fans, optionally with the group's own load sharing, e.g. "2,3:staged"
+    #[structopt(long)]
+    commander_pro: Vec<FanGroup>,
+
+    /// How to spread the speed across the channels of a Commander Pro fan
+    /// group that doesn't pick its own: "split" runs them all at the same
+    /// speed, "staged" only brings in another fan once the previous ones are
+    /// at full speed
+    #[structopt(long, default_value = "split")]
+    fan_sharing: LoadSharing,
+
+    /// Read the sensors and make every decision as usual, but only log the
+    /// messages that would have gone to the fan controller, and the power
+    /// limits and persistence mode that would have been set on the GPUs
+    #[structopt(long, conflicts_with_all = &["output-command", "gpio-pwm-channel", "broker", "commander-pro"])]
+    dry_run: bool,
+
+    /// Read back the RPM our controller reports for each fan every cycle, and
+    /// include it in the logging and telemetry, to check the fans are doing
+    /// what they're told
+    #[structopt(long)]
+    read_rpm: bool,
+
+    /// With --read-rpm, raise a fan stall alert and run every fan at full
+    /// speed once a fan has been stopped, turning far slower than --max-rpm
+    /// says it should, or not reporting at all, for this many cycles in a row
+    #[structopt(long)]
+    stall_cycles: Option<u32>,
+
+    /// Fan channels to watch for stalls, e.g. "0,1". Ones with nothing
+    /// plugged in have to be left out, as they never turn. [default: the
+    /// channels given their own speed by the config or --channel-map, or all
+    /// of them]
+    #[structopt(long, use_delimiter = true)]
+    stall_channels: Vec<u8>,
+
+    /// RPM of the fans at full duty, to judge stalling ones against; without
+    /// it only a stopped fan counts
+    #[structopt(long)]
+    max_rpm: Option<u16>,
+
+    /// Also read a temperature from this file, kept up to date by some external
+    /// tool; treated as a sensor failure if it stops updating
+    #[structopt(long)]
+    temp_file: Option<std::path::PathBuf>,
+
+    /// Age at which a sensor reading is considered stale and treated as a
+    /// sensor failure. --hold-on-error stops holding the last speed once the
+    /// last good GPU reading is this old; readings that come back exactly the
+    /// same for this long are only warned about
+    #[structopt(long, default_value = "30s", parse(try_from_str = units::duration))]
+    max_sample_age: std::time::Duration,
+
+    /// Where to write debug bundles (the last hour of telemetry, recent events
+    /// and the effective config) when sent SIGUSR1
+    #[structopt(long)]
+    debug_bundle_dir: Option<std::path::PathBuf>,
+
+    /// Append every sample to this CSV file, for the chart subcommand or
+    /// anything else that wants more than the last hour
+    #[structopt(long)]
+    telemetry_log: Option<std::path::PathBuf>,
+
+    /// Size in MB at which the telemetry log is moved aside to <name>.1 and
+    /// started afresh
+    #[structopt(long, default_value = "64")]
+    telemetry_log_max_mb: u64,
+
+    /// A second GPU (index, PCI bus ID or UUID) that sits downstream of the
+    /// first in the same airflow, so it breathes the first card's exhaust
+    #[structopt(long, alias = "downstream-uuid")]
+    downstream_gpu: Option<String>,
+
+    /// Instead of reading the GPU through NVML, listen for an agent (see the
+    /// agent subcommand) sending its readings: on localhost at this port, or
+    /// at "host:port". Off localhost it needs --remote-gpu-secret-file
+    #[structopt(long, conflicts_with = "downstream-gpu")]
+    remote_gpu: Option<String>,
+
+    /// File holding a secret the agent has to send before its readings are
+    /// believed
+    #[structopt(long, requires = "remote-gpu")]
+    remote_gpu_secret_file: Option<std::path::PathBuf>,
+
+    /// Extra fan duty per degree the downstream GPU runs hotter than the
+    /// upstream one
+    #[structopt(long, default_value = "0")]
+    delta_bias: f64,
+
+    /// When SM utilization jumps, follow the curve as if power had gone
+    /// straight to this fraction of the limit at 100% utilization until the
+    /// power average catches up, so the fans spin up before the heat arrives
+    #[structopt(long)]
+    utilization_lead: Option<f64>,
+
+    /// Extra fan duty while more than --busy-processes compute processes are
+    /// running, or SM utilization is over --busy-utilization, since several
+    /// jobs launched together heat the card faster than one. Applies as soon
+    /// as they start, before the temperature has moved.
+    #[structopt(long)]
+    busy_bias: Option<u8>,
+
+    /// Compute processes above which --busy-bias applies [default: 1]
+    #[structopt(long)]
+    busy_processes: Option<u32>,
+
+    /// SM utilization percent above which --busy-bias also applies
+    #[structopt(long)]
+    busy_utilization: Option<u32>,
+
+    /// Extra fan duty while the workload has been memory-bound for a minute,
+    /// since that heats the memory and VRMs more than the GPU's own sensor
+    /// lets on
+    #[structopt(long, default_value = "0")]
+    memory_bound_bias: f64,
+
+    /// Intake air temperature sensor, as the path of an hwmon temp*_input file
+    /// or the chip and label that lm-sensors shows, e.g. "nct6775:SYSTIN".
+    /// The fans run faster when it's warmer than --ambient-reference, and
+    /// slower when it's cooler.
+    #[structopt(long)]
+    ambient_sensor: Option<String>,
+
+    /// Ambient temperature the fan curve was made at [default: 25C]
+    #[structopt(long, parse(try_from_str = units::celsius))]
+    ambient_reference: Option<f64>,
+
+    /// Fan duty per degree the ambient temperature is off --ambient-reference
+    /// [default: 3]
+    #[structopt(long)]
+    ambient_bias: Option<f64>,
+
+    /// Chassis or exhaust air temperature sensor, in the same form as
+    /// --ambient-sensor. Above --chassis-temp, the fan and every mapped
+    /// channel get --chassis-boost on top of the curve, even while the GPU's
+    /// fine, for the PSU and drives sharing its exhaust. Outputs driven by
+    /// expressions see the boosted speed as `gpu`.
+    #[structopt(long)]
+    chassis_sensor: Option<String>,
+
+    /// Chassis temperature from which to boost the fans, e.g. "45C"
+    #[structopt(long, parse(try_from_str = units::celsius))]
+    chassis_temp: Option<f64>,
+
+    /// Duty counts added to every fan output while the chassis is over
+    /// --chassis-temp [default: 50]
+    #[structopt(long)]
+    chassis_boost: Option<u8>,
+
+    /// File to keep running totals in across restarts
+    #[structopt(long)]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Minutes the temperature has to keep rising with the fans at full speed
+    /// (or as fast as they're allowed to go) before we raise a thermal runaway
+    /// alert, up to 60
+    #[structopt(long, default_value = "3", parse(try_from_str = units::minutes))]
+    runaway_minutes: std::time::Duration,
+
+    /// Minutes over which to look for the fans speeding up while temperature
+    /// still rises at steady power, a sign of an airflow problem, up to 60
+    #[structopt(long, default_value = "5", parse(try_from_str = units::minutes))]
+    airflow_check_minutes: std::time::Duration,
+
+    /// Watchdog device (e.g. /dev/watchdog) to pet while sensors are fresh
+    /// and the controller is connected, so a hung daemon resets the machine
+    /// rather than cooking the GPU
+    #[structopt(long)]
+    watchdog: Option<std::path::PathBuf>,
+
+    /// Unix socket on which to stream a line per control loop cycle, and
+    /// answer the ctl subcommands
+    #[structopt(long)]
+    ctl_socket: Option<std::path::PathBuf>,
+
+    /// Identifying fields (uuid, hostname, serial) to blank out of anything
+    /// we export
+    #[structopt(long, use_delimiter = true, default_value = "uuid")]
+    redact: Vec<IdentityField>,
+}
+
+/// The critical and boost temperatures to use when they aren't given.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct TempDefaults {
+    critical: u32,
+    boost: u32,
+}
+
+impl Default for TempDefaults {
+    fn default() -> Self {
+        TempDefaults {
+            critical: DEFAULT_CRITICAL_TEMP,
+            boost: DEFAULT_BOOST_TEMP,
+        }
+    }
+}
+
+impl TempDefaults {
+    /// Defaults with some margin below where the card throttles itself, or
+    /// the usual ones if it reports something implausible.
+    fn below_slowdown(slowdown_temp: u32) -> Self {
+        match slowdown_temp.checked_sub(CRITICAL_BELOW_SLOWDOWN + BOOST_BELOW_CRITICAL) {
+            Some(boost) if boost > 0 => TempDefaults {
+                critical: slowdown_temp - CRITICAL_BELOW_SLOWDOWN,
+                boost,
+            },
+            _ => TempDefaults::default(),
+        }
+    }
+}
+
+/// The settings that can be changed without restarting, by sending SIGHUP or
+/// editing the config file.
+struct Tunables {
+    curves: PowerCurves,
+    /// False when there's only a temperature curve
+    follow_power: bool,
+    temp_curve: Option<TempCurve>,
+    /// Never empty
+    temp_sensors: Vec<TempSource>,
+    deadband: Deadband,
+    critical_temp: u32,
+    boost_temp: u32,
+    boost_amount: u8,
+    ema_alpha: Option<f64>,
+}
+
+/// The curves from power to fan speed.
+#[derive(Clone, Debug)]
+struct PowerCurves {
+    fan_curve: FanSpeedTable,
+    /// Replaces `fan_curve` while keeping quiet
+    quiet_fan_curve: Option<FanSpeedTable>,
+}
+
+impl PowerCurves {
+    /// The curve to follow, depending on whether we're keeping quiet.
+    fn get(&self, quiet: bool) -> &FanSpeedTable {
+        match (quiet, &self.quiet_fan_curve) {
+            (true, Some(quiet_fan_curve)) => quiet_fan_curve,
+            _ => &self.fan_curve,
+        }
+    }
+}
+
+/// Moves the curve's speed from the profile we switched away from to the
+/// one we switched to over `period` seconds.
+struct ProfileTransition {
+    period: f64,
+    /// Whether the profile we left was the quiet one, and when we left it
+    from: Option<(bool, std::time::Instant)>,
+}
+
+impl ProfileTransition {
+    fn new(period: f64) -> Self {
+        ProfileTransition { period, from: None }
+    }
+
+    /// Notes a switch away from `was_quiet`, at `now`.
+    fn switch(&mut self, was_quiet: bool, now: std::time::Instant) {
+        let period = std::time::Duration::from_secs_f64(self.period);
+        // Part way through the last switch, head back from wherever we'd got to
+        let started = match self.from {
+            Some((_, started)) if now.saturating_duration_since(started) < period =>
+                now.checked_sub(period - now.saturating_duration_since(started)).unwrap_or(now),
+            _ => now,
+        };
+        self.from = Some((was_quiet, started));
+    }
+
+    /// Notes this cycle's profile, `was_quiet` being the last cycle's, or
+    /// `None` on the first one: starting up quiet is no change anyone could
+    /// hear, so there's nothing to move between.
+    fn follow(&mut self, was_quiet: Option<bool>, quiet: bool, now: std::time::Instant) {
+        if let Some(was_quiet) = was_quiet.filter(|was_quiet| *was_quiet != quiet) {
+            self.switch(was_quiet, now);
+        }
+    }
+
+    /// The speed `curves` give for `power_usage` while `quiet`, part way to
+    /// it from the other profile's during a transition.
+    fn lookup_speed(&mut self, curves: &PowerCurves, quiet: bool, power_usage: f64, now: std::time::Instant) -> u8 {
+        let to = curves.get(quiet).lookup_speed(power_usage);
+        let Some((was_quiet, started)) = self.from else {
+            return to
+        };
+        let progress = now.saturating_duration_since(started).as_secs_f64() / self.period;
+        if progress >= 1.0 || !progress.is_finite() {
+            self.from = None;
+            return to
+        }
+        let from = curves.get(was_quiet).lookup_speed(power_usage);
+        (from as f64 + (to as f64 - from as f64) * progress).round() as u8
+    }
+}
+
+impl Tunables {
+    /// Only `fan_curve`, with the boost and critical temperature, for replays
+    /// that have nothing but the power to go on.
+    fn for_power_curve(fan_curve: FanSpeedTable, critical_temp: u32, boost_temp: u32, boost_amount: u8) -> Self {
+        Tunables {
+            curves: PowerCurves { fan_curve, quiet_fan_curve: None },
+            follow_power: true,
+            temp_curve: None,
+            temp_sensors: vec![TempSource::Gpu],
+            deadband: Deadband::OFF,
+            critical_temp,
+            boost_temp,
+            boost_amount,
+            ema_alpha: None,
+        }
+    }
+
+    fn from_args(args: &Args, temp_defaults: TempDefaults) -> Result<Self, Box<dyn Error>> {
+        let critical_temp = args.critical_temp.unwrap_or(temp_defaults.critical);
+        let boost_temp = args.boost_temp.unwrap_or(temp_defaults.boost);
+        let boost_amount = args.boost_amount.unwrap_or(DEFAULT_BOOST_AMOUNT);
+        if boost_temp > critical_temp {
+            Err(format!(
+                "boost temperature ({}C) can't be above the critical temperature ({}C)",
+                boost_temp, critical_temp
+            ))?
+        }
+        if args.ema_alpha.is_some_and(|alpha| !(alpha > 0.0 && alpha <= 1.0)) {
+            Err("EMA alpha must be above 0 and at most 1")?
+        }
+        if let Some(target_temp) = args.target_temp.filter(|target_temp| *target_temp >= critical_temp) {
+            Err(format!(
+                "target temperature ({}C) must be below the critical temperature ({}C)",
+                target_temp, critical_temp
+            ))?
+        }
+        let fan_curve = fan_curve_from_args(args);
+        let temp_curve = args.temp_curve.clone();
+        let quiet_fan_curve = args.quiet_fan_curve.clone()
+            .map(|curve| curve.shaped_like(&fan_curve));
+        Ok(Tunables {
+            curves: PowerCurves { fan_curve, quiet_fan_curve },
+            follow_power: args.fan_curve.is_some()
+                || args.fan_curve_watts.is_some()
+                || args.temp_curve.is_none(),
+            temp_curve: match args.temp_curve_interpolation {
+                Some(interpolation) => temp_curve.map(|curve| curve.with_interpolation(interpolation)),
+                None => temp_curve,
+            },
+            temp_sensors: if args.temp_sensors.is_empty() {
+                vec![TempSource::Gpu]
+            } else {
+                args.temp_sensors.clone()
+            },
+            // Quantized output only ever moves a whole step at a time
+            deadband: match args.speed_step {
+                Some(_) => Deadband::OFF,
+                None => args.deadband.unwrap_or_default(),
+            },
+            critical_temp,
+            boost_temp,
+            boost_amount,
+            ema_alpha: args.ema_alpha,
+        })
+    }
+}
+
+/// The power curve the arguments describe, or the default one.
+fn fan_curve_from_args(args: &Args) -> FanSpeedTable {
+    let mut fan_curve = args.fan_curve.clone().unwrap_or_else(default_fan_speed_table);
+    fan_curve.interpolation = args.fan_curve_interpolation.unwrap_or(fan_curve.interpolation);
+    fan_curve.below = args.fan_curve_below.unwrap_or(fan_curve.below);
+    fan_curve.above = args.fan_curve_above.unwrap_or(fan_curve.above);
+    fan_curve
+}
+
+/// Notes when the loop starts or stops running degraded.
+fn enter_mode(mode: &mut Mode, new_mode: Mode) {
+    if new_mode != *mode {
+        event!("Mode: {} (was {})", new_mode.name(), mode.name());
+        *mode = new_mode;
+    }
+}
+
+/// `args` with the config file, if any, filling in whatever wasn't given on
+/// the command line.
+fn with_config(args: &Args) -> Result<Args, Box<dyn Error>> {
+    let mut args = args.clone();
+    if let Some(path) = args.config.clone() {
+        Config::load(&path)?.apply(&mut args)?;
+    }
+    Ok(args)
+}
+
+/// Each of `$args`' settings by name, with its value.
+macro_rules! settings {
+    ($args:expr; $($field:ident),*; report: $($report:ident),*) => {
+        vec![
+            $((stringify!($field), format!("{:?}", $args.$field)),)*
+            $((stringify!($report), format!("{:?}", $args.report.$report)),)*
+        ]
+    };
+}
+
+impl Args {
+    /// Every setting by name, with its value, for the effective config.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        settings!(self;
+            config, gpu, combine, speed_override, override_minutes, speed_step, deadband,
+            max_speed, update_interval, align_samples, fan_curve, fan_curve_watts, temp_curve,
+            fan_curve_interpolation, fan_curve_below, fan_curve_above, temp_curve_interpolation,
+            pid, target_temp, on_power_limit_change, startup_history, logging, no_logging,
+            log_every, hold_on_error, failsafe_speed, allow_slow_failsafe, critical_temp,
+            temp_sensors, ema_alpha, latency_budget, boost_temp, boost_amount, throttle_boost,
+            power_guard_temp, power_guard_step, led, buzzer, quiet_hours, quiet_power_limit,
+            quiet_fan_curve, quiet_max_speed, quiet_when_active, profile_transition, channel_map, intake_channel,
+            intake_ratio, intake_floor, outputs, event_routes, spin_up_ramp, fan_stop,
+            kick_start_duty, dither, dither_period, enable_persistence, output_command,
+            extra_output_command, gpio_pwm_channel, broker, commander_pro, fan_sharing, dry_run,
+            read_rpm, stall_cycles, stall_channels, max_rpm, temp_file, max_sample_age,
+            debug_bundle_dir, telemetry_log, telemetry_log_max_mb, downstream_gpu, remote_gpu,
+            remote_gpu_secret_file, delta_bias, utilization_lead, busy_bias, busy_processes,
+            busy_utilization, memory_bound_bias, ambient_sensor, ambient_reference, ambient_bias,
+            chassis_sensor, chassis_temp, chassis_boost, state_file, runaway_minutes,
+            airflow_check_minutes, watchdog, ctl_socket, redact;
+            report: usb_vid, usb_pid, report_template, report_length, report_id, controller)
+    }
+
+    /// Notes which settings `matches`, what these were parsed from, gave.
+    fn given(mut self, matches: Option<&structopt::clap::ArgMatches>) -> Self {
+        if let Some(matches) = matches {
+            self.from_command_line = self.settings().into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| matches.occurrences_of(name.replace('_', "-")) > 0)
+                .collect();
+        }
+        self
+    }
+}
+
+/// Prints the settings `run` would start with.
+fn show_config(args: Args) -> Result<(), Box<dyn Error>> {
+    let effective_config = EffectiveConfig::new(&with_config(&args)?);
+    emit(&effective_config, || effective_config.to_json());
+    Ok(())
+}
+
+fn modified_time(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// How long from `now` until the next multiple of `interval` seconds since
+/// the Unix epoch.
+fn until_next_tick(interval: f64, now: std::time::SystemTime) -> std::time::Duration {
+    let since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let wait = interval - since_epoch % interval;
+    std::time::Duration::from_secs_f64(wait)
+}
+
+/// Runs the control loop, or with `once` a single pass of it.
+fn inner_main(cli_args: Args, once: bool) -> Result<(), Box<dyn Error>> {
+    let args = with_config(&cli_args)?;
+    telemetry::set_event_routes(args.event_routes.clone());
+    let update_interval = args.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL);
+    if update_interval <= 0.0 {
+        Err("update interval must be positive")?
+    }
+    let latency_budget = args.latency_budget.unwrap_or(DEFAULT_LATENCY_BUDGET);
+    if latency_budget <= 0.0 {
+        Err("latency budget must be positive")?
+    }
+    // The command line catches these, but not when the mapping comes from the config file
+    if !args.channel_map.is_empty() && (args.output_command.is_some()
+        || args.gpio_pwm_channel.is_some()
+        || !args.commander_pro.is_empty()
+        || args.broker.is_some()
+        || args.remote_gpu.is_some())
+    {
+        Err("channel mappings need our own HID controller and local GPUs")?
+    }
+    let derived = args.intake_channel
+        .map(|channel| DerivedChannel::intake(channel, args.intake_ratio, args.intake_floor))
+        .into_iter()
+        .chain(args.outputs.iter().cloned().map(Ok))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    DerivedChannel::check_all(&derived)?;
+    if !derived.is_empty() && (args.output_command.is_some()
+        || args.gpio_pwm_channel.is_some()
+        || !args.commander_pro.is_empty()
+        || args.broker.is_some())
+    {
+        Err("outputs driven by expressions need our own HID controller")?
+    }
+    let mut used_channels: Vec<u8> = args.channel_map.iter().map(|mapping| mapping.channel).collect();
+    for output in &derived {
+        if used_channels.contains(&output.channel) {
+            Err(format!("Channel {} is driven by more than one thing", output.channel))?
+        }
+        used_channels.push(output.channel);
+    }
+    if let Some(channel) = used_channels.iter().find(|&&channel| channel as usize >= controller::CHANNELS) {
+        Err(format!("The controller has no channel {}; its channels are 0 to {}", channel, controller::CHANNELS - 1))?
+    }
+
+    let effective_config = EffectiveConfig::new(&args).to_string();
+    let report_template = args.report.report_template.clone().unwrap_or_default();
+
+    let max_sample_age = args.max_sample_age;
+    let temp_file = args.temp_file.clone().map(|path| FileSensor {
+        path,
+        max_age: max_sample_age,
+    });
+    let ambient_sensor = args.ambient_sensor.as_deref()
+        .map(HwmonSensor::find)
+        .transpose()
+        .map_err(|e| format!("Bad --ambient-sensor: {}", e))?;
+    let mut ambient_failing = false;
+    if args.stall_cycles.is_some() && !args.read_rpm {
+        Err("--stall-cycles needs --read-rpm")?
+    }
+    let stall_channels = match (&args.stall_channels[..], &used_channels[..]) {
+        ([], []) => (0..controller::CHANNELS as u8).collect(),
+        ([], used) => used.to_vec(),
+        (stall_channels, _) => stall_channels.to_vec(),
+    };
+    let mut watched = [false; controller::CHANNELS];
+    for channel in stall_channels {
+        *watched.get_mut(channel as usize)
+            .ok_or_else(|| format!("--stall-channels: the controller only has channels 0-{}", controller::CHANNELS - 1))? = true;
+    }
+    let mut stall_check = args.stall_cycles.map(|cycles| diagnostics::StallCheck::new(cycles, args.max_rpm, watched));
+    let mut fan_stalled = false;
+    let mut chassis_guard = match (args.chassis_sensor.as_deref(), args.chassis_temp) {
+        (Some(sensor), Some(temp)) => Some(ChassisGuard::new(
+            HwmonSensor::find(sensor).map_err(|e| format!("Bad --chassis-sensor: {}", e))?,
+            temp,
+            args.chassis_boost.unwrap_or(DEFAULT_CHASSIS_BOOST),
+        )),
+        (None, None) => None,
+        _ => Err("--chassis-sensor and --chassis-temp go together")?,
+    };
+    let mut report_format = args.report.format();
+
+    let mut hidapi = HidApi::new()
+        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+
+    let _ = hidapi.refresh_devices();
+    let mut arbiter = Arbiter::new(args.max_speed);
+    if let (Some(speed), Some(length)) = (args.speed_override, args.override_minutes) {
+        let minutes = length.as_secs_f64() / 60.0;
+        event!(
+            Event::OverrideSet { speed, minutes: Some(minutes) } =>
+            "Overriding the fan speed to {} for {} minutes", Duty(speed), minutes
+        );
+        arbiter.set_override(Override {
+            speed,
+            expires: Some(std::time::Instant::now() + length),
+        });
+    }
+
+    let nvml = match &args.remote_gpu {
+        Some(_) => None,
+        None => Some(init_nvml()?),
+    };
+
+    let mut gpu = match (&args.remote_gpu, &nvml) {
+        (Some(addr), _) => {
+            let secret = args.remote_gpu_secret_file.as_deref().map(read_secret).transpose()?;
+            let remote = RemoteGpu::listen(addr, secret, max_sample_age)?;
+            event!("Waiting for a GPU agent on {}", remote.addr());
+            // Until there's a reading we have nothing to start from, and the
+            // controller's own firmware stays in charge of the fans
+            if !remote.wait(max_sample_age) {
+                Err(format!("No readings from a GPU agent within {}s", max_sample_age.as_secs()))?
+            }
+            Gpu::Remote(remote)
+        },
+        (None, Some(nvml)) => {
+            // Safety still looks at every card that drives a channel
+            let selectors = if args.gpu.is_empty() {
+                args.channel_map.iter().flat_map(|mapping| mapping.gpus.clone()).collect()
+            } else {
+                args.gpu.clone()
+            };
+            Gpu::Local(gpu::find_devices(nvml, &selectors)?, args.combine.unwrap_or_default())
+        },
+        (None, None) => unreachable!("NVML is always loaded for a local GPU"),
+    };
+    gpu.check_persistence(args.enable_persistence, args.dry_run);
+    let downstream_gpu = args.downstream_gpu.as_ref()
+        .zip(nvml.as_ref())
+        .map(|(selector, nvml)| gpu::device_by_selector(nvml, selector))
+        .transpose()
+        .map_err(|e| format!("Failed to find downstream GPU: {}", e))?;
+
+    if let (true, Gpu::Local(devices, combine)) = (args.logging, &gpu) {
+        if devices.len() > 1 {
+            event!("Following {} GPUs, combined by {}", devices.len(), combine.name());
+        }
+        for device in devices {
+            let (name, uuid) = (device.name()?, device.uuid()?);
+            let temp = device.temperature(TemperatureSensor::Gpu)?;
+            emit(
+                format!("{:?} - {} - {} - {}", device, name, uuid, temp),
+                || format!(
+                    r#"{{"gpu":{},"uuid":{},"temp":{}}}"#,
+                    json_string(&name), json_string(&uuid), temp
+                ),
+            );
+        }
+    }
+
+    // The card knows better than we do where it starts to suffer
+    let temp_defaults = match gpu.slowdown_temp() {
+        Ok(slowdown_temp) => {
+            let temp_defaults = TempDefaults::below_slowdown(slowdown_temp);
+            if args.critical_temp.is_none() || args.boost_temp.is_none() {
+                event!(
+                    "GPU slows down at {}C, so defaulting to critical at {}C and boost at {}C",
+                    slowdown_temp,
+                    temp_defaults.critical,
+                    temp_defaults.boost,
+                );
+            }
+            temp_defaults
+        },
+        Err(_) => TempDefaults::default(),
+    };
+    // Only now that the card's said where it slows down can the thresholds be
+    // checked against each other
+    let mut tunables = Tunables::from_args(&args, temp_defaults)?;
+
+    let reading = gpu.reading()?;
+    let gpu::Reading { power_usage, power_limit, .. } = reading;
+    let temp = reading.hottest(&tunables.temp_sensors);
+    if tunables.temp_sensors != [TempSource::Gpu] {
+        let names: Vec<_> = tunables.temp_sensors.iter().map(|sensor| sensor.name()).collect();
+        event!("Following the hottest of the {} temperatures", names.join(" and "));
+        if tunables.temp_sensors.contains(&TempSource::Memory) && reading.mem_temp.is_none() {
+            event!("The GPU doesn't report its memory temperature");
+        }
+    }
+
+    let initial_power_limit = power_limit;
+    let mut quiet_power_limit_applied = false;
+    // Put back on the way out, even on an error
+    let mut power_limits = match (&nvml, args.quiet_power_limit.is_some() || args.power_guard_temp.is_some()) {
+        (Some(nvml), true) => Some(
+            gpu.power_limits(nvml, args.dry_run)
+                .map_err(|e| format!("--quiet-power-limit and --power-guard-temp need the GPU's power limits: {}", e))?
+        ),
+        (None, true) => Err("--quiet-power-limit and --power-guard-temp can't change a remote GPU's power limit")?,
+        (_, false) => None,
+    };
+    let keeps_quiet = args.quiet_power_limit.is_some()
+        || args.quiet_fan_curve.is_some()
+        || args.quiet_max_speed.is_some();
+    if keeps_quiet && args.quiet_hours.is_none() && !args.quiet_when_active {
+        Err("quiet settings need --quiet-hours or --quiet-when-active to say when to keep quiet")?
+    }
+    let mut quiet = false;
+    let profile_transition = args.profile_transition.unwrap_or(DEFAULT_PROFILE_TRANSITION);
+    if profile_transition < 0.0 {
+        Err("profile transition can't be negative")?
+    }
+    let mut profile_transition = ProfileTransition::new(profile_transition);
+    let mut session_watch = session::SessionWatch::new();
+    let rescale_on_limit_change = args.fan_curve_watts.is_some()
+        || args.on_power_limit_change == PowerLimitPolicy::Rescale;
+    // Curves in watts always keep their wattages, and the rest do if asked to
+    let curve_for_limit = |base_curve: &FanSpeedTable, power_limit: u32| {
+        if args.on_power_limit_change == PowerLimitPolicy::Rescale {
+            base_curve.rescaled(initial_power_limit as f64 / power_limit as f64)
+        } else {
+            base_curve.clone()
+        }
+    };
+    let curves_for_limit = |curves: &PowerCurves, power_limit: u32| PowerCurves {
+        fan_curve: match &args.fan_curve_watts {
+            Some(watt_curve) => {
+                watt_curve.to_fraction_table(power_limit as f64 / 1000.0)
+                    .shaped_like(&curves.fan_curve)
+            },
+            None => curve_for_limit(&curves.fan_curve, power_limit),
+        },
+        // Always in fractions of the limit, even alongside a curve in watts
+        quiet_fan_curve: curves.quiet_fan_curve.as_ref()
+            .map(|curve| curve_for_limit(curve, power_limit)),
+    };
+    let mut power_curves = curves_for_limit(&tunables.curves, power_limit);
+    let mut current_power_limit = power_limit;
+
+    // We want to keep a 1 minute history
+    let samples = decision::history_samples(update_interval);
+    let mut history = History::new(samples, args.startup_history, temp, power_usage as f64 / power_limit as f64);
+
+    let hold_on_error = args.hold_on_error.unwrap_or(0);
+    let failsafe_speed = failsafe_speed(&args)?;
+    let mut failed_reads = 0;
+    let mut last_good = None;
+    let mut latency_over_budget = false;
+    let mut was_critical = false;
+    let mut was_throttling = false;
+    let mut was_busy = false;
+    let mut fan_rpms = [None; controller::CHANNELS];
+    // (max temp, curve speed, adjusted speed) last logged, and the cycles since
+    let mut logged = None;
+    let mut cycles_since_logged = 0;
+
+    let mut identity = vec![];
+    if let Ok(uuid) = gpu.uuid() {
+        identity.push((IdentityField::Uuid, uuid));
+    }
+    if let Ok(serial) = gpu.serial() {
+        identity.push((IdentityField::Serial, serial));
+    }
+    if let Some(hostname) = telemetry::hostname() {
+        identity.push((IdentityField::Hostname, hostname));
+    }
+    for (name, window) in [("--runaway-minutes", args.runaway_minutes), ("--airflow-check-minutes", args.airflow_check_minutes)] {
+        if window > TELEMETRY_HISTORY {
+            Err(format!("{} can be at most {} minutes, as far back as we keep samples", name, TELEMETRY_HISTORY.as_secs() / 60))?
+        }
+    }
+    let mut gpu_reading_unchanged = sensors::Unchanging::new();
+    let mut last_gpu_read = std::time::Instant::now();
+    let mut telemetry = Telemetry::new((TELEMETRY_HISTORY.as_secs_f64() / update_interval).ceil() as usize);
+    let mut pid = args.pid.zip(args.target_temp)
+        .map(|(params, target_temp)| Pid::new(params, target_temp));
+    let mut memory_bound_check = diagnostics::MemoryBoundCheck::new(samples);
+    if args.utilization_lead.is_some_and(|lead| !(0.0..=1.0).contains(&lead)) {
+        Err("--utilization-lead must be between 0.0 and 1.0")?
+    }
+    let mut utilization_lead = args.utilization_lead
+        .map(|full_power| UtilizationLead::new(full_power, std::time::Duration::from_secs_f64(samples as f64 * update_interval)));
+    let mut airflow_check = diagnostics::AirflowCheck::new(
+        (args.airflow_check_minutes.as_secs_f64() / update_interval).ceil() as usize
+    );
+    let mut runaway_check = diagnostics::RunawayCheck::new(
+        (args.runaway_minutes.as_secs_f64() / update_interval).ceil() as usize,
+        args.runaway_minutes.as_secs_f64() / 60.0,
+    );
+    // A single pass would never see it through to putting the limit back
+    let mut power_guard = match (args.power_guard_temp, once) {
+        (Some(temp), false) => {
+            let step = args.power_guard_step.unwrap_or(DEFAULT_POWER_GUARD_STEP);
+            if step <= 0.0 {
+                Err("--power-guard-step must be more than 0")?
+            }
+            Some(PowerGuard::new(temp, (step * 1000.0) as u32))
+        },
+        _ => None,
+    };
+    if args.telemetry_log_max_mb == 0 {
+        Err("--telemetry-log-max-mb must be at least 1")?
+    }
+    let mut telemetry_log = args.telemetry_log.as_deref()
+        .map(|path| {
+            TelemetryLog::open(path, args.telemetry_log_max_mb * 1_000_000)
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let mut telemetry_log_failing = false;
+    let bundle_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, bundle_requested.clone())?;
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone())?;
+    let mut config_modified = args.config.as_deref().and_then(modified_time);
+    let shutdown_requested = shutdown_flag()?;
+
+    let mut writer = FanWriter::from_args(
+        &args,
+        args.channel_map.iter().map(|mapping| mapping.channel),
+        derived.iter().map(|output| (output.name.clone(), output.channel)),
+        std::time::Instant::now(),
+    )?;
+    let mut counters = args.state_file.as_deref()
+        .map(Counters::load)
+        .unwrap_or_default();
+    let mut counters_saved_at = std::time::Instant::now();
+    if once {
+        // Keep the deadband and the minute of history working across runs
+        writer.prev_speed = counters.last_speed;
+        history.resume(counters.history.iter()
+            .filter(|s| s.age() <= state::HISTORY_WINDOW)
+            .map(|s| (s.temp, s.power)));
+    }
+
+    let mut watchdog = args.watchdog.as_deref()
+        .map(Watchdog::open)
+        .transpose()
+        .map_err(|e| format!("Failed to open watchdog: {}", e))?;
+
+    #[cfg(unix)]
+    let mut ctl_server = args.ctl_socket.as_deref()
+        .map(|path| ctl::CtlServer::bind(path, effective_config.clone()))
+        .transpose()
+        .map_err(|e| format!("Failed to open control socket: {}", e))?;
+    #[cfg(not(unix))]
+    if args.ctl_socket.is_some() {
+        Err("control sockets are only supported on Unix")?
+    }
+
+    let mut channels = match &nvml {
+        Some(nvml) => args.channel_map.iter()
+            .map(|mapping| {
+                let devices = gpu::find_devices(nvml, &mapping.gpus)?;
+                FanChannel::new(
+                    mapping.channel,
+                    Gpu::Local(devices, mapping.combine),
+                    mapping.fan_curve.clone(),
+                    mapping.offset,
+                    samples,
+                    args.startup_history,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+
+    if args.extra_output_command.len() > OutputHealth::MAX {
+        Err(format!("There can be at most {} extra output commands", OutputHealth::MAX))?
+    }
+    let extra_outputs: Vec<ExtraOutput> = args.extra_output_command.iter()
+        .map(|command| ExtraOutput::start(command))
+        .collect();
+    let mut extra_health = OutputHealth { count: extra_outputs.len(), failing: 0 };
+    let mut extra_sent = None;
+
+    let mut fan_controller: Option<FanOutput> = None;
+    let mut connected_before = false;
+    // Reused from cycle to cycle, so working out the speeds doesn't allocate
+    // once the loop is going. Reading the sensors still can: NVML's field
+    // values and file reads hand back fresh buffers.
+    let mut channel_speeds: Vec<u8> = Vec::with_capacity(channels.len());
+    let mut derived_speeds: Vec<u8> = Vec::with_capacity(derived.len());
+    let mut fan_stopped = false;
+    let mut mode = Mode::Normal;
+    let mut passed_once = false;
+    let mut profile_known = false;
+    loop {
+        if once {
+            if passed_once {
+                break
+            }
+        } else if args.align_samples {
+            thread::sleep(until_next_tick(update_interval, std::time::SystemTime::now()));
+        } else {
+            thread::sleep(std::time::Duration::from_millis((update_interval * 1000.0) as u64));
+        }
+        passed_once = true;
+        if shutdown_requested.load(Ordering::Relaxed) {
+            break
+        }
+
+        // Pick up changes to the curve and thresholds, keeping the history
+        let modified = args.config.as_deref().and_then(modified_time);
+        if reload_requested.swap(false, Ordering::Relaxed) || modified != config_modified {
+            config_modified = modified;
+            match with_config(&cli_args).and_then(|args| Tunables::from_args(&args, temp_defaults)) {
+                Ok(reloaded) => {
+                    tunables = reloaded;
+                    power_curves = curves_for_limit(&tunables.curves, current_power_limit);
+                    event!(
+                        "Reloaded settings: critical at {}C, boost of {} at {}C",
+                        tunables.critical_temp,
+                        tunables.boost_amount,
+                        tunables.boost_temp,
+                    );
+                },
+                Err(e) => event!("Failed to reload settings, keeping the old ones: {}", e),
+            }
+        }
+
+        let quiet_hours = args.quiet_hours.is_some_and(|quiet_hours| quiet_hours.is_now());
+        let now_quiet = quiet_hours || (args.quiet_when_active && session_watch.active());
+        profile_transition.follow(profile_known.then_some(quiet), now_quiet, std::time::Instant::now());
+        profile_known = true;
+        if now_quiet != quiet {
+            quiet = now_quiet;
+            event!(
+                Event::ProfileSwitched { profile: if quiet { "quiet" } else { "normal" } } =>
+                "Switching to the {} profile ({})",
+                if quiet { "quiet" } else { "normal" },
+                match (quiet_hours, quiet) {
+                    (true, _) => "quiet hours",
+                    (false, true) => "someone's at the machine",
+                    (false, false) if args.quiet_when_active => "nobody's at the machine",
+                    (false, false) => "quiet hours are over",
+                },
+            );
+        }
+        let speed_cap = match (quiet, args.quiet_max_speed) {
+            (true, Some(quiet_max_speed)) => Some(args.max_speed.map_or(quiet_max_speed, |cap| cap.min(quiet_max_speed))),
+            _ => args.max_speed,
+        };
+        arbiter.set_speed_cap(speed_cap);
+
+        // A single pass would only put the limit straight back on the way out
+        // The power guard puts back whichever limit is due when it lets go
+        let guarding = power_guard.as_ref().is_some_and(PowerGuard::engaged);
+        if let (Some(quiet_power_limit), Some(power_limits), false, true, false) =
+            (args.quiet_power_limit, &mut power_limits, once, quiet != quiet_power_limit_applied, guarding)
+        {
+            let quiet_limit = (quiet_power_limit * 1000.0) as u32;
+            match power_limits.set(|card| if quiet { quiet_limit } else { card.initial }) {
+                Ok(()) => {
+                    if !args.dry_run {
+                        event!(
+                            "Setting power limits to {}",
+                            if quiet { format!("{:.0} W", quiet_power_limit) } else { "what they were".to_string() }
+                        );
+                    }
+                    quiet_power_limit_applied = quiet;
+                },
+                Err(e) => event!("Failed to set power limit: {}", e),
+            }
+        }
+
+        if let Some(path) = &args.state_file {
+            if counters_saved_at.elapsed() >= COUNTERS_SAVE_INTERVAL {
+                if let Err(e) = counters.save(path) {
+                    event!("Failed to save state file: {}", e);
+                }
+                counters_saved_at = std::time::Instant::now();
+            }
+        }
+
+        // The fan controller might get disconnected, so handle that potential
+        // Ugh, this code is ugly :(
+        if fan_controller.as_mut().is_some_and(|output| !output.is_connected()) {
+            event!(Event::ControllerLost { error: "disconnected".to_string() } => "Fan output disconnected");
+            counters.controller_errors += 1;
+            fan_controller = None;
+        }
+        let fan_controller_ref = match &mut fan_controller {
+            Some(device) => device,
+            None => {
+                let output: Result<FanOutput, Box<dyn Error>> = match (&args.output_command, args.gpio_pwm_channel) {
+                    _ if args.dry_run => Ok(Box::new(DryRunOutput::new(report_format.clone(), report_template.clone()))),
+                    (Some(command), _) => ProcessOutput::spawn(command).map(|process| Box::new(process) as FanOutput),
+                    #[cfg(all(feature = "gpio", target_os = "linux"))]
+                    (None, Some(channel)) => output::GpioOutput::open(channel).map(|gpio| Box::new(gpio) as FanOutput),
+                    #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+                    (None, Some(_)) => Err("GPIO PWM output requires building with the gpio feature on Linux".into()),
+                    #[cfg(unix)]
+                    (None, None) if args.broker.is_some() => {
+                        broker::BrokerOutput::connect(args.broker.as_deref().expect("checked above"))
+                            .map(|broker| Box::new(broker) as FanOutput)
+                    },
+                    #[cfg(not(unix))]
+                    (None, None) if args.broker.is_some() => Err("the broker is only supported on Unix".into()),
+                    (None, None) if !args.commander_pro.is_empty() => {
+                        let _ = hidapi.refresh_devices();
+                        CommanderPro::open(&hidapi, &args.commander_pro, args.fan_sharing)
+                            .map(|hub| Box::new(hub) as FanOutput)
+                    },
+                    (None, None) => {
+                        let _ = hidapi.refresh_devices();
+                        controller::open_controllers(&hidapi, &mut report_format, &args.report.controller)
+                            .map(|devices| {
+                                Box::new(HidOutput::new(devices, report_format.clone(), report_template.clone())) as FanOutput
+                            })
+                    },
+                };
+                match output {
+                    Ok(output) => {
+                        fan_rpms = [None; controller::CHANNELS];
+                        if let Some(stall_check) = &mut stall_check {
+                            stall_check.reset();
+                        }
+                        fan_stalled = false;
+                        if connected_before {
+                            event!("Fan controller reconnected");
+                        }
+                        writer.opened(connected_before);
+                        connected_before = true;
+                        fan_controller.insert(output)
+                    },
+                    Err(e) => {
+                        event!("{}", e);
+                        enter_mode(&mut mode, Mode::Offline);
+                        let sample = Sample::offline(writer.prev_speed);
+                        #[cfg(unix)]
+                        if let Some(ctl_server) = &mut ctl_server {
+                            ctl_server.broadcast(&sample.to_string());
+                        }
+                        telemetry.record(sample);
+                        continue
+                    },
+                }
+            },
+        };
+
+        let mut sample_temp = None;
+        let mut sample_power = None;
+        let mut sample_temp_delta = None;
+        let mut sample_clocks = (None, None);
+        let cycle_started = std::time::Instant::now();
+        let sample_time = chrono::Local::now();
+        let (speed, thermal_state) = 'speed: {
+            let reading = match gpu.reading() {
+                Ok(reading) => {
+                    last_gpu_read = std::time::Instant::now();
+                    reading
+                },
+                Err(e) => {
+                    event!("Error updating fan controller: {}", e);
+                    break 'speed (255, ThermalState::Fault)
+                },
+            };
+            let memory_bound = memory_bound_check.update(&reading);
+            let gpu::Reading { power_usage, power_limit, sm_clock, mem_clock, sm_util, thermal_throttle, compute_processes, .. } = reading;
+            let temp = reading.hottest(&tunables.temp_sensors);
+            if power_limit != current_power_limit {
+                event!(
+                    "Power limit changed from {:.0} W to {:.0} W",
+                    current_power_limit as f64 / 1000.0,
+                    power_limit as f64 / 1000.0
+                );
+                if rescale_on_limit_change {
+                    // Keep the history meaning the same wattage too
+                    history.rescale_power(power_limit as f64 / current_power_limit as f64);
+                }
+                power_curves = curves_for_limit(&tunables.curves, power_limit);
+                current_power_limit = power_limit;
+            }
+            // A wedged driver can keep handing back the same reading, though
+            // so can an idle card; the read itself worked, so just say so
+            let changing = (reading.temp, reading.mem_temp, power_usage, sm_clock, mem_clock, sm_util, reading.mem_util);
+            if let Some(age) = gpu_reading_unchanged.went_unchanged(changing, max_sample_age) {
+                event!("GPU reading hasn't changed at all for {:.1}s", age.as_secs_f64());
+            }
+            // With two cards in series, the downstream one is the limiting factor
+            let temp_delta = match &downstream_gpu {
+                Some(downstream) => match downstream.temperature(TemperatureSensor::Gpu) {
+                    Ok(downstream_temp) => Some(downstream_temp as i32 - temp as i32),
+                    Err(e) => {
+                        event!("Error updating fan controller: {}", e);
+                        break 'speed (255, ThermalState::Fault)
+                    },
+                },
+                None => None,
+            };
+            let temp = temp.max((temp as i32 + temp_delta.unwrap_or(0)) as u32);
+            sample_temp_delta = temp_delta;
+            // The external sensor counts towards the same safety thresholds
+            let temp = match &temp_file {
+                Some(sensor) => match sensor.read() {
+                    Ok(file_temp) => temp.max(file_temp.clamp(0.0, 255.0) as u32),
+                    Err(e) => {
+                        event!("Error updating fan controller: {}", e);
+                        break 'speed (255, ThermalState::Fault)
+                    },
+                },
+                None => temp,
+            };
+
+            sample_temp = Some(temp);
+            sample_clocks = (sm_clock, mem_clock);
+            counters.energy_joules += power_usage as f64 / 1000.0 * update_interval;
+            if temp >= tunables.boost_temp {
+                counters.seconds_above_boost += update_interval;
+            }
+            sample_power = Some(power_usage as f64 / power_limit as f64);
+            let window = history.push(temp, power_usage as f64 / power_limit as f64, tunables.ema_alpha);
+            counters.remember(HistorySample::now(temp as u8, power_usage as f64 / power_limit as f64));
+            if window.is_critical(&tunables) {
+                break 'speed (255, ThermalState::Critical)
+            }
+            let Window { max_temp, boost_check_temp, average_power } = window;
+            let average_power = match &mut utilization_lead {
+                Some(lead) => lead.update(sm_util, average_power),
+                None => average_power,
+            };
+            let speed = match &mut pid {
+                Some(pid) => pid.update(temp, update_interval),
+                None => {
+                    let power_speed = tunables.follow_power.then(|| profile_transition.lookup_speed(
+                        &power_curves, quiet, average_power, std::time::Instant::now()
+                    ));
+                    decision::curves_speed(&tunables, temp, power_speed)
+                },
+            };
+            let delta_bias = temp_delta.unwrap_or(0).max(0) as f64 * args.delta_bias;
+            let memory_bound_bias = if memory_bound { args.memory_bound_bias } else { 0.0 };
+            // Queued jobs landing together outrun the temperature and power
+            // average both
+            let busy_bias = match args.busy_bias {
+                Some(busy_bias) => {
+                    let busy_processes = args.busy_processes.unwrap_or(DEFAULT_BUSY_PROCESSES);
+                    let busy = compute_processes.is_some_and(|n| n > busy_processes)
+                        || args.busy_utilization.zip(sm_util).is_some_and(|(threshold, util)| util > threshold);
+                    if busy != was_busy {
+                        if busy {
+                            event!(
+                                "GPU is busy ({} compute processes, {}% utilization), adding {} to the fan duty",
+                                compute_processes.map_or("?".to_string(), |n| n.to_string()),
+                                sm_util.map_or("?".to_string(), |util| util.to_string()),
+                                busy_bias,
+                            );
+                        } else {
+                            event!("GPU is no longer busy, back to the curve");
+                        }
+                        was_busy = busy;
+                    }
+                    if busy { busy_bias as f64 } else { 0.0 }
+                },
+                None => 0.0,
+            };
+            // Not a safety sensor, so losing it only loses the compensation
+            let ambient_bias = match ambient_sensor.as_ref().map(HwmonSensor::read) {
+                Some(Ok(ambient)) => {
+                    ambient_failing = false;
+                    (ambient - args.ambient_reference.unwrap_or(DEFAULT_AMBIENT_REFERENCE))
+                        * args.ambient_bias.unwrap_or(DEFAULT_AMBIENT_BIAS)
+                },
+                Some(Err(e)) => {
+                    if !ambient_failing {
+                        event!("Failed to read the ambient temperature, not compensating for it: {}", e);
+                    }
+                    ambient_failing = true;
+                    0.0
+                },
+                None => 0.0,
+            };
+            let window = Window { average_power, ..window };
+            let Decision { curve_speed: speed, speed: adj_speed, thermal_state } = decision::decide(
+                &tunables,
+                &window,
+                speed,
+                delta_bias + memory_bound_bias + busy_bias + ambient_bias,
+            );
+            // The card knows it's too hot well before a minute's average does
+            let (adj_speed, thermal_state) = match args.throttle_boost {
+                Some(throttle_boost) => {
+                    let throttling = thermal_throttle == Some(true);
+                    if throttling != was_throttling {
+                        if throttling {
+                            event!("GPU is throttling for heat, boosting the fans to at least {}", Duty(throttle_boost));
+                        } else {
+                            event!("GPU stopped throttling for heat, back to the curve");
+                        }
+                        was_throttling = throttling;
+                    }
+                    if throttling {
+                        (adj_speed.max(throttle_boost), ThermalState::Warm)
+                    } else {
+                        (adj_speed, thermal_state)
+                    }
+                },
+                None => (adj_speed, thermal_state),
+            };
+
+            cycles_since_logged += 1;
+            let changed = logged != Some((max_temp, speed, adj_speed));
+            let due = args.log_every.is_none_or(|every| cycles_since_logged >= every.get());
+            if args.logging && (changed || due) {
+                logged = Some((max_temp, speed, adj_speed));
+                cycles_since_logged = 0;
+                let or_null = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+                emit(
+                    format!(
+                        "Avg power {:.1}, Max temp {}, Temp delta {}, SM clock {}, Mem clock {}, Comp speed {}, Prev speed {}, Adj speed {}{}",
+                        average_power * 100.0,
+                        max_temp,
+                        temp_delta.map(|d| d.to_string()).unwrap_or_else(|| "none".to_string()),
+                        sm_clock.map(|c| c.to_string()).unwrap_or_else(|| "none".to_string()),
+                        mem_clock.map(|c| c.to_string()).unwrap_or_else(|| "none".to_string()),
+                        Duty(speed),
+                        writer.prev_speed.map(|i| Duty(i).to_string()).unwrap_or_else(|| "none".to_string()),
+                        Duty(adj_speed),
+                        // How the fans took the previous speed
+                        if args.read_rpm { format!(", RPM {}", telemetry::Rpms(&fan_rpms)) } else { String::new() },
+                    ),
+                    || format!(
+                        r#"{{"avg_power":{:.4},"max_temp":{},"temp_delta":{},"sm_clock":{},"mem_clock":{},"curve_speed":{},"prev_speed":{},"speed":{},"rpm":[{}]}}"#,
+                        average_power,
+                        max_temp,
+                        or_null(temp_delta.map(|d| d.to_string())),
+                        or_null(sm_clock.map(|c| c.to_string())),
+                        or_null(mem_clock.map(|c| c.to_string())),
+                        speed,
+                        or_null(writer.prev_speed.map(|s| s.to_string())),
+                        adj_speed,
+                        fan_rpms.iter().map(|rpm| or_null(rpm.map(|rpm| rpm.to_string()))).collect::<Vec<_>>().join(","),
+                    ),
+                );
+            }
+            // Hysteresis keeps it from stopping and starting over and over
+            let stop = thermal_state == ThermalState::Normal && args.fan_stop
+                .is_some_and(|fan_stop| fan_stop.stopped(fan_stopped, boost_check_temp, average_power));
+            if stop != fan_stopped {
+                event!("{} the fan", if stop { "Stopping" } else { "Restarting" });
+                fan_stopped = stop;
+            }
+            break 'speed (if stop { 0 } else { adj_speed }, thermal_state)
+        };
+        // Ride out the odd driver hiccup at the last good speed rather than
+        // waking the house
+        // Only for so long, though: the last good reading goes stale too
+        let holding = failed_reads < hold_on_error && last_gpu_read.elapsed() <= max_sample_age;
+        let (speed, thermal_state, cycle_mode) = match (thermal_state, last_good) {
+            (ThermalState::Fault, Some((speed, thermal_state))) if holding => {
+                failed_reads += 1;
+                event!("Holding the last speed through failed read {} of {}", failed_reads, hold_on_error);
+                (speed, thermal_state, Mode::HoldLast)
+            },
+            (ThermalState::Fault, _) => {
+                failed_reads += 1;
+                (speed, thermal_state, Mode::FailsafeMax)
+            },
+            _ => {
+                failed_reads = 0;
+                last_good = Some((speed, thermal_state));
+                (speed, thermal_state, Mode::Normal)
+            },
+        };
+        let critical = thermal_state == ThermalState::Critical;
+        if critical && !was_critical {
+            let temp = history.max_temp();
+            event!(Event::CriticalTemp { temp } => "GPU reached {}C, running the fan at full speed", temp);
+        }
+        was_critical = critical;
+        let emergency = match thermal_state {
+            ThermalState::Critical => Some(255),
+            ThermalState::Fault => Some(failsafe_speed),
+            _ => None,
+        };
+        let chassis_boost = chassis_guard.as_mut().map_or(0, ChassisGuard::update);
+        let (speed, speed_source) = arbitrate(
+            &mut arbiter,
+            speed.saturating_add(chassis_boost),
+            emergency,
+            critical,
+            fan_stalled,
+            args.speed_step,
+        );
+        // Each channel follows its own GPU, through the same arbitration and
+        // safety stage as the main speed, so an override, the cap or a
+        // critical temperature reach every channel
+        channel_speeds.clear();
+        channel_speeds.extend(channels.iter_mut()
+            .map(|channel| {
+                let (channel_speed, emergency) = match channel.update(&tunables, &power_curves, quiet) {
+                    Ok(channel_speed) => (channel_speed, emergency),
+                    Err(e) => {
+                        event!("Error updating fan controller channel {}: {}", channel.channel, e);
+                        (failsafe_speed, emergency.or(Some(failsafe_speed)))
+                    },
+                };
+                arbitrate(
+                    &mut arbiter,
+                    channel_speed.saturating_add(chassis_boost),
+                    emergency,
+                    critical,
+                    fan_stalled,
+                    args.speed_step,
+                ).0
+            }));
+
+        if !derived.is_empty() {
+            let gpu_fan_speed = channel_speeds.iter().copied().max().unwrap_or(speed);
+            DerivedChannel::speeds(&derived, gpu_fan_speed, sample_temp, sample_power, &mut derived_speeds);
+        }
+        // Everything after this point is what the latency budget covers
+        let decided = Decided {
+            speed,
+            source: speed_source,
+            thermal_state,
+            channel_speeds: &channel_speeds,
+            derived_speeds: &derived_speeds,
+            quiet_hours: args.quiet_hours.is_some_and(|q| q.is_now()),
+            deadband: tunables.deadband,
+        };
+        if !writer.write(fan_controller_ref.as_mut(), &decided, &mut counters, std::time::Instant::now()) {
+            fan_controller = None;
+        }
+
+        let latency = cycle_started.elapsed();
+
+        // Outside the latency budget: these answer in their own time
+        if extra_sent != Some(speed) || extra_health.failing != 0 {
+            for output in &extra_outputs {
+                output.set_speed(speed, thermal_state);
+            }
+            extra_sent = Some(speed);
+        }
+        for (i, output) in extra_outputs.iter().enumerate() {
+            let error = output.error();
+            if error.is_some() != extra_health.is_failing(i) {
+                match &error {
+                    Some(e) => event!("Extra output {:?} failing: {}", output.command, e),
+                    None => event!("Extra output {:?} working again", output.command),
+                }
+                extra_health.failing ^= 1 << i;
+            }
+        }
+
+        let over_budget = latency.as_secs_f64() > update_interval * latency_budget;
+        if over_budget && !latency_over_budget {
+            event!(
+                "Cycle took {:.0} ms, over the budget of {:.0} ms",
+                latency.as_secs_f64() * 1000.0,
+                update_interval * latency_budget * 1000.0,
+            );
+        } else if !over_budget && latency_over_budget {
+            event!("Cycle latency back within budget");
+        }
+        latency_over_budget = over_budget;
+
+        if let (true, Some(output)) = (args.read_rpm, &mut fan_controller) {
+            if let Err(e) = output.read_rpm(&mut fan_rpms) {
+                event!(Event::ControllerLost { error: e.to_string() } => "Error reading fan RPM: {}", e);
+                counters.controller_errors += 1;
+                fan_controller = None;
+            }
+        }
+        if let (Some(stall_check), Some(_)) = (&mut stall_check, &fan_controller) {
+            let duties = writer.duties::<{ controller::CHANNELS }>();
+            fan_stalled = stall_check.update(&duties, &fan_rpms);
+        }
+
+        // Losing the controller part way through trumps everything else
+        let cycle_mode = if fan_controller.is_none() { Mode::Offline } else { cycle_mode };
+        let cycle_mode = if cycle_mode == Mode::Normal && extra_health.failing != 0 { Mode::Degraded } else { cycle_mode };
+        enter_mode(&mut mode, cycle_mode);
+        let sample = Sample {
+            time: sample_time,
+            temp: sample_temp,
+            power: sample_power,
+            temp_delta: sample_temp_delta,
+            sm_clock: sample_clocks.0,
+            mem_clock: sample_clocks.1,
+            speed,
+            rpm: fan_rpms,
+            source: speed_source.name(),
+            extra_outputs: extra_health,
+            latency,
+            mode,
+        };
+        #[cfg(unix)]
+        if let Some(ctl_server) = &mut ctl_server {
+            ctl_server.set_override_active(arbiter.has_override());
+            for event in telemetry::take_pending_events() {
+                ctl_server.broadcast(&event);
+            }
+            ctl_server.broadcast(&sample.to_string());
+            if let Some((speed, minutes)) = ctl_server.take_boost() {
+                let boost = Override {
+                    speed,
+                    expires: Some(std::time::Instant::now() + std::time::Duration::from_secs_f64(minutes * 60.0)),
+                };
+                match arbiter.set_boost(boost) {
+                    Ok(()) => event!("Boosting the fans to at least {} for {} minutes", Duty(speed), minutes),
+                    Err(e) => event!("Not boosting the fans: {}", e),
+                }
+            }
+        }
+        if let Some(log) = &mut telemetry_log {
+            match log.write(&sample) {
+                Ok(()) => telemetry_log_failing = false,
+                Err(e) => {
+                    if !telemetry_log_failing {
+                        event!("Failed to write telemetry log: {}", e);
+                    }
+                    telemetry_log_failing = true;
+                },
+            }
+        }
+        telemetry.record(sample);
+        airflow_check.update(&telemetry);
+        runaway_check.update(&telemetry, speed_cap.unwrap_or(255));
+        if let (Some(power_guard), Some(power_limits), Some(temp)) = (&mut power_guard, &mut power_limits, sample_temp) {
+            if let Some(below) = power_guard.update(temp, speed) {
+                let quiet_limit = args.quiet_power_limit
+                    .filter(|_| quiet_power_limit_applied)
+                    .map(|watts| (watts * 1000.0) as u32);
+                let limits = power_limits.set(|card| quiet_limit.unwrap_or(card.initial).saturating_sub(below));
+                if let Err(e) = limits {
+                    event!("Failed to set power limit: {}", e);
+                }
+            }
+        }
+        // Only a healthy loop gets to keep the machine alive: holding the last
+        // speed through a failed read isn't healthy, whatever the state says
+        if let Some(watchdog) = &mut watchdog {
+            // Every sensor read this cycle, or there'd be no temperature
+            if sample_temp.is_some() {
+                if let Err(e) = watchdog.pet() {
+                    event!("Failed to pet watchdog: {}", e);
+                }
+            }
+        }
+
+        if bundle_requested.swap(false, Ordering::Relaxed) {
+            let dir = args.debug_bundle_dir.clone().unwrap_or_else(std::env::temp_dir);
+            match telemetry::write_debug_bundle(
+                &dir,
+                &effective_config,
+                &telemetry,
+                &counters,
+                &identity,
+                &args.redact,
+            ) {
+                Ok(path) => event!("Wrote debug bundle to {}", path.display()),
+                Err(e) => event!("Failed to write debug bundle: {}", e),
+            }
+        }
+    }
+
+    if let Some(power_limits) = &mut power_limits {
+        power_limits.restore()
+            .map_err(|e| format!("Failed to restore power limit: {}", e))?;
+    }
+    if let Some(path) = &args.state_file {
+        counters.save(path)
+            .map_err(|e| format!("Failed to save state file: {}", e))?;
+    }
+    if let Some(watchdog) = watchdog {
+        watchdog.disarm()
+            .map_err(|e| format!("Failed to disarm watchdog: {}", e))?;
+    }
+    // Let cron or Task Scheduler know it didn't take
+    if once && fan_controller.is_none() {
+        Err("failed to update the fan controller")?
+    }
+    Ok(())
+}
+
+/// Set by SIGTERM or SIGINT, for the commands that drive the fans to put
+/// them somewhere safe before exiting.
+fn shutdown_flag() -> Result<Arc<AtomicBool>, Box<dyn Error>> {
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, shutdown_requested.clone())?;
+    }
+    Ok(shutdown_requested)
+}
+
+/// Sleeps for `duration`, or less if `shutdown_requested` gets set, in which
+/// case it returns true.
+fn sleep_unless_shutdown(shutdown_requested: &AtomicBool, duration: std::time::Duration) -> bool {
+    let started = std::time::Instant::now();
+    while let Some(left) = duration.checked_sub(started.elapsed()).filter(|left| !left.is_zero()) {
+        if shutdown_requested.load(Ordering::Relaxed) {
+            return true
+        }
+        thread::sleep(std::time::Duration::from_millis(250).min(left));
+    }
+    shutdown_requested.load(Ordering::Relaxed)
+}
+
+fn init_nvml() -> Result<Nvml, Box<dyn Error>> {
+    let nvml = if cfg!(windows) {
+        Nvml::init()
+    } else {
+        Nvml::builder()
+            .lib_path("./libnvidia-ml.so".as_ref())
+            .init()
+    };
+    Ok(nvml.map_err(|e| format!("Failed to init NVML: {}", e))?)
+}
+
+fn set_speed(args: SetArgs) -> Result<(), Box<dyn Error>> {
+    let speed = match (args.speed.or(args.duty), args.percent, args.rpm_target, args.max_rpm) {
+        (Some(speed), ..) => speed,
+        (None, Some(percent), ..) => (percent * 255.0 / 100.0).round() as u8,
+        (None, None, Some(target), Some(max_rpm)) => {
+            if target > max_rpm {
+                Err(format!("--rpm-target {} is faster than the fans go at full duty (--max-rpm {})", target, max_rpm))?
+            }
+            (target as f64 / max_rpm.max(1) as f64 * 255.0).round() as u8
+        },
+        _ => Err("Give a speed, --duty, --percent or --rpm-target with --max-rpm")?,
+    };
+
+    let hidapi = HidApi::new()
+        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+    let report = args.controller.report()?;
+    let mut output = HidOutput::open(&hidapi, &report)?;
+    let controllers = output.boards();
+    let report_format = output.format().clone();
+    let message = report_format.template_message(&report.report_template.unwrap_or_default(), speed);
+    // Whatever the controller queued up before now was measured at the old
+    // speed, and mustn't be mistaken for the readback
+    if !args.readback.is_zero() {
+        output.read_rpm(&mut [None; controller::CHANNELS])
+            .map_err(|e| format!("Error reading fan controller: {}", e))?;
+    }
+    output.set_speed(speed, ThermalState::Normal)
+        .map_err(|e| format!("Error updating fan controller: {}", e))?;
+    let bytes = message.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ");
+    emit(
+        format!(
+            "Set speed to {}{}: wrote [{}] to {} controller{} ({}, {} byte reports)",
+            Duty(speed),
+            args.rpm_target.map_or(String::new(), |target| format!(" for {} RPM", target)),
+            bytes,
+            controllers,
+            if controllers == 1 { "" } else { "s" },
+            report_format.report_id.map_or("no report ID".to_string(), |id| format!("report ID {}", id)),
+            report_format.len,
+        ),
+        || format!(
+            r#"{{"speed":{},"percent":{:.1},"rpm_target":{},"message":"{}","report_id":{},"report_length":{},"controllers":{}}}"#,
+            speed,
+            Duty(speed).percent(),
+            args.rpm_target.map_or("null".to_string(), |target| target.to_string()),
+            bytes,
+            report_format.report_id.map_or("null".to_string(), |id| id.to_string()),
+            report_format.len,
+            controllers,
+        ),
+    );
+
+    // The controller only reports RPM every so often, and the fans take a
+    // moment to get to the new speed anyway
+    let readback = args.readback;
+    if readback.is_zero() {
+        return Ok(())
+    }
+    let mut fan_rpms = [None; controller::CHANNELS];
+    let started = std::time::Instant::now();
+    while let Some(left) = readback.checked_sub(started.elapsed()).filter(|left| !left.is_zero()) {
+        thread::sleep(std::time::Duration::from_millis(250).min(left));
+        output.read_rpm(&mut fan_rpms)
+            .map_err(|e| format!("Error reading fan controller: {}", e))?;
+    }
+    let or_null = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+    emit(
+        match (fan_rpms.iter().any(Option::is_some), args.rpm_target) {
+            (false, _) => format!(
+                "No RPM heard from the controller in {:.1}s; its firmware may not report it",
+                readback.as_secs_f64(),
+            ),
+            (true, None) => format!("Read back RPM {}", telemetry::Rpms(&fan_rpms)),
+            (true, Some(target)) => format!("Read back RPM {} against a target of {}", telemetry::Rpms(&fan_rpms), target),
+        },
+        || format!(
+            r#"{{"fan_rpms":[{}]}}"#,
+            fan_rpms.iter().map(|rpm| or_null(rpm.map(|rpm| rpm.to_string()))).collect::<Vec<_>>().join(","),
+        ),
+    );
+    Ok(())
+}
+
+fn list_devices(args: ControllerArgs) -> Result<(), Box<dyn Error>> {
+    let report = args.report()?;
+    match init_nvml() {
+        Ok(nvml) => {
+            if telemetry::output_format() == OutputFormat::Plain {
+                println!("GPUs:");
+            }
+            let count = match nvml.device_count() {
+                Ok(count) => count,
+                Err(e) => {
+                    emit(
+                        format!("  Failed to count GPUs: {}", e),
+                        || format!(r#"{{"type":"gpu","error":{}}}"#, json_string(&e.to_string())),
+                    );
+                    0
+                },
+            };
+            for i in 0..count {
+                // One card in a bad state shouldn't hide the rest
+                let info = nvml.device_by_index(i)
+                    .and_then(|device| Ok((
+                        device.name()?,
+                        device.pci_info()?.bus_id,
+                        device.uuid()?,
+                        device.temperature(TemperatureSensor::Gpu)?,
+                        gpu::persistence_mode(&device),
+                    )));
+                let (name, bus_id, uuid, temp, persistence) = match info {
+                    Ok(info) => info,
+                    Err(e) => {
+                        emit(
+                            format!("  {}: error: {}", i, e),
+                            || format!(r#"{{"type":"gpu","index":{},"error":{}}}"#, i, json_string(&e.to_string())),
+                        );
+                        continue
+                    },
+                };
+                emit(
+                    format!(
+                        "  {}: {} - {} - {} - {}C - persistence mode {}",
+                        i,
+                        name,
+                        bus_id,
+                        uuid,
+                        temp,
+                        match persistence {
+                            Some(true) => "on",
+                            Some(false) => "off",
+                            None => "unknown",
+                        },
+                    ),
+                    || format!(
+                        r#"{{"type":"gpu","index":{},"name":{},"pci_bus_id":{},"uuid":{},"temp":{},"persistence_mode":{}}}"#,
+                        i,
+                        json_string(&name),
+                        json_string(&bus_id),
+                        json_string(&uuid),
+                        temp,
+                        persistence.map_or("null".to_string(), |on| on.to_string()),
+                    ),
+                );
+            }
+        },
+        Err(e) => emit(
+            format!("No GPUs: {}", e),
+            || format!(r#"{{"type":"gpu","error":{}}}"#, json_string(&e.to_string())),
+        ),
+    }
+
+    let hidapi = match HidApi::new() {
+        Ok(hidapi) => hidapi,
+        Err(e) => {
+            emit(
+                format!("No fan controllers: failed to init HidApi: {}", e),
+                || format!(r#"{{"type":"controller","error":{}}}"#, json_string(&e.to_string())),
+            );
+            return Ok(())
+        },
+    };
+    if telemetry::output_format() == OutputFormat::Plain {
+        println!("Fan controllers:");
+    }
+    let format = report.format();
+    for device in hidapi.device_list() {
+        let kind = match (device.vendor_id(), device.product_id()) {
+            ids if ids == (format.vid, format.pid) => "fan controller",
+            (commander_pro::COMMANDER_PRO_VID, commander_pro::COMMANDER_PRO_PID) => "Commander Pro",
+            _ => continue,
+        };
+        let path = device.path().to_string_lossy();
+        emit(
+            format!(
+                "  {} - {:04x}:{:04x} - {} - serial {}",
+                kind,
+                device.vendor_id(),
+                device.product_id(),
+                path,
+                device.serial_number().unwrap_or("unknown"),
+            ),
+            || format!(
+                r#"{{"type":"controller","kind":{},"vid":{},"pid":{},"path":{},"serial":{}}}"#,
+                json_string(kind),
+                device.vendor_id(),
+                device.product_id(),
+                json_string(&path),
+                device.serial_number().map(json_string).unwrap_or_else(|| "null".to_string()),
+            ),
+        );
+    }
+    Ok(())
+}
+
+fn test_curve(args: TestCurveArgs) -> Result<(), Box<dyn Error>> {
+    if args.step <= 0.0 {
+        Err("step must be positive")?
+    }
+    let file_curve = args.config.as_deref()
+        .map(Config::load)
+        .transpose()?
+        .and_then(|config| config.fan_curve().transpose())
+        .transpose()?;
+    let mut curve = args.fan_curve
+        .or(file_curve)
+        .unwrap_or_else(default_fan_speed_table);
+    curve.interpolation = args.fan_curve_interpolation.unwrap_or(curve.interpolation);
+    curve.below = args.fan_curve_below.unwrap_or(curve.below);
+    curve.above = args.fan_curve_above.unwrap_or(curve.above);
+
+    if args.traces {
+        let traces = match &args.trace_dir {
+            Some(dir) => traces::load_dir(dir)?,
+            None => traces::built_in()?,
+        };
+        let critical_temp = args.critical_temp.unwrap_or(DEFAULT_CRITICAL_TEMP);
+        let boost_temp = args.boost_temp.unwrap_or(DEFAULT_BOOST_TEMP);
+        if boost_temp > critical_temp {
+            Err(format!(
+                "boost temperature ({}C) can't be above the critical temperature ({}C)",
+                boost_temp, critical_temp
+            ))?
+        }
+        let boost_amount = args.boost_amount.unwrap_or(DEFAULT_BOOST_AMOUNT);
+        let tunables = |curve| Tunables::for_power_curve(curve, critical_temp, boost_temp, boost_amount);
+        let temp_limit = args.temp_limit.unwrap_or(critical_temp);
+        if !traces::check_curve(&tunables(curve), &tunables(default_fan_speed_table()), &traces, temp_limit)? {
+            Err("fan curve doesn't keep every trace under the temperature limit")?
+        }
+        return Ok(())
+    }
+    let steps = (1.0 / args.step).round() as usize;
+    for i in 0..=steps {
+        let power_usage = (i as f64 * args.step).min(1.0);
+        let speed = curve.lookup_speed(power_usage);
+        emit(
+            format!("{:4.0}% -> {}", power_usage * 100.0, Duty(speed)),
+            || format!(r#"{{"power":{:.4},"speed":{}}}"#, power_usage, speed),
+        );
+    }
+    Ok(())
+}
+
+fn check_measurements(args: CheckMeasurementsArgs) -> Result<(), Box<dyn Error>> {
+    let CheckMeasurementsArgs { measurements, temp_limit, args } = args;
+    let args = with_config(&args)?;
+    if args.fan_curve_watts.is_some() {
+        Err("measurements can't be checked against --fan-curve-watts")?
+    }
+    let temp_limit = temp_limit.or(args.boost_temp).unwrap_or(DEFAULT_BOOST_TEMP);
+    let measurements = measurements::load(&measurements)?;
+    if !measurements::check_curve(&fan_curve_from_args(&args), &measurements, temp_limit) {
+        Err("fan curve doesn't meet the measured requirements")?
+    }
+    Ok(())
+}
+
+fn simulate(args: SimulateArgs) -> Result<(), Box<dyn Error>> {
+    let SimulateArgs { from, slowdown_temp, args } = args;
+    let args = with_config(&args)?;
+    if args.pid.is_some() {
+        Err("simulate only replays the fan curve, not --pid")?
+    }
+    // The defaults depend on the card, which isn't here to ask
+    let temp_defaults = match (slowdown_temp, args.critical_temp, args.boost_temp) {
+        (Some(slowdown_temp), _, _) => TempDefaults::below_slowdown(slowdown_temp),
+        (None, Some(_), Some(_)) => TempDefaults::default(),
+        _ => Err("give the GPU's --slowdown-temp, or both --critical-temp and --boost-temp")?,
+    };
+    let tunables = Tunables::from_args(&args, temp_defaults)?;
+    simulate::run(&from, &tunables, &replay_limits(&args)?)
+}
+
+/// --failsafe-speed, refusing slow ones that weren't asked for explicitly.
+fn failsafe_speed(args: &Args) -> Result<u8, Box<dyn Error>> {
+    let speed = args.failsafe_speed.unwrap_or(255);
+    if speed < MIN_FAILSAFE_SPEED && !args.allow_slow_failsafe {
+        Err(format!(
+            "--failsafe-speed {} would leave the GPUs with little or no airflow while they can't be read; \
+             use at least {} or give --allow-slow-failsafe",
+            speed, MIN_FAILSAFE_SPEED
+        ))?
+    }
+    Ok(speed)
+}
+
+fn replay_limits(args: &Args) -> Result<simulate::Limits, Box<dyn Error>> {
+    let update_interval = args.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL);
+    if update_interval <= 0.0 {
+        Err("update interval must be positive")?
+    }
+    Ok(simulate::Limits {
+        max_speed: args.max_speed,
+        speed_step: args.speed_step,
+        hold_on_error: args.hold_on_error.unwrap_or(0),
+        failsafe_speed: failsafe_speed(args)?,
+        samples: decision::history_samples(update_interval),
+        startup_history: args.startup_history,
+    })
+}
+
+fn soak(args: SoakArgs) -> Result<(), Box<dyn Error>> {
+    let SoakArgs { hours, load_command, load_levels, plateau_minutes, args } = args;
+    let args = with_config(&args)?;
+    if args.pid.is_some() {
+        Err("soak only checks the fan curve, not --pid")?
+    }
+    if hours <= 0.0 || plateau_minutes.is_zero() {
+        Err("the soak and its plateaus must have some length")?
+    }
+    if load_levels.is_empty() {
+        Err("no load levels to go through")?
+    }
+    let telemetry_log = args.telemetry_log.clone()
+        .ok_or("soak checks what the control loop logged; give the same --telemetry-log as `run`")?;
+    let update_interval = args.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL);
+    if update_interval <= 0.0 {
+        Err("update interval must be positive")?
+    }
+    let nvml = init_nvml()?;
+    let device = gpu::find_device(&nvml, args.gpu.first().map(String::as_str))?;
+    let temp_defaults = device.temperature_threshold(TemperatureThreshold::Slowdown)
+        .map(TempDefaults::below_slowdown)
+        .unwrap_or_default();
+    let tunables = Tunables::from_args(&args, temp_defaults)?;
+    let plan = soak::Plan {
+        load_command,
+        levels: load_levels,
+        plateau: plateau_minutes,
+        total: std::time::Duration::from_secs_f64(hours * 3600.0),
+        interval: std::time::Duration::from_secs_f64(update_interval),
+    };
+    if !soak::run(&device, &plan, &telemetry_log, &tunables, &replay_limits(&args)?)? {
+        Err("soak test failed")?
+    }
+    Ok(())
+}
+
+fn agent(args: AgentArgs) -> Result<(), Box<dyn Error>> {
+    if args.update_interval.is_zero() {
+        Err("update interval must be positive")?
+    }
+    let nvml = init_nvml()?;
+    let device = gpu::find_device(&nvml, args.gpu.as_deref())?;
+    let secret = args.secret_file.as_deref().map(read_secret).transpose()?;
+    gpu::run_agent(&device, &args.target, secret.as_deref(), args.update_interval)
+}
+
+/// The secret shared between the agent and the controller, from its file.
+fn read_secret(path: &std::path::Path) -> Result<String, Box<dyn Error>> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read secret from {}: {}", path.display(), e))?;
+    let secret = secret.trim();
+    if secret.is_empty() || secret.contains('\n') {
+        Err(format!("{} should hold the secret on a single line", path.display()))?
+    }
+    Ok(secret.to_string())
+}
+
+fn autotune(args: AutotuneArgs) -> Result<(), Box<dyn Error>> {
+    if args.update_interval <= 0.0 {
+        Err("update interval must be positive")?
+    }
+    if args.low_speed >= args.high_speed {
+        Err("low speed must be below the high speed")?
+    }
+    let nvml = init_nvml()?;
+    let device = gpu::find_device(&nvml, args.gpu.as_deref())?;
+    let critical_temp = args.critical_temp.unwrap_or_else(|| {
+        device.temperature_threshold(TemperatureThreshold::Slowdown)
+            .map(TempDefaults::below_slowdown)
+            .unwrap_or_default()
+            .critical
+    });
+    if args.target_temp >= critical_temp {
+        Err(format!(
+            "target temperature ({}C) must be below the critical temperature ({}C)",
+            args.target_temp, critical_temp
+        ))?
+    }
+    let hidapi = HidApi::new()
+        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+    let mut output = HidOutput::open(&hidapi, &args.controller.report()?)?;
+    let mut set_speed = |speed: u8| {
+        output.set_speed(speed, ThermalState::Normal)
+            .map_err(|e| format!("Error updating fan controller: {}", e))
+    };
+
+    let shutdown_requested = shutdown_flag()?;
+
+    let mut tune = RelayTune::new(args.target_temp, args.low_speed, args.high_speed);
+    let started = std::time::Instant::now();
+    let params = loop {
+        let time = started.elapsed().as_secs_f64();
+        if time > args.max_minutes.as_secs_f64() {
+            set_speed(255)?;
+            Err(format!(
+                "Gave up after {} minutes with {} of {} oscillations; try a steadier load or other speeds",
+                args.max_minutes.as_secs_f64() / 60.0, tune.cycles(), args.cycles
+            ))?
+        }
+        let temp = match device.temperature(TemperatureSensor::Gpu) {
+            Ok(temp) => temp,
+            Err(e) => {
+                set_speed(255)?;
+                Err(format!("Failed to read GPU: {}", e))?
+            },
+        };
+        // The same failsafe as the control loop
+        if temp >= critical_temp {
+            set_speed(255)?;
+            Err(format!("Aborted at a critical {}C; left the fan at full speed", temp))?
+        }
+
+        let speed = tune.update(temp, time);
+        set_speed(speed)?;
+        emit(
+            format!("{:6.0}s {}C -> {} ({} oscillations)", time, temp, Duty(speed), tune.cycles()),
+            || format!(
+                r#"{{"time":{:.1},"temp":{},"speed":{},"cycles":{}}}"#,
+                time, temp, speed, tune.cycles()
+            ),
+        );
+        if let Some(params) = tune.result(args.cycles) {
+            break params
+        }
+        if sleep_unless_shutdown(&shutdown_requested, std::time::Duration::from_secs_f64(args.update_interval)) {
+            // Whatever the relay had it at could be the low speed
+            set_speed(255)?;
+            Err(format!(
+                "Interrupted with {} of {} oscillations; left the fan at full speed",
+                tune.cycles(), args.cycles
+            ))?
+        }
+    };
+
+    // Somewhere safe until the real control loop takes over
+    set_speed(255)?;
+    emit(
+        format!("Suggested gains: --pid {} --target-temp {}", params, args.target_temp),
+        || format!(r#"{{"pid":{},"target_temp":{}}}"#, json_string(&params.to_string()), args.target_temp),
+    );
+    Ok(())
+}
+
+/// A load on the GPU from a command, stopped when it's dropped.
+struct LoadCommand(std::process::Child);
+
+impl LoadCommand {
+    fn start(command: &str) -> Result<Self, Box<dyn Error>> {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        let child = std::process::Command::new(shell)
+            .args([flag, command])
+            .spawn()
+            .map_err(|e| format!("Failed to start load command: {}", e))?;
+        Ok(LoadCommand(child))
+    }
+}
+
+impl Drop for LoadCommand {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn characterize(args: CharacterizeArgs) -> Result<(), Box<dyn Error>> {
+    if args.update_interval <= 0.0 {
+        Err("update interval must be positive")?
+    }
+    if args.duties.is_empty() {
+        Err("no fan speeds to measure at")?
+    }
+    let nvml = init_nvml()?;
+    let device = gpu::find_device(&nvml, args.gpu.as_deref())?;
+    let critical_temp = args.critical_temp.unwrap_or_else(|| {
+        device.temperature_threshold(TemperatureThreshold::Slowdown)
+            .map(TempDefaults::below_slowdown)
+            .unwrap_or_default()
+            .critical
+    });
+    let hidapi = HidApi::new()
+        .map_err(|e| format!("Failed to init HidApi: {}", e))?;
+    let mut output = HidOutput::open(&hidapi, &args.controller.report()?)?;
+    let mut set_speed = |speed: u8| {
+        output.set_speed(speed, ThermalState::Normal)
+            .map_err(|e| format!("Error updating fan controller: {}", e))
+    };
+    let shutdown_requested = shutdown_flag()?;
+    let _load = args.load_command.as_deref()
+        .map(LoadCommand::start)
+        .transpose()?;
+
+    let settle_len = (args.settle_minutes.as_secs_f64() / args.update_interval).ceil() as usize;
+    let mut measurements = vec![];
+    'duties: for &duty in &args.duties {
+        set_speed(duty)?;
+        let mut settle = measurements::Settle::new(settle_len, args.settle_tolerance);
+        let started = std::time::Instant::now();
+        let measurement = loop {
+            if sleep_unless_shutdown(&shutdown_requested, std::time::Duration::from_secs_f64(args.update_interval)) {
+                // Dropping the load command on the way out stops it
+                set_speed(255)?;
+                Err(format!(
+                    "Interrupted with {} of {} speeds measured; left the fan at full speed",
+                    measurements.len(), args.duties.len()
+                ))?
+            }
+            let reading = match gpu::Reading::from_device(&device) {
+                Ok(reading) => reading,
+                Err(e) => {
+                    set_speed(255)?;
+                    Err(format!("Failed to read GPU: {}", e))?
+                },
+            };
+            // The same failsafe as the control loop; slower speeds would only
+            // be hotter still
+            if reading.temp >= critical_temp {
+                set_speed(255)?;
+                event!("Reached a critical {}C at {}, not going any slower", reading.temp, Duty(duty));
+                break 'duties
+            }
+            if let Some(measurement) = settle.update(duty, reading.temp, reading.power_fraction()) {
+                break measurement
+            }
+            if started.elapsed() > args.max_minutes {
+                event!(
+                    "{} didn't settle within {} minutes, skipping it",
+                    Duty(duty),
+                    args.max_minutes.as_secs_f64() / 60.0,
+                );
+                continue 'duties
+            }
+        };
+        emit(
+            measurement.to_string(),
+            || format!(
+                r#"{{"power":{:.4},"duty":{},"temp":{}}}"#,
+                measurement.power, measurement.duty, measurement.temp
+            ),
+        );
+        measurements.push(measurement);
+    }
+
+    // Somewhere safe until the real control loop takes over
+    set_speed(255)?;
+    if let Some(path) = &args.out {
+        let mut contents = String::from("# Steady-state temperatures measured by characterize.\n# power% @ duty/255 => temperature\n");
+        for measurement in &measurements {
+            contents += &format!("{}\n", measurement);
+        }
+        std::fs::write(path, contents)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    if measurements.is_empty() {
+        Err("Nothing settled; try a steadier load or a longer --max-minutes")?
+    }
+    Ok(())
+}
+
+fn self_update(args: SelfUpdateArgs) -> Result<(), Box<dyn Error>> {
+    let release = update::latest_release()?;
+    let newer = release.is_newer();
+    emit(
+        if newer {
+            format!("{} is available (this is {})", release.version, env!("CARGO_PKG_VERSION"))
+        } else {
+            format!("Up to date ({})", env!("CARGO_PKG_VERSION"))
+        },
+        || format!(
+            r#"{{"current":{},"latest":{},"newer":{}}}"#,
+            json_string(env!("CARGO_PKG_VERSION")), json_string(&release.version), newer
+        ),
+    );
+    if args.install && !args.check && newer {
+        update::install(&release)?;
+        event!("Installed {}; restart to use it", release.version);
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = Cli::clap().get_matches();
+    let cli = Cli::from_clap(&matches);
+    telemetry::set_output_format(cli.output);
+    let command_line = matches.subcommand().1;
+    let result = match cli.command {
+        Command::Run(args) => inner_main(args.given(command_line), false),
+        Command::Once(args) => inner_main(args.given(command_line), true),
+        Command::ShowConfig(args) => show_config(args.given(command_line)),
+        Command::Set(args) => set_speed(args),
+        Command::ListDevices(args) => list_devices(args),
+        Command::TestCurve(args) => test_curve(args),
+        Command::Agent(args) => agent(args),
+        #[cfg(unix)]
+        Command::Broker(args) => args.socket_group.as_deref()
+            .map(broker::group_id)
+            .transpose()
+            .and_then(|group| Ok((group, args.controller.report()?)))
+            .and_then(|(group, report)| broker::serve(
+                &args.socket,
+                group,
+                report.format(),
+                report.report_template.clone().unwrap_or_default(),
+                report.controller,
+            )),
+        #[cfg(not(unix))]
+        Command::Broker(_) => Err("the broker needs Unix sockets".into()),
+        Command::Autotune(args) => autotune(args),
+        Command::Characterize(args) => characterize(args),
+        Command::Chart(args) => chart::render(&args.from, args.since, &args.out).map(|()| emit(
+            format!("Wrote {}", args.out.display()),
+            || format!(r#"{{"out":{}}}"#, json_string(&args.out.display().to_string())),
+        )),
+        Command::Simulate(args) => simulate(args),
+        Command::CheckMeasurements(args) => check_measurements(args),
+        Command::Soak(args) => soak(args),
+        Command::SelfUpdate(args) => self_update(args),
+        #[cfg(target_os = "linux")]
+        Command::EmulateController(args) => emulate::run(args.max_rpm),
+        #[cfg(not(target_os = "linux"))]
+        Command::EmulateController(_) => Err("controller emulation needs Linux's uhid".into()),
+        #[cfg(unix)]
+        Command::Ctl(CtlCommand::Tail { socket }) => ctl::tail(&socket),
+        #[cfg(unix)]
+        Command::Ctl(CtlCommand::ShowConfig { socket }) => ctl::show_config(&socket),
+        #[cfg(unix)]
+        Command::Ctl(CtlCommand::Boost { socket, speed, minutes }) => ctl::boost(&socket, speed, minutes),
+        #[cfg(not(unix))]
+        Command::Ctl(_) => Err("control sockets are only supported on Unix".into()),
+        Command::Protocol(ProtocolCommand::Dump(args)) => args.report().map(|report| {
+            println!("{}", controller::protocol_json(
+                &report.format(),
+                &report.report_template.unwrap_or_default(),
+            ));
+        }),
+    };
+    match result {
+        Ok(()) => (),
+        Err(e) => {
+            match telemetry::output_format() {
+                OutputFormat::Json => println!(r#"{{"error":{}}}"#, json_string(&e.to_string())),
+                _ => println!("Error occurred: {}", e),
+            }
+            std::process::exit(1);
+        },
+    }
+    /*
+    let device = (0..nvml.device_count().unwrap())
+        .map(|i| nvml.device_by_index(i).unwrap())
+        .find(|device| device.uuid().unwrap() == "GPU-b60cae4e-f524-14a8-2233-2dc2126b6754")
+        .unwrap();
+    */
+
+    // for i in 0..nvml.device_count().unwrap() {
+    //     let device = nvml.device_by_index(i).unwrap();
+    //     println!("{:?} - {} - {} - {}", device, device.name().unwrap(), device.uuid().unwrap(), device.temperature(TemperatureSensor::Gpu).unwrap());
+    // }
+
+    /*
+    let arg: u8 = env::args().nth(1).unwrap().parse().unwrap();
+    println!("{}", arg);
+
+    let hidapi = HidApi::new().unwrap();
+    for device in hidapi.device_list() {
+        println!("{:?}", device.path());
+    }
+    let device = hidapi.open(0x1209, 0x0010).unwrap();
     println!("{:?}", device.check_error());
     println!("{:?}", device.get_product_string());
     let mut buf = [0u8; 64];
@@ -411,3 +3913,257 @@ fn main() {
 
     // println!("Hello, world!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Counts the allocations made on each thread, so tests running
+    /// alongside each other don't get in the way.
+    struct CountingAllocator;
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(|count| count.get());
+        f();
+        ALLOCATIONS.with(|count| count.get()) - before
+    }
+
+    #[test]
+    fn curves_interpolate_between_their_points() {
+        let curve = FanSpeedTable::new(vec![(0.2, 0), (0.6, 200)]);
+        // Ramping in from off and out to full speed
+        assert_eq!(curve.lookup_speed(0.1), 0);
+        assert_eq!(curve.lookup_speed(0.4), 100);
+        assert_eq!(curve.lookup_speed(0.8), 227);
+        assert_eq!(curve.lookup_speed(1.5), 255);
+        let step = curve.clone().with_interpolation(Interpolation::Step);
+        assert_eq!(step.lookup_speed(0.4), 0);
+        assert_eq!(step.lookup_speed(0.6), 200);
+        // Cubic never overshoots, even around a sharp bend
+        let cubic = FanSpeedTable::new(vec![(0.3, 0), (0.5, 180), (0.6, 190), (0.9, 255)])
+            .with_interpolation(Interpolation::Cubic)
+            .with_extrapolation(Extrapolation::Clamp, Extrapolation::Clamp);
+        let speeds: Vec<u8> = (0..=100).map(|i| cubic.lookup_speed(i as f64 / 100.0)).collect();
+        assert!(speeds.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", speeds);
+        assert_eq!((cubic.lookup_speed(0.5), cubic.lookup_speed(0.6)), (180, 190));
+    }
+
+    #[test]
+    fn curves_extrapolate_beyond_their_end_points() {
+        let curve = FanSpeedTable::new(vec![(0.2, 50), (0.6, 150)]);
+        let beyond = |below, above| {
+            let curve = curve.clone().with_extrapolation(below, above);
+            (curve.lookup_speed(0.1), curve.lookup_speed(0.8))
+        };
+        assert_eq!(beyond(Extrapolation::Ramp, Extrapolation::Ramp), (25, 202));
+        assert_eq!(beyond(Extrapolation::Clamp, Extrapolation::Clamp), (50, 150));
+        assert_eq!(beyond(Extrapolation::Extrapolate, Extrapolation::Extrapolate), (24, 200));
+        assert_eq!(beyond(Extrapolation::Max, Extrapolation::Max), (255, 255));
+        // Rescaling keeps the shape
+        let rescaled = curve.clone().with_extrapolation(Extrapolation::Clamp, Extrapolation::Clamp).rescaled(0.5);
+        assert_eq!((rescaled.lookup_speed(0.2), rescaled.lookup_speed(0.5)), (100, 150));
+    }
+
+    #[test]
+    fn curves_parse_from_the_command_line() {
+        let curve: FanSpeedTable = "0.2:50, 0.6:150".parse().unwrap();
+        assert_eq!(curve.table, vec![(0.2, 50), (0.6, 150)]);
+        for bad in ["1.2:50", "0.5", "0.5:256", ""] {
+            assert!(bad.parse::<FanSpeedTable>().is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn deadband_holds_back_small_moves_only() {
+        assert_eq!("12".parse::<Deadband>().unwrap(), Deadband(12.0));
+        assert_eq!("5%".parse::<Deadband>().unwrap(), Deadband(12.75));
+        assert_eq!("off".parse::<Deadband>().unwrap(), Deadband::OFF);
+        assert!("300".parse::<Deadband>().is_err());
+        assert!("-1".parse::<Deadband>().is_err());
+        let deadband = Deadband::default();
+        assert!(within_deadband(100, 110, deadband));
+        assert!(!within_deadband(100, 120, deadband));
+        // Stopping and full speed always get through
+        assert!(!within_deadband(10, 0, deadband));
+        assert!(!within_deadband(250, 255, deadband));
+        assert!(within_deadband(255, 255, deadband));
+        assert_eq!(quantize_speed(100, std::num::NonZeroU8::new(16).unwrap()), 96);
+        assert_eq!(quantize_speed(250, std::num::NonZeroU8::new(16).unwrap()), 255);
+    }
+
+    #[test]
+    fn fan_stop_has_hysteresis() {
+        let fan_stop: FanStop = "45:50".parse().unwrap();
+        assert_eq!(fan_stop, FanStop::Temp { stop: 45, start: 50 });
+        assert!(fan_stop.stopped(false, 44, 0.0));
+        assert!(!fan_stop.stopped(false, 46, 0.0));
+        // Once stopped, it takes the start threshold to get going again
+        assert!(fan_stop.stopped(true, 48, 0.0));
+        assert!(!fan_stop.stopped(true, 50, 0.0));
+        assert_eq!("15%:25%".parse::<FanStop>().unwrap(), FanStop::Power { stop: 0.15, start: 0.25 });
+        for bad in ["50:45", "45:25%", "45"] {
+            assert!(bad.parse::<FanStop>().is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn working_out_the_speed_doesnt_allocate() {
+        let curve = default_fan_speed_table();
+        let mut temp_history = CircleBuf::with_capacity(12);
+        let mut power_history = CircleBuf::with_capacity(12);
+        let mut power_ema = Ema(0.5);
+        let derived = vec![
+            DerivedChannel::new("intake".to_string(), 1, "max(40, 0.6 * gpu)".parse().unwrap()),
+            DerivedChannel::new("exhaust".to_string(), 2, "out_intake + temp - power".parse().unwrap()),
+        ];
+        let mut derived_speeds = Vec::with_capacity(derived.len());
+        let path = std::env::temp_dir().join(format!("tesla_fan-alloc-test-{}.csv", std::process::id()));
+        let mut log = TelemetryLog::open(&path, u64::MAX).unwrap();
+        let mut telemetry = Telemetry::new(4);
+        let mut cycle = |i: u32| {
+            temp_history.push(60 + (i % 5) as u8);
+            power_history.push(0.4 + f64::from(i % 3) / 10.0);
+            let power = power_ema.push(power_history.iter().sum::<f64>() / power_history.len() as f64, 0.3);
+            let speed = curve.lookup_speed(power);
+            DerivedChannel::speeds(&derived, speed, temp_history.iter().max().map(|t| u32::from(*t)), Some(power), &mut derived_speeds);
+            let mut sample = Sample::offline(Some(speed));
+            sample.temp = Some(60);
+            sample.power = Some(power);
+            log.write(&sample).unwrap();
+            telemetry.record(sample);
+        };
+        // The buffers grow to size over the first few cycles
+        for i in 0..20 {
+            cycle(i);
+        }
+        let allocated = allocations(|| {
+            for i in 20..40 {
+                cycle(i);
+            }
+        });
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(allocated, 0);
+    }
+
+    #[test]
+    fn duties_are_whole_counts() {
+        assert_eq!(parse_duty("153"), Ok(153));
+        assert_eq!(parse_duty(" 0 "), Ok(0));
+        assert_eq!(parse_duty("255"), Ok(255));
+        for bad in ["256", "-1", "60%", "1.5", ""] {
+            assert!(parse_duty(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn percentages_take_either_decimal_separator() {
+        assert_eq!(parse_percent("60"), Ok(60.0));
+        assert_eq!(parse_percent("62.5%"), Ok(62.5));
+        assert_eq!(parse_percent("62,5 %"), Ok(62.5));
+        assert_eq!(parse_percent("0"), Ok(0.0));
+        assert_eq!(parse_percent("100%"), Ok(100.0));
+        for bad in ["100.1", "-5", "abc", "", "%"] {
+            assert!(parse_percent(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn power_guard_steps_down_only_when_flat_out() {
+        let mut guard = PowerGuard::new(80, 10_000);
+        // Hot, but the fans still have more to give
+        assert_eq!(guard.update(85, 200), None);
+        assert!(!guard.engaged());
+        assert_eq!(guard.update(85, 255), Some(10_000));
+        assert!(guard.engaged());
+        // Too soon after the last step for another
+        assert_eq!(guard.update(86, 255), None);
+        // Not cool enough to let go yet
+        assert_eq!(guard.update(80 - POWER_GUARD_HYSTERESIS + 1, 255), None);
+        assert_eq!(guard.update(80 - POWER_GUARD_HYSTERESIS, 255), Some(0));
+        assert!(!guard.engaged());
+    }
+
+    #[test]
+    fn thresholds_are_checked_against_the_cards_own_defaults() {
+        let args = Args::from_iter(["run", "--boost-temp", "80"]);
+        // Over the usual critical temperature, but not this card's
+        assert!(Tunables::from_args(&args, TempDefaults::default()).is_err());
+        let tunables = Tunables::from_args(&args, TempDefaults::below_slowdown(95)).unwrap();
+        assert_eq!((tunables.boost_temp, tunables.critical_temp), (80, 85));
+    }
+
+    #[test]
+    fn effective_config_says_where_each_setting_came_from() {
+        let matches = Args::clap().get_matches_from(["run", "-t", "5", "--critical-temp", "80"]);
+        let mut args = Args::from_clap(&matches).given(Some(&matches));
+        let config: Config = toml::from_str("critical_temp = 90\nboost_temp = 70\n").unwrap();
+        config.apply(&mut args).unwrap();
+        let source = |name: &str| EffectiveConfig::new(&args).to_string().lines()
+            .find(|line| line.split(' ').next() == Some(name))
+            .map(|line| line.rsplit("# ").next().unwrap().to_string());
+        assert_eq!(source("update_interval").as_deref(), Some("command line"));
+        assert_eq!(source("critical_temp").as_deref(), Some("command line"));
+        assert_eq!(source("boost_temp").as_deref(), Some("config file"));
+        assert_eq!(source("ema_alpha").as_deref(), Some("default"));
+        assert_eq!(source("intake_ratio").as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn profile_switches_move_between_the_curves_gradually() {
+        let curves = PowerCurves {
+            fan_curve: FanSpeedTable::from_points(vec![(0.0, 200), (1.0, 200)]).unwrap(),
+            quiet_fan_curve: Some(FanSpeedTable::from_points(vec![(0.0, 100), (1.0, 100)]).unwrap()),
+        };
+        let start = std::time::Instant::now();
+        let at = |secs: u64| start + std::time::Duration::from_secs(secs);
+        let mut transition = ProfileTransition::new(60.0);
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, start), 100);
+        transition.switch(false, start);
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, start), 200);
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, at(30)), 150);
+        // Switching back part way carries on from where it had got to
+        transition.switch(true, at(45));
+        assert_eq!(transition.lookup_speed(&curves, false, 0.5, at(45)), 125);
+        assert_eq!(transition.lookup_speed(&curves, false, 0.5, at(75)), 175);
+        assert_eq!(transition.lookup_speed(&curves, false, 0.5, at(90)), 200);
+        // Starting up quiet goes straight to the quiet curve
+        let mut transition = ProfileTransition::new(60.0);
+        transition.follow(None, true, start);
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, start), 100);
+        transition.follow(Some(true), true, at(10));
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, at(10)), 100);
+        transition.follow(Some(true), false, at(20));
+        assert_eq!(transition.lookup_speed(&curves, false, 0.5, at(50)), 150);
+        // No transition at all
+        let mut transition = ProfileTransition::new(0.0);
+        transition.switch(false, start);
+        assert_eq!(transition.lookup_speed(&curves, true, 0.5, start), 100);
+    }
+
+    #[test]
+    fn slow_failsafe_needs_opting_in() {
+        let args = |extra: &[&str]| Args::from_iter(["run"].iter().chain(extra));
+        assert_eq!(failsafe_speed(&args(&[])).unwrap(), 255);
+        assert_eq!(failsafe_speed(&args(&["--failsafe-speed", "128"])).unwrap(), 128);
+        assert!(failsafe_speed(&args(&["--failsafe-speed", "0"])).is_err());
+        assert_eq!(failsafe_speed(&args(&["--failsafe-speed", "0", "--allow-slow-failsafe"])).unwrap(), 0);
+    }
+}
@@ -0,0 +1,152 @@
+//! Cooling measurements, gathered by hand or by `characterize`, and checking a
+//! fan curve against them.
+//!
+//! One measurement per line, in the same form as the notes in main.rs:
+//!
+//! ```text
+//! 40% @  65/255 => 66c
+//! ```
+//!
+//! meaning that at 40% of the power limit, a duty of 65 settled at 66 degrees.
+//! Blank lines and lines starting with `#` are ignored, as is a leading `//`.
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::{Duty, FanSpeedTable};
+use crate::telemetry::emit;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Measurement {
+    /// Fraction of the power limit, 0.0-1.0
+    pub power: f64,
+    pub duty: u8,
+    pub temp: u32,
+}
+
+impl std::str::FromStr for Measurement {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (power, rest) = s.split_once('@').ok_or("missing '@'")?;
+        let (duty, temp) = rest.split_once("=>").ok_or("missing '=>'")?;
+
+        let power: f64 = power.trim().trim_end_matches('%').trim().parse()?;
+        let duty = duty.trim();
+        let duty: u8 = duty.strip_suffix("/255").unwrap_or(duty).trim().parse()?;
+        let temp = temp.trim();
+        let temp: u32 = temp.strip_suffix(['c', 'C']).unwrap_or(temp).trim().parse()?;
+        Ok(Measurement {
+            power: power / 100.0,
+            duty,
+            temp,
+        })
+    }
+}
+
+impl std::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.0}% @ {:3}/255 => {}c", self.power * 100.0, self.duty, self.temp)
+    }
+}
+
+/// Watches readings at a fixed duty until the temperature stops moving.
+pub struct Settle {
+    /// (temp, fraction of the power limit), the most recent last
+    window: Vec<(u32, f64)>,
+    len: usize,
+    tolerance: u32,
+}
+
+impl Settle {
+    /// Settled once `len` readings in a row stay within `tolerance` degrees.
+    pub fn new(len: usize, tolerance: u32) -> Self {
+        Settle {
+            window: Vec::with_capacity(len),
+            len: len.max(2),
+            tolerance,
+        }
+    }
+
+    /// Adds a reading, returning the measurement once it's settled.
+    pub fn update(&mut self, duty: u8, temp: u32, power: f64) -> Option<Measurement> {
+        if self.window.len() == self.len {
+            self.window.remove(0);
+        }
+        self.window.push((temp, power));
+        if self.window.len() < self.len {
+            return None
+        }
+        let temps = self.window.iter().map(|(temp, _)| *temp);
+        let (min, max) = (temps.clone().min()?, temps.max()?);
+        if max - min > self.tolerance {
+            return None
+        }
+        let n = self.window.len() as f64;
+        Some(Measurement {
+            power: self.window.iter().map(|(_, power)| power).sum::<f64>() / n,
+            duty,
+            temp: (self.window.iter().map(|(temp, _)| *temp as f64).sum::<f64>() / n).round() as u32,
+        })
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Measurement>, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    contents.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim().trim_start_matches("//").trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse()
+                .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e).into())
+        })
+        .collect()
+}
+
+/// Prints, for each measured power level, whether the curve asks for at least
+/// the lowest duty that was measured to stay at or under `temp_limit`.
+/// Returns whether every level passed.
+pub fn check_curve(curve: &FanSpeedTable, measurements: &[Measurement], temp_limit: u32) -> bool {
+    let mut powers: Vec<f64> = measurements.iter().map(|m| m.power).collect();
+    powers.sort_by(|a, b| a.total_cmp(b));
+    powers.dedup();
+
+    let mut ok = true;
+    for power in powers {
+        let at_power = measurements.iter().filter(|m| m.power == power);
+        let speed = curve.lookup_speed(power);
+        match at_power.clone().filter(|m| m.temp <= temp_limit).map(|m| m.duty).min() {
+            Some(needed) if speed >= needed => emit(
+                format!(
+                    "ok:   at {:.0}% power your curve gives {}, measured data says you need >= {} to stay under {}c",
+                    power * 100.0, Duty(speed), needed, temp_limit
+                ),
+                || format!(r#"{{"power":{},"speed":{},"needed":{},"ok":true}}"#, power, speed, needed),
+            ),
+            Some(needed) => {
+                ok = false;
+                emit(
+                    format!(
+                        "FAIL: at {:.0}% power your curve gives {}, measured data says you need >= {} to stay under {}c",
+                        power * 100.0, Duty(speed), needed, temp_limit
+                    ),
+                    || format!(r#"{{"power":{},"speed":{},"needed":{},"ok":false}}"#, power, speed, needed),
+                );
+            },
+            None => {
+                ok = false;
+                let hottest = at_power.max_by_key(|m| m.duty).expect("power level came from a measurement");
+                emit(
+                    format!(
+                        "FAIL: at {:.0}% power nothing measured stays under {}c; {} still reached {}c",
+                        power * 100.0, temp_limit, Duty(hottest.duty), hottest.temp
+                    ),
+                    || format!(r#"{{"power":{},"speed":{},"needed":null,"ok":false}}"#, power, speed),
+                );
+            },
+        }
+    }
+    ok
+}
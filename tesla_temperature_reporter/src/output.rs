@@ -0,0 +1,605 @@
+//! Where fan speed decisions end up.
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hidapi::{HidDevice, HidResult};
+
+#[cfg(unix)]
+use crate::broker::BrokerOutput;
+use crate::commander_pro::CommanderPro;
+use crate::controller::{CHANNELS, ReportFormat, ReportTemplate};
+use crate::telemetry::event;
+use crate::{Duty, ThermalState};
+
+/// How long to kick the fan for to get it turning again, at the least. It
+/// lasts until the first cycle after this.
+const KICKSTART_TIME: Duration = Duration::from_secs(1);
+const RAMP_STEPS: u32 = 10;
+/// How long an RPM reading stands for. A fan that has gone quiet for longer
+/// than this is reported as having nothing to say, rather than still turning.
+pub const RPM_MAX_AGE: Duration = Duration::from_secs(15);
+/// How long an output command gets to answer before we give up on it and
+/// start it afresh. Long enough for a slow BMC behind ipmitool.
+const PROCESS_ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something that drives the fans. The control loop only ever talks to the
+/// fans through this, so a new kind of hardware needs nothing more than an
+/// implementation of it.
+pub trait FanController {
+    fn set_speed(&mut self, speed: u8, thermal_state: ThermalState) -> Result<(), Box<dyn Error>>;
+
+    /// Fills in `rpms` with what each fan has reported within `RPM_MAX_AGE`,
+    /// and None for the ones that haven't. Outputs that can't tell us leave
+    /// it alone.
+    fn read_rpm(&mut self, _rpms: &mut [Option<u16>; CHANNELS]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Whether the output is still there, as far as it can tell without
+    /// trying a write. One that isn't gets dropped and opened afresh.
+    fn is_connected(&mut self) -> bool {
+        true
+    }
+
+    /// Whether this is our own controller, which also has the status LED,
+    /// buzzer and separately driven channels.
+    fn speaks_our_protocol(&self) -> bool {
+        false
+    }
+
+    /// Sends one of our protocol's messages. Only for outputs that
+    /// `speaks_our_protocol`.
+    fn write(&mut self, _msg: &[u8]) -> Result<(), Box<dyn Error>> {
+        Err("this output doesn't speak our controller's protocol")?
+    }
+}
+
+/// Getting a stopped fan going and up to speed, e.g. after a controller that
+/// may have power-cycled comes back. It moves on a step each time the control
+/// loop comes round rather than holding the loop up. Without a ramp the fan
+/// is kicked first; with one it's brought up gradually over that long, so a
+/// weak USB supply isn't hit with the inrush all at once.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpinUp {
+    Kick { kick: u8, until: Instant },
+    Ramp { started: Instant, length: Duration },
+}
+
+impl SpinUp {
+    pub fn start(ramp: Option<Duration>, kick: u8, now: Instant) -> Self {
+        match ramp {
+            Some(length) => SpinUp::Ramp { started: now, length },
+            None => SpinUp::Kick { kick, until: now + KICKSTART_TIME },
+        }
+    }
+
+    /// What to send at `now` on the way to `speed`, or None once the fan is
+    /// there.
+    pub fn speed(self, speed: u8, now: Instant) -> Option<u8> {
+        match self {
+            SpinUp::Kick { kick, until } => (now < until).then_some(kick.max(speed)),
+            SpinUp::Ramp { started, length } => {
+                let progress = now.saturating_duration_since(started).as_secs_f64() / length.as_secs_f64();
+                let step = (progress * RAMP_STEPS as f64) as u32 + 1;
+                (step < RAMP_STEPS).then(|| (speed as u32 * step / RAMP_STEPS) as u8)
+            },
+        }
+    }
+}
+
+/// Whichever output the control loop is driving.
+pub type FanOutput = Box<dyn FanController>;
+
+/// Our own controllers, all driven the same.
+pub struct HidOutput {
+    boards: Vec<Board>,
+    format: ReportFormat,
+    template: ReportTemplate,
+}
+
+/// One of the controllers behind a `HidOutput`, each with its own fans.
+struct Board {
+    device: HidDevice,
+    /// What this board's fans last reported, and when
+    rpms: [Option<(u16, Instant)>; CHANNELS],
+    /// Set once a write or read to this board has failed
+    lost: bool,
+}
+
+impl HidOutput {
+    pub fn new(devices: Vec<HidDevice>, format: ReportFormat, template: ReportTemplate) -> Self {
+        HidOutput {
+            boards: devices.into_iter()
+                .map(|device| Board { device, rpms: [None; CHANNELS], lost: false })
+                .collect(),
+            format,
+            template,
+        }
+    }
+
+    /// Opens the controllers `report` picks out.
+    pub fn open(hidapi: &hidapi::HidApi, report: &crate::ReportArgs) -> Result<Self, Box<dyn Error>> {
+        let mut format = report.format();
+        let devices = crate::controller::open_controllers(hidapi, &mut format, &report.controller)?;
+        Ok(HidOutput::new(devices, format, report.report_template.clone().unwrap_or_default()))
+    }
+
+    pub fn boards(&self) -> usize {
+        self.boards.len()
+    }
+
+    pub fn format(&self) -> &ReportFormat {
+        &self.format
+    }
+
+    /// Does `op` on every board, even after one fails, so the others still
+    /// get their speed. Boards that fail are marked lost, and the first
+    /// failure is returned.
+    fn each_board(
+        &mut self,
+        mut op: impl FnMut(&ReportFormat, &ReportTemplate, &mut Board) -> HidResult<()>,
+    ) -> Result<(), Box<dyn Error>> {
+        let boards = self.boards.len();
+        let mut first_error = None;
+        for (i, board) in self.boards.iter_mut().enumerate() {
+            if let Err(e) = op(&self.format, &self.template, board) {
+                board.lost = true;
+                let e = if boards == 1 { e.to_string() } else { format!("controller {} of {}: {}", i + 1, boards, e) };
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e)?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl FanController for HidOutput {
+    fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        self.each_board(|format, template, board| format.write_template(&board.device, template, speed).map(drop))
+    }
+
+    /// With several controllers, each channel's slowest fan, so one that
+    /// stalls on any board shows up.
+    fn read_rpm(&mut self, rpms: &mut [Option<u16>; CHANNELS]) -> Result<(), Box<dyn Error>> {
+        let read = self.each_board(|format, _, board| {
+            while let Some((channel, rpm)) = format.read_rpm(&board.device)? {
+                if let Some(known) = board.rpms.get_mut(channel as usize) {
+                    *known = Some((rpm, Instant::now()));
+                }
+            }
+            Ok(())
+        });
+        for (channel, rpm) in rpms.iter_mut().enumerate() {
+            *rpm = self.boards.iter()
+                .filter_map(|board| board.rpms[channel])
+                .filter(|(_, heard)| heard.elapsed() <= RPM_MAX_AGE)
+                .map(|(rpm, _)| rpm)
+                .min();
+        }
+        read
+    }
+
+    /// Asks each board for its product string, so one that's been unplugged
+    /// is noticed before anything gets written to it, not only after.
+    fn is_connected(&mut self) -> bool {
+        for board in &mut self.boards {
+            if !board.lost && board.device.get_product_string().is_err() {
+                board.lost = true;
+            }
+        }
+        !self.boards.iter().any(|board| board.lost)
+    }
+
+    fn speaks_our_protocol(&self) -> bool {
+        true
+    }
+
+    fn write(&mut self, msg: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.each_board(|format, _, board| format.write(&board.device, msg).map(drop))
+    }
+}
+
+/// Stands in for our own controller with --dry-run, logging each message
+/// instead of sending it.
+pub struct DryRunOutput {
+    format: ReportFormat,
+    template: ReportTemplate,
+}
+
+impl DryRunOutput {
+    pub fn new(format: ReportFormat, template: ReportTemplate) -> Self {
+        DryRunOutput { format, template }
+    }
+}
+
+impl FanController for DryRunOutput {
+    fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        log_dry_run(&self.format.template_message(&self.template, speed));
+        Ok(())
+    }
+
+    fn speaks_our_protocol(&self) -> bool {
+        true
+    }
+
+    fn write(&mut self, msg: &[u8]) -> Result<(), Box<dyn Error>> {
+        log_dry_run(msg);
+        Ok(())
+    }
+}
+
+impl FanController for ProcessOutput {
+    fn set_speed(&mut self, speed: u8, thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        self.send(speed, thermal_state)
+    }
+
+    fn is_connected(&mut self) -> bool {
+        !self.lost && matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+impl FanController for CommanderPro {
+    fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        CommanderPro::set_speed(self, speed)
+    }
+}
+
+#[cfg(unix)]
+impl FanController for BrokerOutput {
+    fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        BrokerOutput::set_speed(self, speed)
+    }
+
+    fn is_connected(&mut self) -> bool {
+        BrokerOutput::is_connected(self)
+    }
+
+    fn read_rpm(&mut self, rpms: &mut [Option<u16>; CHANNELS]) -> Result<(), Box<dyn Error>> {
+        let heard = self.rpm()?;
+        *rpms = [None; CHANNELS];
+        for (channel, rpm) in heard {
+            if let Some(known) = rpms.get_mut(channel as usize) {
+                *known = Some(rpm);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+impl FanController for GpioOutput {
+    fn set_speed(&mut self, speed: u8, _thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        GpioOutput::set_speed(self, speed)
+    }
+}
+
+fn log_dry_run(msg: &[u8]) {
+    let bytes: Vec<String> = msg.iter().map(|b| format!("{:02x}", b)).collect();
+    event!("Dry run, not sending [{}]", bytes.join(" "));
+}
+
+/// A slow triangle wave added to the commanded duty, for fans that whine at
+/// particular steady duties: wandering a little either side spreads the tone.
+#[derive(Copy, Clone, Debug)]
+pub struct Dither {
+    /// Furthest either side of the commanded duty to go
+    pub amplitude: u8,
+    /// Time for one full swing down and back up
+    pub period: Duration,
+}
+
+impl Dither {
+    /// `speed` dithered at `elapsed` into the wave. A stopped fan stays
+    /// stopped, and a running one is never dithered down to stopped.
+    pub fn apply(self, speed: u8, elapsed: Duration) -> u8 {
+        if speed == 0 || self.amplitude == 0 || self.period.is_zero() {
+            return speed
+        }
+        let phase = elapsed.as_secs_f64() / self.period.as_secs_f64() % 1.0;
+        let wave = 1.0 - 4.0 * (phase - 0.5).abs();
+        let offset = (self.amplitude as f64 * wave).round() as i32;
+        (speed as i32 + offset).clamp(1, 255) as u8
+    }
+}
+
+/// How a speed gets spread across several fans cooling the same card.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadSharing {
+    /// Every fan runs at the commanded speed, which is quieter than fewer fans
+    /// running faster
+    Split,
+    /// Treat the speed as a share of the fans' combined capacity and bring
+    /// fans in one at a time, each only once the previous one is at full speed
+    Staged,
+}
+
+impl LoadSharing {
+    pub fn duties(self, speed: u8, fans: usize) -> impl Iterator<Item = u8> {
+        let total = speed as usize * fans;
+        (0..fans).map(move |i| match self {
+            LoadSharing::Split => speed,
+            LoadSharing::Staged => total.saturating_sub(255 * i).min(255) as u8,
+        })
+    }
+}
+
+impl std::str::FromStr for LoadSharing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(LoadSharing::Split),
+            "staged" => Ok(LoadSharing::Staged),
+            _ => Err(format!("Unknown load sharing policy {}; expected split or staged", s)),
+        }
+    }
+}
+
+/// A long-running child process that receives each decision as a JSON line on
+/// its stdin and answers with a line on its stdout: "ok" on success, anything
+/// else is treated as an error message. This lets people drive hardware we
+/// don't know about from whatever language they like.
+///
+/// The talking happens on a thread of its own, so a command that stops
+/// reading or answering costs us `PROCESS_ANSWER_TIMEOUT` and the output,
+/// rather than the control loop.
+pub struct ProcessOutput {
+    child: Child,
+    requests: Sender<String>,
+    answers: Receiver<std::io::Result<String>>,
+    answer_timeout: Duration,
+    /// Set once an answer hasn't come; a late one would be taken for the
+    /// next request's, so the command has to be started afresh
+    lost: bool,
+}
+
+impl ProcessOutput {
+    pub fn spawn(command: &str) -> Result<Self, Box<dyn Error>> {
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+        let mut child = cmd.arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start output command: {}", e))?;
+        let stdin = child.stdin.take().ok_or("Output command has no stdin")?;
+        let stdout = child.stdout.take().ok_or("Output command has no stdout")?;
+        let (requests, requests_in) = std::sync::mpsc::channel();
+        let (answers_out, answers) = std::sync::mpsc::channel();
+        thread::spawn(move || talk_to_process(stdin, BufReader::new(stdout), requests_in, answers_out));
+        Ok(ProcessOutput {
+            child,
+            requests,
+            answers,
+            answer_timeout: PROCESS_ANSWER_TIMEOUT,
+            lost: false,
+        })
+    }
+
+    pub fn send(&mut self, speed: u8, thermal_state: ThermalState) -> Result<(), Box<dyn Error>> {
+        if self.lost {
+            Err("output command stopped answering")?
+        }
+        let request = format!(
+            r#"{{"speed":{},"percent":{:.1},"thermal_state":"{}"}}"#,
+            speed,
+            Duty(speed).percent(),
+            thermal_state.name(),
+        );
+        self.requests.send(request).map_err(|_| "output command exited")?;
+        let line = match self.answers.recv_timeout(self.answer_timeout) {
+            Ok(Ok(line)) if !line.is_empty() => line,
+            Ok(Ok(_)) | Err(RecvTimeoutError::Disconnected) => {
+                self.lost = true;
+                Err("output command exited")?
+            },
+            Ok(Err(e)) => {
+                self.lost = true;
+                Err(e)?
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                self.lost = true;
+                Err(format!("output command didn't answer within {:.1}s", self.answer_timeout.as_secs_f64()))?
+            },
+        };
+        match line.trim() {
+            "ok" => Ok(()),
+            msg => Err(format!("output command reported: {}", msg))?,
+        }
+    }
+}
+
+impl Drop for ProcessOutput {
+    fn drop(&mut self) {
+        // Which also frees a talking thread stuck on the pipes
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Writes each request to the command and hands back the line it answers
+/// with, empty once it has exited.
+fn talk_to_process(
+    mut stdin: ChildStdin,
+    mut stdout: BufReader<ChildStdout>,
+    requests: Receiver<String>,
+    answers: Sender<std::io::Result<String>>,
+) {
+    for request in requests {
+        let answer = writeln!(stdin, "{}", request)
+            .and_then(|()| stdin.flush())
+            .and_then(|()| {
+                let mut line = String::new();
+                stdout.read_line(&mut line).map(|_| line)
+            });
+        if answers.send(answer).is_err() {
+            break
+        }
+    }
+}
+
+/// An output command run alongside the main output, e.g. for chassis fans
+/// behind IPMI, on its own thread: however slow or broken it is, the main
+/// output is never held up.
+pub struct ExtraOutput {
+    pub command: String,
+    speeds: Sender<(u8, ThermalState)>,
+    status: Arc<Mutex<ExtraStatus>>,
+}
+
+#[derive(Default)]
+struct ExtraStatus {
+    /// Why the last send failed, until one succeeds
+    error: Option<String>,
+    /// When the oldest speed not yet sent was handed over. A command stuck
+    /// on it never fails, so this is how it shows up.
+    waiting_since: Option<Instant>,
+}
+
+impl ExtraOutput {
+    pub fn start(command: &str) -> Self {
+        let (speeds, receiver) = std::sync::mpsc::channel();
+        let status = Arc::new(Mutex::new(ExtraStatus::default()));
+        let shared = status.clone();
+        let command_owned = command.to_string();
+        thread::spawn(move || run_extra(&command_owned, receiver, &shared));
+        ExtraOutput {
+            command: command.to_string(),
+            speeds,
+            status,
+        }
+    }
+
+    /// Hands the output a new speed, without waiting for it to be sent.
+    pub fn set_speed(&self, speed: u8, thermal_state: ThermalState) {
+        self.status.lock().unwrap_or_else(|e| e.into_inner()).waiting_since.get_or_insert_with(Instant::now);
+        let _ = self.speeds.send((speed, thermal_state));
+    }
+
+    /// Why the output is failing, if it is, including having sat on a speed
+    /// for longer than a command gets to answer.
+    pub fn error(&self) -> Option<String> {
+        let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.error.clone().or_else(|| {
+            status.waiting_since
+                .map(|since| since.elapsed())
+                .filter(|waited| *waited > PROCESS_ANSWER_TIMEOUT)
+                .map(|waited| format!("no answer for {:.0}s", waited.as_secs_f64()))
+        })
+    }
+}
+
+fn run_extra(command: &str, speeds: Receiver<(u8, ThermalState)>, status: &Mutex<ExtraStatus>) {
+    let mut process = None;
+    while let Ok(mut latest) = speeds.recv() {
+        // Only the newest speed matters if the command has fallen behind
+        while let Ok(newer) = speeds.try_recv() {
+            latest = newer;
+        }
+        let result = match &mut process {
+            Some(process) => Ok(process),
+            None => ProcessOutput::spawn(command).map(|spawned| process.insert(spawned)),
+        }
+        .and_then(|process| process.send(latest.0, latest.1));
+        let mut status = status.lock().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(()) => *status = ExtraStatus::default(),
+            Err(e) => {
+                // Started afresh with the next speed
+                process = None;
+                status.error = Some(e.to_string());
+            },
+        }
+    }
+}
+
+/// A fan wired directly to one of a Raspberry Pi's hardware PWM channels.
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+pub struct GpioOutput {
+    pwm: rppal::pwm::Pwm,
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+impl GpioOutput {
+    pub fn open(channel: u8) -> Result<Self, Box<dyn Error>> {
+        use rppal::pwm::{Channel, Polarity, Pwm};
+
+        let channel = match channel {
+            0 => Channel::Pwm0,
+            1 => Channel::Pwm1,
+            _ => Err("GPIO PWM channel must be 0 or 1")?,
+        };
+        // 25kHz is what 4-pin PWM fans expect
+        let pwm = Pwm::with_frequency(channel, 25_000.0, 0.0, Polarity::Normal, true)
+            .map_err(|e| format!("Failed to open GPIO PWM channel: {}", e))?;
+        Ok(GpioOutput { pwm })
+    }
+
+    pub fn set_speed(&self, speed: u8) -> Result<(), Box<dyn Error>> {
+        self.pwm.set_duty_cycle(speed as f64 / 255.0)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spin_up_moves_on_each_cycle() {
+        let start = Instant::now();
+        let kick = SpinUp::start(None, 255, start);
+        assert_eq!(kick.speed(100, start), Some(255));
+        assert_eq!(kick.speed(100, start + KICKSTART_TIME), None);
+        let ramp = SpinUp::start(Some(Duration::from_secs(10)), 255, start);
+        assert_eq!(ramp.speed(200, start), Some(20));
+        assert_eq!(ramp.speed(200, start + Duration::from_secs(5)), Some(120));
+        assert_eq!(ramp.speed(200, start + Duration::from_secs(9)), None);
+        assert_eq!(ramp.speed(200, start + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn process_output_passes_on_answers() {
+        let mut output = ProcessOutput::spawn("while read line; do echo ok; done").unwrap();
+        assert!(output.send(100, ThermalState::Normal).is_ok());
+        assert!(output.send(120, ThermalState::Normal).is_ok());
+        assert!(output.is_connected());
+
+        let mut output = ProcessOutput::spawn("read line; echo jammed").unwrap();
+        let e = output.send(100, ThermalState::Normal).unwrap_err();
+        assert_eq!(e.to_string(), "output command reported: jammed");
+        assert!(output.send(100, ThermalState::Normal).is_err());
+    }
+
+    #[test]
+    fn a_silent_process_output_is_lost() {
+        let mut output = ProcessOutput::spawn("read line; exec sleep 60").unwrap();
+        output.answer_timeout = Duration::from_millis(200);
+        assert!(output.send(100, ThermalState::Normal).is_err());
+        assert!(!output.is_connected());
+    }
+
+    #[test]
+    fn a_stuck_extra_output_counts_as_failing() {
+        let output = ExtraOutput::start("read line; exec sleep 60");
+        output.set_speed(100, ThermalState::Normal);
+        assert_eq!(output.error(), None);
+        output.status.lock().unwrap().waiting_since = Some(Instant::now() - PROCESS_ANSWER_TIMEOUT * 2);
+        assert!(output.error().is_some_and(|e| e.starts_with("no answer")));
+    }
+}
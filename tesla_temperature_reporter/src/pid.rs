@@ -0,0 +1,224 @@
+//! Holding the GPU at a target temperature with a PID loop, as an alternative
+//! to looking the speed up in a fan curve, and working out gains for it.
+
+use std::error::Error;
+
+/// Gains, written as "p:i:d". The output is in duty counts, so `p` is counts
+/// per degree over the target.
+#[derive(Copy, Clone, Debug)]
+pub struct PidParams {
+    p: f64,
+    i: f64,
+    d: f64,
+}
+
+impl std::fmt::Display for PidParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.3}:{:.4}:{:.3}", self.p, self.i, self.d)
+    }
+}
+
+impl std::str::FromStr for PidParams {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let params = PidParams {
+            p: parts.next().ok_or("Too few parts; missing p")?.trim().parse()?,
+            i: parts.next().ok_or("Too few parts; missing i")?.trim().parse()?,
+            d: parts.next().ok_or("Too few parts; missing d")?.trim().parse()?,
+        };
+        if parts.next().is_some() {
+            Err("Too many parts; expected p:i:d")?
+        }
+        if [params.p, params.i, params.d].iter().any(|gain| *gain < 0.0 || !gain.is_finite()) {
+            Err("PID gains can't be negative")?
+        }
+        Ok(params)
+    }
+}
+
+pub struct Pid {
+    params: PidParams,
+    target_temp: f64,
+    integral: f64,
+    prev_temp: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(params: PidParams, target_temp: u32) -> Self {
+        Pid {
+            params,
+            target_temp: target_temp as f64,
+            integral: 0.0,
+            prev_temp: None,
+        }
+    }
+
+    /// The fan speed for `temp`, `dt` seconds after the last update.
+    pub fn update(&mut self, temp: u32, dt: f64) -> u8 {
+        let PidParams { p, i, d } = self.params;
+        let temp = temp as f64;
+        let error = temp - self.target_temp;
+        // Taken on the temperature rather than the error, so that the first
+        // update doesn't kick
+        let derivative = self.prev_temp.map_or(0.0, |prev| (temp - prev) / dt);
+        self.prev_temp = Some(temp);
+
+        // Anti-windup: stop integrating while the output is pinned at either
+        // end and the error would only pin it harder
+        let unclamped = p * error + i * (self.integral + error * dt) + d * derivative;
+        let saturated = (unclamped > 255.0 && error > 0.0) || (unclamped < 0.0 && error < 0.0);
+        if !saturated {
+            self.integral += error * dt;
+        }
+
+        let output = p * error + i * self.integral + d * derivative;
+        output.clamp(0.0, 255.0) as u8
+    }
+}
+
+/// A relay test (Åström–Hägglund): the fan is switched between two speeds
+/// whenever the temperature crosses the target, which makes it oscillate
+/// around it. How far and how fast it swings gives the ultimate gain and
+/// period, and from those Ziegler–Nichols gives the PID gains.
+pub struct RelayTune {
+    target_temp: f64,
+    low_speed: u8,
+    high_speed: u8,
+    high: bool,
+    /// Most extreme temperature since the last switch
+    extreme: f64,
+    /// Seconds at each switch to high speed
+    rises: Vec<f64>,
+    peaks: Vec<f64>,
+    troughs: Vec<f64>,
+}
+
+// Degrees either side of the target before switching, so sensor noise can't
+// chatter the relay
+const HYSTERESIS: f64 = 0.5;
+
+impl RelayTune {
+    pub fn new(target_temp: u32, low_speed: u8, high_speed: u8) -> Self {
+        RelayTune {
+            target_temp: target_temp as f64,
+            low_speed,
+            high_speed,
+            high: false,
+            extreme: target_temp as f64,
+            rises: vec![],
+            peaks: vec![],
+            troughs: vec![],
+        }
+    }
+
+    /// Feeds in the temperature `time` seconds into the test, and returns the
+    /// speed to run the fan at.
+    pub fn update(&mut self, temp: u32, time: f64) -> u8 {
+        let temp = temp as f64;
+        if self.high {
+            self.extreme = self.extreme.max(temp);
+            if temp < self.target_temp - HYSTERESIS {
+                self.peaks.push(self.extreme);
+                self.high = false;
+                self.extreme = temp;
+            }
+        } else {
+            self.extreme = self.extreme.min(temp);
+            if temp > self.target_temp + HYSTERESIS {
+                self.troughs.push(self.extreme);
+                self.rises.push(time);
+                self.high = true;
+                self.extreme = temp;
+            }
+        }
+        if self.high {
+            self.high_speed
+        } else {
+            self.low_speed
+        }
+    }
+
+    /// Full oscillations seen so far, not counting the first, which is still
+    /// settling.
+    pub fn cycles(&self) -> usize {
+        self.rises.len().saturating_sub(2)
+    }
+
+    /// Suggested gains from the last `cycles` oscillations, once there have
+    /// been that many.
+    pub fn result(&self, cycles: usize) -> Option<PidParams> {
+        if cycles == 0 || self.cycles() < cycles || self.peaks.len() < cycles {
+            return None
+        }
+        let last = |values: &[f64]| -> f64 {
+            values[values.len() - cycles..].iter().sum::<f64>() / cycles as f64
+        };
+        let rises = &self.rises[self.rises.len() - cycles - 1..];
+        let period = (rises[cycles] - rises[0]) / cycles as f64;
+        let amplitude = (last(&self.peaks) - last(&self.troughs)) / 2.0;
+        if amplitude <= 0.0 || period <= 0.0 {
+            return None
+        }
+
+        let relay = (self.high_speed as f64 - self.low_speed as f64) / 2.0;
+        let ultimate_gain = 4.0 * relay / (std::f64::consts::PI * amplitude);
+        Some(PidParams {
+            p: 0.6 * ultimate_gain,
+            i: 1.2 * ultimate_gain / period,
+            d: 0.075 * ultimate_gain * period,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gains_parse_as_p_i_d() {
+        let params: PidParams = "2:0.1:1".parse().unwrap();
+        assert_eq!((params.p, params.i, params.d), (2.0, 0.1, 1.0));
+        for bad in ["1:2", "1:2:3:4", "-1:0:0", "a:b:c"] {
+            assert!(bad.parse::<PidParams>().is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn pid_follows_the_error_without_kicking_or_winding_up() {
+        let mut pid = Pid::new(PidParams { p: 10.0, i: 0.0, d: 0.0 }, 70);
+        assert_eq!(pid.update(75, 5.0), 50);
+        assert_eq!(pid.update(65, 5.0), 0);
+        // The first reading has nothing to take a derivative from
+        let mut pid = Pid::new(PidParams { p: 0.0, i: 0.0, d: 10.0 }, 70);
+        assert_eq!(pid.update(80, 1.0), 0);
+        assert_eq!(pid.update(82, 1.0), 20);
+        // Pinned at full speed for a long while, then just under the target:
+        // without anti-windup the integral would keep it flat out
+        let mut pid = Pid::new(PidParams { p: 10.0, i: 1.0, d: 0.0 }, 70);
+        for _ in 0..10 {
+            assert_eq!(pid.update(100, 10.0), 255);
+        }
+        assert_eq!(pid.update(69, 10.0), 0);
+    }
+
+    #[test]
+    fn relay_tune_finds_gains_from_the_oscillation() {
+        let mut tune = RelayTune::new(70, 100, 200);
+        assert!(tune.result(2).is_none());
+        for second in 0..300 {
+            let angle = 2.0 * std::f64::consts::PI * second as f64 / 60.0;
+            let temp = (70.0 + 3.0 * angle.sin()).round() as u32;
+            let speed = tune.update(temp, second as f64);
+            assert_eq!(speed, if tune.high { 200 } else { 100 });
+        }
+        assert!(tune.cycles() >= 2);
+        let params = tune.result(2).unwrap();
+        // A 3 degree swing every 60 s against a relay of 50 either way
+        let ultimate_gain = 4.0 * 50.0 / (std::f64::consts::PI * 3.0);
+        assert!((params.p - 0.6 * ultimate_gain).abs() < 0.1, "{}", params);
+        assert!((params.i - 1.2 * ultimate_gain / 60.0).abs() < 0.01, "{}", params);
+        assert!((params.d - 0.075 * ultimate_gain * 60.0).abs() < 1.0, "{}", params);
+    }
+}
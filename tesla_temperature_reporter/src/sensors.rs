@@ -0,0 +1,160 @@
+//! Temperature inputs other than the GPU itself.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A temperature written to a file by some other tool (a thermal camera script,
+/// a BMC exporter, ...). The file holds either a bare number or a JSON object
+/// with a "temp" field.
+#[derive(Clone, Debug)]
+pub struct FileSensor {
+    pub path: PathBuf,
+    /// How old the file may get before we stop trusting it
+    pub max_age: Duration,
+}
+
+impl FileSensor {
+    pub fn read(&self) -> Result<f64, Box<dyn Error>> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| format!("Failed to stat {}: {}", self.path.display(), e))?;
+        check_fresh(self.path.display(), modified, self.max_age)?;
+
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+        parse_temp(&contents)
+            .ok_or_else(|| format!("No temperature found in {}", self.path.display()).into())
+    }
+}
+
+/// A temperature from the kernel's hwmon interface, which is where lm-sensors
+/// gets the motherboard's readings from too.
+#[derive(Clone, Debug)]
+pub struct HwmonSensor {
+    /// The sensor's `temp*_input` file, in millidegrees
+    pub path: PathBuf,
+}
+
+impl HwmonSensor {
+    const ROOT: &'static str = "/sys/class/hwmon";
+
+    /// Finds a sensor given either the path of its `temp*_input` file or the
+    /// chip and label that `sensors` shows, e.g. "nct6775:SYSTIN".
+    pub fn find(sensor: &str) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(sensor);
+        if path.is_file() {
+            return Ok(HwmonSensor { path: path.to_owned() })
+        }
+        let (chip, label) = sensor.split_once(':')
+            .ok_or_else(|| format!("{} is neither a file nor a chip:label pair", sensor))?;
+        let read = |path: &Path| std::fs::read_to_string(path).map(|s| s.trim().to_string()).unwrap_or_default();
+        let mut found = vec![];
+        for dir in std::fs::read_dir(Self::ROOT)
+            .map_err(|e| format!("Failed to list {}: {}", Self::ROOT, e))?
+        {
+            let dir = dir?.path();
+            let name = read(&dir.join("name"));
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?.file_name().to_string_lossy().into_owned();
+                let Some(input) = entry.strip_suffix("_label").filter(|input| input.starts_with("temp")) else {
+                    continue
+                };
+                let entry_label = read(&dir.join(&entry));
+                if name == chip && entry_label == label {
+                    return Ok(HwmonSensor { path: dir.join(format!("{}_input", input)) })
+                }
+                found.push(format!("{}:{}", name, entry_label));
+            }
+        }
+        found.sort();
+        Err(format!("No hwmon temperature sensor {}; found {}", sensor, found.join(", ")))?
+    }
+
+    pub fn read(&self) -> Result<f64, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+        let millidegrees: f64 = contents.trim().parse()
+            .map_err(|e| format!("Bad temperature in {}: {}", self.path.display(), e))?;
+        Ok(millidegrees / 1000.0)
+    }
+}
+
+/// Fails if a sample taken at `taken` is older than `max_age`, so that a source
+/// that has quietly stopped updating can't keep reporting a comfortable value.
+pub fn check_fresh(source: impl std::fmt::Display, taken: SystemTime, max_age: Duration) -> Result<(), Box<dyn Error>> {
+    let age = SystemTime::now().duration_since(taken).unwrap_or_default();
+    if age > max_age {
+        Err(format!("{} is stale: sampled {:.1}s ago", source, age.as_secs_f64()))?
+    }
+    Ok(())
+}
+
+/// Notices a source that keeps giving exactly the same answer, which is what
+/// a wedged driver handing back a cached reading can look like. An idle card
+/// can look like that too, so it's only worth a warning.
+pub struct Unchanging<T> {
+    last: Option<T>,
+    since: Instant,
+    warned: bool,
+}
+
+impl<T: PartialEq> Unchanging<T> {
+    pub fn new() -> Self {
+        Unchanging { last: None, since: Instant::now(), warned: false }
+    }
+
+    /// How long `value` has been the same, the first time that's longer than
+    /// `max_age`; once for each value that sticks.
+    pub fn went_unchanged(&mut self, value: T, max_age: Duration) -> Option<Duration> {
+        if self.last.as_ref() != Some(&value) {
+            self.last = Some(value);
+            self.since = Instant::now();
+            self.warned = false;
+        }
+        let age = self.since.elapsed();
+        if age <= max_age || self.warned {
+            return None
+        }
+        self.warned = true;
+        Some(age)
+    }
+}
+
+fn parse_temp(contents: &str) -> Option<f64> {
+    let contents = contents.trim();
+    if !contents.starts_with('{') {
+        return contents.parse().ok()
+    }
+
+    // Just enough JSON to pull out a top-level number
+    let (_, rest) = contents.split_once("\"temp\"")?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest.find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unchanging_value_is_noticed_once() {
+        let mut reading = Unchanging::new();
+        assert_eq!(reading.went_unchanged(60, Duration::from_secs(1)), None);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(reading.went_unchanged(60, Duration::ZERO).is_some());
+        assert_eq!(reading.went_unchanged(60, Duration::ZERO), None);
+        assert_eq!(reading.went_unchanged(61, Duration::from_secs(1)), None);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(reading.went_unchanged(61, Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn parses_bare_and_json_temperatures() {
+        assert_eq!(parse_temp(" 41.5\n"), Some(41.5));
+        assert_eq!(parse_temp(r#"{"sensor": "cam", "temp": 38}"#), Some(38.0));
+        assert_eq!(parse_temp("warm"), None);
+    }
+}
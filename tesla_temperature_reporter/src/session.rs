@@ -0,0 +1,112 @@
+//! Whether someone is sitting at the machine, so a workstation can keep its
+//! fans down while it's in use and let them go for unattended jobs.
+//!
+//! This asks logind through `loginctl` for a local graphical or console
+//! session that's active and not idle, which covers the usual desktops and
+//! the screen locking idle hint they set.
+
+use std::error::Error;
+use std::process::Command;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::telemetry::event;
+
+/// loginctl isn't free, and people don't come and go every few seconds
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Asks loginctl on a thread of its own, so a slow or hung logind can't hold
+/// up the control loop; `active` only ever looks at the latest answer.
+pub struct SessionWatch {
+    /// Answers from the checking thread, once it's been started
+    answers: Option<Receiver<Result<bool, String>>>,
+    active: bool,
+    failing: bool,
+}
+
+impl SessionWatch {
+    pub fn new() -> Self {
+        SessionWatch {
+            answers: None,
+            active: false,
+            failing: false,
+        }
+    }
+
+    /// Whether there's someone at the machine, as of the last check. Failing
+    /// to tell counts as nobody, as does not having heard yet.
+    pub fn active(&mut self) -> bool {
+        let answers = self.answers.get_or_insert_with(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            thread::spawn(move || {
+                while sender.send(active_session().map_err(|e| e.to_string())).is_ok() {
+                    thread::sleep(CHECK_INTERVAL);
+                }
+            });
+            receiver
+        });
+        // Only the latest answer matters
+        let mut latest = None;
+        loop {
+            match answers.try_recv() {
+                Ok(answer) => latest = Some(answer),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    latest = Some(Err("the checking thread stopped".to_string()));
+                    break
+                },
+            }
+        }
+        let active = match latest {
+            None => return self.active,
+            Some(Ok(active)) => {
+                self.failing = false;
+                active
+            },
+            Some(Err(e)) => {
+                if !self.failing {
+                    event!("Failed to check for an active session: {}", e);
+                }
+                self.failing = true;
+                false
+            },
+        };
+        if active != self.active {
+            event!("{}", if active { "Someone's at the machine" } else { "Nobody's at the machine" });
+        }
+        self.active = active;
+        self.active
+    }
+}
+
+fn loginctl(args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("loginctl")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run loginctl: {}", e))?;
+    if !output.status.success() {
+        Err(format!("loginctl failed: {}", String::from_utf8_lossy(&output.stderr).trim()))?
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn active_session() -> Result<bool, Box<dyn Error>> {
+    if !cfg!(target_os = "linux") {
+        Err("session detection needs logind, so only works on Linux")?
+    }
+    let sessions = loginctl(&["list-sessions", "--no-legend"])?;
+    for id in sessions.lines().filter_map(|line| line.split_whitespace().next()) {
+        let properties = loginctl(&[
+            "show-session", id, "-p", "Type", "-p", "Remote", "-p", "Active", "-p", "IdleHint",
+        ])?;
+        let property = |name: &str| properties.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or("");
+        let local = matches!(property("Type"), "x11" | "wayland" | "mir" | "tty") && property("Remote") == "no";
+        if local && property("Active") == "yes" && property("IdleHint") == "no" {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
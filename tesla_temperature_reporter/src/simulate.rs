@@ -0,0 +1,188 @@
+//! Replaying recorded temperature and power through the control logic, to see
+//! what a curve or config change would have done without touching the fans.
+//!
+//! The curve, the boost and the critical temperature go through the same
+//! `decision` as the control loop, over a history of as many samples as the
+//! loop keeps at `--update-interval`; the deadband and failed reads follow the
+//! loop's handling of them. Things that depend on the live machine, like
+//! quiet hours, the channels and the ambient sensor, are left out.
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::arbitration::SpeedSource;
+use crate::decision::{self, History};
+use crate::telemetry::{LoggedSample, Mode, emit};
+use crate::{Duty, StartupHistory, ThermalState, Tunables, quantize_speed, within_deadband};
+
+/// What the loop settings that aren't in `Tunables` come to.
+pub struct Limits {
+    pub max_speed: Option<u8>,
+    pub speed_step: Option<std::num::NonZeroU8>,
+    pub hold_on_error: u32,
+    pub failsafe_speed: u8,
+    /// How many samples the history holds
+    pub samples: usize,
+    pub startup_history: StartupHistory,
+}
+
+/// What the control loop would have done with one sample.
+pub struct Decision {
+    pub speed: u8,
+    pub source: SpeedSource,
+    pub mode: Mode,
+}
+
+/// The control loop's decision for each of `samples`, which are oldest first.
+pub fn replay(samples: &[LoggedSample], tunables: &Tunables, limits: &Limits) -> Vec<Decision> {
+    let mut prev_speed: Option<u8> = None;
+    let mut last_good: Option<u8> = None;
+    let mut failed_reads = 0;
+    // Starts out from the first good reading, as the loop's does
+    let mut history: Option<History> = None;
+    let mut decisions = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let (speed, source, mode) = match (sample.temp, sample.power) {
+            (Some(temp), Some(power)) => {
+                failed_reads = 0;
+                let history = history.get_or_insert_with(|| {
+                    History::new(limits.samples, limits.startup_history, temp, power)
+                });
+                let window = history.push(temp, power, tunables.ema_alpha);
+                let power_speed = tunables.follow_power.then(|| tunables.curves.fan_curve.lookup_speed(window.average_power));
+                let speed = decision::curves_speed(tunables, temp, power_speed);
+                let decision = decision::decide(tunables, &window, speed, 0.0);
+                if decision.thermal_state == ThermalState::Critical {
+                    (255, SpeedSource::Safety, Mode::Normal)
+                } else {
+                    let speed = limits.max_speed.map_or(decision.speed, |cap| decision.speed.min(cap));
+                    last_good = Some(speed);
+                    (speed, SpeedSource::Curve, Mode::Normal)
+                }
+            },
+            _ => {
+                failed_reads += 1;
+                match last_good {
+                    Some(speed) if failed_reads <= limits.hold_on_error => (speed, SpeedSource::Curve, Mode::HoldLast),
+                    _ => (limits.failsafe_speed, SpeedSource::EmergencyMax, Mode::FailsafeMax),
+                }
+            },
+        };
+        // Small moves on the curve never reach the controller
+        let speed = match prev_speed {
+            Some(prev) if source == SpeedSource::Curve && within_deadband(prev, speed, tunables.deadband) => prev,
+            _ => speed,
+        };
+        let speed = limits.speed_step.map_or(speed, |step| quantize_speed(speed, step));
+        prev_speed = Some(speed);
+        decisions.push(Decision { speed, source, mode });
+    }
+    decisions
+}
+
+/// Prints each change of speed the control loop would have made over the
+/// samples in `path`, then a summary against what was recorded.
+pub fn run(path: &Path, tunables: &Tunables, limits: &Limits) -> Result<(), Box<dyn Error>> {
+    let samples = LoggedSample::load(path)?;
+    if samples.is_empty() {
+        Err(format!("No samples in {}", path.display()))?
+    }
+
+    let mut prev_speed: Option<u8> = None;
+    let (mut changes, mut at_max, mut differed) = (0, 0, 0);
+    for (sample, &Decision { speed, source, mode }) in samples.iter().zip(&replay(&samples, tunables, limits)) {
+        if prev_speed != Some(speed) {
+            changes += 1;
+            let or_unknown = |v: Option<String>| v.unwrap_or_else(|| "?".to_string());
+            emit(
+                format!(
+                    "{} temp={} power={} -> {} ({}, {})",
+                    sample.time.format("%Y-%m-%d %H:%M:%S"),
+                    or_unknown(sample.temp.map(|temp| format!("{}c", temp))),
+                    or_unknown(sample.power.map(|power| format!("{:.1}%", power * 100.0))),
+                    Duty(speed),
+                    source.name(),
+                    mode.name(),
+                ),
+                || format!(
+                    r#"{{"time":"{}","speed":{},"source":"{}","mode":"{}","recorded_speed":{}}}"#,
+                    sample.time.format("%Y-%m-%d %H:%M:%S"),
+                    speed,
+                    source.name(),
+                    mode.name(),
+                    sample.speed.map_or("null".to_string(), |speed| speed.to_string()),
+                ),
+            );
+        }
+        prev_speed = Some(speed);
+        at_max += usize::from(speed == 255);
+        differed += usize::from(sample.speed.is_some_and(|recorded| recorded != speed));
+    }
+
+    let n = samples.len();
+    emit(
+        format!(
+            "{} samples, {} speed changes, {:.0}% at full speed, {:.0}% different from what was recorded",
+            n,
+            changes,
+            at_max as f64 / n as f64 * 100.0,
+            differed as f64 / n as f64 * 100.0,
+        ),
+        || format!(
+            r#"{{"samples":{},"changes":{},"at_max":{:.3},"differed":{:.3}}}"#,
+            n,
+            changes,
+            at_max as f64 / n as f64,
+            differed as f64 / n as f64,
+        ),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, TempDefaults};
+    use structopt::StructOpt;
+
+    fn sample(temp: Option<u32>, power: Option<f64>) -> LoggedSample {
+        LoggedSample { time: chrono::NaiveDateTime::default(), temp, power, speed: None }
+    }
+
+    #[test]
+    fn replay_boosts_holds_and_goes_flat_out_like_the_loop() {
+        let args = Args::from_iter([
+            "simulate", "--fan-curve", "0:0,1:200", "--critical-temp", "85", "--boost-temp", "75",
+            "--boost-amount", "30", "--deadband", "off",
+        ]);
+        let tunables = Tunables::from_args(&args, TempDefaults::default()).unwrap();
+        let limits = Limits {
+            max_speed: None,
+            speed_step: None,
+            hold_on_error: 1,
+            failsafe_speed: 240,
+            samples: 3,
+            startup_history: StartupHistory::WarmUp,
+        };
+        let samples = [
+            sample(Some(60), Some(0.5)),
+            sample(Some(76), Some(0.5)),
+            sample(None, None),
+            sample(None, None),
+            sample(Some(85), Some(0.5)),
+            // The 85 is still in the minute of history
+            sample(Some(60), Some(0.5)),
+        ];
+        let decisions: Vec<_> = replay(&samples, &tunables, &limits).iter()
+            .map(|decision| (decision.speed, decision.mode))
+            .collect();
+        assert_eq!(decisions, [
+            (100, Mode::Normal),
+            (130, Mode::Normal),
+            (130, Mode::HoldLast),
+            (240, Mode::FailsafeMax),
+            (255, Mode::Normal),
+            (255, Mode::Normal),
+        ]);
+    }
+}
@@ -0,0 +1,155 @@
+//! Hours of alternating load to check the fans keep up, e.g. after building a
+//! new shroud or swapping fans.
+//!
+//! The soak doesn't drive the fans itself: `run` does that as usual, with
+//! `--telemetry-log`. Meanwhile the soak starts the load command for each
+//! plateau and watches the temperature, and at the end reads back what the
+//! control loop logged over the soak and checks that
+//!
+//! - the GPU never reached the critical temperature, and
+//! - the fans never ran slower than the curve, boost and failsafe asked for,
+//!   give or take the deadband, going by a replay as in `simulate`.
+//!
+//! Settings the replay leaves out, like quiet hours and the ambient sensor,
+//! can rightly run the fans slower, so soak with them off or outside them.
+
+use std::error::Error;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nvml_wrapper::Device;
+use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+
+use crate::simulate::{self, Limits};
+use crate::telemetry::{LoggedSample, emit, event};
+use crate::{Duty, LoadCommand, Tunables, within_deadband};
+
+/// The plateaus to go through.
+pub struct Plan {
+    /// Run for each plateau with `{load}` replaced by its level
+    pub load_command: String,
+    /// Percentages, taken in turn and round again; nothing runs at 0
+    pub levels: Vec<u8>,
+    pub plateau: Duration,
+    pub total: Duration,
+    /// How often to read the temperature
+    pub interval: Duration,
+}
+
+/// Runs the plan, then prints a report on whether it passed. The GPU reaching
+/// the critical temperature ends the soak early.
+pub fn run(
+    device: &Device,
+    plan: &Plan,
+    telemetry_log: &Path,
+    tunables: &Tunables,
+    limits: &Limits,
+) -> Result<bool, Box<dyn Error>> {
+    let started = chrono::Local::now().naive_local();
+    let soak_started = Instant::now();
+    let mut peak: Option<u32> = None;
+    let mut reached_critical = false;
+    let mut plateaus = 0;
+    'soak: for &level in plan.levels.iter().cycle() {
+        let Some(left) = plan.total.checked_sub(soak_started.elapsed()).filter(|left| !left.is_zero()) else {
+            break
+        };
+        let length = plan.plateau.min(left);
+        plateaus += 1;
+        emit(
+            format!("Plateau {}: {}% load for {:.0} minutes", plateaus, level, length.as_secs_f64() / 60.0),
+            || format!(r#"{{"plateau":{},"load":{},"seconds":{:.0}}}"#, plateaus, level, length.as_secs_f64()),
+        );
+        let mut load = match level {
+            0 => None,
+            _ => Some(LoadCommand::start(&plan.load_command.replace("{load}", &level.to_string()))?),
+        };
+        let plateau_started = Instant::now();
+        while let Some(left) = length.checked_sub(plateau_started.elapsed()).filter(|left| !left.is_zero()) {
+            thread::sleep(plan.interval.min(left));
+            match device.temperature(TemperatureSensor::Gpu) {
+                Ok(temp) => {
+                    peak = peak.max(Some(temp));
+                    if temp >= tunables.critical_temp {
+                        event!("Reached a critical {}C at {}% load, ending the soak", temp, level);
+                        reached_critical = true;
+                        break 'soak
+                    }
+                },
+                Err(e) => event!("Failed to read GPU: {}", e),
+            }
+            if let Some(status) = load.as_mut().and_then(|load| load.0.try_wait().ok().flatten()) {
+                event!("Load command exited early ({}), idling for the rest of the plateau", status);
+                load = None;
+            }
+        }
+    }
+
+    // The whole log, so the replay has the minute of history before the soak
+    let samples = LoggedSample::load(telemetry_log)?;
+    let first = samples.iter().position(|sample| sample.time >= started).unwrap_or(samples.len());
+    if first == samples.len() {
+        Err(format!(
+            "Nothing logged to {} during the soak; is `run --telemetry-log` running with it?",
+            telemetry_log.display()
+        ))?
+    }
+    let decisions = simulate::replay(&samples, tunables, limits);
+    let mut logged_critical = 0;
+    let mut under_policy = 0;
+    for (sample, decision) in samples[first..].iter().zip(&decisions[first..]) {
+        peak = peak.max(sample.temp);
+        logged_critical += usize::from(sample.temp.is_some_and(|temp| temp >= tunables.critical_temp));
+        let Some(speed) = sample.speed else {
+            continue
+        };
+        if speed < decision.speed && !within_deadband(decision.speed, speed, tunables.deadband) {
+            under_policy += 1;
+            emit(
+                format!(
+                    "{} fans at {} where the policy asked for {} ({})",
+                    sample.time.format("%Y-%m-%d %H:%M:%S"),
+                    Duty(speed),
+                    Duty(decision.speed),
+                    decision.source.name(),
+                ),
+                || format!(
+                    r#"{{"time":"{}","speed":{},"policy_speed":{},"source":"{}"}}"#,
+                    sample.time.format("%Y-%m-%d %H:%M:%S"),
+                    speed,
+                    decision.speed,
+                    decision.source.name(),
+                ),
+            );
+        }
+    }
+
+    let passed = !reached_critical && logged_critical == 0 && under_policy == 0;
+    let checked = samples.len() - first;
+    emit(
+        format!(
+            "{}: {:.1} hours over {} plateaus, peak {} (critical {}C), {} of {} logged samples at the critical temperature, {} with the fans under policy",
+            if passed { "PASS" } else { "FAIL" },
+            soak_started.elapsed().as_secs_f64() / 3600.0,
+            plateaus,
+            peak.map_or("?".to_string(), |peak| format!("{}C", peak)),
+            tunables.critical_temp,
+            logged_critical,
+            checked,
+            under_policy,
+        ),
+        || format!(
+            r#"{{"passed":{},"hours":{:.2},"plateaus":{},"peak":{},"critical_temp":{},"samples":{},"at_critical":{},"under_policy":{}}}"#,
+            passed,
+            soak_started.elapsed().as_secs_f64() / 3600.0,
+            plateaus,
+            peak.map_or("null".to_string(), |peak| peak.to_string()),
+            tunables.critical_temp,
+            checked,
+            logged_critical,
+            under_policy,
+        ),
+    );
+    Ok(passed)
+}
@@ -0,0 +1,229 @@
+//! State that outlives a single run of the daemon.
+//!
+//! The state file is plain `key=value` lines, starting with a `version` line
+//! and ending with a `checksum` line covering everything before it. It's
+//! written to a temporary file and renamed into place, so a crash mid-write
+//! leaves the previous file intact. Anything we can't make sense of is set
+//! aside and we start over from zero rather than refusing to run.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::telemetry::event;
+
+const STATE_VERSION: u32 = 1;
+/// How far back the history goes, as for the control loop's own
+pub const HISTORY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Running totals, kept across restarts so long-term numbers stay meaningful.
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    pub speed_changes: u64,
+    pub controller_errors: u64,
+    pub seconds_above_boost: f64,
+    pub energy_joules: f64,
+    /// The speed we last sent the controller, for `once` to pick up from
+    pub last_speed: Option<u8>,
+    /// The readings from the last `HISTORY_WINDOW`, oldest first, so `once`
+    /// can still look back over it however often it's run
+    pub history: Vec<HistorySample>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HistorySample {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub temp: u8,
+    /// Fraction of the power limit
+    pub power: f64,
+}
+
+impl HistorySample {
+    pub fn now(temp: u8, power: f64) -> Self {
+        HistorySample {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            temp,
+            power,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(self.time))
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for HistorySample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{:.4}", self.time, self.temp, self.power)
+    }
+}
+
+impl std::str::FromStr for HistorySample {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+        let mut next = || fields.next().ok_or("history sample needs time:temp:power");
+        let sample = HistorySample {
+            time: next()?.parse()?,
+            temp: next()?.parse()?,
+            power: next()?.parse()?,
+        };
+        Ok(sample)
+    }
+}
+
+/// 64-bit FNV-1a; we only need to notice truncation and stray edits.
+fn checksum(data: &str) -> u64 {
+    data.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+impl Counters {
+    /// Loads the counters from `path`, starting from zero if it doesn't exist
+    /// or can't be trusted.
+    pub fn load(path: &Path) -> Self {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Counters::default(),
+            Err(e) => {
+                event!("Failed to read state file {}, starting from zero: {}", path.display(), e);
+                return Counters::default()
+            },
+        };
+
+        match Counters::parse(&contents) {
+            Ok(counters) => counters,
+            Err(e) => {
+                let backup = with_suffix(path, ".corrupt");
+                event!(
+                    "State file {} is unusable ({}); moving it to {} and starting from zero",
+                    path.display(),
+                    e,
+                    backup.display()
+                );
+                let _ = std::fs::rename(path, &backup);
+                Counters::default()
+            },
+        }
+    }
+
+    fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let body_end = contents.rfind("checksum=").ok_or("missing checksum, file is truncated")?;
+        let (body, checksum_line) = contents.split_at(body_end);
+        let expected = u64::from_str_radix(checksum_line["checksum=".len()..].trim(), 16)?;
+        if checksum(body) != expected {
+            Err("checksum mismatch")?
+        }
+
+        let mut counters = Counters::default();
+        let mut version = None;
+        for line in body.lines() {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value.parse::<u32>()?),
+                "speed_changes" => counters.speed_changes = value.parse()?,
+                "controller_errors" => counters.controller_errors = value.parse()?,
+                "seconds_above_boost" => counters.seconds_above_boost = value.parse()?,
+                "energy_joules" => counters.energy_joules = value.parse()?,
+                "last_speed" => counters.last_speed = Some(value.parse()?),
+                "history" => {
+                    counters.history = value.split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()?
+                },
+                // Keys from a newer or older version; not ours to worry about
+                _ => (),
+            }
+        }
+        match version {
+            Some(v) if v > STATE_VERSION => Err(format!("written by a newer version ({})", v))?,
+            Some(_) => Ok(counters),
+            None => Err("missing version")?,
+        }
+    }
+
+    /// Adds a reading to the history, dropping any older than
+    /// `HISTORY_WINDOW`.
+    pub fn remember(&mut self, sample: HistorySample) {
+        self.history.push(sample);
+        self.history.retain(|sample| sample.age() <= HISTORY_WINDOW);
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let body = format!("version={}\n{}", STATE_VERSION, self);
+        let tmp_path = with_suffix(path, ".tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        writeln!(file, "{}checksum={:016x}", body, checksum(&body))?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+impl std::fmt::Display for Counters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "speed_changes={}", self.speed_changes)?;
+        writeln!(f, "controller_errors={}", self.controller_errors)?;
+        writeln!(f, "seconds_above_boost={:.1}", self.seconds_above_boost)?;
+        writeln!(f, "energy_joules={:.1}", self.energy_joules)?;
+        if let Some(speed) = self.last_speed {
+            writeln!(f, "last_speed={}", speed)?;
+        }
+        if !self.history.is_empty() {
+            let history: Vec<_> = self.history.iter().map(|s| s.to_string()).collect();
+            writeln!(f, "history={}", history.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_is_trimmed_by_age() {
+        let now = HistorySample::now(60, 0.5);
+        let at = |secs_ago: u64, temp| HistorySample { time: now.time - secs_ago, temp, power: 0.5 };
+        let mut counters = Counters::default();
+        // However many samples there are, only the age counts
+        for secs_ago in (1..=120).rev() {
+            counters.remember(at(secs_ago, 50));
+        }
+        counters.remember(now);
+        assert!(counters.history.iter().all(|sample| sample.age() <= HISTORY_WINDOW));
+        assert_eq!(counters.history.last().map(|sample| sample.temp), Some(60));
+        assert!(counters.history.len() <= 62, "kept {} samples", counters.history.len());
+        // Sparse samples, as from `once` run by a timer, are all kept
+        let mut counters = Counters::default();
+        for secs_ago in [50, 30, 10] {
+            counters.remember(at(secs_ago, 55));
+        }
+        assert_eq!(counters.history.len(), 3);
+    }
+
+    #[test]
+    fn saved_counters_read_back() {
+        let mut counters = Counters { speed_changes: 3, last_speed: Some(120), ..Counters::default() };
+        counters.remember(HistorySample::now(64, 0.25));
+        let body = format!("version={}\n{}", STATE_VERSION, counters);
+        let parsed = Counters::parse(&format!("{}checksum={:016x}\n", body, checksum(&body))).unwrap();
+        assert_eq!(parsed.speed_changes, 3);
+        assert_eq!(parsed.last_speed, Some(120));
+        assert_eq!(parsed.history.len(), 1);
+        assert_eq!(parsed.history[0].temp, 64);
+        assert!(Counters::parse(&format!("{}checksum=0\n", body)).is_err());
+    }
+}
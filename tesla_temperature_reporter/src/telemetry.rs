@@ -0,0 +1,885 @@
+//! In-memory record of what the control loop has been up to, so it can be
+//! dumped into a debug bundle when something odd happens.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike};
+use serde::Deserialize;
+
+use crate::controller::CHANNELS;
+use crate::state::Counters;
+
+const MAX_EVENTS: usize = 200;
+/// Events waiting for the webhook worker; more than this and the server is
+/// too slow or down, and new events are dropped rather than piling up.
+const MAX_PENDING_WEBHOOKS: usize = 32;
+/// Goes up whenever an event loses a field or a field changes meaning; new
+/// fields and new event types don't count.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+static EVENTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+/// Typed events as JSON, waiting to go out on the control socket
+static PENDING_EVENTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static EVENT_ROUTES: Mutex<EventRoutes> = Mutex::new(EventRoutes {
+    severities: BTreeMap::new(),
+    sinks: BTreeMap::new(),
+    webhook: None,
+});
+/// (url, json) for the one thread that posts webhooks
+static WEBHOOK_QUEUE: OnceLock<SyncSender<(String, String)>> = OnceLock::new();
+static OUTPUT_FORMAT: AtomicU8 = AtomicU8::new(OutputFormat::Plain as u8);
+
+/// How we print things, for people or for scripts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Plain,
+    /// One JSON object per line
+    Json,
+    /// Nothing but errors
+    Quiet,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            "quiet" => Ok(OutputFormat::Quiet),
+            _ => Err(format!("Unknown output format {}; expected json, plain or quiet", s)),
+        }
+    }
+}
+
+pub fn set_output_format(format: OutputFormat) {
+    OUTPUT_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn output_format() -> OutputFormat {
+    match OUTPUT_FORMAT.load(Ordering::Relaxed) {
+        x if x == OutputFormat::Json as u8 => OutputFormat::Json,
+        x if x == OutputFormat::Quiet as u8 => OutputFormat::Quiet,
+        _ => OutputFormat::Plain,
+    }
+}
+
+/// Prints `plain` or `json` depending on the output format. `json` is only
+/// built when it's needed.
+pub fn emit(plain: impl std::fmt::Display, json: impl FnOnce() -> String) {
+    match output_format() {
+        OutputFormat::Plain => println!("{}", plain),
+        OutputFormat::Json => println!("{}", json()),
+        OutputFormat::Quiet => (),
+    }
+}
+
+/// `s` as a quoted JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Prints a notable event and remembers it for debug bundles. With a typed
+/// [`Event`] in front, e.g. `event!(Event::CriticalTemp { temp } => "...")`,
+/// integrations get its fields as well as the message.
+macro_rules! event {
+    ($event:expr => $($arg:tt)*) => {
+        $crate::telemetry::record_typed_event($event, format!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::telemetry::record_event(format!($($arg)*))
+    };
+}
+pub(crate) use event;
+
+/// The events integrations can rely on. In JSON, each is an object with
+/// `schema` (see [`EVENT_SCHEMA_VERSION`]), `type`, `time`, the
+/// human-readable `event` message and the fields below.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// `speed` in duty counts, `source` as in the telemetry, `channel` null
+    /// when every output was set at once
+    SpeedChanged {
+        speed: u8,
+        source: &'static str,
+        channel: Option<u8>,
+    },
+    /// The fan controller stopped answering; we'll keep trying to reconnect
+    ControllerLost {
+        error: String,
+    },
+    /// The GPU reached the critical temperature, in degrees C
+    CriticalTemp {
+        temp: u32,
+    },
+    /// The temperature kept rising for `minutes` with the fans flat out, by
+    /// `rise` degrees C to `temp`: a dead fan or a shroud that's come off
+    ThermalRunaway {
+        temp: u32,
+        rise: f64,
+        minutes: f64,
+    },
+    /// A fan on `channel` turning at `rpm` for several cycles, far slower than
+    /// `duty` should have it
+    FanStall {
+        channel: u8,
+        rpm: u16,
+        duty: u8,
+    },
+    /// e.g. "quiet" and "normal" for quiet hours
+    ProfileSwitched {
+        profile: &'static str,
+    },
+    /// A fixed speed taking over from the curve, for `minutes` if it expires
+    OverrideSet {
+        speed: u8,
+        minutes: Option<f64>,
+    },
+}
+
+impl Event {
+    pub const NAMES: &'static [&'static str] = &[
+        "speed_changed",
+        "controller_lost",
+        "critical_temp",
+        "thermal_runaway",
+        "fan_stall",
+        "profile_switched",
+        "override_set",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::SpeedChanged { .. } => "speed_changed",
+            Event::ControllerLost { .. } => "controller_lost",
+            Event::CriticalTemp { .. } => "critical_temp",
+            Event::ThermalRunaway { .. } => "thermal_runaway",
+            Event::FanStall { .. } => "fan_stall",
+            Event::ProfileSwitched { .. } => "profile_switched",
+            Event::OverrideSet { .. } => "override_set",
+        }
+    }
+
+    pub fn default_severity(&self) -> Severity {
+        match self {
+            Event::SpeedChanged { .. } => Severity::Debug,
+            Event::ProfileSwitched { .. } | Event::OverrideSet { .. } => Severity::Info,
+            Event::ControllerLost { .. } => Severity::Warning,
+            Event::CriticalTemp { .. } | Event::ThermalRunaway { .. } | Event::FanStall { .. } => Severity::Critical,
+        }
+    }
+
+    pub fn to_json(&self, msg: &str, severity: Severity) -> String {
+        let or_null = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+        let fields = match self {
+            Event::SpeedChanged { speed, source, channel } => format!(
+                r#""speed":{},"source":{},"channel":{}"#,
+                speed,
+                json_string(source),
+                or_null(channel.map(|c| c.to_string())),
+            ),
+            Event::ControllerLost { error } => format!(r#""error":{}"#, json_string(error)),
+            Event::CriticalTemp { temp } => format!(r#""temp":{}"#, temp),
+            Event::ThermalRunaway { temp, rise, minutes } => format!(
+                r#""temp":{},"rise":{:.1},"minutes":{}"#,
+                temp, rise, minutes,
+            ),
+            Event::FanStall { channel, rpm, duty } => format!(
+                r#""channel":{},"rpm":{},"duty":{}"#,
+                channel, rpm, duty,
+            ),
+            Event::ProfileSwitched { profile } => format!(r#""profile":{}"#, json_string(profile)),
+            Event::OverrideSet { speed, minutes } => format!(
+                r#""speed":{},"minutes":{}"#,
+                speed,
+                or_null(minutes.map(|m| m.to_string())),
+            ),
+        };
+        format!(
+            r#"{{"schema":{},"type":"{}","severity":"{}","time":"{}","event":{},{}}}"#,
+            EVENT_SCHEMA_VERSION,
+            self.name(),
+            severity.name(),
+            Local::now().format("%Y-%m-%dT%H:%M:%S%:z"),
+            json_string(msg),
+            fields,
+        )
+    }
+}
+
+pub fn record_event(msg: String) {
+    emit(&msg, || format!(r#"{{"event":{}}}"#, json_string(&msg)));
+    remember_event(&msg);
+}
+
+/// How bad an event is, which decides where it goes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Debug,
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Debug => "debug",
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// Somewhere events can go. Debug bundles always get every event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    /// Printed along with everything else
+    Log,
+    /// Streamed to the control socket
+    Ctl,
+    /// POSTed as JSON to the configured URL
+    Webhook,
+}
+
+/// Which typed events go where, from `[events]` in the config.
+#[derive(Clone, Debug, Default)]
+pub struct EventRoutes {
+    /// Overrides of events' default severities, by event name
+    pub severities: BTreeMap<String, Severity>,
+    /// Severities not listed go to the log and the control socket
+    pub sinks: BTreeMap<Severity, Vec<Sink>>,
+    pub webhook: Option<String>,
+}
+
+impl EventRoutes {
+    fn severity(&self, event: &Event) -> Severity {
+        self.severities.get(event.name())
+            .copied()
+            .unwrap_or_else(|| event.default_severity())
+    }
+
+    fn sinks(&self, severity: Severity) -> &[Sink] {
+        self.sinks.get(&severity)
+            .map_or(&[Sink::Log, Sink::Ctl], Vec::as_slice)
+    }
+}
+
+pub fn set_event_routes(routes: EventRoutes) {
+    *EVENT_ROUTES.lock().unwrap_or_else(|e| e.into_inner()) = routes;
+}
+
+pub fn record_typed_event(event: Event, msg: String) {
+    let routes = EVENT_ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+    let severity = routes.severity(&event);
+    let json = event.to_json(&msg, severity);
+    for sink in routes.sinks(severity) {
+        match sink {
+            Sink::Log => emit(&msg, || json.clone()),
+            Sink::Ctl => {
+                let mut pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+                if pending.len() == MAX_EVENTS {
+                    pending.pop_front();
+                }
+                pending.push_back(json.clone());
+            },
+            Sink::Webhook => {
+                if let Some(url) = routes.webhook.clone() {
+                    post_webhook(url, json.clone());
+                }
+            },
+        }
+    }
+    drop(routes);
+    remember_event(&msg);
+}
+
+/// Queues `json` for the webhook worker, so a slow server can't hold up the
+/// control loop, and a burst of events can't start a curl apiece all at once.
+fn post_webhook(url: String, json: String) {
+    let queue = WEBHOOK_QUEUE.get_or_init(|| {
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<(String, String)>(MAX_PENDING_WEBHOOKS);
+        std::thread::spawn(move || {
+            for (url, json) in receiver {
+                send_webhook(&url, &json);
+            }
+        });
+        sender
+    });
+    match queue.try_send((url, json)) {
+        Ok(()) => (),
+        Err(TrySendError::Full(_)) => record_event(format!(
+            "Dropped an event for the webhook: {} are already waiting to go",
+            MAX_PENDING_WEBHOOKS,
+        )),
+        Err(TrySendError::Disconnected(_)) => record_event("Dropped an event for the webhook: its worker has stopped".to_string()),
+    }
+}
+
+/// POSTs `json` to `url`, through curl for the same reasons as `update`.
+fn send_webhook(url: &str, json: &str) {
+    let result = std::process::Command::new("curl")
+        .args(["-fsS", "-m", "10", "-X", "POST", "-H", "Content-Type: application/json", "-d"])
+        .arg(json)
+        .arg(url)
+        .output();
+    match result {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => record_event(format!(
+            "Failed to send event to webhook: {}",
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )),
+        Err(e) => record_event(format!("Failed to send event to webhook: failed to run curl: {}", e)),
+    }
+}
+
+fn remember_event(msg: &str) {
+    let mut events = EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(format!("{} {}", Local::now().format("%Y-%m-%d %H:%M:%S"), msg));
+}
+
+/// Typed events recorded since the last call, as JSON.
+pub fn take_pending_events() -> Vec<String> {
+    PENDING_EVENTS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .drain(..)
+        .collect()
+}
+
+/// How degraded the control loop is, so monitoring can tell fans at full
+/// speed because the GPU is hot from fans at full speed because we've lost
+/// track of it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Reading the GPU and driving the fans as usual, however hot it is
+    Normal,
+    /// Holding the last good speed through a failed read (--hold-on-error)
+    HoldLast,
+    /// Can't read the GPU, because NVML is down or a sensor has gone stale, so
+    /// the fans are at --failsafe-speed
+    FailsafeMax,
+    /// Can't reach the fan controller, so the fans are wherever it left them
+    Offline,
+    /// Driving the fans as usual, but one of the --extra-output-command
+    /// outputs is failing
+    Degraded,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::HoldLast => "HOLD_LAST",
+            Mode::FailsafeMax => "FAILSAFE_MAX",
+            Mode::Offline => "OFFLINE",
+            Mode::Degraded => "DEGRADED",
+        }
+    }
+}
+
+pub struct Sample {
+    pub time: DateTime<Local>,
+    pub temp: Option<u32>,
+    pub power: Option<f64>,
+    /// How much hotter the downstream GPU is than the upstream one
+    pub temp_delta: Option<i32>,
+    /// In MHz
+    pub sm_clock: Option<u32>,
+    pub mem_clock: Option<u32>,
+    pub speed: u8,
+    /// What each of the controller's fans last reported
+    pub rpm: [Option<u16>; CHANNELS],
+    pub source: &'static str,
+    pub extra_outputs: OutputHealth,
+    /// From reading the sensors to the last write to the fan controller
+    pub latency: Duration,
+    pub mode: Mode,
+}
+
+impl Sample {
+    /// A cycle that never got as far as reading the GPU, because there was
+    /// no fan controller to drive.
+    pub fn offline(speed: Option<u8>) -> Self {
+        Sample {
+            time: Local::now(),
+            temp: None,
+            power: None,
+            temp_delta: None,
+            sm_clock: None,
+            mem_clock: None,
+            speed: speed.unwrap_or(0),
+            rpm: [None; CHANNELS],
+            source: "none",
+            extra_outputs: OutputHealth::default(),
+            latency: Duration::ZERO,
+            mode: Mode::Offline,
+        }
+    }
+
+    pub fn csv(&self) -> Csv<'_> {
+        Csv(self)
+    }
+}
+
+impl std::fmt::Display for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.time.format("%Y-%m-%d %H:%M:%S"))?;
+        match self.temp {
+            Some(temp) => write!(f, " temp={}c", temp)?,
+            None => write!(f, " temp=?")?,
+        }
+        match self.power {
+            Some(power) => write!(f, " power={:.1}%", power * 100.0)?,
+            None => write!(f, " power=?")?,
+        }
+        if let Some(delta) = self.temp_delta {
+            write!(f, " delta={}c", delta)?;
+        }
+        if let Some(sm_clock) = self.sm_clock {
+            write!(f, " sm={}MHz", sm_clock)?;
+        }
+        if let Some(mem_clock) = self.mem_clock {
+            write!(f, " mem={}MHz", mem_clock)?;
+        }
+        write!(
+            f,
+            " speed={} source={} latency={:.1}ms mode={}",
+            crate::Duty(self.speed),
+            self.source,
+            self.latency.as_secs_f64() * 1000.0,
+            self.mode.name(),
+        )?;
+        if self.rpm.iter().any(Option::is_some) {
+            write!(f, " rpm={}", Rpms(&self.rpm))?;
+        }
+        if self.extra_outputs.count > 0 {
+            write!(f, " extra_outputs={}", self.extra_outputs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Each channel's RPM as e.g. "1200/?", for the plain text output.
+pub struct Rpms<'a>(pub &'a [Option<u16>]);
+
+impl std::fmt::Display for Rpms<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, rpm) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            match rpm {
+                Some(rpm) => write!(f, "{}", rpm)?,
+                None => f.write_str("?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which of the --extra-output-command outputs are failing, kept as bits
+/// rather than a list so a sample doesn't allocate.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OutputHealth {
+    pub count: usize,
+    /// Bit i set while output i is failing
+    pub failing: u32,
+}
+
+impl OutputHealth {
+    /// The most extra outputs there can be
+    pub const MAX: usize = 32;
+
+    pub fn is_failing(&self, i: usize) -> bool {
+        self.failing & (1 << i) != 0
+    }
+}
+
+/// Each output's state in order, e.g. "ok/FAILED".
+impl std::fmt::Display for OutputHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for i in 0..self.count {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            f.write_str(if self.is_failing(i) { "FAILED" } else { "ok" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Columns of `Sample::csv`, with an rpm column for each of the controller's
+/// channels. New ones go on the end, so logs started by an older version
+/// still line up.
+pub fn csv_header() -> String {
+    let rpms: Vec<String> = (0..CHANNELS).map(|channel| format!("rpm{}", channel)).collect();
+    format!(
+        "time,temp,power_pct,temp_delta,sm_clock,mem_clock,speed,source,latency_ms,mode,{},extra_outputs",
+        rpms.join(","),
+    )
+}
+
+/// A sample as a line of CSV under `csv_header`, missing readings left blank.
+pub struct Csv<'a>(&'a Sample);
+
+impl std::fmt::Display for Csv<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sample = self.0;
+        // Written field by field, since this goes out every cycle; chrono's
+        // own formatting builds a String first
+        let time = sample.time;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02},",
+            time.year(),
+            time.month(),
+            time.day(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+        )?;
+        if let Some(temp) = sample.temp {
+            write!(f, "{}", temp)?;
+        }
+        f.write_str(",")?;
+        if let Some(power) = sample.power {
+            write!(f, "{:.1}", power * 100.0)?;
+        }
+        f.write_str(",")?;
+        if let Some(delta) = sample.temp_delta {
+            write!(f, "{}", delta)?;
+        }
+        f.write_str(",")?;
+        if let Some(sm_clock) = sample.sm_clock {
+            write!(f, "{}", sm_clock)?;
+        }
+        f.write_str(",")?;
+        if let Some(mem_clock) = sample.mem_clock {
+            write!(f, "{}", mem_clock)?;
+        }
+        write!(
+            f,
+            ",{},{},{:.1},{}",
+            sample.speed,
+            sample.source,
+            sample.latency.as_secs_f64() * 1000.0,
+            sample.mode.name(),
+        )?;
+        for rpm in &sample.rpm {
+            f.write_str(",")?;
+            if let Some(rpm) = rpm {
+                write!(f, "{}", rpm)?;
+            }
+        }
+        write!(f, ",{}", sample.extra_outputs)
+    }
+}
+
+/// The `--telemetry-log` file. Once it reaches its size limit it's moved
+/// aside to `<name>.1`, replacing any older one, and a fresh file started, so
+/// a box left running for months can't fill its disk.
+pub struct TelemetryLog {
+    path: PathBuf,
+    file: std::fs::File,
+    len: u64,
+    max_len: u64,
+    /// Reused for each line, so the control loop doesn't allocate one
+    line: String,
+}
+
+impl TelemetryLog {
+    pub fn open(path: &Path, max_len: u64) -> std::io::Result<Self> {
+        let mut log = TelemetryLog {
+            path: path.to_owned(),
+            file: Self::open_file(path)?,
+            len: 0,
+            max_len,
+            line: String::new(),
+        };
+        log.len = log.file.metadata()?.len();
+        if log.len == 0 {
+            log.write_line(&csv_header())?;
+        }
+        Ok(log)
+    }
+
+    fn open_file(path: &Path) -> std::io::Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.len += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> std::io::Result<()> {
+        if self.len >= self.max_len {
+            let mut rotated = self.path.as_os_str().to_owned();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            self.file = Self::open_file(&self.path)?;
+            self.len = 0;
+            self.write_line(&csv_header())?;
+        }
+        let mut line = std::mem::take(&mut self.line);
+        line.clear();
+        let _ = std::fmt::Write::write_fmt(&mut line, format_args!("{}", sample.csv()));
+        let written = self.write_line(&line);
+        self.line = line;
+        written
+    }
+}
+
+/// A sample read back from a `--telemetry-log` file or a debug bundle.
+pub struct LoggedSample {
+    pub time: NaiveDateTime,
+    pub temp: Option<u32>,
+    /// Fraction of the power limit
+    pub power: Option<f64>,
+    pub speed: Option<u8>,
+}
+
+impl LoggedSample {
+    /// The samples in a telemetry log or debug bundle, oldest first.
+    pub fn load(path: &Path) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        // A bundle has other sections first, and nothing after the samples
+        // that would parse as one
+        let csv = match contents.split_once(TELEMETRY_SECTION) {
+            Some((_, csv)) => csv,
+            None => &contents,
+        };
+        let mut lines = csv.lines().map(str::trim).filter(|line| !line.is_empty());
+        let header: Vec<&str> = lines.next().ok_or_else(|| format!("{} is empty", path.display()))?
+            .split(',')
+            .collect();
+        let column = |name: &str| header.iter()
+            .position(|&column| column == name)
+            .ok_or_else(|| format!("{} has no {} column", path.display(), name));
+        let (time, temp, power, speed) = (column("time")?, column("temp")?, column("power_pct")?, column("speed")?);
+
+        let mut samples = vec![];
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').collect();
+            let Some(Ok(time)) = fields.get(time).map(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S")) else {
+                continue
+            };
+            let field = |i: usize| fields.get(i).copied().unwrap_or("");
+            samples.push(LoggedSample {
+                time,
+                temp: field(temp).parse().ok(),
+                power: field(power).parse::<f64>().ok().map(|power| power / 100.0),
+                speed: field(speed).parse().ok(),
+            });
+        }
+        samples.sort_by_key(|sample| sample.time);
+        Ok(samples)
+    }
+}
+
+/// The most recent control loop samples.
+pub struct Telemetry {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+}
+
+impl Telemetry {
+    pub fn new(capacity: usize) -> Self {
+        Telemetry {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The last `n` samples, oldest first.
+    pub fn recent(&self, n: usize) -> impl Iterator<Item = &Sample> {
+        self.samples.iter().skip(self.samples.len().saturating_sub(n))
+    }
+
+    pub fn record(&mut self, sample: Sample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+/// Identifying details that people may not want to publish.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IdentityField {
+    Uuid,
+    Hostname,
+    Serial,
+}
+
+impl IdentityField {
+    pub fn name(self) -> &'static str {
+        match self {
+            IdentityField::Uuid => "uuid",
+            IdentityField::Hostname => "hostname",
+            IdentityField::Serial => "serial",
+        }
+    }
+}
+
+impl std::str::FromStr for IdentityField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid" => Ok(IdentityField::Uuid),
+            "hostname" => Ok(IdentityField::Hostname),
+            "serial" => Ok(IdentityField::Serial),
+            _ => Err(format!("Unknown field {}; expected uuid, hostname or serial", s)),
+        }
+    }
+}
+
+pub fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Heads the debug bundle's CSV of samples, so `chart` can find it.
+pub const TELEMETRY_SECTION: &str = "== telemetry ==";
+
+/// Writes a single text file with everything needed to make sense of a bug
+/// report. The values of any `identity` fields listed in `redact` are blanked
+/// out wherever they appear.
+pub fn write_debug_bundle(
+    dir: &Path,
+    config: &str,
+    telemetry: &Telemetry,
+    counters: &Counters,
+    identity: &[(IdentityField, String)],
+    redact: &[IdentityField],
+) -> std::io::Result<PathBuf> {
+    let mut bundle = String::new();
+    bundle += &format!("{} {} debug bundle\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    bundle += &format!("created {}\n", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    for (field, value) in identity {
+        bundle += &format!("{}: {}\n", field.name(), value);
+    }
+    bundle += "\n";
+
+    bundle += "== config ==\n";
+    bundle += config;
+    bundle += "\n\n== counters ==\n";
+    bundle += &counters.to_string();
+    bundle += "\n== events ==\n";
+    for event in EVENTS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        bundle += event;
+        bundle += "\n";
+    }
+
+    bundle += &format!("\n{}\n{}\n", TELEMETRY_SECTION, csv_header());
+    for sample in &telemetry.samples {
+        bundle += &format!("{}\n", sample.csv());
+    }
+
+    for (field, value) in identity {
+        if redact.contains(field) && !value.is_empty() {
+            bundle = bundle.replace(value.as_str(), &format!("<{} redacted>", field.name()));
+        }
+    }
+
+    let path = dir.join(format!(
+        "tesla_fan-debug-{}.txt",
+        Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    std::fs::File::create(&path)?.write_all(bundle.as_bytes())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Sample {
+        Sample {
+            temp: Some(65),
+            power: Some(0.5),
+            speed: 120,
+            rpm: [Some(1500); CHANNELS],
+            source: "curve",
+            mode: Mode::Normal,
+            ..Sample::offline(None)
+        }
+    }
+
+    #[test]
+    fn csv_has_an_rpm_column_per_channel() {
+        let header = csv_header();
+        let columns: Vec<&str> = header.split(',').collect();
+        for channel in 0..CHANNELS {
+            assert!(columns.contains(&format!("rpm{}", channel).as_str()));
+        }
+        assert_eq!(sample().csv().to_string().split(',').count(), columns.len());
+    }
+
+    #[test]
+    fn telemetry_log_rotates_at_its_limit() {
+        let dir = std::env::temp_dir().join(format!("tesla_fan-rotate-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("telemetry.csv");
+        let line_len = sample().csv().to_string().len() as u64 + 1;
+        let header_len = csv_header().len() as u64 + 1;
+        let mut log = TelemetryLog::open(&path, header_len + 2 * line_len).unwrap();
+        for _ in 0..3 {
+            log.write(&sample()).unwrap();
+        }
+        let current = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(dir.join("telemetry.csv.1")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(rotated.lines().count(), 3);
+        assert_eq!(current.lines().count(), 2);
+        assert_eq!(current.lines().next(), Some(csv_header().as_str()));
+    }
+
+    #[test]
+    fn logged_samples_read_back() {
+        let path = std::env::temp_dir().join(format!("tesla_fan-telemetry-test-{}.csv", std::process::id()));
+        std::fs::write(&path, format!("{}\n{}\n", csv_header(), sample().csv())).unwrap();
+        let samples = LoggedSample::load(&path);
+        let _ = std::fs::remove_file(&path);
+        let samples = samples.unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].temp, Some(65));
+        assert_eq!(samples[0].power, Some(0.5));
+        assert_eq!(samples[0].speed, Some(120));
+    }
+}
@@ -0,0 +1,338 @@
+//! Replaying fan curves against traces of typical workloads, to see how a
+//! curve change would have played out before it goes near the hardware.
+//!
+//! The built-in traces are in `traces/` (see the README there); they were
+//! made with the same model of the card the replay uses, not recorded. Each
+//! gives the power the GPU drew over time. The replay takes that through the
+//! control loop's `decision` (the minute of history, the curve, the boost and
+//! the critical temperature) and works out where the temperature would go
+//! from a model of the card built from m40_measurements.txt. A telemetry log
+//! from a real card can be replayed the same way.
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::decision::{self, History};
+use crate::measurements::Measurement;
+use crate::telemetry::{LoggedSample, emit, json_string};
+use crate::{StartupHistory, Tunables};
+
+const BUILT_IN: &[(&str, &str)] = &[
+    ("idle", include_str!("../traces/idle.txt")),
+    ("training_ramp", include_str!("../traces/training_ramp.txt")),
+    ("bursty_inference", include_str!("../traces/bursty_inference.txt")),
+    ("cooldown", include_str!("../traces/cooldown.txt")),
+];
+
+const MODEL_MEASUREMENTS: &str = include_str!("../m40_measurements.txt");
+/// Where the measurements with the fan off line up, in degrees C
+const MODEL_AMBIENT: f64 = 24.0;
+/// How long the card takes to get most of the way to a new temperature
+const MODEL_TIME_CONSTANT: f64 = 90.0;
+
+#[derive(Copy, Clone, Debug)]
+struct TraceSample {
+    seconds: f64,
+    /// Fraction of the power limit, 0.0-1.0
+    power: f64,
+    temp: u32,
+}
+
+impl std::str::FromStr for TraceSample {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let mut field = || fields.next().ok_or("expected seconds, power% and temp");
+        let seconds = field()?.parse()?;
+        let power: f64 = field()?.trim_end_matches('%').parse()?;
+        let temp = field()?.trim_end_matches(['c', 'C']).parse()?;
+        Ok(TraceSample {
+            seconds,
+            power: power / 100.0,
+            temp,
+        })
+    }
+}
+
+pub struct Trace {
+    name: String,
+    samples: Vec<TraceSample>,
+}
+
+impl Trace {
+    fn parse(name: &str, contents: &str) -> Result<Self, Box<dyn Error>> {
+        let samples = contents.lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                line.parse()
+                    .map_err(|e| format!("{}:{}: {}", name, i + 1, e).into())
+            })
+            .collect::<Result<Vec<TraceSample>, Box<dyn Error>>>()?;
+        Trace::new(name, samples)
+    }
+
+    /// The readings in a --telemetry-log file, skipping failed reads.
+    fn from_log(name: &str, logged: &[LoggedSample]) -> Result<Self, Box<dyn Error>> {
+        let Some(start) = logged.first().map(|sample| sample.time) else {
+            Err(format!("{}: no samples", name))?
+        };
+        let samples = logged.iter()
+            .filter_map(|sample| Some(TraceSample {
+                seconds: (sample.time - start).num_milliseconds() as f64 / 1000.0,
+                power: sample.power?,
+                temp: sample.temp?,
+            }))
+            .collect();
+        Trace::new(name, samples)
+    }
+
+    fn new(name: &str, samples: Vec<TraceSample>) -> Result<Self, Box<dyn Error>> {
+        if samples.len() < 2 {
+            Err(format!("{}: needs at least two samples", name))?
+        }
+        if samples.windows(2).any(|pair| pair[1].seconds <= pair[0].seconds) {
+            Err(format!("{}: samples must go forward in time", name))?
+        }
+        Ok(Trace {
+            name: name.to_string(),
+            samples,
+        })
+    }
+
+    /// How many samples make up the control loop's minute of history, going
+    /// by how far apart the first two are.
+    fn history_samples(&self) -> usize {
+        decision::history_samples(self.samples[1].seconds - self.samples[0].seconds)
+    }
+}
+
+/// The traces that come with us.
+pub fn built_in() -> Result<Vec<Trace>, Box<dyn Error>> {
+    BUILT_IN.iter()
+        .map(|(name, contents)| Trace::parse(name, contents))
+        .collect()
+}
+
+/// Every `.txt` trace and `.csv` telemetry log in `dir`, by file name.
+pub fn load_dir(dir: &Path) -> Result<Vec<Trace>, Box<dyn Error>> {
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|extension| extension == "txt" || extension == "csv"));
+    paths.sort();
+    if paths.is_empty() {
+        Err(format!("No .txt traces or .csv telemetry logs in {}", dir.display()))?
+    }
+    paths.iter()
+        .map(|path| {
+            let name = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+            if path.extension().is_some_and(|extension| extension == "csv") {
+                return Trace::from_log(&name, &LoggedSample::load(path)?)
+            }
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            Trace::parse(&name, &contents)
+        })
+        .collect()
+}
+
+/// Steady state temperature for a power and duty, from how many degrees over
+/// ambient each percent of power was worth at the measured duties.
+struct Model {
+    /// (duty, degrees per percent of the power limit), by duty
+    resistance: Vec<(f64, f64)>,
+}
+
+impl Model {
+    fn from_measurements(measurements: &[Measurement]) -> Self {
+        let mut duties: Vec<u8> = measurements.iter().map(|m| m.duty).collect();
+        duties.sort();
+        duties.dedup();
+        let resistance = duties.into_iter()
+            .map(|duty| {
+                let at_duty: Vec<f64> = measurements.iter()
+                    .filter(|m| m.duty == duty && m.power > 0.0)
+                    .map(|m| (m.temp as f64 - MODEL_AMBIENT) / (m.power * 100.0))
+                    .collect();
+                (duty as f64, at_duty.iter().sum::<f64>() / at_duty.len().max(1) as f64)
+            })
+            .collect();
+        Model { resistance }
+    }
+
+    fn steady_temp(&self, power: f64, duty: u8) -> f64 {
+        let duty = duty as f64;
+        let resistance = match self.resistance.iter().position(|(d, _)| *d >= duty) {
+            Some(0) => self.resistance[0].1,
+            Some(i) => {
+                let ((d0, r0), (d1, r1)) = (self.resistance[i - 1], self.resistance[i]);
+                r0 + (r1 - r0) * (duty - d0) / (d1 - d0)
+            },
+            None => self.resistance.last().map_or(0.0, |(_, r)| *r),
+        };
+        MODEL_AMBIENT + power * 100.0 * resistance
+    }
+}
+
+/// How a curve got on with a trace.
+#[derive(Copy, Clone, Debug)]
+struct Outcome {
+    peak: f64,
+    /// Seconds spent over the limit
+    over_limit: f64,
+    /// Fraction of the trace spent at full speed
+    at_max: f64,
+}
+
+/// Plays `trace` through `tunables`' power curve, boost and critical
+/// temperature, with the temperature coming from `model` rather than the
+/// trace after the first sample.
+fn replay(tunables: &Tunables, model: &Model, trace: &Trace, temp_limit: u32) -> Outcome {
+    let first = trace.samples[0];
+    let mut temp = first.temp as f64;
+    let mut outcome = Outcome {
+        peak: temp,
+        over_limit: 0.0,
+        at_max: 0.0,
+    };
+    let mut history = History::new(trace.history_samples(), StartupHistory::WarmUp, first.temp, first.power);
+    for pair in trace.samples.windows(2) {
+        let (sample, next) = (pair[0], pair[1]);
+        let modelled_temp = temp.round() as u32;
+        let window = history.push(modelled_temp, sample.power, tunables.ema_alpha);
+        let power_speed = tunables.curves.fan_curve.lookup_speed(window.average_power);
+        let speed = decision::curves_speed(tunables, modelled_temp, Some(power_speed));
+        let speed = decision::decide(tunables, &window, speed, 0.0).speed;
+        let dt = next.seconds - sample.seconds;
+        temp += (model.steady_temp(sample.power, speed) - temp) * (1.0 - (-dt / MODEL_TIME_CONSTANT).exp());
+        outcome.peak = outcome.peak.max(temp);
+        if temp > temp_limit as f64 {
+            outcome.over_limit += dt;
+        }
+        if speed == 255 {
+            outcome.at_max += dt;
+        }
+    }
+    let length = trace.samples.last().map_or(0.0, |s| s.seconds) - first.seconds;
+    outcome.at_max /= length;
+    outcome
+}
+
+fn built_in_model() -> Result<Model, Box<dyn Error>> {
+    let measurements = MODEL_MEASUREMENTS.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect::<Result<Vec<Measurement>, _>>()
+        .map_err(|e| format!("Bad built-in measurements: {}", e))?;
+    Ok(Model::from_measurements(&measurements))
+}
+
+/// Prints how `tunables` and `default`, which differ only in their power
+/// curve, got on with each trace. Returns whether `tunables` kept every trace
+/// at or under `temp_limit`.
+pub fn check_curve(
+    tunables: &Tunables,
+    default: &Tunables,
+    traces: &[Trace],
+    temp_limit: u32,
+) -> Result<bool, Box<dyn Error>> {
+    let model = built_in_model()?;
+    let mut ok = true;
+    for trace in traces {
+        let outcome = replay(tunables, &model, trace, temp_limit);
+        let default = replay(default, &model, trace, temp_limit);
+        let passed = outcome.over_limit == 0.0;
+        ok &= passed;
+        emit(
+            format!(
+                "{} {}: peak {:.1}c, {:.0}s over {}c, {:.0}% at max (default curve: peak {:.1}c, {:.0}s over, {:.0}% at max)",
+                if passed { "ok:  " } else { "FAIL:" },
+                trace.name,
+                outcome.peak,
+                outcome.over_limit,
+                temp_limit,
+                outcome.at_max * 100.0,
+                default.peak,
+                default.over_limit,
+                default.at_max * 100.0,
+            ),
+            || format!(
+                r#"{{"trace":{},"peak":{:.1},"over_limit":{},"at_max":{:.3},"default_peak":{:.1},"default_over_limit":{},"default_at_max":{:.3},"ok":{}}}"#,
+                json_string(&trace.name),
+                outcome.peak,
+                outcome.over_limit,
+                outcome.at_max,
+                default.peak,
+                default.over_limit,
+                default.at_max,
+                passed,
+            ),
+        );
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DEFAULT_BOOST_AMOUNT, DEFAULT_BOOST_TEMP, DEFAULT_CRITICAL_TEMP, default_fan_speed_table};
+
+    fn flat_curve(critical_temp: u32, boost_temp: u32, boost_amount: u8) -> Tunables {
+        Tunables::for_power_curve("0:0,1:0".parse().unwrap(), critical_temp, boost_temp, boost_amount)
+    }
+
+    #[test]
+    fn the_default_curve_keeps_the_built_in_traces_under_critical() {
+        let model = built_in_model().unwrap();
+        let tunables = Tunables::for_power_curve(
+            default_fan_speed_table(),
+            DEFAULT_CRITICAL_TEMP,
+            DEFAULT_BOOST_TEMP,
+            DEFAULT_BOOST_AMOUNT,
+        );
+        for trace in built_in().unwrap() {
+            let outcome = replay(&tunables, &model, &trace, DEFAULT_CRITICAL_TEMP);
+            assert_eq!(outcome.over_limit, 0.0, "{} peaked at {:.1}c", trace.name, outcome.peak);
+        }
+    }
+
+    #[test]
+    fn the_boost_and_critical_temperature_step_in_for_a_curve() {
+        let model = built_in_model().unwrap();
+        let trace = built_in().unwrap().into_iter().find(|trace| trace.name == "training_ramp").unwrap();
+        let alone = replay(&flat_curve(255, 255, 0), &model, &trace, 80);
+        let boosted = replay(&flat_curve(255, 50, 100), &model, &trace, 80);
+        let critical = replay(&flat_curve(60, 60, 0), &model, &trace, 80);
+        assert_eq!(alone.at_max, 0.0);
+        assert!(boosted.peak < alone.peak - 20.0, "{:?} against {:?}", boosted, alone);
+        // Full speed once it gets there, which holds it not far over
+        assert!(critical.at_max > 0.5);
+        assert!(critical.peak < 70.0 && critical.over_limit == 0.0, "{:?}", critical);
+    }
+
+    #[test]
+    fn telemetry_logs_replay_without_their_failed_reads() {
+        let start = chrono::NaiveDateTime::default();
+        let logged = |seconds, temp, power| LoggedSample {
+            time: start + chrono::Duration::seconds(seconds),
+            temp,
+            power,
+            speed: None,
+        };
+        let trace = Trace::from_log("log", &[
+            logged(0, Some(40), Some(0.1)),
+            logged(5, None, None),
+            logged(10, Some(45), Some(0.6)),
+            logged(15, Some(50), Some(0.7)),
+        ]).unwrap();
+        let seconds: Vec<f64> = trace.samples.iter().map(|sample| sample.seconds).collect();
+        assert_eq!(seconds, [0.0, 10.0, 15.0]);
+        assert_eq!(trace.history_samples(), 6);
+        assert!(Trace::from_log("log", &[logged(0, None, None), logged(5, Some(40), Some(0.1))]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Settings written with their units, like "2s", "80C" or "180W", on the
+//! command line and in the config file. A bare number is taken in the unit
+//! the setting has always used: seconds, degrees C or watts.
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+const DURATION: &[(&str, f64)] = &[("ms", 0.001), ("s", 1.0), ("m", 60.0), ("h", 3600.0), ("d", 86400.0)];
+const TEMPERATURE: &[(&str, f64)] = &[("C", 1.0), ("°C", 1.0)];
+const POWER: &[(&str, f64)] = &[("W", 1.0), ("kW", 1000.0)];
+
+/// `s` in the base unit of `units`, where `what` and `example` are for errors.
+fn quantity(s: &str, what: &str, units: &[(&str, f64)], example: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+')).unwrap_or(s.len());
+    let (number, unit) = (&s[..split], s[split..].trim());
+    let expected = || {
+        let names: Vec<&str> = units.iter().map(|(name, _)| *name).collect();
+        let names = match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+            None => String::new(),
+        };
+        format!("expected a number, optionally followed by {}, e.g. {:?}", names, example)
+    };
+    let number: f64 = number.parse()
+        .map_err(|_| format!("Bad {} {:?}: {}", what, s, expected()))?;
+    let scale = match unit {
+        "" => 1.0,
+        _ => units.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|(_, scale)| *scale)
+            .ok_or_else(|| format!("Unknown unit {:?} in {} {:?}: {}", unit, what, s, expected()))?,
+    };
+    Ok(number * scale)
+}
+
+/// A length of time, e.g. "2s", "500ms" or "1.5m", in seconds.
+pub fn seconds(s: &str) -> Result<f64, String> {
+    duration(s).map(|duration| duration.as_secs_f64())
+}
+
+/// A length of time, e.g. "2s", "500ms" or "1.5m". Negative lengths and ones
+/// too long to represent are refused rather than left to panic later.
+pub fn duration(s: &str) -> Result<Duration, String> {
+    to_duration(s, quantity(s, "length of time", DURATION, "2s")?)
+}
+
+/// As `duration`, but a bare number is in minutes, for the settings that have
+/// always been given in minutes.
+pub fn minutes(s: &str) -> Result<Duration, String> {
+    let minutes = quantity(s, "length of time", DURATION, "30m")?;
+    let seconds = match s.trim().ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+        true => minutes * 60.0,
+        false => minutes,
+    };
+    to_duration(s, seconds)
+}
+
+fn to_duration(s: &str, seconds: f64) -> Result<Duration, String> {
+    if seconds < 0.0 {
+        Err(format!("Bad length of time {:?}: can't be negative", s.trim()))?
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|e| format!("Bad length of time {:?}: {}", s.trim(), e))
+}
+
+/// A temperature, e.g. "45.5C", in degrees C.
+pub fn celsius(s: &str) -> Result<f64, String> {
+    quantity(s, "temperature", TEMPERATURE, "80C")
+}
+
+/// A temperature in whole degrees C, e.g. "80C", as NVML reports them.
+pub fn whole_celsius(s: &str) -> Result<u32, String> {
+    let temp = celsius(s)?;
+    if temp < 0.0 || temp.fract() != 0.0 || temp > u32::MAX as f64 {
+        Err(format!("Bad temperature {:?}: expected whole degrees C, e.g. \"80C\"", s.trim()))?
+    }
+    Ok(temp as u32)
+}
+
+/// A power, e.g. "180W", in watts.
+pub fn watts(s: &str) -> Result<f64, String> {
+    quantity(s, "power", POWER, "180W")
+}
+
+/// A config file value that can be written either as a bare number or as a
+/// string with its unit.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum WithUnit {
+    Number(f64),
+    Text(String),
+}
+
+impl WithUnit {
+    /// The value of config key `key`, read with `parse`.
+    pub fn parse<T>(
+        value: &Option<WithUnit>,
+        key: &str,
+        parse: fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, Box<dyn Error>> {
+        let text = match value {
+            Some(WithUnit::Number(number)) => number.to_string(),
+            Some(WithUnit::Text(text)) => text.clone(),
+            None => return Ok(None),
+        };
+        Ok(Some(parse(&text).map_err(|e| format!("Bad {} in config: {}", key, e))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_units() {
+        assert_eq!(duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(duration("2d"), Ok(Duration::from_secs(2 * 86400)));
+        assert_eq!(duration(" 3 "), Ok(Duration::from_secs(3)));
+        assert_eq!(duration("0"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn bare_minutes_are_minutes() {
+        assert_eq!(minutes("3"), Ok(Duration::from_secs(180)));
+        assert_eq!(minutes("0.5"), Ok(Duration::from_secs(30)));
+        assert_eq!(minutes("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(minutes("2h"), Ok(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn negative_and_unrepresentable_durations_are_refused() {
+        for bad in ["-1s", "-1", "-0.5m", "1e400", "inf", "nan", "99999999999999999999999999d", "2x", ""] {
+            assert!(duration(bad).is_err(), "{:?} was accepted", bad);
+            assert!(minutes(bad).is_err(), "{:?} was accepted", bad);
+            assert!(seconds(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn temperatures_and_powers() {
+        assert_eq!(celsius("45.5C"), Ok(45.5));
+        assert_eq!(celsius("80"), Ok(80.0));
+        assert_eq!(whole_celsius("80c"), Ok(80));
+        assert!(whole_celsius("80.5C").is_err());
+        assert!(whole_celsius("-5C").is_err());
+        assert_eq!(watts("180W"), Ok(180.0));
+        assert_eq!(watts("1.2kW"), Ok(1200.0));
+        assert!(watts("180F").is_err());
+    }
+
+    #[test]
+    fn config_values_with_or_without_units() {
+        let parsed = WithUnit::parse(&Some(WithUnit::Number(5.0)), "update_interval", seconds).unwrap();
+        assert_eq!(parsed, Some(5.0));
+        let parsed = WithUnit::parse(&Some(WithUnit::Text("500ms".to_string())), "update_interval", seconds).unwrap();
+        assert_eq!(parsed, Some(0.5));
+        assert!(WithUnit::parse(&Some(WithUnit::Text("-1s".to_string())), "update_interval", seconds).is_err());
+        assert_eq!(WithUnit::parse(&None, "update_interval", seconds).unwrap(), None);
+    }
+}
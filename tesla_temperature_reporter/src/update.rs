@@ -0,0 +1,241 @@
+//! Checking the project's GitHub releases for something newer than this build.
+//!
+//! This goes through `curl` rather than pulling an HTTP and TLS stack into
+//! every build for the sake of one request; it's there on nearly every Linux
+//! box and on Windows 10 and later.
+//!
+//! Each release binary is published with a `<binary>.sha256` next to it, and
+//! nothing gets installed unless the download matches it.
+
+use std::error::Error;
+use std::path::Path;
+use std::process::Command;
+
+const RELEASES_URL: &str =
+    "https://api.github.com/repos/aprilwade/NvidiaTeslaExternalFanController/releases/latest";
+
+pub struct Release {
+    pub version: String,
+    /// Download URL of the binary for this platform, if the release has one
+    pub binary_url: Option<String>,
+    /// Download URL of the binary's SHA-256, if the release has one
+    pub checksum_url: Option<String>,
+}
+
+impl Release {
+    pub fn is_newer(&self) -> bool {
+        is_newer(&self.version, env!("CARGO_PKG_VERSION"))
+    }
+}
+
+/// Whether `version` comes after `current`, with missing parts counting as 0
+/// so that "1.2" and "1.2.0" are the same.
+fn is_newer(version: &str, current: &str) -> bool {
+    let mut version = parse_version(version);
+    let mut current = parse_version(current);
+    let len = version.len().max(current.len());
+    version.resize(len, 0);
+    current.resize(len, 0);
+    version > current
+}
+
+/// Release binaries are named like `tesla_temperature_reporter-linux-x86_64`.
+fn binary_name() -> String {
+    format!(
+        "{}-{}-{}{}",
+        env!("CARGO_PKG_NAME"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::env::consts::EXE_SUFFIX,
+    )
+}
+
+/// "v1.2.3" as [1, 2, 3]; anything after a '-' is ignored.
+fn parse_version(version: &str) -> Vec<u64> {
+    version.trim_start_matches('v')
+        .split('-')
+        .next()
+        .unwrap_or("")
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Every string value of `key` in `json`, without bothering to parse the rest.
+fn json_strings<'a>(json: &'a str, key: &str) -> Vec<&'a str> {
+    let pattern = format!("\"{}\"", key);
+    json.match_indices(&pattern)
+        .filter_map(|(i, _)| {
+            let rest = json[i + pattern.len()..].trim_start().strip_prefix(':')?.trim_start();
+            let rest = rest.strip_prefix('"')?;
+            Some(&rest[..rest.find('"')?])
+        })
+        .collect()
+}
+
+fn curl(args: &[&str]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let output = Command::new("curl")
+        .args(["-fsSL", "-H", "User-Agent: tesla_temperature_reporter"])
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+    if !output.status.success() {
+        Err(format!("curl failed: {}", String::from_utf8_lossy(&output.stderr).trim()))?
+    }
+    Ok(output.stdout)
+}
+
+pub fn latest_release() -> Result<Release, Box<dyn Error>> {
+    let body = String::from_utf8(curl(&[RELEASES_URL])?)?;
+    let version = json_strings(&body, "tag_name")
+        .first()
+        .ok_or("No tag_name in the latest release")?
+        .to_string();
+    let binary_name = binary_name();
+    let checksum_name = format!("{}.sha256", binary_name);
+    let urls = json_strings(&body, "browser_download_url");
+    let asset = |name: &str| urls.iter()
+        .find(|url| url.rsplit('/').next() == Some(name))
+        .map(|url| url.to_string());
+    Ok(Release {
+        version,
+        binary_url: asset(&binary_name),
+        checksum_url: asset(&checksum_name),
+    })
+}
+
+/// Downloads `release`'s binary and puts it in place of the running one.
+pub fn install(release: &Release) -> Result<(), Box<dyn Error>> {
+    let url = release.binary_url.as_deref()
+        .ok_or_else(|| format!("Release {} has no {} to install", release.version, binary_name()))?;
+    let checksum_url = release.checksum_url.as_deref()
+        .ok_or_else(|| format!("Release {} has no checksum for {}, so it can't be checked", release.version, binary_name()))?;
+    // "<hex digest>  <file name>", as sha256sum writes it, or just the digest
+    let checksum = String::from_utf8(curl(&[checksum_url])?)?;
+    let expected = checksum.split_whitespace()
+        .next()
+        .ok_or("The release's checksum file is empty")?
+        .to_ascii_lowercase();
+    let current = std::env::current_exe()?;
+    let download = current.with_extension("download");
+    curl(&["-o", path_str(&download)?, url])?;
+    let actual = hex(&sha256(&std::fs::read(&download)?));
+    if actual != expected {
+        let _ = std::fs::remove_file(&download);
+        Err(format!("The download's SHA-256 is {}, but the release says {}; not installing it", actual, expected))?
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&download, std::fs::Permissions::from_mode(0o755))?;
+    }
+    // Windows won't let a running executable be replaced, only renamed
+    #[cfg(windows)]
+    let old = current.with_extension("old");
+    #[cfg(windows)]
+    std::fs::rename(&current, &old)?;
+    if let Err(e) = std::fs::rename(&download, &current) {
+        // Put the running binary back rather than leave nothing to start next time
+        #[cfg(windows)]
+        if let Err(restore) = std::fs::rename(&old, &current) {
+            Err(format!(
+                "Failed to replace {}: {}, then failed to restore it from {}: {}",
+                current.display(), e, old.display(), restore
+            ))?
+        }
+        Err(format!("Failed to replace {}: {}", current.display(), e))?
+    }
+    Ok(())
+}
+
+/// SHA-256 of `data`, as in FIPS 180-4.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 32];
+    for (out, word) in digest.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn path_str(path: &Path) -> Result<&str, Box<dyn Error>> {
+    Ok(path.to_str().ok_or("path isn't valid UTF-8")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_version_parts_are_zero() {
+        assert!(!is_newer("1.2", "1.2.0"));
+        assert!(!is_newer("v1.2.0", "1.2"));
+        assert!(is_newer("1.2.1", "1.2"));
+        assert!(is_newer("v1.10", "1.9.9"));
+        assert!(!is_newer("1.2.0-rc1", "1.2.0"));
+    }
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Two blocks once padded
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
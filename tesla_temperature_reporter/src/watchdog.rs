@@ -0,0 +1,29 @@
+//! Optional dead-man switch: a hardware/software watchdog device that resets
+//! the machine unless we keep petting it, which we only do while healthy.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+pub struct Watchdog {
+    file: File,
+}
+
+impl Watchdog {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(Watchdog { file })
+    }
+
+    pub fn pet(&mut self) -> std::io::Result<()> {
+        self.file.write_all(b"\0")?;
+        self.file.flush()
+    }
+
+    /// Tells the driver we're stopping on purpose (the "magic close"), so a
+    /// clean shutdown doesn't reset the machine.
+    pub fn disarm(mut self) -> std::io::Result<()> {
+        self.file.write_all(b"V")?;
+        self.file.flush()
+    }
+}